@@ -4,7 +4,10 @@ use futures::executor::block_on;
 use futures::join;
 use log;
 use nundb::*;
-use signal_hook::{consts::SIGINT, iterator::Signals};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT},
+    iterator::Signals,
+};
 use std::thread;
 
 use std::sync::Arc;
@@ -13,8 +16,12 @@ use clap::ArgMatches;
 use env_logger::{Builder, Env, Target};
 
 use nundb::configuration::{
-    NUN_HTTP_ADDR, NUN_LOG_LEVEL, NUN_PWD, NUN_REPLICATE_ADDR, NUN_TCP_ADDR, NUN_USER, NUN_WS_ADDR,
+    NUN_CLUSTER_SECRET, NUN_HTTP_ADDR, NUN_LOG_LEVEL, NUN_PWD, NUN_REPLICATE_ADDR, NUN_RESP_ADDR,
+    NUN_TCP_ADDR, NUN_TLS_CA, NUN_TLS_CERT, NUN_TLS_KEY, NUN_TLS_LISTENERS, NUN_USER, NUN_WS_ADDR,
 };
+use nundb::network::listener_ops::{init_listeners_limits, ListenersLimits};
+use nundb::network::tls_ops::{init_tls, reload_tls, TlsConfig};
+use nundb::storage::encryption::{init_encryption, Keyring};
 
 fn init_logger() {
     let env = Env::default().filter_or("NUN_LOG_LEVEL", NUN_LOG_LEVEL.as_str());
@@ -33,6 +40,38 @@ fn main() -> Result<(), String> {
         let tcp_address = start_match
             .value_of("tcp-address")
             .unwrap_or(NUN_TCP_ADDR.as_str());
+        let tls_config = TlsConfig::new(
+            start_match
+                .value_of("tls-cert")
+                .unwrap_or(NUN_TLS_CERT.as_str()),
+            start_match
+                .value_of("tls-key")
+                .unwrap_or(NUN_TLS_KEY.as_str()),
+            start_match
+                .value_of("tls-ca")
+                .unwrap_or(NUN_TLS_CA.as_str()),
+            start_match
+                .value_of("tls-listeners")
+                .unwrap_or(NUN_TLS_LISTENERS.as_str()),
+        );
+        if let Err(e) = init_tls(tls_config) {
+            println!("Invalid TLS configuration: {}", e);
+            std::process::exit(1);
+        }
+        match ListenersLimits::from_env() {
+            Ok(limits) => init_listeners_limits(limits),
+            Err(e) => {
+                println!("Invalid listeners configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+        match Keyring::from_env() {
+            Ok(keyring) => init_encryption(keyring),
+            Err(e) => {
+                println!("Invalid encryption configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
         return start_db(
             matches.value_of("user").unwrap_or(NUN_USER.as_str()),
             matches.value_of("pwd").unwrap_or(NUN_PWD.as_str()),
@@ -43,12 +82,18 @@ fn main() -> Result<(), String> {
                 .value_of("http-address")
                 .unwrap_or(NUN_HTTP_ADDR.as_str()),
             tcp_address,
+            start_match
+                .value_of("resp-address")
+                .unwrap_or(NUN_RESP_ADDR.as_str()),
             start_match
                 .value_of("replicate-address")
                 .unwrap_or(NUN_REPLICATE_ADDR.as_str()),
             start_match
                 .value_of("external-address")
                 .unwrap_or(tcp_address),
+            start_match
+                .value_of("cluster-secret")
+                .unwrap_or(NUN_CLUSTER_SECRET.as_str()),
         );
     } else {
         return nundb::command_line::commands::exec_command(&matches);
@@ -61,8 +106,10 @@ fn start_db(
     ws_address: &str,
     http_address: &str,
     tcp_address: &str,
+    resp_address: &str,
     replicate_address: &str,
     external_tcpaddress: &str,
+    cluster_secret: &str,
 ) -> Result<(), String> {
     if user == "" || pwd == "" {
         println!("NUN_USER and NUN_PWD must be provided via command line (nun-db -u $USER -p $PWD ...) or env var.");
@@ -96,14 +143,28 @@ fn start_db(
         is_oplog_valid,
     );
 
+    if cluster_secret == "" {
+        log::warn!(
+            "NUN_CLUSTER_SECRET is not set, the nodes will use NUN_PWD to authenticate each other"
+        );
+    }
+    dbs.set_cluster_secret(cluster_secret);
+
     Databases::load_all_dbs(&dbs);
-    let mut signals = Signals::new(&[SIGINT]).unwrap();
+    let mut signals = Signals::new(&[SIGINT, SIGHUP]).unwrap();
     let dbs_to_signal = dbs.clone();
     thread::spawn(move || {
         for sig in signals.forever() {
             println!("Received signal {:?}", sig);
-            db_ops::safe_shutdown(&dbs_to_signal);
-            std::process::exit(0);
+            if sig == SIGHUP {
+                match reload_tls() {
+                    Ok(_) => log::info!("TLS certificates reloaded"),
+                    Err(e) => log::error!("Error reloading the TLS certificates: {}", e),
+                }
+            } else {
+                db_ops::safe_shutdown(&dbs_to_signal);
+                std::process::exit(0);
+            }
         }
     });
 
@@ -145,8 +206,7 @@ fn start_db(
             &replicate_address_to_thread,
             &tcp_address_to_election.to_string(),
             &external_tcpaddress.to_string(),
-            &dbs_self_election.user.to_string(),
-            &dbs_self_election.pwd.to_string(),
+            &dbs_self_election.cluster_secret(),
         );
         nundb::election_ops::start_inital_election(dbs_self_election)
     });
@@ -170,6 +230,14 @@ fn start_db(
     let _http_thread =
         thread::spawn(|| nundb::network::http_ops::start_http_client(db_http, http_address));
 
+    if resp_address != "" {
+        let db_resp = dbs.clone();
+        let resp_address = resp_address.to_string();
+        let _resp_thread = thread::spawn(move || {
+            nundb::network::resp_ops::start_resp_client(db_resp, &resp_address)
+        });
+    }
+
     let join_all_promises = async {
         join!(replication_thread_creator, replication_thread);
     };
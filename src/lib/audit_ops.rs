@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bo::*;
+use crate::security::clean_string_to_log;
+
+// Number of entries kept in memory for the audit-log command
+const MAX_RECENT_ENTRIES: usize = 1000;
+
+/// One line of the audit trail
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub time: u64,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub result: String,
+}
+
+pub struct AuditLog {
+    recent: Mutex<VecDeque<AuditEntry>>,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    /// Entries are appended to `path` when it is not empty
+    pub fn new(path: &str) -> AuditLog {
+        let file = if path.is_empty() {
+            None
+        } else {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    log::error!("Could not open the audit log {}: {}", path, e);
+                    None
+                }
+            }
+        };
+        AuditLog {
+            recent: Mutex::new(VecDeque::new()),
+            file: Mutex::new(file),
+        }
+    }
+
+    pub fn append(&self, entry: AuditEntry) {
+        let line = serde_json::to_string(&entry).unwrap();
+        log::info!("[audit] {}", line);
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            if let Err(e) = file.write_all(format!("{}\n", line).as_bytes()) {
+                log::error!("Error writing to the audit log: {}", e);
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == MAX_RECENT_ENTRIES {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    /// Last `count` entries, oldest first
+    pub fn recent(&self, count: usize) -> Vec<AuditEntry> {
+        let recent = self.recent.lock().unwrap();
+        recent
+            .iter()
+            .skip(recent.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Who is sending the request: the admin account, the database user, a node or anonymous
+pub fn audit_actor(client: &Client) -> String {
+    if client.is_cluster_node() {
+        return String::from("node");
+    }
+    if let Some(admin_user_name) = client.admin_user_name() {
+        return admin_user_name;
+    }
+    match (client.selected_db_name(), client.selected_db_user_name()) {
+        (Some(db_name), Some(user_name)) => format!("{}/{}", db_name, user_name),
+        (Some(db_name), None) => format!("{}/all", db_name),
+        _ => String::from("anonymous"),
+    }
+}
+
+pub fn audit_result(response: &Response) -> String {
+    match response {
+        Response::Error { msg } => format!("failure: {}", msg.trim_end()),
+        _ => String::from("success"),
+    }
+}
+
+impl Databases {
+    /// Records an operation in the audit trail, secrets are masked like in the logs
+    pub fn audit(self: &Arc<Databases>, actor: &str, action: &str, target: &str, result: &str) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        self.audit_log.append(AuditEntry {
+            time,
+            actor: clean_string_to_log(actor, self),
+            action: action.to_string(),
+            target: clean_string_to_log(target, self),
+            result: clean_string_to_log(result, self),
+        });
+    }
+}
+
+/// Audits the administrative requests once they are processed, auth and permission denied
+/// events are audited where they are checked
+pub fn audit_request(
+    dbs: &Arc<Databases>,
+    client: &Client,
+    request: &Request,
+    db_name: &Option<String>,
+    response: &Response,
+) {
+    let selected_db = db_name.clone().unwrap_or_default();
+    let (action, target) = match request {
+        Request::CreateDb { name, .. } => ("create-db", name.to_string()),
+        Request::CreateUser { user_name, .. } => {
+            ("create-user", format!("{}/{}", selected_db, user_name))
+        }
+        Request::RemoveUser { user_name } => {
+            ("remove-user", format!("{}/{}", selected_db, user_name))
+        }
+        Request::RotateUserToken { user_name, .. } => (
+            "rotate-user-token",
+            format!("{}/{}", selected_db, user_name),
+        ),
+        Request::RotateDbToken { .. } => ("rotate-db-token", selected_db),
+        Request::CreateAdmin {
+            user_name, roles, ..
+        } => (
+            "create-admin",
+            format!("{} {}", user_name, AdminRole::roles_to_str_value(roles)),
+        ),
+        Request::SetPermissions { user, permissions } => (
+            "set-permissions",
+            format!(
+                "{}/{} {}",
+                selected_db,
+                user,
+                Permission::permissions_to_str_value(permissions)
+            ),
+        ),
+        Request::SetRateLimits { limits } => (
+            "set-rate-limits",
+            format!("{} {}", selected_db, limits.to_str_value()),
+        ),
+        Request::Snapshot { db_names, .. } => ("snapshot", db_names.join("|")),
+        Request::SetPrimary { name } => ("set-primary", name.to_string()),
+        Request::Resolve {
+            opp_id,
+            db_name,
+            key,
+            ..
+        } => ("resolve", format!("{}/{} {}", db_name, key, opp_id)),
+        _ => return,
+    };
+    dbs.audit(
+        &audit_actor(client),
+        action,
+        &target,
+        &audit_result(response),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(action: &str) -> AuditEntry {
+        AuditEntry {
+            time: 1,
+            actor: String::from("admin"),
+            action: action.to_string(),
+            target: String::from("db"),
+            result: String::from("success"),
+        }
+    }
+
+    #[test]
+    fn should_append_entries_to_the_file_and_keep_the_recent_ones() {
+        let path = std::env::temp_dir().join("nun-audit-test.log");
+        let _ = std::fs::remove_file(&path);
+        let audit_log = AuditLog::new(path.to_str().unwrap());
+        for i in 0..(MAX_RECENT_ENTRIES + 2) {
+            audit_log.append(entry(&format!("action-{}", i)));
+        }
+        let recent = audit_log.recent(2);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(
+            recent[1].action,
+            format!("action-{}", MAX_RECENT_ENTRIES + 1)
+        );
+        assert_eq!(audit_log.recent(usize::MAX).len(), MAX_RECENT_ENTRIES);
+
+        let lines: Vec<AuditEntry> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), MAX_RECENT_ENTRIES + 2);
+        assert_eq!(lines[0], entry("action-0"));
+    }
+}
@@ -0,0 +1,629 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+use crate::bo::*;
+use crate::disk_ops::*;
+use crate::security::is_admin_account_key;
+use crate::storage::common::build_s3_client;
+use crate::storage::disk::NodeDrive;
+
+const S3_URI_PREFIX: &str = "s3://";
+const OP_TIME_SIZE: usize = 8;
+const OP_KEY_SIZE: usize = 8;
+const OP_DB_ID_SIZE: usize = 8;
+const OP_RECORD_SIZE: usize = OP_TIME_SIZE + OP_KEY_SIZE + OP_DB_ID_SIZE + 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseBackup {
+    pub name: String,
+    pub id: usize,
+    pub consensus_strategy: String,
+    pub values: Vec<(String, String, i32)>,
+}
+
+/// Point-in-time copy of all databases, the keys map and the oplog
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BackupArchive {
+    pub created_at: u64,
+    pub oplog_position: u64,
+    pub keys_map: HashMap<String, u64>,
+    pub op_log: Vec<u8>,
+    // Lines of the oplog values file, they are replayed to restore an earlier point in time
+    pub op_log_values: Vec<u8>,
+    pub databases: Vec<DatabaseBackup>,
+}
+
+impl BackupArchive {
+    /// Creates the archive from the in-memory state. The writers wait while the values of all
+    /// databases are copied, not while they are serialized or uploaded.
+    /// Only the admin accounts of the $admin database are included, its $$token comes from NUN_PWD.
+    pub fn from_dbs(dbs: &Arc<Databases>) -> BackupArchive {
+        let (oplog_position, keys_map, databases) = {
+            let dbs_map = dbs.map.read().unwrap();
+            // Holding the values of every database at once makes the copy a single point in time
+            let fenced: Vec<_> = dbs_map
+                .values()
+                .map(|db| (db, db.map.read().unwrap()))
+                .collect();
+            let oplog_position = Databases::next_op_log_id();
+            let keys_map = dbs.keys_map.read().unwrap().clone();
+            let databases = fenced
+                .iter()
+                .map(|(db, values)| DatabaseBackup::from_values(db, values))
+                .collect();
+            (oplog_position, keys_map, databases)
+        };
+        // Read after the copy, so the changes still on their way to the oplog are included
+        let op_log_values = read_op_log_values_bytes();
+        let op_log = read_op_log_bytes();
+        BackupArchive {
+            created_at: now_as_millis(),
+            oplog_position,
+            keys_map,
+            op_log,
+            op_log_values,
+            databases,
+        }
+    }
+}
+
+impl DatabaseBackup {
+    fn from_values(db: &Database, in_memory: &HashMap<String, Value>) -> DatabaseBackup {
+        let values = db
+            .all_values_with(in_memory)
+            .iter()
+            .filter(|(key, value)| {
+                value.state != ValueStatus::Deleted
+                    && (db.name != ADMIN_DB || is_admin_account_key(key))
+            })
+            .map(|(key, value)| (key.to_string(), value.value.to_string(), value.version))
+            .collect();
+        DatabaseBackup {
+            name: db.name.to_string(),
+            id: db.metadata.id,
+            consensus_strategy: db.metadata.consensus_strategy.to_string(),
+            values,
+        }
+    }
+
+    fn to_db(&self) -> Database {
+        let value_data: HashMap<String, Value> = self
+            .values
+            .iter()
+            .map(|(key, value, version)| {
+                (
+                    key.to_string(),
+                    Value {
+                        value: value.to_string(),
+                        version: *version,
+                        opp_id: Databases::next_op_log_id(),
+                        state: ValueStatus::New,
+                        value_disk_addr: 0,
+                        key_disk_addr: 0,
+                    },
+                )
+            })
+            .collect();
+        Database::create_db_from_value_hash(
+            self.name.to_string(),
+            value_data,
+            DatabaseMataData::new(
+                self.id,
+                ConsensuStrategy::from(self.consensus_strategy.to_string()),
+            ),
+        )
+    }
+}
+
+/// Writes a backup of all databases to a file path or to an s3://bucket/key uri
+pub fn backup(dbs: &Arc<Databases>, destination: &String) -> Result<BackupArchive, String> {
+    let archive = BackupArchive::from_dbs(dbs);
+    let bytes = match bincode::serialize(&archive) {
+        Ok(bytes) => bytes,
+        Err(e) => return Err(format!("Could not serialize the backup, {}", e)),
+    };
+    log::info!(
+        "Will write backup of {} dbs to {}, oplog position {}",
+        archive.databases.len(),
+        destination,
+        archive.oplog_position
+    );
+    write_archive_bytes(destination, bytes)?;
+    Ok(archive)
+}
+
+/// Rebuilds NUN_DBS_DIR from a backup archive, with `until` the databases are rewound to the
+/// state they had at that oplog id
+pub fn restore(source: &String, until: Option<u64>) -> Result<BackupArchive, String> {
+    let bytes = read_archive_bytes(source)?;
+    let mut archive: BackupArchive = match bincode::deserialize(&bytes) {
+        Ok(archive) => archive,
+        Err(e) => return Err(format!("Invalid backup archive {}, {}", source, e)),
+    };
+    if let Some(until) = until {
+        archive.databases = rewind(&archive, until)?;
+        archive.op_log = op_log_until(&archive.op_log, until);
+        archive.op_log_values = op_log_values_until(&archive.op_log_values, until);
+        archive.oplog_position = until;
+    }
+    for db_backup in &archive.databases {
+        let db = db_backup.to_db();
+        NodeDrive::storage_data_disk(&db, true, &db_backup.name);
+        log::info!("Restored db {}", db_backup.name);
+    }
+    let restored_ops = restore_op_log(
+        archive.keys_map.clone(),
+        &archive.op_log,
+        &archive.op_log_values,
+    );
+    log::info!("Restored {} oplog records", restored_ops);
+    Ok(archive)
+}
+
+// A line of the oplog values file
+struct ValueChange {
+    time: u64,
+    db: String,
+    key: String,
+    request: Request,
+}
+
+/// Parses the oplog values, returns the time they start at and the changes
+fn parse_op_log_values(op_log_values: &[u8]) -> Result<(Option<u64>, Vec<ValueChange>), String> {
+    let mut since = None;
+    let mut changes = Vec::new();
+    for line in String::from_utf8_lossy(op_log_values).lines() {
+        let (time, request_str) = match line.split_once(' ') {
+            Some((time, request_str)) => match time.parse::<u64>() {
+                Ok(time) => (time, request_str),
+                Err(_) => return Err(format!("Invalid oplog values line {}", line)),
+            },
+            None => return Err(format!("Invalid oplog values line {}", line)),
+        };
+        if request_str == OP_LOG_VALUES_HEADER {
+            since = Some(time);
+            continue;
+        }
+        let request = Request::parse(request_str)?;
+        let (db, key) = match &request {
+            Request::ReplicateSet { db, key, .. }
+            | Request::ReplicateIncrement { db, key, .. }
+            | Request::ReplicateRemove { db, key } => (db.to_string(), key.to_string()),
+            _ => return Err(format!("Invalid oplog values line {}", line)),
+        };
+        changes.push(ValueChange {
+            time,
+            db,
+            key,
+            request,
+        });
+    }
+    Ok((since, changes))
+}
+
+/// Replays the values of the keys changed after `until` up to it, the other keys kept the
+/// values of the backup. The databases created after `until` are dropped.
+fn rewind(archive: &BackupArchive, until: u64) -> Result<Vec<DatabaseBackup>, String> {
+    let (since, changes) = parse_op_log_values(&archive.op_log_values)?;
+    match since {
+        Some(since) if since <= until => (),
+        Some(since) => {
+            return Err(format!(
+                "The oplog of the backup has values since {}, can't restore until {}",
+                since, until
+            ))
+        }
+        None => return Err(String::from("The backup has no oplog values to replay")),
+    }
+    // Changes copied to the backup may be newer than its position, they are replayed as well
+    let replay_after = until.min(archive.oplog_position);
+    let created_after = dbs_created_after(&archive.op_log, until);
+    Ok(archive
+        .databases
+        .iter()
+        .filter(|db_backup| !created_after.contains(&(db_backup.id as u64)))
+        .map(|db_backup| {
+            let db_changes: Vec<&ValueChange> = changes
+                .iter()
+                .filter(|change| change.db == db_backup.name)
+                .collect();
+            let changed_keys: HashSet<&String> = db_changes
+                .iter()
+                .filter(|change| change.time > replay_after)
+                .map(|change| &change.key)
+                .collect();
+            let db = db_backup.to_db();
+            db.map
+                .write()
+                .unwrap()
+                .retain(|key, _| !changed_keys.contains(key));
+            for change in db_changes
+                .iter()
+                .filter(|change| change.time <= until && changed_keys.contains(&change.key))
+            {
+                replay_change(&db, &change.request);
+            }
+            let values = db.map.read().unwrap();
+            DatabaseBackup::from_values(&db, &values)
+        })
+        .collect())
+}
+
+fn replay_change(db: &Database, request: &Request) {
+    let response = match request {
+        Request::ReplicateSet {
+            key,
+            value,
+            version,
+            ..
+        } => db.set_value(&Change::new(key.to_string(), value.to_string(), *version)),
+        Request::ReplicateIncrement { key, inc, .. } => db.inc_value(key.to_string(), *inc),
+        Request::ReplicateRemove { key, .. } => db.remove_value(key.to_string()),
+        _ => Response::Ok {},
+    };
+    if let Response::Error { msg } = response {
+        log::warn!("Could not replay a change of {}, {}", db.name, msg);
+    }
+}
+
+fn op_time(record: &[u8]) -> u64 {
+    let mut time_buffer = [0; OP_TIME_SIZE];
+    time_buffer.copy_from_slice(&record[..OP_TIME_SIZE]);
+    u64::from_le_bytes(time_buffer)
+}
+
+fn dbs_created_after(op_log: &[u8], until: u64) -> HashSet<u64> {
+    op_log
+        .chunks_exact(OP_RECORD_SIZE)
+        .filter(|record| {
+            op_time(record) > until && record[OP_RECORD_SIZE - 1] == ReplicateOpp::CreateDb.to_u8()
+        })
+        .map(|record| {
+            let mut db_id_buffer = [0; OP_DB_ID_SIZE];
+            db_id_buffer.copy_from_slice(&record[OP_TIME_SIZE + OP_KEY_SIZE..OP_RECORD_SIZE - 1]);
+            u64::from_le_bytes(db_id_buffer)
+        })
+        .collect()
+}
+
+fn op_log_until(op_log: &[u8], until: u64) -> Vec<u8> {
+    op_log
+        .chunks_exact(OP_RECORD_SIZE)
+        .filter(|record| op_time(record) <= until)
+        .flatten()
+        .copied()
+        .collect()
+}
+
+fn op_log_values_until(op_log_values: &[u8], until: u64) -> Vec<u8> {
+    String::from_utf8_lossy(op_log_values)
+        .lines()
+        .filter(|line| {
+            line.split_once(' ')
+                .and_then(|(time, _)| time.parse::<u64>().ok())
+                .is_some_and(|time| time <= until)
+        })
+        .flat_map(|line| format!("{}\n", line).into_bytes())
+        .collect()
+}
+
+fn now_as_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+fn split_s3_uri(uri: &str) -> Option<(String, String)> {
+    let path = uri.strip_prefix(S3_URI_PREFIX)?;
+    match path.split_once("/") {
+        Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+            Some((bucket.to_string(), key.to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn write_archive_bytes(destination: &String, bytes: Vec<u8>) -> Result<(), String> {
+    if destination.starts_with(S3_URI_PREFIX) {
+        let (bucket, key) = match split_s3_uri(destination) {
+            Some(bucket_key) => bucket_key,
+            None => return Err(format!("Invalid s3 uri {}", destination)),
+        };
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            build_s3_client()
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(aws_sdk_s3::primitives::ByteStream::from(bytes))
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("Could not upload the backup to {}, {}", destination, e))
+        })
+    } else {
+        if let Some(parent) = Path::new(destination).parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        fs::write(destination, bytes)
+            .map_err(|e| format!("Could not write the backup to {}, {}", destination, e))
+    }
+}
+
+fn read_archive_bytes(source: &String) -> Result<Vec<u8>, String> {
+    if source.starts_with(S3_URI_PREFIX) {
+        let (bucket, key) = match split_s3_uri(source) {
+            Some(bucket_key) => bucket_key,
+            None => return Err(format!("Invalid s3 uri {}", source)),
+        };
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let object = build_s3_client()
+                .get_object()
+                .bucket(bucket)
+                .key(key)
+                .send()
+                .await
+                .map_err(|e| format!("Could not download the backup {}, {}", source, e))?;
+            match object.body.collect().await {
+                Ok(data) => Ok(data.into_bytes().to_vec()),
+                Err(e) => Err(format!("Could not read the backup {}, {}", source, e)),
+            }
+        })
+    } else {
+        fs::read(source).map_err(|e| format!("Could not read the backup {}, {}", source, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replication_ops::{
+        get_replicate_increment_message, get_replicate_message, get_replicate_remove_message,
+    };
+    use crate::storage::disk::create_db_from_file_name;
+    use futures::channel::mpsc::{channel, Receiver, Sender};
+
+    fn create_dbs() -> Arc<Databases> {
+        let (sender, _): (Sender<String>, Receiver<String>) = channel(100);
+        Arc::new(Databases::new(
+            String::from(""),
+            String::from(""),
+            String::from(""),
+            String::from(""),
+            sender.clone(),
+            sender.clone(),
+            HashMap::new(),
+            1 as u128,
+            true,
+        ))
+    }
+
+    fn get_test_dir() -> String {
+        format!("/tmp/dbs-test-{}", thread_id::get())
+    }
+
+    #[test]
+    fn should_parse_s3_uri() {
+        assert_eq!(
+            split_s3_uri("s3://bucket/backups/nun.bkp"),
+            Some((String::from("bucket"), String::from("backups/nun.bkp")))
+        );
+        assert_eq!(split_s3_uri("s3://bucket"), None);
+        assert_eq!(split_s3_uri("/tmp/nun.bkp"), None);
+    }
+
+    #[test]
+    fn should_only_include_the_admin_accounts_of_the_admin_db() {
+        let dbs = create_dbs();
+        {
+            let dbs_map = dbs.map.read().unwrap();
+            let admin_db = dbs_map.get(ADMIN_DB).unwrap();
+            for key in [
+                "$$user_creator",
+                "$$role_$creator",
+                "$$jwt_secret",
+                "some-db",
+            ] {
+                admin_db.set_value(&Change::new(key.to_string(), String::from("x"), -1));
+            }
+        }
+        let archive = BackupArchive::from_dbs(&dbs);
+        assert_eq!(archive.databases.len(), 1);
+        let mut keys: Vec<&String> = archive.databases[0]
+            .values
+            .iter()
+            .map(|(key, _, _)| key)
+            .collect();
+        keys.sort();
+        assert_eq!(archive.databases[0].name, ADMIN_DB);
+        assert_eq!(keys, vec!["$$role_$creator", "$$user_creator"]);
+    }
+
+    #[test]
+    fn should_backup_and_restore_all_dbs() {
+        let dbs = create_dbs();
+        let db_name = String::from("backup-restore-test");
+        let db = Database::new(
+            db_name.to_string(),
+            DatabaseMataData::new(1, ConsensuStrategy::Arbiter),
+        );
+        db.set_value(&Change::new(String::from("name"), String::from("jose"), -1));
+        db.set_value(&Change::new(String::from("age"), String::from("20"), -1));
+        db.set_value(&Change::new(String::from("gone"), String::from("x"), -1));
+        db.remove_value(String::from("gone"));
+        dbs.add_database(db);
+        dbs.keys_map
+            .write()
+            .unwrap()
+            .insert(String::from("backup-restore-test_name"), 1);
+
+        let backup_file = format!("{}/backups/nun.bkp", get_test_dir());
+        let archive = backup(&dbs, &backup_file).unwrap();
+        assert_eq!(archive.databases.len(), 2);
+
+        let restored = restore(&backup_file, None).unwrap();
+        assert_eq!(restored, archive);
+        assert_eq!(load_keys_map_from_disk(), archive.keys_map);
+
+        let (restored_db, _) = create_db_from_file_name(&db_name, &dbs);
+        assert_eq!(restored_db.get_value(String::from("name")).unwrap(), "jose");
+        assert_eq!(restored_db.get_value(String::from("age")).unwrap(), "20");
+        assert_eq!(restored_db.get_value(String::from("gone")), None);
+        assert_eq!(
+            restored_db.metadata.consensus_strategy,
+            ConsensuStrategy::Arbiter
+        );
+    }
+
+    fn op_record(time: u64, db_id: u64, opp: ReplicateOpp) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&time.to_le_bytes());
+        record.extend_from_slice(&1_u64.to_le_bytes());
+        record.extend_from_slice(&db_id.to_le_bytes());
+        record.push(opp.to_u8());
+        record
+    }
+
+    fn values_of(db_backup: &DatabaseBackup) -> HashMap<String, String> {
+        db_backup
+            .values
+            .iter()
+            .map(|(key, value, _)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn should_filter_the_oplog_until_the_given_time() {
+        let mut op_log = Vec::new();
+        op_log.extend(op_record(10, 1, ReplicateOpp::CreateDb));
+        op_log.extend(op_record(20, 1, ReplicateOpp::Update));
+        op_log.extend(op_record(30, 2, ReplicateOpp::CreateDb));
+        assert_eq!(
+            op_log_until(&op_log, 20),
+            op_log[..2 * OP_RECORD_SIZE].to_vec()
+        );
+        assert_eq!(dbs_created_after(&op_log, 20), HashSet::from([2]));
+
+        let op_log_values = b"10 oplog-values\n20 replicate-remove db name\n";
+        assert_eq!(
+            op_log_values_until(op_log_values, 15),
+            b"10 oplog-values\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn should_rewind_the_databases_until_the_given_time() {
+        let db_name = String::from("rewind-test");
+        let lines = vec![
+            format!("10 {}", OP_LOG_VALUES_HEADER),
+            format!(
+                "10 {}",
+                get_replicate_message(
+                    db_name.clone(),
+                    String::from("name"),
+                    String::from("jose"),
+                    0
+                )
+            ),
+            format!(
+                "10 {}",
+                get_replicate_message(
+                    db_name.clone(),
+                    String::from("city"),
+                    String::from("rio; rj"),
+                    0
+                )
+            ),
+            format!(
+                "20 {}",
+                get_replicate_message(
+                    db_name.clone(),
+                    String::from("name"),
+                    String::from("maria"),
+                    1
+                )
+            ),
+            format!(
+                "30 {}",
+                get_replicate_message(db_name.clone(), String::from("age"), String::from("20"), -1)
+            ),
+            format!(
+                "40 {}",
+                get_replicate_increment_message(
+                    db_name.clone(),
+                    String::from("age"),
+                    String::from("5")
+                )
+            ),
+            format!(
+                "50 {}",
+                get_replicate_remove_message(db_name.clone(), String::from("name"))
+            ),
+        ];
+        let mut op_log = Vec::new();
+        op_log.extend(op_record(5, 1, ReplicateOpp::CreateDb));
+        op_log.extend(op_record(35, 2, ReplicateOpp::CreateDb));
+        let archive = BackupArchive {
+            created_at: 0,
+            oplog_position: 60,
+            keys_map: HashMap::new(),
+            op_log,
+            op_log_values: format!("{}\n", lines.join("\n")).into_bytes(),
+            databases: vec![
+                DatabaseBackup {
+                    name: db_name.clone(),
+                    id: 1,
+                    consensus_strategy: ConsensuStrategy::Newer.to_string(),
+                    values: vec![
+                        (String::from("age"), String::from("25"), 2),
+                        (String::from("city"), String::from("rio; rj"), 1),
+                    ],
+                },
+                DatabaseBackup {
+                    name: String::from("created-later"),
+                    id: 2,
+                    consensus_strategy: ConsensuStrategy::Newer.to_string(),
+                    values: vec![],
+                },
+            ],
+        };
+
+        let databases = rewind(&archive, 25).unwrap();
+        assert_eq!(databases.len(), 1);
+        assert_eq!(
+            values_of(&databases[0]),
+            HashMap::from([
+                (String::from("name"), String::from("maria")),
+                (String::from("city"), String::from("rio; rj")),
+            ])
+        );
+
+        let databases = rewind(&archive, 45).unwrap();
+        assert_eq!(databases.len(), 2);
+        assert_eq!(
+            values_of(&databases[0]),
+            HashMap::from([
+                (String::from("name"), String::from("maria")),
+                (String::from("age"), String::from("25")),
+                (String::from("city"), String::from("rio; rj")),
+            ])
+        );
+
+        // Before the oplog values started
+        assert!(rewind(&archive, 5).is_err());
+    }
+
+    #[test]
+    fn should_fail_to_restore_missing_archive() {
+        let result = restore(&String::from("/tmp/missing-nun-backup.bkp"), None);
+        assert!(result.is_err());
+    }
+}
@@ -1,13 +1,20 @@
 use atomic_float::*;
-use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::channel::mpsc::{channel, Receiver, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::{self, Display};
 use std::hash::DefaultHasher;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, Weak};
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use crate::{db_ops::*, disk_ops::*, security::SECURY_KEYS_PREFIX};
+use crate::{
+    audit_ops::AuditLog, configuration::NUN_AUDIT_LOG, configuration::NUN_CLUSTER_SECRET,
+    db_ops::*, disk_ops::*, network::framing_ops::Framing,
+    network::handshake_ops::PROTOCOL_VERSION, rate_limit_ops::RateLimitConfig,
+    rate_limit_ops::RateLimits, rate_limit_ops::TokenBucket, security::hash_token,
+    security::SECURY_KEYS_PREFIX, storage::ColdStorage,
+};
 
 pub const IN_CONFLICT_RESOLUTION_KEY_VERSION: i32 = -2;
 
@@ -18,9 +25,26 @@ const INVALID_VERSION_ERROR: &'static str = "Invalid version!";
 
 pub struct Client {
     pub auth: Arc<AtomicBool>,
+    pub admin_roles: RwLock<Vec<AdminRole>>,
+    pub admin_user_name: RwLock<Option<String>>,
+    // `#id` of the command being processed, echoed on its direct responses
+    pub request_id: RwLock<Option<String>>,
+    // Protocol version sent in `hello`, see network::handshake_ops
+    pub protocol_version: RwLock<Option<u32>>,
+    // Only connections authenticated with the cluster secret can send the node to node commands
+    pub cluster_node: AtomicBool,
+    // Tcp connection presented a certificate signed by NUN_TLS_CA
+    pub verified_certificate: AtomicBool,
+    // Watching connections get heartbeats, see network::heartbeat_ops
+    pub watching: Arc<AtomicBool>,
     pub cluster_member: Mutex<Option<ClusterMember>>,
     pub selected_db: Arc<SelectedDatabase>,
     pub sender: Sender<String>,
+    // Source ip of the connection, used by the per ip rate limit
+    pub remote_address: RwLock<Option<String>>,
+    pub rate_limit: Mutex<TokenBucket>,
+    // Shared with the thread writing the messages of the connection
+    pub framing: Arc<RwLock<Framing>>,
 }
 
 impl Client {
@@ -33,8 +57,67 @@ impl Client {
         }
     }
 
+    // The security ($$) keys hold the tokens and permissions, only cluster admins touch them
     pub fn is_admin_auth(&self) -> bool {
-        self.auth.load(Ordering::SeqCst)
+        self.has_admin_role(&AdminRole::ClusterAdmin)
+    }
+
+    // cluster-admin has all the other roles
+    pub fn has_admin_role(&self, role: &AdminRole) -> bool {
+        if !self.auth.load(Ordering::SeqCst) {
+            return false;
+        }
+        let roles = self.admin_roles.read().unwrap();
+        roles.contains(&AdminRole::ClusterAdmin) || roles.contains(role)
+    }
+
+    pub fn auth_as(&self, roles: Vec<AdminRole>) {
+        let mut admin_roles = self.admin_roles.write().unwrap();
+        *admin_roles = roles;
+        self.auth.store(true, Ordering::SeqCst);
+    }
+
+    pub fn admin_user_name(&self) -> Option<String> {
+        self.admin_user_name.read().unwrap().clone()
+    }
+
+    // Nodes only get the node to node commands, not the admin ones
+    pub fn auth_as_node(&self) {
+        self.cluster_node.store(true, Ordering::SeqCst);
+    }
+
+    /// Clients this node creates to process the messages of the other nodes speak its protocol
+    pub fn auth_as_local_node(&self) {
+        self.auth_as_node();
+        *self.protocol_version.write().unwrap() = Some(PROTOCOL_VERSION);
+    }
+
+    pub fn is_cluster_node(&self) -> bool {
+        self.cluster_node.load(Ordering::SeqCst)
+    }
+
+    pub fn set_watching(&self, watching: bool) {
+        self.watching.store(watching, Ordering::SeqCst);
+    }
+
+    pub fn is_watching(&self) -> bool {
+        self.watching.load(Ordering::SeqCst)
+    }
+
+    pub fn set_remote_address(&self, address: Option<String>) {
+        *self.remote_address.write().unwrap() = address;
+    }
+
+    pub fn remote_address(&self) -> Option<String> {
+        self.remote_address.read().unwrap().clone()
+    }
+
+    pub fn set_framing(&self, framing: Framing) {
+        *self.framing.write().unwrap() = framing;
+    }
+
+    pub fn framing(&self) -> Framing {
+        *self.framing.read().unwrap()
     }
 
     pub fn left(&self, dbs: &Arc<Databases>) {
@@ -61,12 +144,24 @@ impl Client {
     pub fn new_empty(sender: Sender<String>) -> Client {
         Client {
             auth: Arc::new(AtomicBool::new(false)),
+            admin_roles: RwLock::new(Vec::new()),
+            admin_user_name: RwLock::new(None),
+            request_id: RwLock::new(None),
+            protocol_version: RwLock::new(None),
+            watching: Arc::new(AtomicBool::new(false)),
+            cluster_node: AtomicBool::new(false),
+            verified_certificate: AtomicBool::new(false),
             cluster_member: Mutex::new(None),
             selected_db: Arc::new(SelectedDatabase {
                 name: RwLock::new(None),
                 user_name: RwLock::new(None),
+                permissions: RwLock::new(None),
+                expires_at: RwLock::new(None),
             }),
             sender,
+            remote_address: RwLock::new(None),
+            rate_limit: Mutex::new(TokenBucket::default()),
+            framing: Arc::new(RwLock::new(Framing::Text)),
         }
     }
 
@@ -97,6 +192,26 @@ impl Client {
             Err(e) => log::warn!("send_message::try_send {}", e),
         };
     }
+
+    /// Prefixes a response of the last command with its request id, if it had one
+    pub fn label_message(&self, message: &str) -> String {
+        match &*self.request_id.read().unwrap() {
+            Some(request_id) => format!("#{} {}", request_id, message),
+            None => message.to_string(),
+        }
+    }
+
+    /// Sends a response of the command being processed, watchers and arbiters keep the sender
+    /// so their notifications are never labeled
+    pub fn try_send_direct_message(&self, message: String) -> Result<(), TrySendError<String>> {
+        self.sender.clone().try_send(self.label_message(&message))
+    }
+
+    pub fn send_direct_message(&self, message: &str) {
+        if let Err(e) = self.try_send_direct_message(message.to_string()) {
+            log::warn!("send_direct_message::try_send {}", e);
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -130,6 +245,7 @@ pub enum StorageStrategy {
     Disk = 0,
     S3 = 1,
     S3Patition = 2,
+    Embedded = 3,
 }
 
 impl From<String> for StorageStrategy {
@@ -140,6 +256,7 @@ impl From<String> for StorageStrategy {
             "disk" => Disk,
             "s3" => S3,
             "s3_patition" => S3Patition,
+            "embedded" => Embedded,
             _ => Disk,
         }
     }
@@ -151,6 +268,7 @@ impl Display for StorageStrategy {
             StorageStrategy::Disk => write!(f, "Disk"),
             StorageStrategy::S3 => write!(f, "S3"),
             StorageStrategy::S3Patition => write!(f, "S3Patition"),
+            StorageStrategy::Embedded => write!(f, "Embedded"),
         }
     }
 }
@@ -281,6 +399,36 @@ pub struct ClusterState {
 pub struct SelectedDatabase {
     pub name: RwLock<Option<String>>,
     pub user_name: RwLock<Option<String>>,
+    // Inline permissions and expiration (unix seconds) of the signed access token used in use-db
+    pub permissions: RwLock<Option<Vec<Permission>>>,
+    pub expires_at: RwLock<Option<u64>>,
+}
+
+impl SelectedDatabase {
+    pub fn set_access_scope(&self, permissions: Option<Vec<Permission>>, expires_at: Option<u64>) {
+        *self.permissions.write().unwrap() = permissions;
+        *self.expires_at.write().unwrap() = expires_at;
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match *self.expires_at.read().unwrap() {
+            Some(expires_at) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_secs();
+                now >= expires_at
+            }
+            None => false,
+        }
+    }
+}
+
+// Client that selected a database, kept to revoke it if the token it used changes
+pub struct DbSession {
+    pub selected_db: Weak<SelectedDatabase>,
+    pub sender: Sender<String>,
+    pub watching: Arc<AtomicBool>,
 }
 
 pub struct DatabaseMataData {
@@ -466,12 +614,15 @@ pub struct Database {
     pub watchers: Watchers,
     pub connections: RwLock<AtomicUsize>,
     pub metadata: DatabaseMataData,
+    // Where keys not kept in `map` are read from, only set for the embedded storage
+    pub cold_storage: RwLock<Option<Arc<dyn ColdStorage>>>,
+    pub sessions: RwLock<Vec<DbSession>>,
 }
 
 pub struct Databases {
     pub query_ema: std::sync::RwLock<NunEma>,
     pub replication_ema: std::sync::RwLock<NunEma>,
-    pub map: std::sync::RwLock<HashMap<String, Database>>,
+    pub map: std::sync::RwLock<HashMap<String, Arc<Database>>>,
     pub id_name_db_map: std::sync::RwLock<HashMap<u64, String>>,
     pub pending_opps: std::sync::RwLock<HashMap<u64, ReplicationMessage>>,
     pub keys_map: std::sync::RwLock<HashMap<String, u64>>,
@@ -486,6 +637,9 @@ pub struct Databases {
     pub process_id: u128,
     pub user: String,
     pub pwd: String,
+    cluster_secret: RwLock<String>,
+    pub audit_log: AuditLog,
+    pub rate_limits: RateLimits,
     pub is_oplog_valid: Arc<AtomicBool>,
     pub hasher: std::hash::DefaultHasher,
 }
@@ -523,9 +677,12 @@ impl Database {
             name,
             watchers: Watchers {
                 map: RwLock::new(HashMap::new()),
+                key_only: RwLock::new(HashMap::new()),
             },
             connections: RwLock::new(AtomicUsize::new(0)),
             metadata,
+            cold_storage: RwLock::new(None),
+            sessions: RwLock::new(Vec::new()),
         };
     }
 
@@ -558,9 +715,68 @@ impl Database {
         *connections.get_mut() = *connections.get_mut() + 1;
     }
 
+    pub fn register_session(&self, client: &Client) {
+        let selected_db = Arc::downgrade(&client.selected_db);
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|session| {
+            session.selected_db.strong_count() > 0 && !session.selected_db.ptr_eq(&selected_db)
+        });
+        sessions.push(DbSession {
+            selected_db,
+            sender: client.sender.clone(),
+            watching: client.watching.clone(),
+        });
+    }
+
+    /// Clears the selected database and the watches of the clients authenticated with the `user_name` token,
+    /// `None` is the database token. Returns the number of revoked clients
+    pub fn revoke_sessions(
+        &self,
+        user_name: Option<&str>,
+        keep: Option<&Arc<SelectedDatabase>>,
+    ) -> usize {
+        let mut revoked = 0;
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|session| {
+            let selected_db = match session.selected_db.upgrade() {
+                Some(selected_db) => selected_db,
+                None => return false,
+            };
+            let mut name = selected_db.name.write().unwrap();
+            let mut session_user_name = selected_db.user_name.write().unwrap();
+            if name.as_deref() != Some(self.name.as_str()) {
+                return false; // Client moved to other database
+            }
+            if session_user_name.as_deref() != user_name
+                || keep.is_some_and(|keep| Arc::ptr_eq(keep, &selected_db))
+            {
+                return true;
+            }
+            *name = None;
+            *session_user_name = None;
+            selected_db.set_access_scope(None, None);
+            unwatch_all(&session.sender, self);
+            session.watching.store(false, Ordering::SeqCst);
+            self.dec_connections();
+            if let Err(e) = session
+                .sender
+                .clone()
+                .try_send(String::from("error session-revoked\n"))
+            {
+                log::debug!("revoke_sessions::try_send {}", e);
+            }
+            revoked += 1;
+            false
+        });
+        revoked
+    }
+
+    /// Responds with the value after the increment
     pub fn inc_value(&self, key: String, inc: i32) -> Response {
         // This will reduce the lock time of map. It won't wait the notifyt time, we don't need to
         // wait for the update_watchers to release the key
+        // Makes sure a cold key is in memory before the increment
+        self.get_value(key.clone());
         let (value, version) = {
             let mut db = self.map.write().unwrap();
             match i32::from_str_radix(
@@ -583,22 +799,29 @@ impl Database {
         };
 
         self.notify_watchers(key.clone(), value.clone(), version);
-        Response::Ok {}
+        Response::Value {
+            key,
+            value,
+            version,
+        }
     }
 
     pub fn list_keys(&self, pattern: &String, list_system_keys: bool) -> Vec<String> {
         let query_function = get_function_by_pattern(&pattern);
+        let cold_keys = match self.get_cold_storage() {
+            Some(cold_storage) => cold_storage.list_keys(&self.name),
+            None => vec![],
+        };
         let mut keys: Vec<String> = {
-            self.map
-                .read()
-                .unwrap()
-                .iter()
-                .filter(|(key, v)| {
-                    filter_system_keys(list_system_keys, key)
-                        && v.state != ValueStatus::Deleted
-                        && query_function(&key, &pattern)
+            let map = self.map.read().unwrap();
+            map.iter()
+                .filter(|(_key, v)| v.state != ValueStatus::Deleted)
+                .map(|(key, _v)| key)
+                .chain(cold_keys.iter().filter(|key| !map.contains_key(*key)))
+                .filter(|key| {
+                    filter_system_keys(list_system_keys, key) && query_function(key, pattern)
                 })
-                .map(|(key, _v)| key.to_string())
+                .map(|key| key.to_string())
                 .collect()
         };
         keys.sort();
@@ -613,16 +836,62 @@ impl Database {
             name,
             watchers: Watchers {
                 map: RwLock::new(HashMap::new()),
+                key_only: RwLock::new(HashMap::new()),
             },
+            cold_storage: RwLock::new(None),
+            sessions: RwLock::new(Vec::new()),
+        };
+    }
+
+    pub fn set_cold_storage(&self, cold_storage: Arc<dyn ColdStorage>) {
+        *self.cold_storage.write().unwrap() = Some(cold_storage);
+    }
+
+    fn get_cold_storage(&self) -> Option<Arc<dyn ColdStorage>> {
+        self.cold_storage.read().unwrap().clone()
+    }
+
+    /// All values of the database, including the ones only present in the cold storage
+    pub fn all_values(&self) -> HashMap<String, Value> {
+        self.all_values_with(&self.map.read().unwrap())
+    }
+
+    /// Same as `all_values` for callers already holding the lock of `map`
+    pub fn all_values_with(&self, in_memory: &HashMap<String, Value>) -> HashMap<String, Value> {
+        let mut values: HashMap<String, Value> = match self.get_cold_storage() {
+            Some(cold_storage) => cold_storage.list_values(&self.name).into_iter().collect(),
+            None => HashMap::new(),
         };
+        // The in memory values are always newer than the stored ones
+        values.extend(
+            in_memory
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone())),
+        );
+        values
     }
 
     fn notify_watchers(&self, key: String, value: String, version: i32) {
         let watchers = self.watchers.map.read().unwrap();
+        let key_only = self.watchers.key_only.read().unwrap();
+        let key_only = key_only.get(&key);
         match watchers.get(&key) {
             Some(senders) => {
                 for sender in senders {
                     log::debug!("Sending to another client");
+                    if key_only.is_some_and(|key_only| {
+                        key_only
+                            .iter()
+                            .any(|key_only| key_only.same_receiver(sender))
+                    }) {
+                        if let Err(e) = sender
+                            .clone()
+                            .try_send(format!("changed-key {} {}\n", key, version))
+                        {
+                            log::warn!("Request::Set sender.send Error: {}", e);
+                        }
+                        continue;
+                    }
                     match sender.clone().try_send(
                         format_args!("changed {} {}\n", key.to_string(), value.to_string())
                             .to_string(),
@@ -648,14 +917,16 @@ impl Database {
         }
     }
 
+    /// Responds with the removed value, or Ok if there was no value to remove
     pub fn remove_value(&self, key: String) -> Response {
         match key {
             key if key == TOKEN_KEY => Response::Error {
                 msg: "$$token key cannot be removed".to_string(),
             },
             key => {
-                {
-                    if let Some(value) = self.get_value(key.clone()) {
+                let removed = {
+                    let value = self.get_value(key.clone());
+                    if let Some(value) = &value {
                         // If deleted before the key is in disk remove direct from memory
                         if value.state == ValueStatus::New {
                             let mut db = self.map.write().unwrap();
@@ -673,7 +944,8 @@ impl Database {
                             );
                         }
                     }
-                } // Release the lock
+                    value.filter(|value| value.state != ValueStatus::Deleted)
+                }; // Release the lock
                 let mut watchers = self.watchers.map.write().unwrap();
                 match watchers.get_mut(&key) {
                     Some(senders) => {
@@ -689,25 +961,45 @@ impl Database {
                     }
                     _ => {}
                 }
-                Response::Ok {}
+                match removed {
+                    Some(value) => Response::Value {
+                        key,
+                        value: value.value,
+                        version: value.version,
+                    },
+                    None => Response::Ok {},
+                }
             }
         }
     }
 
     pub fn get_value(&self, key: String) -> Option<Value> {
-        let db = self.map.read().unwrap();
-        if let Some(value) = db.get(&key.to_string()) {
-            Some(Value {
-                value: value.value.to_string(),
-                version: value.version,
-                state: value.state,
-                value_disk_addr: value.value_disk_addr,
-                key_disk_addr: value.key_disk_addr,
-                opp_id: value.opp_id,
-            })
-        } else {
-            None
+        {
+            let db = self.map.read().unwrap();
+            if let Some(value) = db.get(&key.to_string()) {
+                return Some(Value {
+                    value: value.value.to_string(),
+                    version: value.version,
+                    state: value.state,
+                    value_disk_addr: value.value_disk_addr,
+                    key_disk_addr: value.key_disk_addr,
+                    opp_id: value.opp_id,
+                });
+            }
         }
+        self.get_cold_value(key)
+    }
+
+    /// Reads a key not present in memory from the cold storage and caches it
+    fn get_cold_value(&self, key: String) -> Option<Value> {
+        let cold_value = self.get_cold_storage()?.get_value(&self.name, &key)?;
+        let mut db = self.map.write().unwrap();
+        // Another thread may have changed the key while it was being read
+        let value = db.entry(key).or_insert(Value {
+            opp_id: Databases::next_op_log_id(),
+            ..cold_value
+        });
+        Some(value.clone())
     }
 
     pub fn set_value_version(
@@ -747,6 +1039,17 @@ impl Database {
         Response::Ok {}
     }
 
+    /// Watches the key without receiving its values, for clients that can't read it
+    pub fn watch_key_without_values(&self, key: &String, sender: &Sender<String>) -> Response {
+        self.watch_key(key, sender);
+        let mut key_only = self.watchers.key_only.write().unwrap();
+        key_only
+            .entry(key.clone())
+            .or_default()
+            .push(sender.clone());
+        Response::Ok {}
+    }
+
     pub fn set_value_as_ok(
         &self,
         key: &String,
@@ -860,7 +1163,7 @@ fn filter_system_keys(list_system_keys: bool, key: &&String) -> bool {
 }
 
 impl Databases {
-    pub fn acquire_dbs_read_lock(&self) -> RwLockReadGuard<HashMap<String, Database>> {
+    pub fn acquire_dbs_read_lock(&self) -> RwLockReadGuard<HashMap<String, Arc<Database>>> {
         self.map.read().expect("Error getting the db.map.read")
     }
     pub fn add_cluster_member(&self, member: ClusterMember) {
@@ -904,12 +1207,27 @@ impl Databases {
             None => {
                 let mut id_name_db_map = self.id_name_db_map.write().unwrap();
                 id_name_db_map.insert(database.metadata.id as u64, database.name.to_string());
-                dbs.insert(db_name.to_string(), database);
+                dbs.insert(db_name.to_string(), Arc::new(database));
                 dbs.get(&String::from(ADMIN_DB))
                     .unwrap()
                     .set_value(&Change::new(db_name.to_string(), String::from("{}"), -1));
                 Response::Ok {}
             }
+            Some(admin_db) if db_name == ADMIN_DB => {
+                // Restores the admin accounts from storage, the $$token always comes from NUN_PWD
+                database
+                    .all_values()
+                    .into_iter()
+                    .filter(|(key, value)| {
+                        key.starts_with(SECURY_KEYS_PREFIX)
+                            && key != TOKEN_KEY
+                            && value.state != ValueStatus::Deleted
+                    })
+                    .for_each(|(key, value)| {
+                        admin_db.set_value(&Change::new(key, value.value, -1));
+                    });
+                Response::Ok {}
+            }
             _ => Response::Error {
                 msg: "database already exists".to_string(),
             },
@@ -973,6 +1291,9 @@ impl Databases {
             process_id,
             user,
             pwd: pwd.to_string(),
+            cluster_secret: RwLock::new(NUN_CLUSTER_SECRET.to_string()),
+            audit_log: AuditLog::new(&NUN_AUDIT_LOG),
+            rate_limits: RateLimits::default(),
             is_oplog_valid: Arc::new(AtomicBool::new(is_oplog_valid)),
             pending_opps: std::sync::RwLock::new(pending_opps),
             hasher: DefaultHasher::new(),
@@ -983,11 +1304,25 @@ impl Databases {
             admin_db_name.to_string(),
             DatabaseMataData::new(0, ConsensuStrategy::Newer),
         ); // id 0 adnmin db
-        admin_db.set_value(&Change::new(String::from(TOKEN_KEY), pwd.to_string(), -1));
+        admin_db.set_value(&Change::new(String::from(TOKEN_KEY), hash_token(&pwd), -1));
         dbs.add_database(admin_db);
         dbs
     }
 
+    /// Secret the nodes use to authenticate each other, the admin password if it is not set
+    pub fn cluster_secret(&self) -> String {
+        let cluster_secret = self.cluster_secret.read().unwrap();
+        if cluster_secret.is_empty() {
+            self.pwd.to_string()
+        } else {
+            cluster_secret.to_string()
+        }
+    }
+
+    pub fn set_cluster_secret(&self, secret: &str) {
+        *self.cluster_secret.write().unwrap() = secret.to_string();
+    }
+
     pub fn next_op_log_id() -> u64 {
         let start = SystemTime::now();
         let since_the_epoch = start
@@ -1061,6 +1396,8 @@ impl Databases {
 
 pub struct Watchers {
     pub map: RwLock<HashMap<String, Vec<Sender<String>>>>,
+    // Senders in `map` watching without the read permission, they get the versions but no values
+    pub key_only: RwLock<HashMap<String, Vec<Sender<String>>>>,
 }
 
 pub enum ReplicateOpp {
@@ -1137,6 +1474,7 @@ pub enum PermissionKind {
     Write,
     Increment,
     Remove,
+    Watch,
 }
 
 impl From<char> for PermissionKind {
@@ -1147,6 +1485,7 @@ impl From<char> for PermissionKind {
             'w' => Write,
             'i' => Increment,
             'x' => Remove,
+            'n' => Watch,
             _ => Read,
         }
     }
@@ -1159,6 +1498,7 @@ impl From<String> for PermissionKind {
             "write" => Write,
             "increment" => Increment,
             "remove" => Remove,
+            "watch" => Watch,
             _ => Read,
         }
     }
@@ -1171,6 +1511,51 @@ impl fmt::Display for PermissionKind {
             PermissionKind::Write => write!(f, "w"),
             PermissionKind::Increment => write!(f, "i"),
             PermissionKind::Remove => write!(f, "x"),
+            PermissionKind::Watch => write!(f, "n"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdminRole {
+    ClusterAdmin,
+    DbCreator,
+    BackupOperator,
+    ReadOnlyMonitor,
+}
+
+impl AdminRole {
+    pub fn parse(val: &str) -> Result<AdminRole, String> {
+        use self::AdminRole::*;
+        match val {
+            "cluster-admin" => Ok(ClusterAdmin),
+            "db-creator" => Ok(DbCreator),
+            "backup-operator" => Ok(BackupOperator),
+            "read-only-monitor" => Ok(ReadOnlyMonitor),
+            _ => Err(format!("Invalid role {}", val)),
+        }
+    }
+
+    pub fn roles_from_str(roles_str: &str) -> Result<Vec<AdminRole>, String> {
+        roles_str.split('|').map(AdminRole::parse).collect()
+    }
+
+    pub fn roles_to_str_value(roles: &[AdminRole]) -> String {
+        roles
+            .iter()
+            .map(|role| role.to_string())
+            .collect::<Vec<String>>()
+            .join("|")
+    }
+}
+
+impl fmt::Display for AdminRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            AdminRole::ClusterAdmin => write!(f, "cluster-admin"),
+            AdminRole::DbCreator => write!(f, "db-creator"),
+            AdminRole::BackupOperator => write!(f, "backup-operator"),
+            AdminRole::ReadOnlyMonitor => write!(f, "read-only-monitor"),
         }
     }
 }
@@ -1179,6 +1564,8 @@ impl fmt::Display for PermissionKind {
 pub struct Permission {
     pub kinds: Vec<PermissionKind>,
     pub keys: Vec<String>,
+    // Deny rules are prefixed with `!` and take precedence over the allow rules
+    pub deny: bool,
 }
 
 impl Permission {
@@ -1197,19 +1584,38 @@ impl Permission {
 
     pub fn from(permision_str: &str) -> Permission {
         let mut permision = permision_str.splitn(2, " ");
-        let kinds = match permision.next() {
-            Some(kind) => kind
-                .to_string()
-                .chars()
-                .map(|c| PermissionKind::from(c))
-                .collect(),
-            None => vec![PermissionKind::Read],
+        let kinds_str = permision.next().unwrap_or("r");
+        let (deny, kinds_str) = match kinds_str.strip_prefix('!') {
+            Some(kinds_str) => (true, kinds_str),
+            None => (false, kinds_str),
         };
+        let kinds = kinds_str.chars().map(PermissionKind::from).collect();
         let keys = match permision.next() {
             Some(keys) => keys.to_string().split(",").map(|s| s.to_string()).collect(),
             None => vec![],
         };
-        Permission { kinds, keys }
+        Permission { kinds, keys, deny }
+    }
+
+    /// Read rules also grant watch so users created before the watch kind keep watching the keys
+    /// they can read
+    pub fn grants(&self, kind: &PermissionKind) -> bool {
+        self.kinds.contains(kind)
+            || (*kind == PermissionKind::Watch && self.kinds.contains(&PermissionKind::Read))
+    }
+
+    /// Keys patterns are globs (`*` and `?`) and `{user}` is replaced by the user name, patterns
+    /// without wildcards in the middle keep the prefix, suffix and substring matching
+    pub fn matches(&self, key: &String, user_name: &str) -> bool {
+        self.keys.iter().any(|pattern| {
+            let pattern = pattern.replace("{user}", user_name);
+            let inner = pattern.trim_start_matches('*').trim_end_matches('*');
+            if inner.contains('*') || pattern.contains('?') {
+                glob_match(&pattern, key)
+            } else {
+                get_function_by_pattern(&pattern)(key, &pattern)
+            }
+        })
     }
 }
 
@@ -1217,7 +1623,8 @@ impl fmt::Display for Permission {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{} {}",
+            "{}{} {}",
+            if self.deny { "!" } else { "" },
             self.kinds
                 .iter()
                 .map(|k| k.to_string())
@@ -1228,12 +1635,33 @@ impl fmt::Display for Permission {
     }
 }
 
+/// One key of an export or import, one per line in the exports
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KeyRecord {
+    pub key: String,
+    pub value: String,
+    pub version: i32,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Request {
     SetPermissions {
         user: String,
         permissions: Vec<Permission>,
     },
+    SetRateLimits {
+        limits: RateLimitConfig,
+    },
+    SetFraming {
+        framing: Framing,
+    },
+    Hello {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
+    Ping {
+        message: Option<String>,
+    },
     Get {
         key: String,
     },
@@ -1278,6 +1706,12 @@ pub enum Request {
         user: String,
         password: String,
     },
+    ClusterAuth {
+        secret: String,
+    },
+    AuditLog {
+        count: usize,
+    },
     CreateDb {
         token: String,
         name: String,
@@ -1287,6 +1721,22 @@ pub enum Request {
         token: String,
         user_name: String,
     },
+    ListUsers {},
+    RemoveUser {
+        user_name: String,
+    },
+    RotateUserToken {
+        user_name: String,
+        token: String,
+    },
+    RotateDbToken {
+        token: String,
+    },
+    CreateAdmin {
+        user_name: String,
+        token: String,
+        roles: Vec<AdminRole>,
+    },
     UseDb {
         token: String,
         name: String,
@@ -1296,6 +1746,17 @@ pub enum Request {
         reclaim_space: bool,
         db_names: Vec<String>,
     },
+    Backup {
+        destination: String,
+    },
+    Export {
+        db_name: String,
+        include_system_keys: bool,
+    },
+    Import {
+        db_name: String,
+        records: Vec<KeyRecord>,
+    },
     ReplicateSnapshot {
         reclaim_space: bool,
         db_names: Vec<String>,
@@ -1475,6 +1936,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn inc_and_remove_should_respond_with_the_changed_value() {
+        let db = Database::new(
+            String::from("some"),
+            DatabaseMataData::new(1, ConsensuStrategy::Newer),
+        );
+        let key = String::from("new");
+        assert!(matches!(
+            db.inc_value(key.clone(), 5),
+            Response::Value { value, .. } if value == "5"
+        ));
+        assert!(matches!(
+            db.remove_value(key.clone()),
+            Response::Value { value, .. } if value == "5"
+        ));
+        assert_eq!(db.remove_value(key), Response::Ok {});
+    }
+
     #[test]
     fn set_should_set_the_latest_version() {
         let db = Database::new(
@@ -1562,6 +2041,40 @@ mod tests {
         assert_eq!(dbs.map.read().expect("error to lock").keys().len(), 2); // Admin db and the db
     }
 
+    #[test]
+    fn add_database_should_restore_the_admin_accounts() {
+        let dbs = get_empty_dbs();
+        let stored_admin_db = Database::new(
+            String::from(ADMIN_DB),
+            DatabaseMataData::new(0, ConsensuStrategy::Newer),
+        );
+        stored_admin_db.set_value(&Change::new(
+            String::from(TOKEN_KEY),
+            String::from("old-pwd"),
+            -1,
+        ));
+        stored_admin_db.set_value(&Change::new(
+            String::from("$$role_$foo"),
+            String::from("db-creator"),
+            -1,
+        ));
+
+        assert_eq!(dbs.add_database(stored_admin_db), Response::Ok {});
+        let dbs_map = dbs.map.read().unwrap();
+        let admin_db = dbs_map.get(ADMIN_DB).unwrap();
+        assert_eq!(
+            admin_db
+                .get_value(String::from("$$role_$foo"))
+                .unwrap()
+                .value,
+            "db-creator"
+        );
+        assert_ne!(
+            admin_db.get_value(String::from(TOKEN_KEY)).unwrap().value,
+            "old-pwd"
+        );
+    }
+
     #[test]
     fn should_return_op_log_size() {
         Oplog::clean_op_log_metadata_files();
@@ -1,5 +1,8 @@
 use clap::{App, Arg, ArgMatches, SubCommand};
 use log;
+use std::io::Read;
+
+use crate::export_ops::{get_import_command, parse_jsonl};
 
 pub fn prepare_args<'a>() -> ArgMatches<'static> {
     return App::new("Nun-db")
@@ -53,6 +56,83 @@ pub fn prepare_args<'a>() -> ArgMatches<'static> {
                         .help("Execute a sequece of command separated commands in the dabtase"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export all keys of a database")
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Database name to be exported"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["jsonl"])
+                        .default_value("jsonl")
+                        .help("Export format"),
+                )
+                .arg(
+                    Arg::with_name("include-system-keys")
+                        .long("include-system-keys")
+                        .help("Also export the $$ keys, e.g: $$token, users and permissions"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("File to write to, default stdout"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Import keys exported with the export command")
+                .arg(
+                    Arg::with_name("file")
+                        .takes_value(true)
+                        .index(1)
+                        .help("JSON lines file to import, default stdin"),
+                )
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .takes_value(true)
+                        .help("Database to import to, default the database in the export header"),
+                )
+                .arg(
+                    Arg::with_name("batch-size")
+                        .long("batch-size")
+                        .takes_value(true)
+                        .default_value("100")
+                        .help("Number of keys sent per import command"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore a backup archive into NUN_DBS_DIR, the server must be stopped")
+                .arg(
+                    Arg::with_name("archive")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1)
+                        .help("Backup file path or s3 uri. e.g: s3://nun-db/backups/all.bkp"),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .takes_value(true)
+                        .help("Restore the databases as they were at this oplog id (nanoseconds since epoch)"),
+                )
+                .arg(
+                    Arg::with_name("dbs-dir")
+                        .long("dbs-dir")
+                        .takes_value(true)
+                        .help("Directory to restore to, default NUN_DBS_DIR"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("start")
                 .arg(
@@ -76,6 +156,12 @@ pub fn prepare_args<'a>() -> ArgMatches<'static> {
                         .takes_value(true)
                         .help("Http address"),
                 )
+                .arg(
+                    Arg::with_name("resp-address")
+                        .long("resp-address")
+                        .takes_value(true)
+                        .help("Redis protocol (RESP) address, disabled by default"),
+                )
                 .arg(
                     Arg::with_name("replicate-address")
                         .short("r")
@@ -90,6 +176,36 @@ pub fn prepare_args<'a>() -> ArgMatches<'static> {
                         .takes_value(true)
                         .help("TCP address to use to join the cluster as a default address, useful when the node tcp address is not reachable from other nodes"),
                 )
+                .arg(
+                    Arg::with_name("cluster-secret")
+                        .long("cluster-secret")
+                        .takes_value(true)
+                        .help("Secret the nodes use to authenticate each other, default NUN_CLUSTER_SECRET"),
+                )
+                .arg(
+                    Arg::with_name("tls-cert")
+                        .long("tls-cert")
+                        .takes_value(true)
+                        .help("PEM certificate chain used to enable TLS, default NUN_TLS_CERT"),
+                )
+                .arg(
+                    Arg::with_name("tls-key")
+                        .long("tls-key")
+                        .takes_value(true)
+                        .help("PEM private key of the TLS certificate, default NUN_TLS_KEY"),
+                )
+                .arg(
+                    Arg::with_name("tls-ca")
+                        .long("tls-ca")
+                        .takes_value(true)
+                        .help("PEM CA used by the nodes to authenticate each other (mutual TLS), default NUN_TLS_CA"),
+                )
+                .arg(
+                    Arg::with_name("tls-listeners")
+                        .long("tls-listeners")
+                        .takes_value(true)
+                        .help("Comma separated listeners using TLS (ws,http,tcp,replication), default NUN_TLS_LISTENERS"),
+                )
                 .about("Start Nun-db service"),
         )
         .get_matches();
@@ -143,5 +259,120 @@ pub fn exec_command(matches: &ArgMatches<'_>) -> Result<(), String> {
         println!("Response {:?}", res,);
         return Ok(());
     }
+
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        let db_name = export_matches.value_of("db").unwrap();
+        log::debug!("Will export the database {}", db_name);
+        let res = send_http_commands(
+            matches,
+            &format!(
+                "export {} {}",
+                db_name,
+                export_matches.is_present("include-system-keys")
+            ),
+        );
+        let jsonl = match res
+            .find(&format!("export {} ", db_name))
+            .and_then(|start| res[start..].find('\n').map(|end| start + end + 1))
+        {
+            Some(start) => &res[start..],
+            None => return Err(format!("Export failed {:?}", res)),
+        };
+        match export_matches.value_of("output") {
+            Some(file_name) => std::fs::write(file_name, jsonl).map_err(|e| e.to_string())?,
+            None => print!("{}", jsonl),
+        }
+        return Ok(());
+    }
+
+    if let Some(import_matches) = matches.subcommand_matches("import") {
+        let jsonl = match import_matches.value_of("file") {
+            Some(file_name) => std::fs::read_to_string(file_name).map_err(|e| e.to_string())?,
+            None => {
+                let mut jsonl = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut jsonl)
+                    .map_err(|e| e.to_string())?;
+                jsonl
+            }
+        };
+        let batch_size = match import_matches
+            .value_of("batch-size")
+            .unwrap()
+            .parse::<usize>()
+        {
+            Ok(batch_size) if batch_size > 0 => batch_size,
+            _ => return Err(String::from("batch-size must be a positive number")),
+        };
+        let (header, records) = parse_jsonl(&jsonl)?;
+        let db_name = match (import_matches.value_of("db"), header) {
+            (Some(db_name), _) => db_name.to_string(),
+            (None, Some(header)) => header.db,
+            (None, None) => return Err(String::from("--db is required if there is no header")),
+        };
+        let mut imported = 0;
+        let mut conflicts = 0;
+        let mut skipped = 0;
+        for batch in records.chunks(batch_size) {
+            let res = send_http_commands(matches, &get_import_command(&db_name, batch));
+            let counts: Vec<u32> = match res.find("import success ") {
+                Some(start) => res[start..]
+                    .split_whitespace()
+                    .skip(2)
+                    .take(3)
+                    .filter_map(|count| count.parse::<u32>().ok())
+                    .collect(),
+                None => return Err(format!("Import failed {:?}", res)),
+            };
+            imported += counts.first().unwrap_or(&0);
+            conflicts += counts.get(1).unwrap_or(&0);
+            skipped += counts.get(2).unwrap_or(&0);
+        }
+        println!(
+            "Imported {} keys into {}, {} version conflicts not imported, {} system keys skipped",
+            imported, db_name, conflicts, skipped
+        );
+        return Ok(());
+    }
+
+    if let Some(restore_matches) = matches.subcommand_matches("restore") {
+        let archive = restore_matches.value_of("archive").unwrap().to_string();
+        let until = match restore_matches.value_of("until") {
+            Some(until) => match until.parse::<u64>() {
+                Ok(until) => Some(until),
+                Err(_) => return Err(String::from("until must be a u64")),
+            },
+            None => None,
+        };
+        if let Some(dbs_dir) = restore_matches.value_of("dbs-dir") {
+            std::env::set_var("NUN_DBS_DIR", dbs_dir);
+        }
+        log::debug!("Will restore the backup {}", archive);
+        let restored = crate::backup_ops::restore(&archive, until)?;
+        println!(
+            "Restored {} databases from {}, oplog position {}",
+            restored.databases.len(),
+            archive,
+            restored.oplog_position
+        );
+        return Ok(());
+    }
     Ok(())
 }
+
+fn send_http_commands(matches: &ArgMatches<'_>, commands: &str) -> String {
+    let body = format!(
+        "auth {user} {pwd}; {commands}",
+        user = matches.value_of("user").unwrap(),
+        pwd = matches.value_of("pwd").unwrap(),
+        commands = commands,
+    );
+    let client = reqwest::blocking::Client::new();
+    client
+        .post(matches.value_of("host").unwrap_or("http://localhost:3013"))
+        .body(body)
+        .send()
+        .unwrap()
+        .text()
+        .unwrap()
+}
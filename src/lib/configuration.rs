@@ -10,7 +10,32 @@ lazy_static! {
     pub static ref NUN_WS_ADDR: String = optional_env_var("NUN_WS_ADDR", "0.0.0.0:3012");
     pub static ref NUN_HTTP_ADDR: String = optional_env_var("NUN_HTTP_ADDR", "0.0.0.0:3013");
     pub static ref NUN_TCP_ADDR: String = optional_env_var("NUN_TCP_ADDR", "0.0.0.0:3014");
+    // Redis protocol listener, disabled if empty
+    pub static ref NUN_RESP_ADDR: String = optional_env_var("NUN_RESP_ADDR", "");
+    // Comma separated CIDRs allowed to connect, empty allows any address. Nodes replicate over the tcp listener
+    pub static ref NUN_TCP_ALLOWLIST: String = optional_env_var("NUN_TCP_ALLOWLIST", "");
+    pub static ref NUN_WS_ALLOWLIST: String = optional_env_var("NUN_WS_ALLOWLIST", "");
+    pub static ref NUN_HTTP_ALLOWLIST: String = optional_env_var("NUN_HTTP_ALLOWLIST", "");
+    pub static ref NUN_RESP_ALLOWLIST: String = optional_env_var("NUN_RESP_ALLOWLIST", "");
+    // Concurrent connections, 0 means unlimited
+    pub static ref NUN_TCP_MAX_CONNECTIONS: usize = optional_env_var("NUN_TCP_MAX_CONNECTIONS", "0").to_string().parse::<usize>().unwrap();
+    pub static ref NUN_WS_MAX_CONNECTIONS: usize = optional_env_var("NUN_WS_MAX_CONNECTIONS", "0").to_string().parse::<usize>().unwrap();
+    pub static ref NUN_HTTP_MAX_CONNECTIONS: usize = optional_env_var("NUN_HTTP_MAX_CONNECTIONS", "0").to_string().parse::<usize>().unwrap();
+    pub static ref NUN_RESP_MAX_CONNECTIONS: usize = optional_env_var("NUN_RESP_MAX_CONNECTIONS", "0").to_string().parse::<usize>().unwrap();
     pub static ref NUN_REPLICATE_ADDR: String = optional_env_var("NUN_REPLICATE_ADDR", "");
+    pub static ref NUN_AUDIT_LOG: String = optional_env_var("NUN_AUDIT_LOG", "");// Audit trail file, only kept in memory if empty
+    // Requests per second, 0 disables the limit, databases can override them with set-rate-limits
+    pub static ref NUN_RATE_LIMIT_CONNECTION: u32 = optional_env_var("NUN_RATE_LIMIT_CONNECTION", "0").to_string().parse::<u32>().unwrap();
+    pub static ref NUN_RATE_LIMIT_USER: u32 = optional_env_var("NUN_RATE_LIMIT_USER", "0").to_string().parse::<u32>().unwrap();
+    pub static ref NUN_RATE_LIMIT_IP: u32 = optional_env_var("NUN_RATE_LIMIT_IP", "0").to_string().parse::<u32>().unwrap();
+    pub static ref NUN_CLUSTER_SECRET: String = optional_env_var("NUN_CLUSTER_SECRET", "");// Can be overridden by command line
+    // Seconds, heartbeats are sent to watching connections idle for this long, 0 disables them
+    pub static ref NUN_HEARTBEAT_INTERVAL: u64 = optional_env_var("NUN_HEARTBEAT_INTERVAL", "30").to_string().parse::<u64>().unwrap();
+    // Seconds without receiving anything before a tcp or ws connection is closed, 0 disables it
+    pub static ref NUN_IDLE_TIMEOUT: u64 = optional_env_var("NUN_IDLE_TIMEOUT", "0").to_string().parse::<u64>().unwrap();
+    // Bytes, largest bulk string (key or value) a RESP client can send
+    pub static ref NUN_MAX_VALUE_SIZE: usize = optional_env_var("NUN_MAX_VALUE_SIZE", "16777216").to_string().parse::<usize>().unwrap();
+    pub static ref NUN_CLUSTER_NAME: String = optional_env_var("NUN_CLUSTER_NAME", "nun-db"); // Sent in the response to hello
     pub static ref NUN_LOG_LEVEL: String = optional_env_var("NUN_LOG_LEVEL", "Info"); //(Off, Error, Warn, Info, Debug, Trace)
     pub static ref NUN_ELECTION_TIMEOUT: u128 = optional_env_var("NUN_ELECTION_TIMEOUT", "1000").to_string().parse::<u128>().unwrap();
     // 1GB
@@ -31,6 +56,16 @@ lazy_static! {
     pub static ref NUN_S3_MAX_INFLIGHT_REQUESTS: usize = optional_env_var("NUN_S3_MAX_INFLIGHT_REQUESTS", "10").to_string().parse::<usize>().unwrap();
     pub static ref NUN_S3_NUMBER_OF_PARTITIONS: u64 = optional_env_var("NUN_S3_NUMBER_OF_PARTITIONS", "10").to_string().parse::<u64>().unwrap();
     pub static ref NUN_S3_RETRY: i32 = optional_env_var("NUN_S3_RETRY", "3").to_string().parse::<i32>().unwrap();
+    pub static ref NUN_S3_MAX_DELTA_SEGMENTS: usize = optional_env_var("NUN_S3_MAX_DELTA_SEGMENTS", "10").to_string().parse::<usize>().unwrap();
+    // TLS is disabled unless the certificate and key paths are set, see network::tls_ops
+    pub static ref NUN_TLS_CERT: String = optional_env_var("NUN_TLS_CERT", "");
+    pub static ref NUN_TLS_KEY: String = optional_env_var("NUN_TLS_KEY", "");
+    pub static ref NUN_TLS_CA: String = optional_env_var("NUN_TLS_CA", "");
+    pub static ref NUN_TLS_LISTENERS: String = optional_env_var("NUN_TLS_LISTENERS", "ws,http,tcp,replication"); // ws, http, tcp, replication
+    // Values files and S3 objects are only encrypted when one of them is set, see storage::encryption
+    pub static ref NUN_ENCRYPTION_KEY: String = optional_env_var("NUN_ENCRYPTION_KEY", "");
+    pub static ref NUN_ENCRYPTION_KEY_FILE: String = optional_env_var("NUN_ENCRYPTION_KEY_FILE", "");
+    pub static ref NUN_EMBEDDED_CACHE_KEYS: usize = optional_env_var("NUN_EMBEDDED_CACHE_KEYS", "100000").to_string().parse::<usize>().unwrap();
 }
 
 pub fn optional_env_var(name: &str, default: &str) -> String {
@@ -179,6 +179,7 @@ impl Database {
     pub fn register_arbiter(&self, client: &Client) -> Response {
         let key = String::from(CONFLICTS_KEY);
         let response = self.watch_key(&key, &client.sender);
+        client.set_watching(true);
         let pendding_conflict = self.list_conflicts_keys(&String::from("")); // List all
         log::debug!(
             "Will send {} conflicts to arbiger to resolve",
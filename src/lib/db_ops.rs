@@ -6,6 +6,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::bo::*;
 use crate::disk_ops::*;
+use crate::security::{hash_token, user_name_key_from_user_name, verify_token};
 
 pub const CONNECTIONS_KEY: &'static str = "$connections";
 
@@ -27,16 +28,13 @@ pub fn create_db(
         let empty_db = Arc::try_unwrap(empty_db_box);
         match empty_db {
             Ok(db) => {
-                set_key_value(TOKEN_KEY.to_string(), token.clone(), -1, &db, &dbs);
+                set_key_value(TOKEN_KEY.to_string(), hash_token(token), -1, &db, dbs);
                 match dbs.add_database(db) {
                     Response::Ok {} => {
-                        match client
-                            .sender
-                            .clone()
-                            .try_send("create-db success\n".to_string())
+                        if let Err(e) =
+                            client.try_send_direct_message("create-db success\n".to_string())
                         {
-                            Ok(_n) => (),
-                            Err(e) => log::warn!("Request::CreateDb  Error: {}", e),
+                            log::warn!("Request::CreateDb  Error: {}", e);
                         }
                         Response::Ok {}
                     }
@@ -45,11 +43,7 @@ pub fn create_db(
             }
             _ => {
                 log::debug!("Could not create the database");
-                match client
-                    .sender
-                    .clone()
-                    .try_send("error create-db-error\n".to_string())
-                {
+                match client.try_send_direct_message("error create-db-error\n".to_string()) {
                     Ok(_n) => Response::Error {
                         msg: String::from("Error clreating the DB"),
                     },
@@ -78,7 +72,7 @@ pub fn snapshot_db_by_name(name: &String, dbs: &Databases, reclaim_space: bool)
     }
 }
 
-pub fn get_key_value(key: &String, sender: &Sender<String>, db: &Database) -> Response {
+pub fn get_key_value(key: &String, client: &Client, db: &Database) -> Response {
     let result = get_key_value_new(key, &db);
     if let Response::Value {
         key: _,
@@ -86,19 +80,15 @@ pub fn get_key_value(key: &String, sender: &Sender<String>, db: &Database) -> Re
         version: _,
     } = result.clone()
     {
-        match sender
-            .clone()
-            .try_send(format_args!("value {}\n", value.to_string()).to_string())
-        {
-            Err(e) => log::warn!("Request::Get sender.send Error: {}", e),
-            _ => (),
+        if let Err(e) = client.try_send_direct_message(format!("value {}\n", value)) {
+            log::warn!("Request::Get sender.send Error: {}", e);
         }
     }
 
     result
 }
 
-pub fn get_key_value_safe(key: &String, sender: &Sender<String>, db: &Database) -> Response {
+pub fn get_key_value_safe(key: &String, client: &Client, db: &Database) -> Response {
     let result = get_key_value_new(key, &db);
     if let Response::Value {
         key: _,
@@ -106,12 +96,10 @@ pub fn get_key_value_safe(key: &String, sender: &Sender<String>, db: &Database)
         value,
     } = result.clone()
     {
-        match sender
-            .clone()
-            .try_send(format_args!("value-version {} {}\n", version, value).to_string())
+        if let Err(e) =
+            client.try_send_direct_message(format!("value-version {} {}\n", version, value))
         {
-            Ok(_n) => (),
-            Err(e) => log::warn!("Request::Get sender.send Error: {}", e),
+            log::warn!("Request::Get sender.send Error: {}", e);
         }
     }
     result
@@ -121,8 +109,7 @@ pub fn get_key_value_safe(key: &String, sender: &Sender<String>, db: &Database)
  * All get keys functions must call this function and parse the result from it
  */
 pub fn get_key_value_new(key: &String, db: &Database) -> Response {
-    let db = db.map.read().unwrap();
-    let (value, version) = match db.get(&key.to_string()) {
+    let (value, version) = match db.get_value(key.to_string()) {
         Some(value) => (value.to_string(), value.version),
         None => (String::from("<Empty>"), 1 as i32),
     };
@@ -137,24 +124,22 @@ pub fn remove_key(key: &String, db: &Database) -> Response {
     db.remove_value(key.to_string())
 }
 
-pub fn is_valid_token(token: &String, db: &Database) -> bool {
-    let db = db.map.read().unwrap();
-    match db.get(&TOKEN_KEY.to_string()) {
-        Some(value) => {
-            log::debug!("[is_valid_token] Token {} value {}", value, token);
-            value == token
-        }
+pub fn is_valid_token(token: &str, db: &Database) -> bool {
+    match db
+        .get_value(TOKEN_KEY.to_string())
+        .filter(|value| value.state != ValueStatus::Deleted)
+    {
+        Some(value) => verify_token(token, &value.value),
         None => false,
     }
 }
 
-pub fn is_valid_user_token(token: &String, user_name: &String, db: &Database) -> bool {
-    let db = db.map.read().unwrap();
-    match db.get(&format!("$$user_{}", user_name)) {
-        Some(value) => {
-            log::debug!("[is_valid_token] Token {} value {}", value, token);
-            value == token
-        }
+pub fn is_valid_user_token(token: &str, user_name: &String, db: &Database) -> bool {
+    match db
+        .get_value(user_name_key_from_user_name(user_name))
+        .filter(|value| value.state != ValueStatus::Deleted)
+    {
+        Some(value) => verify_token(token, &value.value),
         None => false,
     }
 }
@@ -209,6 +194,10 @@ pub fn unwatch_key(key: &String, sender: &Sender<String>, db: &Database) -> Resp
     log::debug!("Senders after unwatch {:?}", senders.len());
     let mut watchers = db.watchers.map.write().expect("db.watchers.map.lock");
     watchers.insert(key.clone(), senders);
+    let mut key_only = db.watchers.key_only.write().unwrap();
+    if let Some(senders) = key_only.get_mut(key) {
+        senders.retain(|x| !x.same_receiver(sender));
+    }
     Response::Ok {}
 }
 
@@ -302,6 +291,30 @@ pub fn get_function_by_pattern(
     query_function
 }
 
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // Position of the last `*` in the pattern and the key position it is matching from
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == key[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, k));
+            p += 1;
+        } else if let Some((star_p, star_k)) = star {
+            p = star_p + 1;
+            k = star_k + 1;
+            star = Some((star_p, star_k + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 fn starts_with(key: &String, pattern: &String) -> bool {
     key.starts_with(&pattern.replace("*", ""))
 }
@@ -322,6 +335,17 @@ mod tests {
 
     pub const SAMPLE_NAME: &'static str = "sample";
 
+    #[test]
+    fn should_match_glob_patterns() {
+        assert!(glob_match("room:*:private", "room:1:private"));
+        assert!(glob_match("room:*:private", "room::private"));
+        assert!(!glob_match("room:*:private", "room:1:public"));
+        assert!(glob_match("user-?", "user-1"));
+        assert!(!glob_match("user-?", "user-10"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("a*b", "ab-c"));
+    }
+
     fn get_dbs() -> Arc<Databases> {
         let (sender, _replication_receiver): (Sender<String>, Receiver<String>) = channel(100);
         let keys_map = HashMap::new();
@@ -360,9 +384,9 @@ mod tests {
         );
         set_key_value(key.clone(), value.clone(), -1, &db, &dbs);
 
-        let (sender, mut receiver): (Sender<String>, Receiver<String>) = channel(100);
+        let (client, mut receiver) = Client::new_empty_and_receiver();
 
-        let _value_in_hash = get_key_value(&key, &sender, &db);
+        let _value_in_hash = get_key_value(&key, &client, &db);
         let message = receiver.try_next().unwrap().unwrap();
         assert_eq!(
             message.to_string(),
@@ -371,7 +395,7 @@ mod tests {
 
         remove_key(&key, &db);
 
-        let _value_in_hash = get_key_value(&key, &sender, &db);
+        let _value_in_hash = get_key_value(&key, &client, &db);
         let message = receiver.try_next().unwrap().unwrap();
         assert_eq!(message.to_string(), "value <Empty>\n".to_string());
     }
@@ -425,9 +449,9 @@ mod tests {
         set_key_value(key.clone(), value.clone(), 1, &db, &dbs);
         set_key_value(key.clone(), value_new.clone(), 2, &db, &dbs);
 
-        let (sender, mut receiver): (Sender<String>, Receiver<String>) = channel(100);
+        let (client, mut receiver) = Client::new_empty_and_receiver();
 
-        let _value_in_hash = get_key_value(&key, &sender, &db);
+        let _value_in_hash = get_key_value(&key, &client, &db);
         let message = receiver.try_next().unwrap().unwrap();
         assert_eq!(
             message.to_string(),
@@ -469,9 +493,9 @@ mod tests {
         );
         set_key_value(key.clone(), value.clone(), -1, &db, &dbs);
 
-        let (sender, mut receiver): (Sender<String>, Receiver<String>) = channel(100);
+        let (client, mut receiver) = Client::new_empty_and_receiver();
 
-        let _value_in_hash = get_key_value(&key, &sender, &db);
+        let _value_in_hash = get_key_value(&key, &client, &db);
         let message = receiver.try_next().unwrap().unwrap();
         assert_eq!(
             message.to_string(),
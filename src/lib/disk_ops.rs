@@ -24,15 +24,18 @@ use crate::configuration::{
     NUN_DECLUTTER_INTERVAL, NUN_READ_STORAGE_STRATEGY, NUN_STORAGE_STRATEGY,
     NUN_WRITE_STORAGE_STRATEGY,
 };
-use crate::storage::disk::{
-    file_name_from_db_name, get_key_value_files_name_from_file_name, NodeDrive,
-};
-use crate::storage::s3::S3Storage;
-use crate::storage::s3_partition::S3PartitionStorage;
+use crate::replication_ops::get_replicate_message;
+use crate::security::migrate_plain_text_tokens;
+use crate::storage::disk::{file_name_from_db_name, get_key_value_files_name_from_file_name};
+use crate::storage::{read_storage_backend, write_storage_backend};
 
 const KEYS_FILE: &'static str = "keys-nun.keys";
 
 const OP_LOG_FILE: &'static str = "oplog-nun.op";
+// Replicated request of each value change, the oplog records have no values
+const OP_LOG_VALUES_FILE: &str = "oplog-nun.values";
+// First line of the oplog values file, followed by the values of the keys that existed before it
+pub const OP_LOG_VALUES_HEADER: &str = "oplog-values";
 const INVALIDATE_OP_LOG_FILE: &'static str = "is-oplog.valid";
 
 const OP_KEY_SIZE: usize = 8;
@@ -71,21 +74,12 @@ impl Databases {
             *NUN_READ_STORAGE_STRATEGY,
             *NUN_WRITE_STORAGE_STRATEGY
         );
-        match *NUN_READ_STORAGE_STRATEGY {
-            StorageStrategy::Disk => NodeDrive::load_all_dbs_from_disk(dbs),
-            StorageStrategy::S3 => S3Storage::load_all_dbs_from_cloud(dbs),
-            StorageStrategy::S3Patition => S3PartitionStorage::load_all_dbs_from_cloud(dbs),
-        }
+        read_storage_backend().load_all_dbs(dbs);
+        migrate_plain_text_tokens(dbs);
     }
 
     pub fn storage_data(db: &Database, db_name: &String, reclame_space: bool) -> u32 {
-        match *NUN_WRITE_STORAGE_STRATEGY {
-            StorageStrategy::Disk => NodeDrive::storage_data_disk(db, reclame_space, db_name),
-            StorageStrategy::S3 => S3Storage::storage_data_on_cloud(db, reclame_space, db_name),
-            StorageStrategy::S3Patition => {
-                S3PartitionStorage::storage_data_on_cloud(db, reclame_space, db_name)
-            }
-        }
+        write_storage_backend().store_db(db, db_name, reclame_space)
     }
 }
 
@@ -156,9 +150,66 @@ impl Oplog {
             }
         }
     }
+    /// Appends the replicated request of a value change, replaying these lines rebuilds the values
+    pub fn write_op_log_value(
+        stream: &mut BufWriter<File>,
+        opp_id: u64,
+        request: &str,
+    ) -> Result<u64, String> {
+        match writeln!(stream, "{} {}", opp_id, request).and_then(|_| stream.flush()) {
+            Ok(_) => Ok(opp_id),
+            Err(e) => {
+                log::error!("Could not write to the oplog values file, {}", e);
+                Err(String::from("Could not write to the oplog values file"))
+            }
+        }
+    }
+
+    pub fn get_op_log_values_file_name() -> String {
+        format!(
+            "{dir}/{sufix}",
+            dir = get_dir_name(),
+            sufix = OP_LOG_VALUES_FILE
+        )
+    }
+
+    /// A new values file starts with the values of all keys, so it can be replayed from its first line
+    pub fn get_op_log_values_file_append_mode(dbs: &Arc<Databases>) -> BufWriter<File> {
+        let file_name = Oplog::get_op_log_values_file_name();
+        let is_new = !Path::new(&file_name).exists();
+        let mut stream = BufWriter::new(
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&file_name)
+                .unwrap(),
+        );
+        if is_new {
+            let opp_id = Databases::next_op_log_id();
+            writeln!(stream, "{} {}", opp_id, OP_LOG_VALUES_HEADER).unwrap();
+            for db in dbs.map.read().unwrap().values() {
+                for (key, value) in db.all_values() {
+                    if value.state != ValueStatus::Deleted {
+                        // Replaying the set on a missing key adds one to the version
+                        let request = get_replicate_message(
+                            db.name.clone(),
+                            key,
+                            value.value,
+                            value.version - 1,
+                        );
+                        writeln!(stream, "{} {}", opp_id, request).unwrap();
+                    }
+                }
+            }
+            stream.flush().unwrap();
+        }
+        stream
+    }
+
     pub fn clean_op_log_metadata_files() {
         remove_invalidate_oplog_file();
         remove_op_log_file();
+        remove_op_log_values_file();
         if let Ok(entries) = read_dir(get_op_log_dir_name()) {
             for entry in entries {
                 let file_name = entry.unwrap().file_name().into_string().unwrap();
@@ -257,6 +308,13 @@ fn remove_invalidate_oplog_file() {
     }
 }
 
+fn remove_keys_file() {
+    let key_file_name = get_keys_map_file_name();
+    if Path::new(&key_file_name).exists() {
+        fs::remove_file(key_file_name).unwrap();
+    }
+}
+
 // @todo speed up saving use the same key general and local
 fn write_keys_map_to_disk(keys: HashMap<String, u64>) {
     let keys_file_name = get_keys_map_file_name();
@@ -379,6 +437,15 @@ fn remove_op_log_file() {
     }
 }
 
+fn remove_op_log_values_file() {
+    let file_name = Oplog::get_op_log_values_file_name();
+    if Path::new(&file_name).exists() {
+        if let Err(e) = fs::remove_file(&file_name) {
+            log::error!("Could not delete the {}, {}", file_name, e);
+        }
+    }
+}
+
 fn get_log_file_read_mode(file_name: &String) -> File {
     match OpenOptions::new().read(true).open(file_name) {
         Err(e) => {
@@ -434,6 +501,73 @@ pub fn read_operations_since(since: u64) -> HashMap<String, OpLogRecord> {
     opps_since
 }
 
+/// Reads all op log records as raw bytes, oldest records first
+pub fn read_op_log_bytes() -> Vec<u8> {
+    let mut op_log = Vec::new();
+    let mut oplog_entries = get_op_log_entries_by_creation_date();
+    oplog_entries.reverse();
+    for oplog_file_entry in oplog_entries {
+        let file_name = oplog_file_entry.file_name().into_string().unwrap();
+        if file_name.ends_with(".op") {
+            let full_path = format!("{}/{}", get_op_log_dir_name(), file_name);
+            get_log_file_read_mode(&full_path)
+                .read_to_end(&mut op_log)
+                .unwrap();
+        }
+    }
+    get_log_file_read_mode(&Oplog::get_op_log_file_name())
+        .read_to_end(&mut op_log)
+        .unwrap();
+    // Ignores a partially written record at the end of the file
+    op_log.truncate(op_log.len() - op_log.len() % OP_RECORD_SIZE);
+    op_log
+}
+
+/// Reads the oplog values file, see `Oplog::write_op_log_value`
+pub fn read_op_log_values_bytes() -> Vec<u8> {
+    let mut op_log_values = Vec::new();
+    if let Ok(mut file) = File::open(Oplog::get_op_log_values_file_name()) {
+        file.read_to_end(&mut op_log_values).unwrap();
+    }
+    // Ignores a partially written line at the end of the file
+    let complete_size = op_log_values
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |position| position + 1);
+    op_log_values.truncate(complete_size);
+    op_log_values
+}
+
+/// Replaces the keys map and the op log files with the ones restored from a backup
+pub fn restore_op_log(keys_map: HashMap<String, u64>, op_log: &[u8], op_log_values: &[u8]) -> u64 {
+    Oplog::clean_op_log_metadata_files();
+    remove_keys_file();
+    write_keys_map_to_disk(keys_map);
+    // Without values the next start writes a new values file from the restored databases
+    if !op_log_values.is_empty() {
+        fs::write(Oplog::get_op_log_values_file_name(), op_log_values).unwrap();
+    }
+
+    let mut op_log_file = BufWriter::new(
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(Oplog::get_op_log_file_name())
+            .unwrap(),
+    );
+    let mut restored = 0;
+    for record in op_log.chunks_exact(OP_RECORD_SIZE) {
+        op_log_file.write_all(record).unwrap();
+        restored += 1;
+    }
+    op_log_file.flush().unwrap();
+
+    let mut file_writer = get_invalidate_file_write_mode();
+    file_writer.seek(SeekFrom::Start(0)).unwrap();
+    file_writer.write_all(&[1]).unwrap();
+    restored
+}
+
 fn get_op_log_entries_by_creation_date() -> Vec<fs::DirEntry> {
     let oplog_dir_name = get_op_log_dir_name();
     if !Path::new(&oplog_dir_name).exists() {
@@ -643,7 +777,7 @@ mod tests {
         configuration::NUN_LOG_LEVEL,
         storage::disk::{
             create_db_from_file_name, db_name_from_file_name,
-            get_key_value_files_name_from_file_name,
+            get_key_value_files_name_from_file_name, NodeDrive,
         },
     };
     use env_logger::{Builder, Env, Target};
@@ -1289,13 +1423,6 @@ mod tests {
         dbs
     }
 
-    fn remove_keys_file() {
-        let key_file_name = get_keys_map_file_name();
-        if Path::new(&key_file_name).exists() {
-            fs::remove_file(key_file_name).unwrap();
-        }
-    }
-
     #[test]
     fn should_store_and_load_keys() {
         let mut keys = HashMap::new();
@@ -1307,6 +1434,57 @@ mod tests {
         assert_eq!(keys_from_fisk.get("key_2"), Some(&2));
     }
 
+    #[test]
+    fn should_restore_the_op_log() {
+        Oplog::clean_op_log_metadata_files();
+        let mut op_log = Vec::new();
+        for time in [10 as u64, 20, 30] {
+            op_log.extend_from_slice(&time.to_le_bytes());
+            op_log.extend_from_slice(&[0; OP_RECORD_SIZE - OP_TIME_SIZE]);
+        }
+
+        let mut keys = HashMap::new();
+        keys.insert(String::from("key_1"), 1);
+        let restored = restore_op_log(keys, &op_log, b"10 oplog-values\n");
+        assert_eq!(restored, 3);
+        assert_eq!(Oplog::last_op_time(), 30);
+        assert_eq!(load_keys_map_from_disk().get("key_1"), Some(&1));
+        assert_eq!(is_oplog_valid(), true);
+        assert_eq!(read_op_log_values_bytes(), b"10 oplog-values\n".to_vec());
+    }
+
+    #[test]
+    fn should_start_the_op_log_values_with_the_current_values() {
+        Oplog::clean_op_log_metadata_files();
+        let dbs = create_test_dbs();
+        let db = Database::new(
+            String::from("values-test"),
+            DatabaseMataData::new(1, ConsensuStrategy::Newer),
+        );
+        db.set_value(&Change::new(String::from("name"), String::from("jose"), -1));
+        dbs.add_database(db);
+
+        let mut stream = Oplog::get_op_log_values_file_append_mode(&dbs);
+        Oplog::write_op_log_value(&mut stream, 20, "replicate-remove values-test name").unwrap();
+        // Only a new file gets the current values
+        Oplog::get_op_log_values_file_append_mode(&dbs);
+        let op_log_values = String::from_utf8(read_op_log_values_bytes()).unwrap();
+        let lines: Vec<&str> = op_log_values.lines().collect();
+        assert!(lines[0].ends_with(" oplog-values"));
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.contains("values-test name"))
+                .map(|line| line.split_once(' ').unwrap().1)
+                .collect::<Vec<&str>>(),
+            vec![
+                "replicate values-test name -1 \"jose\"",
+                "replicate-remove values-test name"
+            ]
+        );
+        assert_eq!(lines.last(), Some(&"20 replicate-remove values-test name"));
+    }
+
     #[test]
     fn should_create_a_new_file_if_invalidate_file_does_not_exists() {
         remove_invalidate_oplog_file();
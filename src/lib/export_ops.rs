@@ -0,0 +1,314 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::bo::*;
+use crate::db_ops::*;
+use crate::replication_ops::*;
+use crate::security::SECURY_KEYS_PREFIX;
+
+/// First line of an export, describes the database the keys came from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportHeader {
+    pub db: String,
+    pub consensus_strategy: String,
+    pub keys: usize,
+}
+
+/// Returns the keys of a database as JSON lines sorted by key, the first line is the header.
+/// `$connections` is never exported and `$$` keys are only exported if `include_system_keys`
+pub fn export_db_jsonl(db: &Database, include_system_keys: bool) -> (usize, String) {
+    let mut records: Vec<KeyRecord> = {
+        db.all_values()
+            .iter()
+            .filter(|(key, value)| {
+                value.state != ValueStatus::Deleted
+                    && key.as_str() != CONNECTIONS_KEY
+                    && (include_system_keys || !key.starts_with(SECURY_KEYS_PREFIX))
+            })
+            .map(|(key, value)| KeyRecord {
+                key: key.to_string(),
+                value: value.value.to_string(),
+                version: value.version,
+            })
+            .collect()
+    };
+    records.sort_by(|a, b| a.key.cmp(&b.key));
+    let header = ExportHeader {
+        db: db.name.to_string(),
+        consensus_strategy: db.metadata.consensus_strategy.to_string(),
+        keys: records.len(),
+    };
+    let mut jsonl = serde_json::to_string(&header).unwrap();
+    jsonl.push('\n');
+    for record in &records {
+        jsonl.push_str(&serde_json::to_string(record).unwrap());
+        jsonl.push('\n');
+    }
+    (records.len(), jsonl)
+}
+
+/// Parses JSON lines into key records, header and empty lines are skipped
+pub fn parse_jsonl(jsonl: &str) -> Result<(Option<ExportHeader>, Vec<KeyRecord>), String> {
+    let mut header = None;
+    let mut records = Vec::new();
+    for (line_number, line) in jsonl.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<KeyRecord>(line) {
+            Ok(record) => records.push(record),
+            Err(e) => match serde_json::from_str::<ExportHeader>(line) {
+                Ok(h) => header = Some(h),
+                Err(_) => return Err(format!("Invalid record at line {}, {}", line_number + 1, e)),
+            },
+        }
+    }
+    Ok((header, records))
+}
+
+/// Builds the import command for a batch of records. `;` is escaped because it splits
+/// commands in the http and web socket protocols, it can only show up inside JSON strings
+pub fn get_import_command(db_name: &str, records: &[KeyRecord]) -> String {
+    format!(
+        "import {} {}",
+        db_name,
+        serde_json::to_string(records)
+            .unwrap()
+            .replace(';', "\\u003b")
+    )
+}
+
+pub fn parse_import_records(records: &str) -> Result<Vec<KeyRecord>, String> {
+    serde_json::from_str(records).map_err(|e| format!("Invalid import records, {}", e))
+}
+
+/// Sets all records keeping their versions and replicates each key that was set, returns the
+/// number of imported keys, the number of keys the conflict resolution kept or left pending and
+/// the number of skipped system keys. `$$` keys and `$connections` are not data of the database,
+/// so exports with the system keys can be imported without overwriting the tokens
+pub fn import_records(
+    db: &Database,
+    records: &[KeyRecord],
+    dbs: &Arc<Databases>,
+) -> (u32, u32, u32) {
+    let (system_records, records): (Vec<&KeyRecord>, Vec<&KeyRecord>) =
+        records.iter().partition(|record| {
+            record.key.starts_with(SECURY_KEYS_PREFIX) || record.key == CONNECTIONS_KEY
+        });
+    let mut imported = 0;
+    let mut conflicts = 0;
+    for record in records {
+        // set stores version + 1 for new keys
+        let version = record.version - 1;
+        // Conflicts go through the consensus strategy of the database like any other set
+        match set_key_value(
+            record.key.to_string(),
+            record.value.to_string(),
+            version,
+            db,
+            dbs,
+        ) {
+            Response::Set { value, .. } if value == record.value => {
+                imported += 1;
+                let message = get_replicate_message(
+                    db.name.to_string(),
+                    record.key.to_string(),
+                    record.value.to_string(),
+                    version,
+                );
+                if dbs.is_primary() {
+                    replicate_web(&dbs.replication_sender, message);
+                } else {
+                    send_message_to_primary(message, dbs);
+                }
+            }
+            _ => {
+                log::debug!("Could not import the key {}", record.key);
+                conflicts += 1;
+            }
+        }
+    }
+    (imported, conflicts, system_records.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::{channel, Receiver, Sender};
+    use std::collections::HashMap;
+
+    fn create_dbs() -> (Arc<Databases>, Receiver<String>) {
+        let (sender, _): (Sender<String>, Receiver<String>) = channel(100);
+        let (replication_sender, replication_receiver): (Sender<String>, Receiver<String>) =
+            channel(100);
+        let dbs = Arc::new(Databases::new(
+            String::from(""),
+            String::from(""),
+            String::from(""),
+            String::from(""),
+            sender,
+            replication_sender,
+            HashMap::new(),
+            1 as u128,
+            true,
+        ));
+        dbs.node_state.swap(
+            ClusterRole::Primary as usize,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        (dbs, replication_receiver)
+    }
+
+    fn create_db() -> Database {
+        let db = Database::new(
+            String::from("export-test"),
+            DatabaseMataData::new(1, ConsensuStrategy::Newer),
+        );
+        db.set_value(&Change::new(
+            String::from("name"),
+            String::from("jose;1"),
+            -1,
+        ));
+        db.set_value(&Change::new(
+            String::from("name"),
+            String::from("maria"),
+            -1,
+        ));
+        db.set_value(&Change::new(String::from("$$token"), String::from("t"), -1));
+        db.set_value(&Change::new(
+            String::from("$connections"),
+            String::from("1"),
+            -1,
+        ));
+        db
+    }
+
+    #[test]
+    fn should_export_keys_without_system_keys() {
+        let (count, jsonl) = export_db_jsonl(&create_db(), false);
+        assert_eq!(count, 1);
+        assert_eq!(
+            jsonl,
+            "{\"db\":\"export-test\",\"consensus_strategy\":\"newer\",\"keys\":1}\n{\"key\":\"name\",\"value\":\"maria\",\"version\":1}\n"
+        );
+    }
+
+    #[test]
+    fn should_export_system_keys_if_asked_to() {
+        let (count, jsonl) = export_db_jsonl(&create_db(), true);
+        assert_eq!(count, 2);
+        assert!(jsonl.contains("{\"key\":\"$$token\",\"value\":\"t\",\"version\":0}"));
+        assert!(!jsonl.contains("$connections"));
+    }
+
+    #[test]
+    fn should_parse_exported_jsonl() {
+        let (_, jsonl) = export_db_jsonl(&create_db(), false);
+        let (header, records) = parse_jsonl(&jsonl).unwrap();
+        assert_eq!(header.unwrap().db, "export-test");
+        assert_eq!(
+            records,
+            vec![KeyRecord {
+                key: String::from("name"),
+                value: String::from("maria"),
+                version: 1,
+            }]
+        );
+        assert!(parse_jsonl("{\"foo\": 1}").is_err());
+    }
+
+    #[test]
+    fn should_escape_semicolons_in_the_import_command() {
+        let records = vec![KeyRecord {
+            key: String::from("name"),
+            value: String::from("jose;1"),
+            version: 1,
+        }];
+        let command = get_import_command("vue", &records);
+        assert!(!command.contains(';'));
+        let parsed = parse_import_records(command.splitn(3, ' ').last().unwrap()).unwrap();
+        assert_eq!(parsed, records);
+    }
+
+    #[test]
+    fn should_import_keeping_versions_and_replicate() {
+        let (dbs, mut replication_receiver) = create_dbs();
+        let db = Database::new(
+            String::from("import-test"),
+            DatabaseMataData::new(1, ConsensuStrategy::None),
+        );
+        db.set_value(&Change::new(String::from("age"), String::from("20"), 10));
+        let records = vec![
+            KeyRecord {
+                key: String::from("name"),
+                value: String::from("maria"),
+                version: 5,
+            },
+            KeyRecord {
+                key: String::from("age"),
+                value: String::from("30"),
+                version: 2,
+            },
+        ];
+        assert_eq!(import_records(&db, &records, &dbs), (1, 1, 0));
+        let name = db.get_value(String::from("name")).unwrap();
+        assert_eq!(name.value, "maria");
+        assert_eq!(name.version, 5);
+        assert_eq!(db.get_value(String::from("age")).unwrap().value, "20");
+        let replicated = replication_receiver.try_next().unwrap().unwrap();
+        assert!(replicated.ends_with("replicate import-test name 4 \"maria\""));
+
+        // The conflicts are resolved with the consensus strategy of the database
+        let newer_db = Database::new(
+            String::from("import-newer-test"),
+            DatabaseMataData::new(2, ConsensuStrategy::Newer),
+        );
+        newer_db.set_value(&Change::new(String::from("age"), String::from("20"), 10));
+        assert_eq!(import_records(&newer_db, &records[1..], &dbs), (1, 0, 0));
+        assert_eq!(newer_db.get_value(String::from("age")).unwrap().value, "30");
+    }
+
+    #[test]
+    fn should_skip_importing_system_keys() {
+        let (dbs, _replication_receiver) = create_dbs();
+        let db = create_db();
+        for key in ["$$token", "$$permission_$maria", CONNECTIONS_KEY] {
+            let records = vec![
+                KeyRecord {
+                    key: String::from("name"),
+                    value: String::from("jose"),
+                    version: 3,
+                },
+                KeyRecord {
+                    key: key.to_string(),
+                    value: String::from("pwd"),
+                    version: 9,
+                },
+            ];
+            assert_eq!(import_records(&db, &records, &dbs).2, 1);
+            assert!(db
+                .get_value(key.to_string())
+                .is_none_or(|value| value.value != "pwd"));
+        }
+    }
+
+    #[test]
+    fn should_import_an_export_with_the_system_keys() {
+        let (dbs, _replication_receiver) = create_dbs();
+        let (_, jsonl) = export_db_jsonl(&create_db(), true);
+        let (_, records) = parse_jsonl(&jsonl).unwrap();
+        let db = Database::new(
+            String::from("import-copy"),
+            DatabaseMataData::new(2, ConsensuStrategy::Newer),
+        );
+        db.set_value(&Change::new(
+            String::from("$$token"),
+            String::from("copy"),
+            -1,
+        ));
+        assert_eq!(import_records(&db, &records, &dbs), (1, 0, 1));
+        assert_eq!(db.get_value(String::from("name")).unwrap().value, "maria");
+        assert_eq!(db.get_value(String::from("$$token")).unwrap().value, "copy");
+    }
+}
@@ -1,3 +1,5 @@
+pub mod audit_ops;
+pub mod backup_ops;
 pub mod bo;
 pub mod client;
 pub mod command_line;
@@ -6,10 +8,12 @@ pub mod consensus_ops;
 pub mod db_ops;
 pub mod disk_ops;
 pub mod election_ops;
+pub mod export_ops;
 pub mod monitoring;
 pub mod network;
 pub mod parse_request;
 pub mod process_request;
+pub mod rate_limit_ops;
 pub mod replication_ops;
 pub mod security;
 pub mod storage;
@@ -1,4 +1,5 @@
 use crate::bo::*;
+use crate::network::listener_ops::listeners_limits;
 use atomic_float::*;
 use std::sync::atomic::Ordering;
 impl Databases {
@@ -20,9 +21,11 @@ impl Databases {
 
     pub fn get_monitoring_state(&self) -> String {
         format!(
-            "replication_time_moving_avg: {:?}, get_query_time_moving_avg: {:?}",
+            "replication_time_moving_avg: {:?}, get_query_time_moving_avg: {:?}, {}, {}",
             self.get_replication_time_moving_avg(),
             self.get_query_time_moving_avg(),
+            self.rate_limits.get_state(),
+            listeners_limits().get_state(),
         )
     }
 }
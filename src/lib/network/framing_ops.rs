@@ -0,0 +1,74 @@
+use serde_json::Value as JsonValue;
+
+/// How commands and messages are delimited on a connection, negotiated with `framing`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    // Lines on tcp and `;` separated commands on web sockets and http, values can't contain them
+    Text,
+    // Every frame is a json string, or an array of them, so values can contain anything
+    Json,
+}
+
+impl Framing {
+    pub fn parse(value: &str) -> Result<Framing, String> {
+        match value.trim() {
+            "text" => Ok(Framing::Text),
+            "json" => Ok(Framing::Json),
+            _ => Err(format!("Invalid framing {}, use text or json", value)),
+        }
+    }
+}
+
+/// Commands of a json frame, a string with one command or an array of them
+pub fn decode_json_frame(frame: &str) -> Result<Vec<String>, String> {
+    let invalid = || String::from("Invalid json frame, send a string or an array of strings");
+    match serde_json::from_str::<JsonValue>(frame).map_err(|_| invalid())? {
+        JsonValue::String(command) => Ok(vec![command]),
+        JsonValue::Array(commands) => commands
+            .into_iter()
+            .map(|command| match command {
+                JsonValue::String(command) => Ok(command),
+                _ => Err(invalid()),
+            })
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+/// Text messages end with a line break, the json message is the string without it
+pub fn encode_json_message(message: &str) -> String {
+    let message = message.strip_suffix('\n').unwrap_or(message);
+    JsonValue::String(message.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_decode_json_frames() {
+        assert_eq!(
+            decode_json_frame(r#""set name a;b\nc""#),
+            Ok(vec![String::from("set name a;b\nc")])
+        );
+        assert_eq!(
+            decode_json_frame(r#"["use-db test test-1", "get name"]"#),
+            Ok(vec![
+                String::from("use-db test test-1"),
+                String::from("get name")
+            ])
+        );
+        assert!(decode_json_frame("get name").is_err());
+        assert!(decode_json_frame(r#"["get name", 1]"#).is_err());
+    }
+
+    #[test]
+    fn should_encode_messages_as_json_strings() {
+        assert_eq!(
+            encode_json_message("changed name a;b\nc\n"),
+            r#""changed name a;b\nc""#
+        );
+        assert_eq!(Framing::parse("json"), Ok(Framing::Json));
+        assert!(Framing::parse("binary").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::bo::{Client, Databases};
+use crate::configuration::NUN_CLUSTER_NAME;
+
+/// Version of the protocol spoken by clients and nodes, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest protocol version this node still talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version of the other nodes, older nodes replicate values without encoding them
+pub const MIN_NODE_PROTOCOL_VERSION: u32 = 3;
+// Clients and nodes older than `hello` never send it
+const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+pub const CAPABILITIES: &[&str] = &[
+    "heartbeats",
+    "json-framing",
+    "request-ids",
+    "watch-versions",
+];
+
+fn min_protocol_version(client: &Client) -> u32 {
+    if client.is_cluster_node() {
+        MIN_NODE_PROTOCOL_VERSION
+    } else {
+        MIN_PROTOCOL_VERSION
+    }
+}
+
+/// Fails for peers whose `hello` sent an incompatible protocol version
+pub fn check_peer_protocol(client: &Client) -> Result<(), String> {
+    let protocol_version = client
+        .protocol_version
+        .read()
+        .unwrap()
+        .unwrap_or(LEGACY_PROTOCOL_VERSION);
+    let min_protocol_version = min_protocol_version(client);
+    if protocol_version >= min_protocol_version {
+        Ok(())
+    } else {
+        Err(format!(
+            "Incompatible protocol version {}, the oldest supported is {}",
+            protocol_version, min_protocol_version
+        ))
+    }
+}
+
+/// Capabilities of the client this server supports, all of them when the client lists none
+pub fn negotiate_capabilities(capabilities: &[String]) -> Vec<&'static str> {
+    CAPABILITIES
+        .iter()
+        .copied()
+        .filter(|capability| {
+            capabilities.is_empty() || capabilities.iter().any(|c| c == capability)
+        })
+        .collect()
+}
+
+/// Fails for the `server-info` of nodes this node can't replicate with
+pub fn check_server_info(server_info: &str) -> Result<(), String> {
+    check_node_server_info(server_info, PROTOCOL_VERSION)
+}
+
+fn check_node_server_info(server_info: &str, protocol_version: u32) -> Result<(), String> {
+    let field = |name: &str| {
+        server_info
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+            .and_then(|value| value.parse::<u32>().ok())
+    };
+    // Peers without `min-node-protocol` still refuse the nodes older than MIN_NODE_PROTOCOL_VERSION
+    let min_node_protocol = field("min-node-protocol").or_else(|| {
+        field("min-protocol").map(|min_protocol| min_protocol.max(MIN_NODE_PROTOCOL_VERSION))
+    });
+    match (field("protocol"), min_node_protocol) {
+        (Some(protocol), Some(min_node_protocol))
+            if protocol >= MIN_NODE_PROTOCOL_VERSION && min_node_protocol <= protocol_version =>
+        {
+            Ok(())
+        }
+        (Some(protocol), Some(min_node_protocol)) => Err(format!(
+            "Incompatible node protocol {} (oldest supported {}), this node speaks {} (oldest supported {})",
+            protocol, min_node_protocol, protocol_version, MIN_NODE_PROTOCOL_VERSION
+        )),
+        _ => Err(format!("Invalid server-info {}", server_info.trim())),
+    }
+}
+
+/// Response to `hello`
+pub fn server_info(dbs: &Databases, capabilities: &[String]) -> String {
+    format!(
+        "server-info version={} protocol={} min-protocol={} min-node-protocol={} framings=text,json role={} cluster={} capabilities={}\n",
+        env!("CARGO_PKG_VERSION"),
+        PROTOCOL_VERSION,
+        MIN_PROTOCOL_VERSION,
+        MIN_NODE_PROTOCOL_VERSION,
+        dbs.get_role(),
+        *NUN_CLUSTER_NAME,
+        negotiate_capabilities(capabilities).join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_negotiate_the_supported_capabilities() {
+        assert_eq!(negotiate_capabilities(&[]), CAPABILITIES.to_vec());
+        assert_eq!(
+            negotiate_capabilities(&[String::from("request-ids"), String::from("compression")]),
+            vec!["request-ids"]
+        );
+    }
+
+    #[test]
+    fn should_check_the_peer_protocol_version() {
+        let (client, _receiver) = Client::new_empty_and_receiver();
+        assert!(check_peer_protocol(&client).is_ok());
+        *client.protocol_version.write().unwrap() = Some(PROTOCOL_VERSION);
+        assert!(check_peer_protocol(&client).is_ok());
+        *client.protocol_version.write().unwrap() = Some(0);
+        assert!(check_peer_protocol(&client).is_err());
+
+        // Nodes older than the value encoding of the replication messages are refused
+        client.auth_as_node();
+        *client.protocol_version.write().unwrap() = None;
+        assert!(check_peer_protocol(&client).is_err());
+        *client.protocol_version.write().unwrap() = Some(2);
+        assert!(check_peer_protocol(&client).is_err());
+        *client.protocol_version.write().unwrap() = Some(PROTOCOL_VERSION);
+        assert!(check_peer_protocol(&client).is_ok());
+    }
+
+    #[test]
+    fn should_refuse_incompatible_nodes_from_their_server_info() {
+        assert!(check_server_info(&format!(
+            "server-info version=1.0 protocol={} min-protocol={} role=primary",
+            PROTOCOL_VERSION, MIN_PROTOCOL_VERSION
+        ))
+        .is_ok());
+        assert!(check_server_info(
+            "server-info version=0.9 protocol=2 min-protocol=1 framings=text role=primary"
+        )
+        .is_err());
+        assert!(check_server_info(&format!(
+            "server-info version=9.0 protocol=9 min-protocol={}",
+            PROTOCOL_VERSION + 1
+        ))
+        .is_err());
+        assert!(check_server_info("invalid auth").is_err());
+    }
+
+    #[test]
+    fn should_check_the_min_node_protocol_of_the_server_info() {
+        let server_info = format!(
+            "server-info version=1.0 protocol={} min-protocol={} min-node-protocol={} role=primary",
+            PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, MIN_NODE_PROTOCOL_VERSION
+        );
+        assert!(check_node_server_info(&server_info, 3).is_ok());
+        // min-protocol accepts clients speaking 2, min-node-protocol refuses nodes speaking 2
+        assert!(check_node_server_info(&server_info, 2).is_err());
+        assert!(check_node_server_info(
+            "server-info version=1.0 protocol=3 min-protocol=1 role=primary",
+            2
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use crate::bo::Client;
+use crate::configuration::{NUN_HEARTBEAT_INTERVAL, NUN_IDLE_TIMEOUT};
+
+/// Sent to idle watching tcp connections, web sockets get ping frames instead
+pub const HEARTBEAT_MESSAGE: &str = "ping\n";
+const HEARTBEAT_REPLY: &str = "pong";
+
+#[derive(Debug, PartialEq)]
+pub enum IdleAction {
+    Wait,
+    Heartbeat,
+    Close,
+    // The access token of a watching connection expired
+    Expired,
+}
+
+// Expired access tokens are checked even with heartbeats and the idle timeout disabled
+const EXPIRED_TOKEN_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Heartbeat interval and idle timeout of a connection, zero disables them
+#[derive(Clone, Copy)]
+pub struct IdleSettings {
+    heartbeat_interval: Duration,
+    idle_timeout: Duration,
+}
+
+impl IdleSettings {
+    pub fn new(heartbeat_interval: Duration, idle_timeout: Duration) -> IdleSettings {
+        IdleSettings {
+            heartbeat_interval,
+            idle_timeout,
+        }
+    }
+
+    pub fn from_config() -> IdleSettings {
+        IdleSettings::new(
+            Duration::from_secs(*NUN_HEARTBEAT_INTERVAL),
+            Duration::from_secs(*NUN_IDLE_TIMEOUT),
+        )
+    }
+
+    /// How often idle connections are checked
+    pub fn tick(&self) -> Duration {
+        [self.heartbeat_interval, self.idle_timeout]
+            .iter()
+            .copied()
+            .filter(|duration| !duration.is_zero())
+            .fold(EXPIRED_TOKEN_CHECK_INTERVAL, Duration::min)
+    }
+
+    /// What to do with a connection nothing was received from for `idle`
+    pub fn action(&self, client: &Client, idle: Duration) -> IdleAction {
+        // Replication connections are idle while there are no writes
+        if client.is_cluster_node() {
+            IdleAction::Wait
+        } else if client.is_watching() && client.selected_db.is_expired() {
+            IdleAction::Expired
+        } else if !self.idle_timeout.is_zero() && idle >= self.idle_timeout {
+            IdleAction::Close
+        } else if !self.heartbeat_interval.is_zero()
+            && client.is_watching()
+            && idle >= self.heartbeat_interval
+        {
+            IdleAction::Heartbeat
+        } else {
+            IdleAction::Wait
+        }
+    }
+}
+
+/// Replies to heartbeats only refresh the idle time, they are not processed as commands
+pub fn is_heartbeat_reply(command: &str) -> bool {
+    command.trim() == HEARTBEAT_REPLY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_ping_watching_connections_and_close_idle_ones() {
+        let settings = IdleSettings::new(Duration::from_secs(30), Duration::from_secs(90));
+        let (client, _receiver) = Client::new_empty_and_receiver();
+        assert_eq!(settings.tick(), Duration::from_secs(30));
+        assert_eq!(
+            settings.action(&client, Duration::from_secs(40)),
+            IdleAction::Wait
+        );
+        client.set_watching(true);
+        assert_eq!(
+            settings.action(&client, Duration::from_secs(20)),
+            IdleAction::Wait
+        );
+        assert_eq!(
+            settings.action(&client, Duration::from_secs(40)),
+            IdleAction::Heartbeat
+        );
+        assert_eq!(
+            settings.action(&client, Duration::from_secs(90)),
+            IdleAction::Close
+        );
+        client.auth_as_node();
+        assert_eq!(
+            settings.action(&client, Duration::from_secs(90)),
+            IdleAction::Wait
+        );
+    }
+
+    #[test]
+    fn should_disable_heartbeats_and_the_idle_timeout_with_zero() {
+        let settings = IdleSettings::new(Duration::ZERO, Duration::from_secs(60));
+        let (client, _receiver) = Client::new_empty_and_receiver();
+        client.set_watching(true);
+        assert_eq!(settings.tick(), Duration::from_secs(30));
+        assert_eq!(
+            settings.action(&client, Duration::from_secs(59)),
+            IdleAction::Wait
+        );
+        assert_eq!(
+            IdleSettings::new(Duration::ZERO, Duration::ZERO).tick(),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            IdleSettings::new(Duration::ZERO, Duration::ZERO).action(&client, Duration::ZERO),
+            IdleAction::Wait
+        );
+        assert!(is_heartbeat_reply("pong\n"));
+        assert!(!is_heartbeat_reply("ping"));
+    }
+
+    #[test]
+    fn should_close_watching_connections_with_expired_tokens() {
+        let settings = IdleSettings::new(Duration::ZERO, Duration::ZERO);
+        let (client, _receiver) = Client::new_empty_and_receiver();
+        client.selected_db.set_access_scope(None, Some(1));
+        assert_eq!(settings.action(&client, Duration::ZERO), IdleAction::Wait);
+        client.set_watching(true);
+        assert_eq!(
+            settings.action(&client, Duration::ZERO),
+            IdleAction::Expired
+        );
+        client.selected_db.set_access_scope(None, None);
+        assert_eq!(settings.action(&client, Duration::ZERO), IdleAction::Wait);
+    }
+}
@@ -1,25 +1,41 @@
 use futures::channel::mpsc::Receiver;
+use std::io::Write;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use tiny_http;
 
 use crate::bo::*;
+use crate::network::framing_ops::{decode_json_frame, encode_json_message, Framing};
+use crate::network::listener_ops::{listeners_limits, ConnectionPermit};
+use crate::network::rest_ops::{
+    is_watch_request, open_watch_stream, process_rest_request, RestAuth, WatchStream, REST_PREFIX,
+};
+use crate::network::tls_ops::{http_ssl_config, is_tls_enabled_for, tls_generation, TLS_HTTP};
 use crate::process_request::*;
 use crate::security::*;
 
 fn process_commands(
-    commands: &Vec<&str>,
+    commands: &[String],
+    framing: Framing,
     receiver: &mut Receiver<String>,
     dbs: &Arc<Databases>,
     client: &mut Client,
 ) -> Vec<String> {
     let mut responses = Vec::new();
     for command in commands {
-        let clean_command = command.trim();
+        let clean_command = match framing {
+            Framing::Text => command.trim(),
+            Framing::Json => command.as_str(),
+        };
         if clean_command != "" {
-            match process_request(clean_command, dbs, client) {
+            let response = match framing {
+                Framing::Text => process_request(clean_command, dbs, client),
+                Framing::Json => process_framed_request(clean_command, dbs, client),
+            };
+            match response {
                 Response::Error { msg } => {
-                    responses.push(msg.clone());
+                    responses.push(client.label_message(&msg));
                     log::debug!("Http response Error: {}", msg);
                 }
                 Response::VersionError {
@@ -32,7 +48,7 @@ fn process_commands(
                     change: _,
                     db: _,
                 } => {
-                    responses.push(msg.clone());
+                    responses.push(client.label_message(&msg));
                     log::debug!("Http response Error: {}", msg);
                 }
                 _ => {
@@ -43,12 +59,12 @@ fn process_commands(
                                 responses.push(message);
                             }
                             _ => {
-                                responses.push("empty".to_string());
+                                responses.push(client.label_message("empty"));
                                 log::debug!("http_ops::process_message::Empty message");
                             }
                         },
                         Err(e) => {
-                            responses.push("empty".to_string());
+                            responses.push(client.label_message("empty"));
                             log::debug!(
                                 "http_ops::receiver.try_next empty for {}, message {}",
                                 clean_command,
@@ -66,47 +82,192 @@ fn process_commands(
 
     return responses;
 }
+fn handle_request(mut rq: tiny_http::Request, dbs: &Arc<Databases>) {
+    let permit = match listeners_limits().http.accept(rq.remote_addr().copied()) {
+        Ok(permit) => permit,
+        Err(_) => {
+            if let Err(e) = rq.respond(tiny_http::Response::empty(403)) {
+                log::warn!("http_ops response error {}", e);
+            }
+            return;
+        }
+    };
+    let remote_address = rq.remote_addr().map(|addr| addr.ip().to_string());
+    if rq.url().starts_with(REST_PREFIX) {
+        return handle_rest_request(rq, dbs, remote_address, permit);
+    }
+    let (mut client, mut receiver) = Client::new_empty_and_receiver();
+    client.set_remote_address(remote_address);
+    let mut body = String::new();
+    match rq.as_reader().read_to_string(&mut body) {
+        Ok(_) => {
+            log::debug!("[http] body {}", clean_string_to_log(&body, dbs));
+            // A json body is a json frame and gets a json array with the responses
+            let is_json = rq.headers().iter().any(|header| {
+                header.field.equiv("Content-Type")
+                    && header.value.as_str().starts_with("application/json")
+            });
+            let (framing, commands) = if is_json {
+                match decode_json_frame(&body) {
+                    Ok(commands) => (Framing::Json, commands),
+                    Err(e) => {
+                        let response = tiny_http::Response::from_string(e).with_status_code(400);
+                        if let Err(e) = rq.respond(response) {
+                            log::warn!("http_ops response error {}", e);
+                        }
+                        return;
+                    }
+                }
+            } else {
+                (Framing::Text, body.split(';').map(String::from).collect())
+            };
+            let responses = process_commands(&commands, framing, &mut receiver, dbs, &mut client);
+            let response = match framing {
+                Framing::Text => tiny_http::Response::from_string(responses.join(";")),
+                Framing::Json => tiny_http::Response::from_string(format!(
+                    "[{}]",
+                    responses
+                        .iter()
+                        .map(|response| encode_json_message(response))
+                        .collect::<Vec<String>>()
+                        .join(",")
+                ))
+                .with_header(
+                    "Content-Type: application/json"
+                        .parse::<tiny_http::Header>()
+                        .unwrap(),
+                ),
+            };
+            match rq.respond(response) {
+                Ok(_) => {}
+                Err(e) => log::warn!("http_ops response error {}", e),
+            }
+            log::debug!(
+                "[http] Processing the body{}",
+                clean_string_to_log(&body, dbs)
+            );
+        }
+        Err(e) => log::warn!("error {}", e),
+    }
+}
+
+fn handle_rest_request(
+    mut rq: tiny_http::Request,
+    dbs: &Arc<Databases>,
+    remote_address: Option<String>,
+    permit: ConnectionPermit,
+) {
+    let mut body = String::new();
+    if let Err(e) = rq.as_reader().read_to_string(&mut body) {
+        log::warn!("error {}", e);
+        return;
+    }
+    let headers: Vec<(String, String)> = rq
+        .headers()
+        .iter()
+        .map(|header| (header.field.to_string(), header.value.to_string()))
+        .collect();
+    let auth = RestAuth::from_headers(&headers);
+    let rest_response = if is_watch_request(rq.method().as_str(), rq.url()) {
+        let last_event_id = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("last-event-id"))
+            .map(|(_, value)| value.as_str());
+        match open_watch_stream(rq.url(), &auth, last_event_id, dbs, remote_address) {
+            Ok(stream) => return start_watch_stream(rq, stream, permit),
+            Err(response) => response,
+        }
+    } else {
+        process_rest_request(
+            rq.method().as_str(),
+            rq.url(),
+            &auth,
+            &body,
+            dbs,
+            remote_address,
+        )
+    };
+    drop(permit);
+    let response = match rest_response.body {
+        Some(body) => tiny_http::Response::from_string(body.to_string())
+            .with_status_code(rest_response.status)
+            .with_header(
+                "Content-Type: application/json"
+                    .parse::<tiny_http::Header>()
+                    .unwrap(),
+            ),
+        None => tiny_http::Response::from_string("").with_status_code(rest_response.status),
+    };
+    if let Err(e) = rq.respond(response) {
+        log::warn!("http_ops response error {}", e);
+    }
+}
+
+// Streams run in their own thread so they don't hold one of the http workers
+fn start_watch_stream(rq: tiny_http::Request, stream: WatchStream, permit: ConnectionPermit) {
+    let mut writer = rq.into_writer();
+    thread::spawn(move || {
+        let _permit = permit;
+        let headers = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+        match writer
+            .write_all(headers.as_bytes())
+            .and_then(|_| writer.flush())
+        {
+            Ok(_) => stream.stream(&mut writer),
+            Err(e) => log::debug!("[http] watch stream error {}", e),
+        }
+    });
+}
+
+fn bind_http_server(http_address: &str) -> tiny_http::Server {
+    let mut attempts = 0;
+    loop {
+        let server = match http_ssl_config() {
+            Some(ssl_config) => tiny_http::Server::https(http_address, ssl_config),
+            None => tiny_http::Server::http(http_address),
+        };
+        match server {
+            Ok(server) => return server,
+            // The previous server may still be releasing the address after a certificates reload
+            Err(e) if attempts < 50 => {
+                log::debug!("http bind error {}, retrying", e);
+                attempts += 1;
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => panic!("Http bind error {}", e),
+        }
+    }
+}
+
 pub fn start_http_client(dbs: Arc<Databases>, http_address: Arc<String>) {
     let http_address = http_address.to_string();
     log::debug!(
         "Starting the http client with 4 threads in the addr: {}",
         http_address
     );
-    let http_server = tiny_http::Server::http(http_address).unwrap();
-    let http_server = Arc::new(http_server);
-    let mut guards = Vec::with_capacity(4);
-    for _ in 0..4 {
-        let server = http_server.clone();
-        let dbs = dbs.clone();
-        let guard = thread::spawn(move || loop {
-            let (mut client, mut receiver) = Client::new_empty_and_receiver();
-            let mut body = String::new();
-
-            match server.recv() {
-                Ok(mut rq) => match rq.as_reader().read_to_string(&mut body) {
-                    Ok(_) => {
-                        log::debug!("[http] body {}", clean_string_to_log(&body, &dbs));
-                        let commands: Vec<&str> = body.split(';').collect();
-                        let responses =
-                            process_commands(&commands, &mut receiver, &dbs, &mut client);
-                        let response = tiny_http::Response::from_string(responses.join(";"));
-                        match rq.respond(response) {
-                            Ok(_) => {}
-                            Err(e) => log::warn!("http_ops response error {}", e),
-                        }
-                        log::debug!(
-                            "[http] Processing the body{}",
-                            clean_string_to_log(&body, &dbs)
-                        );
+    // The server is started again when the TLS certificates are reloaded
+    loop {
+        let tls_generation_started = tls_generation();
+        let restart_on_reload = is_tls_enabled_for(TLS_HTTP);
+        let http_server = Arc::new(bind_http_server(&http_address));
+        let mut guards = Vec::with_capacity(4);
+        for _ in 0..4 {
+            let server = http_server.clone();
+            let dbs = dbs.clone();
+            let guard = thread::spawn(move || {
+                while !restart_on_reload || tls_generation() == tls_generation_started {
+                    match server.recv_timeout(Duration::from_secs(1)) {
+                        Ok(Some(rq)) => handle_request(rq, &dbs),
+                        Ok(None) => (),
+                        Err(e) => log::error!("server.recv::error {}", e),
                     }
-                    Err(e) => log::warn!("error {}", e),
-                },
-                Err(e) => log::error!("server.recv::error {}", e),
-            }
-        });
-        guards.push(guard);
-    }
-    for h in guards {
-        h.join().unwrap();
+                }
+            });
+            guards.push(guard);
+        }
+        for h in guards {
+            h.join().unwrap();
+        }
+        log::info!("Restarting the http server to use the reloaded certificates");
     }
 }
@@ -0,0 +1,248 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+
+use crate::configuration::{
+    NUN_HTTP_ALLOWLIST, NUN_HTTP_MAX_CONNECTIONS, NUN_RESP_ALLOWLIST, NUN_RESP_MAX_CONNECTIONS,
+    NUN_TCP_ALLOWLIST, NUN_TCP_MAX_CONNECTIONS, NUN_WS_ALLOWLIST, NUN_WS_MAX_CONNECTIONS,
+};
+
+/// Network written as `10.0.0.0/8`, a single address matches only itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let invalid = || format!("Invalid CIDR {}", value);
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (
+                address,
+                Some(prefix_len.parse::<u8>().map_err(|_| invalid())?),
+            ),
+            None => (value, None),
+        };
+        let network = address.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        match prefix_len.unwrap_or(max_len) {
+            prefix_len if prefix_len <= max_len => Ok(Cidr {
+                network,
+                prefix_len,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rest_bits = prefix_len % 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    rest_bits == 0 || {
+        let mask = 0xffu8 << (8 - rest_bits);
+        network[full_bytes] & mask == ip[full_bytes] & mask
+    }
+}
+
+/// Who can connect to a listener and how many connections it can have at once
+pub struct ListenerLimits {
+    pub name: String,
+    // Empty allows any address
+    allowlist: Vec<Cidr>,
+    // 0 means unlimited
+    max_connections: usize,
+    connections: Arc<AtomicUsize>,
+    refused: AtomicU64,
+}
+
+/// Holds one of the connections of a listener until dropped
+pub struct ConnectionPermit {
+    connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ListenerLimits {
+    pub fn new(
+        name: &str,
+        allowlist: &str,
+        max_connections: usize,
+    ) -> Result<ListenerLimits, String> {
+        let allowlist = allowlist
+            .split(',')
+            .map(|cidr| cidr.trim())
+            .filter(|cidr| !cidr.is_empty())
+            .map(Cidr::parse)
+            .collect::<Result<Vec<Cidr>, String>>()?;
+        Ok(ListenerLimits {
+            name: name.to_string(),
+            allowlist,
+            max_connections,
+            connections: Arc::new(AtomicUsize::new(0)),
+            refused: AtomicU64::new(0),
+        })
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.allowlist.is_empty() || self.allowlist.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Checks the peer before the connection is handled, refused connections are counted
+    pub fn accept(&self, peer: Option<SocketAddr>) -> Result<ConnectionPermit, String> {
+        let result = match peer {
+            Some(peer) if self.is_allowed(&peer.ip()) => self.take_connection(),
+            Some(peer) => Err(format!("{} is not in the allowlist", peer.ip())),
+            None if self.allowlist.is_empty() => self.take_connection(),
+            None => Err(String::from("unknown peer address")),
+        };
+        if let Err(e) = &result {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            log::warn!("Refused {} connection: {}", self.name, e);
+        }
+        result
+    }
+
+    fn take_connection(&self) -> Result<ConnectionPermit, String> {
+        let connections = self.connections.fetch_add(1, Ordering::SeqCst);
+        let permit = ConnectionPermit {
+            connections: self.connections.clone(),
+        };
+        if self.max_connections > 0 && connections >= self.max_connections {
+            return Err(format!("max connections {} reached", self.max_connections));
+        }
+        Ok(permit)
+    }
+
+    pub fn refused_count(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
+
+pub struct ListenersLimits {
+    pub tcp: ListenerLimits,
+    pub ws: ListenerLimits,
+    pub http: ListenerLimits,
+    pub resp: ListenerLimits,
+}
+
+impl ListenersLimits {
+    pub fn from_env() -> Result<ListenersLimits, String> {
+        Ok(ListenersLimits {
+            tcp: ListenerLimits::new("tcp", &NUN_TCP_ALLOWLIST, *NUN_TCP_MAX_CONNECTIONS)?,
+            ws: ListenerLimits::new("ws", &NUN_WS_ALLOWLIST, *NUN_WS_MAX_CONNECTIONS)?,
+            http: ListenerLimits::new("http", &NUN_HTTP_ALLOWLIST, *NUN_HTTP_MAX_CONNECTIONS)?,
+            resp: ListenerLimits::new("resp", &NUN_RESP_ALLOWLIST, *NUN_RESP_MAX_CONNECTIONS)?,
+        })
+    }
+
+    pub fn unlimited() -> ListenersLimits {
+        ListenersLimits {
+            tcp: ListenerLimits::new("tcp", "", 0).unwrap(),
+            ws: ListenerLimits::new("ws", "", 0).unwrap(),
+            http: ListenerLimits::new("http", "", 0).unwrap(),
+            resp: ListenerLimits::new("resp", "", 0).unwrap(),
+        }
+    }
+
+    pub fn get_state(&self) -> String {
+        format!(
+            "refused_tcp: {}, refused_ws: {}, refused_http: {}, refused_resp: {}",
+            self.tcp.refused_count(),
+            self.ws.refused_count(),
+            self.http.refused_count(),
+            self.resp.refused_count(),
+        )
+    }
+}
+
+lazy_static! {
+    static ref LISTENERS_LIMITS: RwLock<Arc<ListenersLimits>> =
+        RwLock::new(Arc::new(ListenersLimits::unlimited()));
+}
+
+/// Must be called before the listeners start
+pub fn init_listeners_limits(limits: ListenersLimits) {
+    *LISTENERS_LIMITS.write().unwrap() = Arc::new(limits);
+}
+
+pub fn listeners_limits() -> Arc<ListenersLimits> {
+    LISTENERS_LIMITS.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(address.parse().unwrap(), 3014))
+    }
+
+    #[test]
+    fn should_match_addresses_in_the_cidr() {
+        let cidr = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(cidr.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(!cidr.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+        assert!(Cidr::parse("172.16.0.0/12")
+            .unwrap()
+            .contains(&"172.31.255.255".parse().unwrap()));
+        assert!(!Cidr::parse("172.16.0.0/12")
+            .unwrap()
+            .contains(&"172.32.0.1".parse().unwrap()));
+        assert!(Cidr::parse("127.0.0.1")
+            .unwrap()
+            .contains(&"127.0.0.1".parse().unwrap()));
+        assert!(Cidr::parse("fd00::/8")
+            .unwrap()
+            .contains(&"fd12::1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(&"8.8.8.8".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("internal").is_err());
+    }
+
+    #[test]
+    fn should_refuse_addresses_out_of_the_allowlist() {
+        let limits = ListenerLimits::new("tcp", "10.0.0.0/8, 192.168.1.10", 0).unwrap();
+        assert!(limits.accept(peer("10.3.2.1")).is_ok());
+        assert!(limits.accept(peer("192.168.1.10")).is_ok());
+        assert!(limits.accept(peer("192.168.1.11")).is_err());
+        assert!(limits.accept(None).is_err());
+        assert_eq!(limits.refused_count(), 2);
+        assert!(ListenerLimits::new("tcp", "10.0.0.0/8,nope", 0).is_err());
+    }
+
+    #[test]
+    fn should_limit_the_concurrent_connections() {
+        let limits = ListenerLimits::new("ws", "", 2).unwrap();
+        let first = limits.accept(peer("10.0.0.1")).unwrap();
+        let _second = limits.accept(None).unwrap();
+        assert!(limits.accept(peer("10.0.0.1")).is_err());
+        drop(first);
+        assert!(limits.accept(peer("10.0.0.1")).is_ok());
+        assert_eq!(limits.refused_count(), 1);
+    }
+}
@@ -1,3 +1,10 @@
+pub mod framing_ops;
+pub mod handshake_ops;
+pub mod heartbeat_ops;
 pub mod http_ops;
+pub mod listener_ops;
+pub mod resp_ops;
+pub mod rest_ops;
 pub mod tcp_ops;
+pub mod tls_ops;
 pub mod ws_ops;
@@ -0,0 +1,812 @@
+use futures::channel::mpsc::Receiver;
+use futures::StreamExt;
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::bo::*;
+use crate::configuration::NUN_MAX_VALUE_SIZE;
+use crate::network::listener_ops::{listeners_limits, ConnectionPermit};
+use crate::network::tcp_ops::COMMANDS_PER_CPU;
+use crate::process_request::*;
+
+// Same limit redis uses by default for a single request
+const MAX_ARRAY_SIZE: usize = 1024 * 1024;
+// Commands read ahead of the one being processed
+const COMMANDS_BUFFER: usize = 16;
+
+/// RESP2 and RESP3 values, maps and pushes are sent as arrays to RESP2 clients
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<RespValue>),
+    Map(Vec<(RespValue, RespValue)>),
+    Push(Vec<RespValue>),
+}
+
+impl RespValue {
+    fn bulk(value: &str) -> RespValue {
+        RespValue::Bulk(value.to_string())
+    }
+
+    pub fn encode(&self, protocol: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(protocol, &mut out);
+        out
+    }
+
+    fn encode_into(&self, protocol: u8, out: &mut Vec<u8>) {
+        match self {
+            RespValue::Simple(value) => out.extend(format!("+{}\r\n", value).as_bytes()),
+            RespValue::Error(msg) => {
+                // Errors are a single line
+                out.extend(format!("-{}\r\n", msg.replace(['\r', '\n'], " ")).as_bytes())
+            }
+            RespValue::Integer(value) => out.extend(format!(":{}\r\n", value).as_bytes()),
+            RespValue::Bulk(value) => {
+                out.extend(format!("${}\r\n", value.len()).as_bytes());
+                out.extend(value.as_bytes());
+                out.extend(b"\r\n");
+            }
+            RespValue::Null if protocol >= 3 => out.extend(b"_\r\n"),
+            RespValue::Null => out.extend(b"$-1\r\n"),
+            RespValue::Array(items) => encode_items('*', items, protocol, out),
+            RespValue::Push(items) if protocol >= 3 => encode_items('>', items, protocol, out),
+            RespValue::Push(items) => encode_items('*', items, protocol, out),
+            RespValue::Map(entries) => {
+                let prefix = if protocol >= 3 {
+                    format!("%{}\r\n", entries.len())
+                } else {
+                    format!("*{}\r\n", entries.len() * 2)
+                };
+                out.extend(prefix.as_bytes());
+                for (key, value) in entries {
+                    key.encode_into(protocol, out);
+                    value.encode_into(protocol, out);
+                }
+            }
+        }
+    }
+}
+
+fn encode_items(prefix: char, items: &[RespValue], protocol: u8, out: &mut Vec<u8>) {
+    out.extend(format!("{}{}\r\n", prefix, items.len()).as_bytes());
+    for item in items {
+        item.encode_into(protocol, out);
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Option<String>, String> {
+    let mut line = Vec::new();
+    // Inline commands carry values too, so lines have the same limit
+    match reader
+        .take(max_size as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await
+    {
+        Ok(0) => Ok(None),
+        Ok(_) if line.last() != Some(&b'\n') && line.len() > max_size => {
+            Err(String::from("line too long"))
+        }
+        Ok(_) => {
+            while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            String::from_utf8(line)
+                .map(Some)
+                .map_err(|_| String::from("invalid utf-8"))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_size(value: &str, max: usize) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(size) if size <= max => Ok(size),
+        _ => Err(format!("invalid size {}", value)),
+    }
+}
+
+/// Reads an array of bulk strings or an inline command, `None` when the connection is closed.
+/// Bulk strings are limited to `max_bulk_size` and read as they arrive, so the declared sizes
+/// never allocate memory up front
+pub async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_bulk_size: usize,
+) -> Result<Option<Vec<String>>, String> {
+    let line = match read_line(reader, max_bulk_size).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let size = match line.strip_prefix('*') {
+        Some(size) => parse_size(size, MAX_ARRAY_SIZE)?,
+        None => return Ok(Some(line.split_whitespace().map(String::from).collect())),
+    };
+    let mut args = Vec::new();
+    for _ in 0..size {
+        let line = read_line(reader, max_bulk_size)
+            .await?
+            .ok_or("connection closed")?;
+        let len = match line.strip_prefix('$') {
+            Some(len) => parse_size(len, max_bulk_size)?,
+            None => return Err(format!("expected '$', got '{}'", line)),
+        };
+        let mut value = Vec::new();
+        let read = reader
+            .take(len as u64 + 2)
+            .read_to_end(&mut value)
+            .await
+            .map_err(|e| e.to_string())?;
+        if read < len + 2 {
+            return Err(String::from("connection closed"));
+        }
+        value.truncate(len);
+        args.push(String::from_utf8(value).map_err(|_| String::from("invalid utf-8"))?);
+    }
+    Ok(Some(args))
+}
+
+fn response_to_resp(response: Response) -> RespValue {
+    match response {
+        Response::Error { msg } => RespValue::Error(format!("ERR {}", msg)),
+        _ => RespValue::Simple(String::from("OK")),
+    }
+}
+
+fn wrong_arguments(command: &str) -> RespValue {
+    RespValue::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+/// State of one RESP connection, requests run through process_request as any other client
+pub struct RespSession {
+    dbs: Arc<Databases>,
+    client: Client,
+    receiver: Receiver<String>,
+    protocol: u8,
+    subscriptions: Vec<String>,
+    // Watch notifications received while running a request
+    notifications: Vec<String>,
+}
+
+impl RespSession {
+    pub fn new(dbs: Arc<Databases>, remote_address: Option<String>) -> RespSession {
+        let (client, receiver) = Client::new_empty_and_receiver();
+        client.set_remote_address(remote_address);
+        RespSession {
+            dbs,
+            client,
+            receiver,
+            protocol: 2,
+            subscriptions: Vec::new(),
+            notifications: Vec::new(),
+        }
+    }
+
+    fn run(&mut self, command: &str, request: Request) -> (Response, Vec<String>) {
+        let response = process_parsed_request(request, command, &self.dbs, &mut self.client);
+        let mut messages = Vec::new();
+        while let Ok(message) = self.receiver.try_recv() {
+            if message.starts_with("changed") {
+                self.notifications.push(message);
+            } else {
+                messages.push(message);
+            }
+        }
+        (response, messages)
+    }
+
+    /// Watch notifications received since the last call, as `message` pushes. Watch-only
+    /// clients get `changed-key` notifications, pushed with a null payload
+    pub fn take_pushes(&mut self) -> Vec<RespValue> {
+        while let Ok(message) = self.receiver.try_recv() {
+            self.notifications.push(message);
+        }
+        self.notifications
+            .drain(..)
+            .filter_map(|message| {
+                let message = message.trim_end_matches('\n');
+                let (key, value) = match message.strip_prefix("changed ") {
+                    Some(rest) => rest
+                        .split_once(' ')
+                        .map(|(key, value)| (key, RespValue::bulk(value)))?,
+                    None => {
+                        let (key, _version) =
+                            message.strip_prefix("changed-key ")?.split_once(' ')?;
+                        (key, RespValue::Null)
+                    }
+                };
+                Some(RespValue::Push(vec![
+                    RespValue::bulk("message"),
+                    RespValue::bulk(key),
+                    value,
+                ]))
+            })
+            .collect()
+    }
+
+    fn get(&mut self, key: &str) -> RespValue {
+        match self.run(
+            "GET",
+            Request::Get {
+                key: key.to_string(),
+            },
+        ) {
+            (Response::Value { value, .. }, _) if value == "<Empty>" => RespValue::Null,
+            (Response::Value { value, .. }, _) => RespValue::Bulk(value),
+            (response, _) => response_to_resp(response),
+        }
+    }
+
+    fn auth(&mut self, user: &str, password: &str) -> RespValue {
+        let (response, messages) = self.run(
+            "AUTH",
+            Request::Auth {
+                user: user.to_string(),
+                password: password.to_string(),
+            },
+        );
+        match response {
+            Response::Error { .. } => response_to_resp(response),
+            _ if messages.iter().any(|m| m == "valid auth\n") => {
+                RespValue::Simple(String::from("OK"))
+            }
+            _ => RespValue::Error(String::from(
+                "WRONGPASS invalid username-password pair or user is disabled.",
+            )),
+        }
+    }
+
+    fn increment(&mut self, key: &str, inc: &str, negate: bool) -> RespValue {
+        let inc = match inc.parse::<i32>().ok().and_then(|inc| {
+            if negate {
+                inc.checked_neg()
+            } else {
+                Some(inc)
+            }
+        }) {
+            Some(inc) => inc,
+            None => {
+                return RespValue::Error(String::from(
+                    "ERR value is not an integer or out of range",
+                ))
+            }
+        };
+        match self.run(
+            "INCRBY",
+            Request::Increment {
+                key: key.to_string(),
+                inc,
+            },
+        ) {
+            (Response::Value { value, .. }, _) => match value.parse::<i64>() {
+                Ok(value) => RespValue::Integer(value),
+                Err(_) => RespValue::Error(String::from("ERR value is not an integer")),
+            },
+            // Secondaries forward the increment to the primary, only the primary knows the new value
+            (Response::Ok {}, _) => RespValue::Null,
+            (response, _) => response_to_resp(response),
+        }
+    }
+
+    fn delete(&mut self, keys: &[String]) -> RespValue {
+        let mut removed = 0;
+        for key in keys {
+            match self.run("DEL", Request::Remove { key: key.clone() }) {
+                (response @ Response::Error { .. }, _) => return response_to_resp(response),
+                (Response::Value { .. }, _) => removed += 1,
+                _ => (),
+            }
+        }
+        RespValue::Integer(removed)
+    }
+
+    fn keys(&mut self, pattern: &str) -> RespValue {
+        match self.run(
+            "KEYS",
+            Request::Keys {
+                pattern: pattern.to_string(),
+            },
+        ) {
+            (Response::Value { value, .. }, _) => RespValue::Array(
+                value
+                    .split(',')
+                    .filter(|key| !key.is_empty())
+                    .map(RespValue::bulk)
+                    .collect(),
+            ),
+            (response, _) => response_to_resp(response),
+        }
+    }
+
+    fn subscribe(&mut self, keys: &[String]) -> Vec<RespValue> {
+        let mut replies = Vec::new();
+        for key in keys {
+            let (response, _) = self.run("SUBSCRIBE", Request::Watch { key: key.clone() });
+            if let Response::Error { .. } = response {
+                replies.push(response_to_resp(response));
+                continue;
+            }
+            if !self.subscriptions.contains(key) {
+                self.subscriptions.push(key.clone());
+            }
+            replies.push(self.subscription_reply("subscribe", RespValue::bulk(key)));
+        }
+        replies
+    }
+
+    fn unsubscribe(&mut self, keys: &[String]) -> Vec<RespValue> {
+        let keys = if keys.is_empty() {
+            self.subscriptions.clone()
+        } else {
+            keys.to_vec()
+        };
+        if keys.is_empty() {
+            return vec![self.subscription_reply("unsubscribe", RespValue::Null)];
+        }
+        let mut replies = Vec::new();
+        for key in keys {
+            let (response, _) = self.run("UNSUBSCRIBE", Request::UnWatch { key: key.clone() });
+            if let Response::Error { .. } = response {
+                replies.push(response_to_resp(response));
+                continue;
+            }
+            self.subscriptions
+                .retain(|subscription| *subscription != key);
+            replies.push(self.subscription_reply("unsubscribe", RespValue::Bulk(key)));
+        }
+        replies
+    }
+
+    fn subscription_reply(&self, kind: &str, key: RespValue) -> RespValue {
+        RespValue::Push(vec![
+            RespValue::bulk(kind),
+            key,
+            RespValue::Integer(self.subscriptions.len() as i64),
+        ])
+    }
+
+    fn hello(&mut self, args: &[String]) -> RespValue {
+        let protocol = match args.first().map(|version| version.parse::<u8>()) {
+            None => self.protocol,
+            Some(Ok(version)) if version == 2 || version == 3 => version,
+            Some(_) => {
+                return RespValue::Error(String::from("NOPROTO unsupported protocol version"))
+            }
+        };
+        let mut options = args.iter().skip(1);
+        while let Some(option) = options.next() {
+            match (option.to_lowercase().as_str(), options.next()) {
+                ("auth", Some(user)) => match options.next() {
+                    Some(password) => {
+                        if let error @ RespValue::Error(_) = self.auth(user, password) {
+                            return error;
+                        }
+                    }
+                    None => return wrong_arguments("hello"),
+                },
+                ("setname", Some(_)) => (),
+                _ => return RespValue::Error(format!("ERR syntax error in HELLO {}", option)),
+            }
+        }
+        self.protocol = protocol;
+        RespValue::Map(vec![
+            (RespValue::bulk("server"), RespValue::bulk("nun-db")),
+            (
+                RespValue::bulk("version"),
+                RespValue::bulk(env!("CARGO_PKG_VERSION")),
+            ),
+            (
+                RespValue::bulk("proto"),
+                RespValue::Integer(protocol as i64),
+            ),
+            // nun-db does not speak the redis cluster protocol, so clients must not try it
+            (RespValue::bulk("mode"), RespValue::bulk("standalone")),
+            (RespValue::bulk("role"), RespValue::bulk(self.role())),
+            (RespValue::bulk("modules"), RespValue::Array(vec![])),
+        ])
+    }
+
+    fn role(&self) -> &'static str {
+        if self.dbs.is_primary() {
+            "master"
+        } else {
+            "replica"
+        }
+    }
+
+    /// Replies to a command, subscribe and unsubscribe have one reply per key
+    pub fn handle_command(&mut self, args: &[String]) -> Vec<RespValue> {
+        let command = match args.first() {
+            Some(command) => command.to_lowercase(),
+            None => return vec![],
+        };
+        let reply = match (command.as_str(), &args[1..]) {
+            ("ping", []) => RespValue::Simple(String::from("PONG")),
+            ("ping", [message]) | ("echo", [message]) => RespValue::bulk(message),
+            ("hello", rest) => self.hello(rest),
+            ("auth", [user, password]) => self.auth(user, password),
+            ("auth", [_]) => RespValue::Error(String::from(
+                "ERR nun-db needs the user name and the password, use AUTH user password",
+            )),
+            ("select", [name, token]) => {
+                let (response, _) = self.run(
+                    "SELECT",
+                    Request::UseDb {
+                        name: name.clone(),
+                        token: token.clone(),
+                        user_name: None,
+                    },
+                );
+                response_to_resp(response)
+            }
+            ("select", [name, user_name, token]) => {
+                let (response, _) = self.run(
+                    "SELECT",
+                    Request::UseDb {
+                        name: name.clone(),
+                        token: token.clone(),
+                        user_name: Some(user_name.clone()),
+                    },
+                );
+                response_to_resp(response)
+            }
+            ("get", [key]) => self.get(key),
+            ("set", [key, value]) => {
+                let (response, _) = self.run(
+                    "SET",
+                    Request::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                        version: -1,
+                    },
+                );
+                response_to_resp(response)
+            }
+            ("set", [_, _, ..]) => {
+                RespValue::Error(String::from("ERR SET options are not supported"))
+            }
+            ("del", keys) if !keys.is_empty() => self.delete(keys),
+            ("incr", [key]) => self.increment(key, "1", false),
+            ("decr", [key]) => self.increment(key, "1", true),
+            ("incrby", [key, inc]) => self.increment(key, inc, false),
+            ("decrby", [key, dec]) => self.increment(key, dec, true),
+            ("keys", [pattern]) => self.keys(pattern),
+            ("subscribe", keys) if !keys.is_empty() => return self.subscribe(keys),
+            ("unsubscribe", keys) => return self.unsubscribe(keys),
+            // Clients send these when connecting
+            ("command", _) => RespValue::Array(vec![]),
+            ("client", _) => RespValue::Simple(String::from("OK")),
+            (
+                "ping" | "echo" | "auth" | "select" | "get" | "set" | "del" | "incr" | "decr"
+                | "incrby" | "decrby" | "keys" | "subscribe",
+                _,
+            ) => wrong_arguments(&command),
+            _ => RespValue::Error(format!("ERR unknown command '{}'", args[0])),
+        };
+        vec![reply]
+    }
+
+    pub fn close(&mut self) {
+        self.run("UNWATCH-ALL", Request::UnWatchAll {});
+        self.client.left(&self.dbs);
+    }
+}
+
+pub fn start_resp_client(dbs: Arc<Databases>, resp_address: &str) {
+    log::debug!("starting resp client in the addr: {}", resp_address);
+    let listener = match std::net::TcpListener::bind(resp_address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("RESP Bind error: {}", e);
+            panic!("RESP Bind error");
+        }
+    };
+    listener
+        .set_nonblocking(true)
+        .expect("Could not set the resp listener as nonblocking");
+    let runtime = Runtime::new().expect("Could not create the resp runtime");
+    runtime.block_on(async {
+        let listener = TcpListener::from_std(listener).expect("Could not start the resp listener");
+        accept_clients(dbs, listener).await
+    });
+}
+
+async fn accept_clients(dbs: Arc<Databases>, listener: TcpListener) {
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let commands = Arc::new(Semaphore::new(cpus * COMMANDS_PER_CPU));
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::warn!("RESP accept error: {}", e);
+                continue;
+            }
+        };
+        let permit = match listeners_limits().resp.accept(Some(peer)) {
+            Ok(permit) => permit,
+            Err(_) => continue,
+        };
+        let remote_address = Some(peer.ip().to_string());
+        tokio::spawn(handle_client(
+            socket,
+            dbs.clone(),
+            commands.clone(),
+            remote_address,
+            permit,
+        ));
+    }
+}
+
+/// Sends the commands of the connection, a command is never lost half read while the
+/// connection is pushing watch notifications
+async fn read_commands(
+    mut reader: BufReader<OwnedReadHalf>,
+    sender: mpsc::Sender<Result<Vec<String>, String>>,
+) {
+    loop {
+        let command = read_command(&mut reader, *NUN_MAX_VALUE_SIZE).await;
+        let done = !matches!(command, Ok(Some(_)));
+        if let Some(command) = command.transpose() {
+            if sender.send(command).await.is_err() {
+                break;
+            }
+        }
+        if done {
+            break;
+        }
+    }
+}
+
+async fn handle_client(
+    socket: TcpStream,
+    dbs: Arc<Databases>,
+    commands: Arc<Semaphore>,
+    remote_address: Option<String>,
+    _permit: ConnectionPermit,
+) {
+    if let Err(e) = socket.set_nodelay(true) {
+        log::debug!("RESP set_nodelay error: {}", e);
+    }
+    let (reader, mut writer) = socket.into_split();
+    let (sender, mut received_commands) = mpsc::channel(COMMANDS_BUFFER);
+    let reader = tokio::spawn(read_commands(BufReader::new(reader), sender));
+    let mut session = RespSession::new(dbs, remote_address);
+    loop {
+        let (replies, quit) = tokio::select! {
+            command = received_commands.recv() => match command {
+                Some(Ok(args)) => {
+                    if args.first().map(|c| c.to_lowercase()) == Some(String::from("quit")) {
+                        (vec![RespValue::Simple(String::from("OK"))], true)
+                    } else {
+                        let _command = commands.acquire().await.unwrap();
+                        let replies = tokio::task::block_in_place(|| session.handle_command(&args));
+                        (replies, false)
+                    }
+                }
+                Some(Err(e)) => (
+                    vec![RespValue::Error(format!("ERR Protocol error: {}", e))],
+                    true,
+                ),
+                None => break,
+            },
+            Some(notification) = session.receiver.next() => {
+                session.notifications.push(notification);
+                (vec![], false)
+            }
+        };
+        let out: Vec<u8> = replies
+            .iter()
+            .chain(session.take_pushes().iter())
+            .flat_map(|value| value.encode(session.protocol))
+            .collect();
+        if !out.is_empty() {
+            if let Err(e) = writer.write_all(&out).await {
+                log::debug!("RESP client write error: {}", e);
+                break;
+            }
+        }
+        if quit {
+            break;
+        }
+    }
+    log::debug!("RESP client disconnected");
+    reader.abort();
+    tokio::task::block_in_place(|| session.close());
+    let _ = writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::{channel, Receiver, Sender};
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+
+    fn create_session() -> RespSession {
+        let (sender1, _receiver): (Sender<String>, Receiver<String>) = channel(100);
+        let (sender2, _receiver): (Sender<String>, Receiver<String>) = channel(100);
+        let dbs = Arc::new(Databases::new(
+            String::from("user"),
+            String::from("token"),
+            String::from(""),
+            String::from(""),
+            sender1,
+            sender2,
+            HashMap::new(),
+            1_u128,
+            true,
+        ));
+        dbs.node_state
+            .swap(ClusterRole::Primary as usize, Ordering::Relaxed);
+        let mut session = RespSession::new(dbs, None);
+        assert!(matches!(
+            command(&mut session, "AUTH user wrong")[0],
+            RespValue::Error(_)
+        ));
+        assert_eq!(
+            command(&mut session, "AUTH user token"),
+            vec![RespValue::Simple(String::from("OK"))]
+        );
+        process_request("create-db resp resp-1", &session.dbs, &mut session.client);
+        session
+    }
+
+    #[test]
+    fn should_reply_hello_with_the_role_of_the_node() {
+        let mut session = create_session();
+        let role = |reply: &RespValue| match reply {
+            RespValue::Map(entries) => entries
+                .iter()
+                .find(|(key, _)| *key == RespValue::bulk("role"))
+                .map(|(_, role)| role.clone()),
+            _ => None,
+        };
+        assert_eq!(
+            role(&command(&mut session, "HELLO 3")[0]),
+            Some(RespValue::bulk("master"))
+        );
+        session
+            .dbs
+            .node_state
+            .swap(ClusterRole::Secoundary as usize, Ordering::Relaxed);
+        assert_eq!(
+            role(&command(&mut session, "HELLO 3")[0]),
+            Some(RespValue::bulk("replica"))
+        );
+    }
+
+    fn command(session: &mut RespSession, line: &str) -> Vec<RespValue> {
+        let args: Vec<String> = line.split(' ').map(String::from).collect();
+        session.handle_command(&args)
+    }
+
+    fn read(input: &str) -> Result<Option<Vec<String>>, String> {
+        Runtime::new()
+            .unwrap()
+            .block_on(read_command(&mut input.as_bytes(), 16))
+    }
+
+    #[test]
+    fn should_read_arrays_and_inline_commands() {
+        assert_eq!(
+            read("*3\r\n$3\r\nSET\r\n$4\r\nname\r\n$8\r\njose\r\nma\r\n"),
+            Ok(Some(vec![
+                "SET".to_string(),
+                "name".to_string(),
+                "jose\r\nma".to_string()
+            ]))
+        );
+        assert_eq!(
+            read("GET name\r\n"),
+            Ok(Some(vec!["GET".to_string(), "name".to_string()]))
+        );
+        assert_eq!(read(""), Ok(None));
+        assert!(read("*1\r\n+GET\r\n").is_err());
+        assert!(read("*1\r\n$999999999999\r\n").is_err());
+        assert!(read("*2\r\n$3\r\nGET\r\n").is_err());
+        // Larger than the 16 bytes limit of the tests
+        assert!(read("*1\r\n$17\r\n").is_err());
+        assert!(read("*1\r\n$16\r\nabc").is_err());
+        assert!(read(&format!("SET name {}\r\n", "a".repeat(16))).is_err());
+    }
+
+    #[test]
+    fn should_encode_values_for_each_protocol() {
+        let push = RespValue::Push(vec![RespValue::bulk("message"), RespValue::Null]);
+        assert_eq!(push.encode(2), b"*2\r\n$7\r\nmessage\r\n$-1\r\n");
+        assert_eq!(push.encode(3), b">2\r\n$7\r\nmessage\r\n_\r\n");
+        let map = RespValue::Map(vec![(RespValue::bulk("proto"), RespValue::Integer(3))]);
+        assert_eq!(map.encode(2), b"*2\r\n$5\r\nproto\r\n:3\r\n");
+        assert_eq!(map.encode(3), b"%1\r\n$5\r\nproto\r\n:3\r\n");
+        assert_eq!(
+            RespValue::Error(String::from("ERR a\nb")).encode(2),
+            b"-ERR a b\r\n"
+        );
+    }
+
+    #[test]
+    fn should_map_commands_to_requests() {
+        let mut session = create_session();
+        let ok = vec![RespValue::Simple(String::from("OK"))];
+        assert!(matches!(
+            command(&mut session, "GET name")[0],
+            RespValue::Error(_)
+        ));
+        assert_eq!(command(&mut session, "SELECT resp resp-1"), ok);
+        assert_eq!(command(&mut session, "GET name"), vec![RespValue::Null]);
+        assert_eq!(command(&mut session, "SET name jose"), ok);
+        assert_eq!(
+            command(&mut session, "GET name"),
+            vec![RespValue::bulk("jose")]
+        );
+        assert_eq!(
+            command(&mut session, "INCRBY count 5"),
+            vec![RespValue::Integer(5)]
+        );
+        assert_eq!(
+            command(&mut session, "DECR count"),
+            vec![RespValue::Integer(4)]
+        );
+        assert_eq!(
+            command(&mut session, "KEYS n*"),
+            vec![RespValue::Array(vec![RespValue::bulk("name")])]
+        );
+        assert_eq!(
+            command(&mut session, "DEL name missing"),
+            vec![RespValue::Integer(1)]
+        );
+        assert_eq!(command(&mut session, "GET name"), vec![RespValue::Null]);
+        assert_eq!(
+            command(&mut session, "DEL name"),
+            vec![RespValue::Integer(0)]
+        );
+        session
+            .dbs
+            .node_state
+            .swap(ClusterRole::Secoundary as usize, Ordering::Relaxed);
+        assert_eq!(command(&mut session, "INCR count"), vec![RespValue::Null]);
+        assert!(matches!(
+            command(&mut session, "FLUSHALL")[0],
+            RespValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn should_push_changes_of_subscribed_keys() {
+        let mut session = create_session();
+        command(&mut session, "SELECT resp resp-1");
+        assert_eq!(
+            command(&mut session, "SUBSCRIBE name")[0],
+            RespValue::Push(vec![
+                RespValue::bulk("subscribe"),
+                RespValue::bulk("name"),
+                RespValue::Integer(1)
+            ])
+        );
+        command(&mut session, "SET name jose");
+        assert_eq!(
+            session.take_pushes(),
+            vec![RespValue::Push(vec![
+                RespValue::bulk("message"),
+                RespValue::bulk("name"),
+                RespValue::bulk("jose")
+            ])]
+        );
+        command(&mut session, "UNSUBSCRIBE");
+        command(&mut session, "SET name maria");
+        assert_eq!(session.take_pushes(), vec![]);
+    }
+}
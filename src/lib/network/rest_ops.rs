@@ -0,0 +1,817 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::channel::mpsc::Receiver;
+use futures::StreamExt;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bo::*;
+use crate::process_request::*;
+use crate::rate_limit_ops::RATE_LIMITED_MESSAGE;
+
+pub const REST_PREFIX: &str = "/dbs";
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Credentials sent in the headers, `Authorization: Basic` for admins,
+/// `Authorization: Bearer` for the database token and `X-Nun-Db-User` for database users
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RestAuth {
+    pub admin: Option<(String, String)>,
+    pub token: Option<String>,
+    pub user_name: Option<String>,
+}
+
+impl RestAuth {
+    pub fn from_headers(headers: &[(String, String)]) -> RestAuth {
+        let mut auth = RestAuth::default();
+        for (name, value) in headers {
+            match name.to_lowercase().as_str() {
+                "authorization" => match value.split_once(' ') {
+                    Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                        auth.admin = STANDARD
+                            .decode(credentials.trim())
+                            .ok()
+                            .and_then(|decoded| String::from_utf8(decoded).ok())
+                            .and_then(|decoded| {
+                                decoded
+                                    .split_once(':')
+                                    .map(|(user, pwd)| (user.to_string(), pwd.to_string()))
+                            });
+                    }
+                    Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                        auth.token = Some(token.trim().to_string());
+                    }
+                    _ => (),
+                },
+                "x-nun-db-user" => auth.user_name = Some(value.trim().to_string()),
+                _ => (),
+            }
+        }
+        auth
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RestResponse {
+    pub status: u16,
+    pub body: Option<JsonValue>,
+}
+
+impl RestResponse {
+    fn json(status: u16, body: JsonValue) -> RestResponse {
+        RestResponse {
+            status,
+            body: Some(body),
+        }
+    }
+
+    fn error(status: u16, msg: &str) -> RestResponse {
+        RestResponse::json(status, json!({ "error": msg }))
+    }
+
+    fn from_error(msg: &str) -> RestResponse {
+        // Some errors are also the messages sent to socket clients
+        let msg = msg.trim_end().trim_start_matches("error ");
+        RestResponse::error(error_status(msg), msg)
+    }
+}
+
+fn error_status(msg: &str) -> u16 {
+    match msg {
+        "Not auth" | "Invalid token" | "token-expired" | "no-db-selected" => 401,
+        "permission denied" | "To read security keys you must auth as an admin!" => 403,
+        msg if msg.starts_with("Role ") => 403,
+        "Not a valid database name" => 404,
+        "database already exists" | "Invalid version!" => 409,
+        RATE_LIMITED_MESSAGE => 429,
+        _ => 400,
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match value
+                .get(i + 1..i + 3)
+                .map(|hex| u8::from_str_radix(hex, 16))
+            {
+                Some(Ok(byte)) => {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('=').or(Some((param, ""))))
+        .find(|(param, _)| percent_decode(param) == name)
+        .map(|(_, value)| percent_decode(&value.replace('+', " ")))
+}
+
+fn parse_url(url: &str) -> (Vec<String>, &str) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let segments = path
+        .trim_start_matches(REST_PREFIX)
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect();
+    (segments, query)
+}
+
+struct RestSession {
+    dbs: Arc<Databases>,
+    client: Client,
+    receiver: Receiver<String>,
+}
+
+impl RestSession {
+    fn new(dbs: &Arc<Databases>, remote_address: Option<String>) -> RestSession {
+        let (client, receiver) = Client::new_empty_and_receiver();
+        client.set_remote_address(remote_address);
+        RestSession {
+            dbs: dbs.clone(),
+            client,
+            receiver,
+        }
+    }
+
+    fn close(&mut self) {
+        self.run("unwatch-all", Request::UnWatchAll {});
+        self.client.left(&self.dbs);
+    }
+
+    fn run(&mut self, description: &str, request: Request) -> (Response, Vec<String>) {
+        let response = process_parsed_request(request, description, &self.dbs, &mut self.client);
+        let mut messages = Vec::new();
+        while let Ok(message) = self.receiver.try_recv() {
+            messages.push(message);
+        }
+        (response, messages)
+    }
+
+    fn auth_admin(&mut self, auth: &RestAuth) -> Result<(), RestResponse> {
+        let (user, password) = match &auth.admin {
+            Some(admin) => admin.clone(),
+            None => return Err(RestResponse::error(401, "admin credentials required")),
+        };
+        match self.run("http auth", Request::Auth { user, password }) {
+            (_, messages) if messages.iter().any(|m| m == "valid auth\n") => Ok(()),
+            _ => Err(RestResponse::error(401, "invalid auth")),
+        }
+    }
+
+    fn use_db(&mut self, db_name: &str, auth: &RestAuth) -> Result<(), RestResponse> {
+        if auth.admin.is_some() {
+            self.auth_admin(auth)?;
+        }
+        let token = match &auth.token {
+            Some(token) => token.clone(),
+            None => return Err(RestResponse::error(401, "database token required")),
+        };
+        let request = Request::UseDb {
+            name: db_name.to_string(),
+            token,
+            user_name: auth.user_name.clone(),
+        };
+        match self.run("http use-db", request) {
+            (Response::Error { msg }, _) => Err(RestResponse::from_error(&msg)),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_key(&mut self, key: &str) -> RestResponse {
+        let request = Request::Get {
+            key: key.to_string(),
+        };
+        match self.run("http get", request) {
+            (Response::Value { value, .. }, _) if value == "<Empty>" => {
+                RestResponse::error(404, "key not found")
+            }
+            (
+                Response::Value {
+                    key,
+                    value,
+                    version,
+                },
+                _,
+            ) => RestResponse::json(
+                200,
+                json!({ "key": key, "value": value, "version": version }),
+            ),
+            (Response::Error { msg }, _) => RestResponse::from_error(&msg),
+            _ => RestResponse::error(500, "unexpected response"),
+        }
+    }
+
+    fn put_key(&mut self, key: &str, body: &str) -> RestResponse {
+        let body: JsonValue = match serde_json::from_str(body) {
+            Ok(body) => body,
+            Err(e) => return RestResponse::error(400, &format!("invalid json: {}", e)),
+        };
+        // Values are strings, any other json is stored serialized
+        let value = match body.get("value") {
+            Some(JsonValue::String(value)) => value.clone(),
+            Some(value) => value.to_string(),
+            None => return RestResponse::error(400, "value is required"),
+        };
+        let version = match body.get("version") {
+            None | Some(JsonValue::Null) => -1,
+            Some(version) => match version.as_i64().and_then(|v| i32::try_from(v).ok()) {
+                Some(version) => version,
+                None => return RestResponse::error(400, "version must be an integer"),
+            },
+        };
+        let request = Request::Set {
+            key: key.to_string(),
+            value,
+            version,
+        };
+        match self.run("http set", request) {
+            (
+                Response::VersionError {
+                    msg, old_version, ..
+                },
+                _,
+            ) => RestResponse::json(
+                409,
+                json!({ "error": msg, "key": key, "version": old_version }),
+            ),
+            (Response::Error { msg }, _) => RestResponse::from_error(&msg),
+            _ => self.get_key(key),
+        }
+    }
+
+    fn delete_key(&mut self, key: &str) -> RestResponse {
+        let request = Request::Remove {
+            key: key.to_string(),
+        };
+        match self.run("http remove", request) {
+            (Response::Error { msg }, _) => RestResponse::from_error(&msg),
+            _ => RestResponse {
+                status: 204,
+                body: None,
+            },
+        }
+    }
+
+    fn list_keys(&mut self, prefix: &str) -> RestResponse {
+        let request = Request::Keys {
+            pattern: format!("{}*", prefix),
+        };
+        match self.run("http keys", request) {
+            (Response::Value { value, .. }, _) => {
+                let keys: Vec<&str> = value.split(',').filter(|key| !key.is_empty()).collect();
+                RestResponse::json(200, json!({ "keys": keys }))
+            }
+            (Response::Error { msg }, _) => RestResponse::from_error(&msg),
+            _ => RestResponse::error(500, "unexpected response"),
+        }
+    }
+
+    fn create_db(&mut self, auth: &RestAuth, body: &str) -> RestResponse {
+        if let Err(response) = self.auth_admin(auth) {
+            return response;
+        }
+        let body: JsonValue = match serde_json::from_str(body) {
+            Ok(body) => body,
+            Err(e) => return RestResponse::error(400, &format!("invalid json: {}", e)),
+        };
+        let (name, token) = match (body["name"].as_str(), body["token"].as_str()) {
+            (Some(name), Some(token)) if !name.is_empty() && !token.is_empty() => (name, token),
+            _ => return RestResponse::error(400, "name and token are required"),
+        };
+        let strategy = body["strategy"].as_str().unwrap_or("none");
+        let request = Request::CreateDb {
+            name: name.to_string(),
+            token: token.to_string(),
+            strategy: ConsensuStrategy::from(strategy.to_string()),
+        };
+        match self.run("http create-db", request) {
+            (Response::Error { msg }, _) => RestResponse::from_error(&msg),
+            _ => RestResponse::json(201, json!({ "name": name })),
+        }
+    }
+
+    fn route(&mut self, method: &str, url: &str, auth: &RestAuth, body: &str) -> RestResponse {
+        let (segments, query) = parse_url(url);
+        let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+        match (method, segments.as_slice()) {
+            ("POST", []) => self.create_db(auth, body),
+            (_, []) => RestResponse::error(405, "method not allowed"),
+            ("GET", [db, "keys"]) => {
+                let prefix = query_param(query, "prefix").unwrap_or_default();
+                self.use_db(db, auth)
+                    .map(|_| self.list_keys(&prefix))
+                    .unwrap_or_else(|response| response)
+            }
+            (_, [_, "keys"]) => RestResponse::error(405, "method not allowed"),
+            (method, [db, "keys", key]) if matches!(method, "GET" | "PUT" | "DELETE") => {
+                if let Err(response) = self.use_db(db, auth) {
+                    return response;
+                }
+                match method {
+                    "GET" => self.get_key(key),
+                    "PUT" => self.put_key(key, body),
+                    _ => self.delete_key(key),
+                }
+            }
+            (_, [_, "keys", _]) => RestResponse::error(405, "method not allowed"),
+            _ => RestResponse::error(404, "route not found"),
+        }
+    }
+}
+
+/// Handles the `/dbs` routes, the client only lives for the request
+pub fn process_rest_request(
+    method: &str,
+    url: &str,
+    auth: &RestAuth,
+    body: &str,
+    dbs: &Arc<Databases>,
+    remote_address: Option<String>,
+) -> RestResponse {
+    let mut session = RestSession::new(dbs, remote_address.clone());
+    let response = session.route(method, url, auth, body);
+    log::debug!(
+        "[http] {} {} from {:?} responded {}",
+        method,
+        url,
+        remote_address,
+        response.status
+    );
+    session.close();
+    response
+}
+
+/// `GET /dbs/{db}/watch?keys=a,b` is streamed as server-sent events instead of a json response
+pub fn is_watch_request(method: &str, url: &str) -> bool {
+    let (segments, _) = parse_url(url);
+    method == "GET" && segments.len() == 2 && segments[1] == "watch"
+}
+
+/// Watches keys for a server-sent events stream, the watches are removed when it ends
+pub struct WatchStream {
+    session: RestSession,
+    // Current values of the keys changed after the Last-Event-ID versions
+    missed_events: Vec<String>,
+    // Last version sent of each watched key, in the order of the url
+    versions: Vec<(String, Option<i32>)>,
+}
+
+/// The event id has the version of every watched key, e.g. `a:50,b:3`, a plain number is
+/// read as the version of all the keys
+fn parse_last_event_id(keys: &[String], last_event_id: &str) -> Vec<(String, Option<i32>)> {
+    let last_event_id = last_event_id.trim();
+    let all_keys_version = last_event_id.parse::<i32>().ok();
+    let versions: HashMap<&str, i32> = last_event_id
+        .split(',')
+        .filter_map(|entry| entry.rsplit_once(':'))
+        .filter_map(|(key, version)| Some((key, version.parse::<i32>().ok()?)))
+        .collect();
+    keys.iter()
+        .map(|key| {
+            let version = versions.get(key.as_str()).copied().or(all_keys_version);
+            (key.clone(), version)
+        })
+        .collect()
+}
+
+fn sse_event(key: &str, value: Option<&str>, version: i32, id: &str) -> String {
+    // The data is json so values with new lines fit in a single data line
+    let data = match value {
+        Some(value) => json!({ "key": key, "value": value, "version": version }),
+        None => json!({ "key": key, "version": version }),
+    };
+    format!("id: {}\nevent: changed\ndata: {}\n\n", id, data)
+}
+
+pub fn open_watch_stream(
+    url: &str,
+    auth: &RestAuth,
+    last_event_id: Option<&str>,
+    dbs: &Arc<Databases>,
+    remote_address: Option<String>,
+) -> Result<WatchStream, RestResponse> {
+    let (segments, query) = parse_url(url);
+    let keys: Vec<String> = query_param(query, "keys")
+        .unwrap_or_default()
+        .split(',')
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+    if keys.is_empty() {
+        return Err(RestResponse::error(400, "keys is required"));
+    }
+    let mut session = RestSession::new(dbs, remote_address);
+    let result = session.use_db(&segments[0], auth).and_then(|_| {
+        session.watch_keys(&keys)?;
+        Ok(session.current_versions(&keys))
+    });
+    match result {
+        Ok(current) => {
+            let mut stream = WatchStream {
+                session,
+                missed_events: vec![],
+                versions: current
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.as_ref().map(|(_, version)| *version)))
+                    .collect(),
+            };
+            if let Some(last_event_id) = last_event_id {
+                stream.versions = parse_last_event_id(&keys, last_event_id);
+                stream.add_missed_events(current);
+            }
+            Ok(stream)
+        }
+        Err(response) => {
+            session.close();
+            Err(response)
+        }
+    }
+}
+
+impl RestSession {
+    fn watch_keys(&mut self, keys: &[String]) -> Result<(), RestResponse> {
+        for key in keys {
+            if let (Response::Error { msg }, _) =
+                self.run("http watch", Request::Watch { key: key.clone() })
+            {
+                return Err(RestResponse::from_error(&msg));
+            }
+        }
+        Ok(())
+    }
+
+    // Read after watching so no change is lost between reading the values and watching
+    fn current_versions(&mut self, keys: &[String]) -> Vec<(String, Option<(String, i32)>)> {
+        keys.iter()
+            .map(
+                |key| match self.run("http get", Request::Get { key: key.clone() }) {
+                    (Response::Value { value, version, .. }, _) if value != "<Empty>" => {
+                        (key.clone(), Some((value, version)))
+                    }
+                    _ => (key.clone(), None),
+                },
+            )
+            .collect()
+    }
+}
+
+impl WatchStream {
+    fn add_missed_events(&mut self, current: Vec<(String, Option<(String, i32)>)>) {
+        for (index, (key, value)) in current.into_iter().enumerate() {
+            if let Some((value, version)) = value {
+                // Skips the keys the client already has the version of
+                if self.versions[index].1.is_none_or(|last| version > last) {
+                    let event = self.change_event(&key, Some(&value), version);
+                    self.missed_events.extend(event);
+                }
+            }
+        }
+    }
+
+    // The id carries the versions of all the keys so a resume doesn't miss other keys
+    fn change_event(&mut self, key: &str, value: Option<&str>, version: i32) -> Option<String> {
+        let last_version = self
+            .versions
+            .iter_mut()
+            .find(|(watched_key, _)| watched_key == key)
+            .map(|(_, last_version)| last_version)?;
+        *last_version = Some(version);
+        let id = self
+            .versions
+            .iter()
+            .filter_map(|(key, version)| version.map(|version| format!("{}:{}", key, version)))
+            .collect::<Vec<String>>()
+            .join(",");
+        Some(sse_event(key, value, version, &id))
+    }
+
+    // Watch-only clients get `changed-key` without the value
+    fn message_event(&mut self, message: &str) -> Option<String> {
+        let message = message.trim_end_matches('\n');
+        let change = message
+            .strip_prefix("changed-version ")
+            .or_else(|| message.strip_prefix("changed-key "))
+            .map(|change| change.splitn(3, ' ').collect::<Vec<&str>>());
+        match change.as_deref() {
+            Some([key, version, value]) => {
+                self.change_event(key, Some(value), version.parse().unwrap_or(-1))
+            }
+            Some([key, version]) => self.change_event(key, None, version.parse().unwrap_or(-1)),
+            _ => None,
+        }
+    }
+
+    /// Events of the changes received since the last call
+    pub fn next_events(&mut self) -> Vec<String> {
+        let mut events: Vec<String> = self.missed_events.drain(..).collect();
+        while let Ok(message) = self.session.receiver.try_recv() {
+            events.extend(self.message_event(&message));
+        }
+        events
+    }
+
+    /// Writes the events until the client disconnects, comments keep idle connections alive
+    pub fn stream<W: Write>(mut self, writer: &mut W) {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                log::error!("[http] could not start the watch stream: {}", e);
+                self.session.close();
+                return;
+            }
+        };
+        loop {
+            let mut events = self.next_events();
+            if events.is_empty() {
+                // The timer needs the runtime, so it is created inside block_on
+                let receiver = &mut self.session.receiver;
+                let next = runtime.block_on(async {
+                    tokio::time::timeout(SSE_KEEP_ALIVE, receiver.next()).await
+                });
+                match next {
+                    Ok(Some(message)) => events.extend(self.message_event(&message)),
+                    Ok(None) => break,
+                    Err(_) => events.push(String::from(": keep-alive\n\n")),
+                }
+            }
+            let written = events
+                .iter()
+                .try_for_each(|event| writer.write_all(event.as_bytes()))
+                .and_then(|_| writer.flush());
+            if let Err(e) = written {
+                log::debug!("[http] watch stream closed: {}", e);
+                break;
+            }
+        }
+        self.session.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::{channel, Sender};
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+
+    fn create_dbs() -> Arc<Databases> {
+        let (sender1, _receiver): (Sender<String>, Receiver<String>) = channel(100);
+        let (sender2, _receiver): (Sender<String>, Receiver<String>) = channel(100);
+        let dbs = Arc::new(Databases::new(
+            String::from("user"),
+            String::from("token"),
+            String::from(""),
+            String::from(""),
+            sender1,
+            sender2,
+            HashMap::new(),
+            1_u128,
+            true,
+        ));
+        dbs.node_state
+            .swap(ClusterRole::Primary as usize, Ordering::Relaxed);
+        dbs
+    }
+
+    fn auth(headers: &[(&str, &str)]) -> RestAuth {
+        let headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        RestAuth::from_headers(&headers)
+    }
+
+    fn request(
+        dbs: &Arc<Databases>,
+        method: &str,
+        url: &str,
+        auth: &RestAuth,
+        body: &str,
+    ) -> RestResponse {
+        process_rest_request(method, url, auth, body, dbs, None)
+    }
+
+    #[test]
+    fn should_read_the_credentials_from_the_headers() {
+        // dXNlcjp0b2tlbg== is user:token
+        assert_eq!(
+            auth(&[
+                ("Authorization", "Basic dXNlcjp0b2tlbg=="),
+                ("X-Nun-Db-User", "maria")
+            ]),
+            RestAuth {
+                admin: Some((String::from("user"), String::from("token"))),
+                token: None,
+                user_name: Some(String::from("maria")),
+            }
+        );
+        assert_eq!(
+            auth(&[("authorization", "Bearer db-token")]).token,
+            Some(String::from("db-token"))
+        );
+    }
+
+    #[test]
+    fn should_decode_paths_and_queries() {
+        assert_eq!(percent_decode("user%2F1+a%"), "user/1+a%");
+        assert_eq!(
+            query_param("a=1&prefix=us%24+1", "prefix"),
+            Some("us$ 1".into())
+        );
+        assert_eq!(query_param("a=1", "prefix"), None);
+    }
+
+    #[test]
+    fn should_serve_the_rest_routes() {
+        let dbs = create_dbs();
+        let admin = auth(&[("Authorization", "Basic dXNlcjp0b2tlbg==")]);
+        let db = auth(&[("Authorization", "Bearer test-1")]);
+        let body = r#"{"name": "test", "token": "test-1"}"#;
+
+        assert_eq!(request(&dbs, "POST", "/dbs", &db, body).status, 401);
+        assert_eq!(request(&dbs, "POST", "/dbs", &admin, body).status, 201);
+        assert_eq!(request(&dbs, "POST", "/dbs", &admin, body).status, 409);
+
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/test/keys/name", &admin, "").status,
+            401
+        );
+        let wrong_token = auth(&[("Authorization", "Bearer nope")]);
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/test/keys/name", &wrong_token, "").status,
+            401
+        );
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/other/keys/name", &db, "").status,
+            404
+        );
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/test/keys/name", &db, "").status,
+            404
+        );
+
+        let put = request(
+            &dbs,
+            "PUT",
+            "/dbs/test/keys/name",
+            &db,
+            r#"{"value": "jose"}"#,
+        );
+        assert_eq!(
+            put,
+            RestResponse::json(200, json!({"key": "name", "value": "jose", "version": 0}))
+        );
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/test/keys/name", &db, "").body,
+            Some(json!({"key": "name", "value": "jose", "version": 0}))
+        );
+        request(
+            &dbs,
+            "PUT",
+            "/dbs/test/keys/name",
+            &db,
+            r#"{"value": "jose"}"#,
+        );
+        let conflict = request(
+            &dbs,
+            "PUT",
+            "/dbs/test/keys/name",
+            &db,
+            r#"{"value": "maria", "version": 0}"#,
+        );
+        assert_eq!(conflict.status, 409);
+        assert_eq!(conflict.body.unwrap()["version"], json!(1));
+        assert_eq!(
+            request(&dbs, "PUT", "/dbs/test/keys/name", &db, "jose").status,
+            400
+        );
+
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/test/keys/$$token", &db, "").status,
+            403
+        );
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/test/keys?prefix=na", &db, "").body,
+            Some(json!({"keys": ["name"]}))
+        );
+        assert_eq!(
+            request(&dbs, "DELETE", "/dbs/test/keys/name", &db, "").status,
+            204
+        );
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/test/keys/name", &db, "").status,
+            404
+        );
+        assert_eq!(
+            request(&dbs, "PATCH", "/dbs/test/keys/name", &db, "").status,
+            405
+        );
+        assert_eq!(
+            request(&dbs, "GET", "/dbs/test/values", &db, "").status,
+            404
+        );
+    }
+
+    #[test]
+    fn should_stream_the_changes_of_the_watched_keys() {
+        let dbs = create_dbs();
+        let admin = auth(&[("Authorization", "Basic dXNlcjp0b2tlbg==")]);
+        let db = auth(&[("Authorization", "Bearer test-1")]);
+        let body = r#"{"name": "test", "token": "test-1"}"#;
+        request(&dbs, "POST", "/dbs", &admin, body);
+        request(&dbs, "PUT", "/dbs/test/keys/a", &db, r#"{"value": "one"}"#);
+        request(&dbs, "PUT", "/dbs/test/keys/a", &db, r#"{"value": "two"}"#);
+
+        assert!(is_watch_request("GET", "/dbs/test/watch?keys=a,b"));
+        assert!(!is_watch_request("GET", "/dbs/test/keys/a"));
+        let url = "/dbs/test/watch?keys=a,b";
+        assert_eq!(
+            open_watch_stream(url, &admin, None, &dbs, None)
+                .err()
+                .unwrap()
+                .status,
+            401
+        );
+        let mut stream = open_watch_stream(url, &db, Some("0"), &dbs, None).unwrap();
+        assert_eq!(
+            stream.next_events(),
+            vec![String::from(
+                "id: a:1,b:0\nevent: changed\ndata: {\"key\":\"a\",\"value\":\"two\",\"version\":1}\n\n"
+            )]
+        );
+        request(&dbs, "PUT", "/dbs/test/keys/b", &db, r#"{"value": "a\nb"}"#);
+        assert_eq!(
+            stream.next_events(),
+            vec![String::from(
+                "id: a:1,b:0\nevent: changed\ndata: {\"key\":\"b\",\"value\":\"a\\nb\",\"version\":0}\n\n"
+            )]
+        );
+
+        stream.session.close();
+        let dbs_map = dbs.map.read().unwrap();
+        let watchers = dbs_map.get("test").unwrap().watchers.map.read().unwrap();
+        assert!(watchers.values().all(|senders| senders.is_empty()));
+    }
+
+    #[test]
+    fn should_resume_the_watch_stream_from_the_versions_of_each_key() {
+        let dbs = create_dbs();
+        let admin = auth(&[("Authorization", "Basic dXNlcjp0b2tlbg==")]);
+        let db = auth(&[("Authorization", "Bearer test-1")]);
+        let body = r#"{"name": "test", "token": "test-1"}"#;
+        request(&dbs, "POST", "/dbs", &admin, body);
+        let url = "/dbs/test/watch?keys=a,b,c";
+        let mut stream = open_watch_stream(url, &db, None, &dbs, None).unwrap();
+        for (key, value) in [("a", "1"), ("a", "2"), ("b", "1")] {
+            let url = format!("/dbs/test/keys/{}", key);
+            let body = format!(r#"{{"value": "{}"}}"#, value);
+            request(&dbs, "PUT", &url, &db, &body);
+        }
+        let last_event_id = stream
+            .next_events()
+            .last()
+            .and_then(|event| event.lines().next())
+            .map(|line| line.trim_start_matches("id: ").to_string())
+            .unwrap();
+        assert_eq!(last_event_id, "a:1,b:0");
+        stream.session.close();
+
+        request(&dbs, "PUT", "/dbs/test/keys/b", &db, r#"{"value": "2"}"#);
+        request(&dbs, "PUT", "/dbs/test/keys/c", &db, r#"{"value": "1"}"#);
+        let mut stream = open_watch_stream(url, &db, Some(&last_event_id), &dbs, None).unwrap();
+        assert_eq!(
+            stream.next_events(),
+            vec![
+                String::from(
+                    "id: a:1,b:1\nevent: changed\ndata: {\"key\":\"b\",\"value\":\"2\",\"version\":1}\n\n"
+                ),
+                String::from(
+                    "id: a:1,b:1,c:0\nevent: changed\ndata: {\"key\":\"c\",\"value\":\"1\",\"version\":0}\n\n"
+                ),
+            ]
+        );
+        stream.session.close();
+    }
+}
@@ -1,35 +1,113 @@
 use futures::channel::mpsc::Receiver;
+use futures::StreamExt;
 use log;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream};
+use openssl::ssl::Ssl;
+use openssl::x509::X509VerifyResult;
+use std::pin::Pin;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::thread;
-use std::time;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, WriteHalf,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::sync::{Notify, Semaphore};
+use tokio::time::{Interval, MissedTickBehavior};
+use tokio_openssl::SslStream;
 
 use crate::bo::*;
+use crate::network::framing_ops::{decode_json_frame, encode_json_message, Framing};
+use crate::network::heartbeat_ops::*;
+use crate::network::listener_ops::{listeners_limits, ConnectionPermit};
+use crate::network::tls_ops::{is_node_certificate_required, tcp_acceptor};
 use crate::process_request::*;
 use crate::security::*;
 
+// Time the writer has to flush the last messages after the client disconnects
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Commands block (locks, disk, token hashes), at most this many per cpu run at once
+pub(crate) const COMMANDS_PER_CPU: usize = 2;
+
 pub fn start_tcp_client(dbs: Arc<Databases>, tcp_addressed: &str) {
     log::debug!("starting tcp client in the addr: {}", tcp_addressed);
-    match TcpListener::bind(tcp_addressed) {
-        Ok(listener) => {
-            for stream in listener.incoming() {
-                let dbs = dbs.clone();
-                thread::spawn(move || match stream {
-                    Ok(socket) => {
-                        handle_client(socket, dbs);
-                    }
-                    _ => (),
-                });
-            }
-        }
+    // Binds before the runtime starts, the other nodes may connect as soon as this thread starts
+    let listener = match std::net::TcpListener::bind(tcp_addressed) {
+        Ok(listener) => listener,
         Err(e) => {
             log::error!("TCP Bind error: {}", e);
             panic!("TCP Bind error");
         }
     };
+    listener
+        .set_nonblocking(true)
+        .expect("Could not set the tcp listener as nonblocking");
+    let runtime = Runtime::new().expect("Could not create the tcp runtime");
+    runtime.block_on(async {
+        let listener = TcpListener::from_std(listener).expect("Could not start the tcp listener");
+        accept_clients(dbs, listener).await
+    });
+}
+
+async fn accept_clients(dbs: Arc<Databases>, listener: TcpListener) {
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let commands = Arc::new(Semaphore::new(cpus * COMMANDS_PER_CPU));
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::warn!("TCP accept error: {}", e);
+                continue;
+            }
+        };
+        // Refused sockets are closed when dropped, the permit is released when the client leaves
+        let permit = match listeners_limits().tcp.accept(Some(peer)) {
+            Ok(permit) => permit,
+            Err(_) => continue,
+        };
+        tokio::spawn(start_client(socket, dbs.clone(), commands.clone(), permit));
+    }
+}
+
+async fn start_client(
+    socket: TcpStream,
+    dbs: Arc<Databases>,
+    commands: Arc<Semaphore>,
+    _permit: ConnectionPermit,
+) {
+    let remote_address = socket.peer_addr().ok().map(|addr| addr.ip().to_string());
+    if let Err(e) = socket.set_nodelay(true) {
+        log::debug!("TCP set_nodelay error: {}", e);
+    }
+    match tcp_acceptor() {
+        Some(acceptor) => {
+            let tls_socket = Ssl::new(acceptor.context())
+                .and_then(|ssl| SslStream::new(ssl, socket))
+                .map_err(|e| e.to_string());
+            match tls_socket {
+                Ok(mut tls_socket) => match Pin::new(&mut tls_socket).accept().await {
+                    Ok(_) => {
+                        let ssl = tls_socket.ssl();
+                        let verified_certificate = is_node_certificate_required()
+                            && ssl.peer_certificate().is_some()
+                            && ssl.verify_result() == X509VerifyResult::OK;
+                        handle_client(
+                            tls_socket,
+                            dbs,
+                            commands,
+                            remote_address,
+                            verified_certificate,
+                        )
+                        .await
+                    }
+                    Err(e) => log::warn!("TCP TLS handshake error: {}", e),
+                },
+                Err(e) => log::warn!("TCP TLS handshake error: {}", e),
+            }
+        }
+        None => handle_client(socket, dbs, commands, remote_address, false).await,
+    }
 }
 
 fn process_leave_request(leave_message: &String, dbs: &Arc<Databases>) {
@@ -37,9 +115,9 @@ fn process_leave_request(leave_message: &String, dbs: &Arc<Databases>) {
     // `&*client.cluster_member.lock().unwrap()` as immutable
     // leave request does not use the client, therefore this is safe!
     // Double borrow here may leads to an dead lock
-    // Fake client needs to be auth
+    // Fake client needs to be auth as a node
     let (mut fake_client, _) = Client::new_empty_and_receiver();
-    fake_client.auth.store(true, Ordering::Relaxed);
+    fake_client.auth_as_local_node();
     match process_request(&leave_message, dbs, &mut fake_client) {
         Response::Error { msg } => {
             log::debug!("Error: {} trying to process {}", msg, leave_message);
@@ -50,86 +128,175 @@ fn process_leave_request(leave_message: &String, dbs: &Arc<Databases>) {
     }
 }
 
-fn handle_client(stream: TcpStream, dbs: Arc<Databases>) {
-    let mut reader = BufReader::new(&stream);
-    let writer = &mut BufWriter::new(&stream);
-    let (mut client, mut receiver) = Client::new_empty_and_receiver();
-    writer.write_fmt(format_args!("ok \n")).unwrap();
-    writer.flush().unwrap();
+async fn handle_client<S>(
+    stream: S,
+    dbs: Arc<Databases>,
+    commands: Arc<Semaphore>,
+    remote_address: Option<String>,
+    verified_certificate: bool,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (mut client, receiver) = Client::new_empty_and_receiver();
+    client.set_remote_address(remote_address);
+    client
+        .verified_certificate
+        .store(verified_certificate, Ordering::SeqCst);
+    if writer.write_all(b"ok \n").await.is_err() || writer.flush().await.is_err() {
+        return;
+    }
+    // Notified when the writer stops, e.g. a heartbeat could not be written to a dead peer
+    let writer_stopped = Arc::new(Notify::new());
+    let writer = tokio::spawn(write_messages(
+        receiver,
+        writer,
+        client.framing.clone(),
+        writer_stopped.clone(),
+    ));
+    let idle = IdleSettings::from_config();
+    let tick = idle.tick();
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + tick, tick);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut reader = BufReader::new(reader);
+    let mut buf = String::new();
     loop {
-        let mut buf = String::new();
-        let read_line = reader.read_line(&mut buf);
-        stream.set_nonblocking(true).unwrap();
+        buf.clear();
+        let read_line = tokio::select! {
+            // Lines already received are processed even if the writer stopped, e.g. `join` from a
+            // node that closes the connection right after sending it
+            biased;
+            read_line = read_line_while_alive(&mut reader, &mut buf, &client, &idle, &mut ticker) => {
+                read_line
+            }
+            _ = writer_stopped.notified() => Ok(0),
+        };
+        let _command = commands.acquire().await.unwrap();
         match read_line {
+            Ok(0) | Err(_) => {
+                log::debug!("killing socket client, because of disconnected!!");
+                tokio::task::block_in_place(|| disconnect_client(&dbs, &mut client));
+                break;
+            }
             Ok(_) => {
                 log::debug!("Command print: {}", clean_string_to_log(&buf, &dbs));
-                match buf.as_ref() {
-                    "" => {
-                        log::debug!("killing socket client, because of disconnected!!");
-                        process_request("unwatch-all", &dbs, &mut client);
-                        let member = &*client.cluster_member.lock().unwrap();
-                        if let Some(m) = member {
-                            match m.role {
-                                ClusterRole::Primary => {
-                                    log::debug!("Primary Cluster member disconnected: {}", m.name);
-                                    process_leave_request(&format!("leave {}", m.name), &dbs);
-                                }
-                                ClusterRole::Secoundary => {
-                                    log::debug!(
-                                        "Secoundary Cluster member disconnected: {}",
-                                        m.name
-                                    );
-                                    process_leave_request(
-                                        &format!("replicate-leave {}", m.name),
-                                        &dbs,
-                                    ); // replicate-leave does not efornce election
-                                }
-                                ClusterRole::StartingUp => {
-                                    log::debug!(
-                                        "ClusterMember {} died while still in StartingUp mode",
-                                        m.name
-                                    );
-                                    process_leave_request(
-                                        &format!("replicate-leave {}", m.name),
-                                        &dbs,
-                                    ); // replicate-leave does not efornce election
-                                }
-                            }
-                        }
-                        client.left(&dbs);
-                        break;
-                    }
-                    _ => match process_request(&buf, &dbs, &mut client) {
-                        Response::Error { msg } => {
-                            log::debug!("Error: {}", msg);
-                            match client.sender.try_send(format!("error {} \n", msg)) {
-                                Ok(_) => (),
-                                _ => log::debug!("Error on sending and error request"),
-                            }
-                        }
-                        _ => match client.sender.try_send(format!("ok \n")) {
-                            Ok(_) => log::debug!("Success processed"),
-                            _ => log::debug!("Success processed! error on sender"),
-                        },
-                    },
-                }
+                tokio::task::block_in_place(|| process_frame(&buf, &dbs, &mut client));
             }
-            _ => process_message(&mut receiver, writer),
         }
     }
+    drop(client);
+    let abort_handle = writer.abort_handle();
+    if tokio::time::timeout(WRITER_DRAIN_TIMEOUT, writer)
+        .await
+        .is_err()
+    {
+        abort_handle.abort();
+    }
 }
-fn process_message(receiver: &mut Receiver<String>, writer: &mut BufWriter<&TcpStream>) {
-    match receiver.try_next() {
-        Ok(message_opt) => match message_opt {
-            Some(message) => {
-                writer.write_fmt(format_args!("{}", message)).unwrap();
-                match writer.flush() {
-                    Ok(_n) => (),
-                    Err(e) => log::warn!("process_message Error: {}", e),
+
+/// Reads the next line, sending heartbeats while waiting. Idle connections and watches with
+/// expired tokens read as closed
+async fn read_line_while_alive<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut String,
+    client: &Client,
+    idle: &IdleSettings,
+    ticker: &mut Interval,
+) -> std::io::Result<usize> {
+    let last_read = Instant::now();
+    // The same read is polled across ticks, so no partially read line is lost
+    let read_line = reader.read_line(buf);
+    tokio::pin!(read_line);
+    loop {
+        tokio::select! {
+            read_line = &mut read_line => return read_line,
+            _ = ticker.tick() => match idle.action(client, last_read.elapsed()) {
+                IdleAction::Wait => {}
+                IdleAction::Heartbeat => client.send_message(&String::from(HEARTBEAT_MESSAGE)),
+                IdleAction::Close => {
+                    log::debug!("Closing idle tcp connection");
+                    client.send_message(&String::from("error idle-timeout \n"));
+                    return Ok(0);
+                }
+                IdleAction::Expired => {
+                    // unwatch-all on disconnect replies token-expired and clears the session
+                    log::debug!("Closing tcp connection with an expired token");
+                    return Ok(0);
+                }
+            },
+        }
+    }
+}
+
+fn process_frame(buf: &str, dbs: &Arc<Databases>, client: &mut Client) {
+    match client.framing() {
+        Framing::Text if is_heartbeat_reply(buf) => {}
+        Framing::Text => {
+            let response = process_request(buf, dbs, client);
+            send_response(client, &response);
+        }
+        Framing::Json => match decode_json_frame(buf) {
+            Ok(commands) => {
+                for command in commands
+                    .iter()
+                    .filter(|command| !is_heartbeat_reply(command))
+                {
+                    let response = process_framed_request(command, dbs, client);
+                    send_response(client, &response);
                 }
             }
-            None => log::debug!("tcp_ops::process_message::Empty message"),
+            Err(msg) => client.send_message(&format!("error {} \n", msg)),
         },
-        _ => thread::sleep(time::Duration::from_millis(2)),
     }
 }
+
+fn disconnect_client(dbs: &Arc<Databases>, client: &mut Client) {
+    process_request("unwatch-all", dbs, client);
+    let member = &*client.cluster_member.lock().unwrap();
+    if let Some(m) = member {
+        match m.role {
+            ClusterRole::Primary => {
+                log::debug!("Primary Cluster member disconnected: {}", m.name);
+                process_leave_request(&format!("leave {}", m.name), dbs);
+            }
+            ClusterRole::Secoundary => {
+                log::debug!("Secoundary Cluster member disconnected: {}", m.name);
+                process_leave_request(&format!("replicate-leave {}", m.name), dbs);
+                // replicate-leave does not efornce election
+            }
+            ClusterRole::StartingUp => {
+                log::debug!(
+                    "ClusterMember {} died while still in StartingUp mode",
+                    m.name
+                );
+                process_leave_request(&format!("replicate-leave {}", m.name), dbs);
+                // replicate-leave does not efornce election
+            }
+        }
+    }
+    client.left(dbs);
+}
+
+async fn write_messages<S: AsyncWrite>(
+    mut receiver: Receiver<String>,
+    mut writer: WriteHalf<S>,
+    framing: Arc<RwLock<Framing>>,
+    stopped: Arc<Notify>,
+) {
+    // Ends when the client leaves and the last sender is dropped
+    while let Some(message) = receiver.next().await {
+        let message = match *framing.read().unwrap() {
+            Framing::Text => message,
+            Framing::Json => format!("{}\n", encode_json_message(&message)),
+        };
+        if let Err(e) = writer.write_all(message.as_bytes()).await {
+            log::warn!("write_messages Error: {}", e);
+            break;
+        }
+        if let Err(e) = writer.flush().await {
+            log::warn!("write_messages Error: {}", e);
+            break;
+        }
+    }
+    stopped.notify_one();
+}
@@ -0,0 +1,339 @@
+use lazy_static::lazy_static;
+use log;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use std::fs;
+use std::sync::{Arc, RwLock};
+
+use crate::configuration::{NUN_TLS_CA, NUN_TLS_CERT, NUN_TLS_KEY, NUN_TLS_LISTENERS};
+
+pub const TLS_WS: &str = "ws";
+pub const TLS_HTTP: &str = "http";
+pub const TLS_TCP: &str = "tcp";
+pub const TLS_REPLICATION: &str = "replication";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    // When present nodes authenticate each other with certificates signed by this CA
+    pub ca_path: String,
+    pub listeners: Vec<String>,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: &str, key_path: &str, ca_path: &str, listeners: &str) -> TlsConfig {
+        TlsConfig {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            ca_path: ca_path.to_string(),
+            listeners: listeners
+                .split(',')
+                .map(|listener| listener.trim().to_string())
+                .filter(|listener| !listener.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn from_env() -> TlsConfig {
+        TlsConfig::new(&NUN_TLS_CERT, &NUN_TLS_KEY, &NUN_TLS_CA, &NUN_TLS_LISTENERS)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.cert_path.is_empty() && !self.key_path.is_empty()
+    }
+
+    pub fn is_enabled_for(&self, listener: &str) -> bool {
+        self.is_enabled() && self.listeners.iter().any(|l| l == listener)
+    }
+
+    pub fn is_mutual(&self) -> bool {
+        !self.ca_path.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self
+            .listeners
+            .iter()
+            .find(|l| ![TLS_WS, TLS_HTTP, TLS_TCP, TLS_REPLICATION].contains(&l.as_str()))
+        {
+            Some(listener) => Err(format!("Invalid TLS listener {}", listener)),
+            None if self.cert_path.is_empty() != self.key_path.is_empty() => Err(String::from(
+                "TLS needs both the certificate and the key paths",
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+struct TlsState {
+    config: TlsConfig,
+    ws_acceptor: Option<Arc<SslAcceptor>>,
+    tcp_acceptor: Option<Arc<SslAcceptor>>,
+    generation: u64,
+}
+
+lazy_static! {
+    static ref TLS_STATE: RwLock<TlsState> = RwLock::new(TlsState {
+        config: TlsConfig::default(),
+        ws_acceptor: None,
+        tcp_acceptor: None,
+        generation: 0,
+    });
+}
+
+pub fn build_acceptor(config: &TlsConfig, verify_peer: bool) -> Result<SslAcceptor, String> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+        .map_err(|e| format!("Error creating the TLS acceptor: {}", e))?;
+    builder
+        .set_certificate_chain_file(&config.cert_path)
+        .map_err(|e| format!("Invalid TLS certificate {}: {}", config.cert_path, e))?;
+    builder
+        .set_private_key_file(&config.key_path, SslFiletype::PEM)
+        .map_err(|e| format!("Invalid TLS key {}: {}", config.key_path, e))?;
+    builder
+        .check_private_key()
+        .map_err(|e| format!("TLS key does not match the certificate: {}", e))?;
+    if verify_peer && config.is_mutual() {
+        builder
+            .set_ca_file(&config.ca_path)
+            .map_err(|e| format!("Invalid TLS CA {}: {}", config.ca_path, e))?;
+        // Certificates are optional, only the nodes need one and it is checked at cluster-auth
+        builder.set_verify(SslVerifyMode::PEER);
+    }
+    Ok(builder.build())
+}
+
+fn load_acceptor(
+    config: &TlsConfig,
+    listener: &str,
+    verify_peer: bool,
+) -> Result<Option<Arc<SslAcceptor>>, String> {
+    if config.is_enabled_for(listener) {
+        Ok(Some(Arc::new(build_acceptor(config, verify_peer)?)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Loads the certificates and keeps them for the listeners, fails if any file is invalid
+pub fn init_tls(config: TlsConfig) -> Result<(), String> {
+    config.validate()?;
+    // The listeners read the acceptors for each connection, so reloads apply to them
+    let ws_acceptor = load_acceptor(&config, TLS_WS, false)?;
+    // Tcp is the listener nodes connect to, so it is the one verifying client certificates
+    let tcp_acceptor = load_acceptor(&config, TLS_TCP, true)?;
+    if config.is_enabled_for(TLS_HTTP) {
+        http_ssl_config_from(&config)?;
+    }
+    if config.is_enabled_for(TLS_REPLICATION) {
+        replication_connector_from(&config)?;
+    }
+    let mut state = TLS_STATE.write().unwrap();
+    state.config = config;
+    state.ws_acceptor = ws_acceptor;
+    state.tcp_acceptor = tcp_acceptor;
+    state.generation += 1;
+    Ok(())
+}
+
+/// Reads the certificate files again (SIGHUP), the old certificates are kept if the new ones are invalid
+pub fn reload_tls() -> Result<(), String> {
+    let config = TLS_STATE.read().unwrap().config.clone();
+    if config.is_enabled() {
+        log::info!("Reloading TLS certificates from {}", config.cert_path);
+        init_tls(config)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn tls_generation() -> u64 {
+    TLS_STATE.read().unwrap().generation
+}
+
+pub fn ws_acceptor() -> Option<Arc<SslAcceptor>> {
+    TLS_STATE.read().unwrap().ws_acceptor.clone()
+}
+
+pub fn tcp_acceptor() -> Option<Arc<SslAcceptor>> {
+    TLS_STATE.read().unwrap().tcp_acceptor.clone()
+}
+
+fn http_ssl_config_from(config: &TlsConfig) -> Result<tiny_http::SslConfig, String> {
+    Ok(tiny_http::SslConfig {
+        certificate: read_file(&config.cert_path)?,
+        private_key: read_file(&config.key_path)?,
+    })
+}
+
+pub fn http_ssl_config() -> Option<tiny_http::SslConfig> {
+    let config = TLS_STATE.read().unwrap().config.clone();
+    if config.is_enabled_for(TLS_HTTP) {
+        match http_ssl_config_from(&config) {
+            Ok(ssl_config) => Some(ssl_config),
+            Err(e) => {
+                log::error!("{}", e);
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
+pub fn is_tls_enabled_for(listener: &str) -> bool {
+    TLS_STATE.read().unwrap().config.is_enabled_for(listener)
+}
+
+/// Nodes must present a certificate signed by NUN_TLS_CA on the tcp listener to cluster-auth
+pub fn is_node_certificate_required() -> bool {
+    let config = &TLS_STATE.read().unwrap().config;
+    config.is_mutual() && config.is_enabled_for(TLS_TCP)
+}
+
+// Nodes verify each other with the CA and present their own certificate when mutual TLS is on
+fn replication_identity_from(
+    config: &TlsConfig,
+) -> Result<Option<(native_tls::Certificate, native_tls::Identity)>, String> {
+    if config.is_mutual() {
+        let ca = native_tls::Certificate::from_pem(&read_file(&config.ca_path)?)
+            .map_err(|e| format!("Invalid TLS CA {}: {}", config.ca_path, e))?;
+        // Native tls only reads keys in the PKCS#8 format (BEGIN PRIVATE KEY)
+        let identity = native_tls::Identity::from_pkcs8(
+            &read_file(&config.cert_path)?,
+            &read_file(&config.key_path)?,
+        )
+        .map_err(|e| format!("Invalid TLS identity {}: {}", config.key_path, e))?;
+        Ok(Some((ca, identity)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn replication_connector_from(config: &TlsConfig) -> Result<native_tls::TlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some((ca, identity)) = replication_identity_from(config)? {
+        builder.add_root_certificate(ca).identity(identity);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Error creating the TLS connector: {}", e))
+}
+
+/// Connector used by the node to connect to the other nodes of the cluster
+pub fn replication_connector() -> Result<native_tls::TlsConnector, String> {
+    let config = TLS_STATE.read().unwrap().config.clone();
+    replication_connector_from(&config)
+}
+
+/// Same as `replication_connector` for the async replication streams
+pub fn async_replication_connector() -> Result<async_native_tls::TlsConnector, String> {
+    let config = TLS_STATE.read().unwrap().config.clone();
+    let connector = async_native_tls::TlsConnector::new();
+    Ok(match replication_identity_from(&config)? {
+        Some((ca, identity)) => connector.add_root_certificate(ca).identity(identity),
+        None => connector,
+    })
+}
+
+/// Name used to verify the certificate of the node in `host:port`
+pub fn domain_from_address(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    fn write_self_signed_cert(name: &str) -> (String, String) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", "localhost").unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("{}-cert.pem", name));
+        let key_path = dir.join(format!("{}-key.pem", name));
+        fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+        fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (
+            cert_path.to_str().unwrap().to_string(),
+            key_path.to_str().unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn should_enable_tls_only_for_the_configured_listeners() {
+        let config = TlsConfig::new("cert.pem", "key.pem", "", "ws, tcp");
+        assert!(config.is_enabled_for(TLS_WS));
+        assert!(config.is_enabled_for(TLS_TCP));
+        assert!(!config.is_enabled_for(TLS_HTTP));
+        assert!(!config.is_mutual());
+        assert!(config.validate().is_ok());
+
+        let config = TlsConfig::new("", "", "", "ws,http,tcp,replication");
+        assert!(!config.is_enabled_for(TLS_WS));
+        assert!(TlsConfig::new("cert.pem", "", "", "ws").validate().is_err());
+        assert_eq!(
+            TlsConfig::new("cert.pem", "key.pem", "", "ws,smtp").validate(),
+            Err(String::from("Invalid TLS listener smtp"))
+        );
+    }
+
+    #[test]
+    fn should_build_acceptor_and_connector_from_pem_files() {
+        let (cert_path, key_path) = write_self_signed_cert("nun-tls-test");
+        let config = TlsConfig::new(&cert_path, &key_path, &cert_path, "ws,http,tcp,replication");
+        assert!(build_acceptor(&config, true).is_ok());
+        assert!(http_ssl_config_from(&config).is_ok());
+        assert!(replication_connector_from(&config).is_ok());
+
+        let invalid = TlsConfig::new(&cert_path, "/not/found/key.pem", "", "ws");
+        assert!(build_acceptor(&invalid, false).is_err());
+    }
+
+    #[test]
+    fn should_reload_the_web_socket_certificates() {
+        let (cert_path, key_path) = write_self_signed_cert("nun-tls-reload-test");
+        init_tls(TlsConfig::new(&cert_path, &key_path, "", "ws")).unwrap();
+        let acceptor = ws_acceptor().unwrap();
+        let generation = tls_generation();
+        reload_tls().unwrap();
+        assert_eq!(tls_generation(), generation + 1);
+        assert!(!Arc::ptr_eq(&acceptor, &ws_acceptor().unwrap()));
+        assert!(tcp_acceptor().is_none());
+        assert!(!is_node_certificate_required());
+    }
+
+    #[test]
+    fn should_get_the_domain_from_node_address() {
+        assert_eq!(domain_from_address("nun-db-1:3014"), "nun-db-1");
+        assert_eq!(domain_from_address("127.0.0.1:3014"), "127.0.0.1");
+        assert_eq!(domain_from_address("[::1]:3014"), "::1");
+        assert_eq!(domain_from_address("localhost"), "localhost");
+    }
+}
@@ -2,30 +2,76 @@ use futures::channel::mpsc::{channel, Receiver, Sender};
 use futures::executor::block_on;
 use futures::stream::StreamExt;
 use log;
+use openssl::ssl::SslStream;
 use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
 use thread_id;
-use ws::{CloseCode, Handler, Message};
+use ws::util::{TcpStream, Token};
+use ws::{CloseCode, Frame, Handler, Message};
 
 use crate::bo::*;
+use crate::network::framing_ops::{decode_json_frame, encode_json_message, Framing};
+use crate::network::heartbeat_ops::*;
+use crate::network::listener_ops::{listeners_limits, ConnectionPermit};
+use crate::network::tls_ops::ws_acceptor;
 use crate::process_request::*;
 use crate::security::*;
 
 const TO_CLOSE: &'static str = "##CLOSE##";
+const HEARTBEAT: Token = Token(1);
 
 // Server WebSocket handler
 struct Server {
     out: ws::Sender,
     dbs: Arc<Databases>,
-    client: Client,
+    // Only created once the connection is accepted by the listener limits
+    client: Option<Client>,
+    _permit: Option<ConnectionPermit>,
+    idle: IdleSettings,
+    // Any frame, including the pongs of the heartbeats, keeps the connection alive
+    last_frame: Instant,
+}
+
+impl Server {
+    fn schedule_heartbeat(&self) {
+        if let Err(e) = self
+            .out
+            .timeout(self.idle.tick().as_millis() as u64, HEARTBEAT)
+        {
+            log::warn!("ws_ops::schedule_heartbeat::Error {}", e);
+        }
+    }
+
+    // Runs once, when the connection closes or is closed for being idle
+    fn disconnect(&mut self) {
+        let mut client = match self.client.take() {
+            Some(client) => client,
+            None => return,
+        };
+        match client.sender.try_send(TO_CLOSE.to_string()) {
+            //To close the read thread
+            Ok(_) => {}
+            Err(e) => log::warn!("on_close::Error {}", e),
+        }
+        process_request("unwatch-all", &self.dbs, &mut client);
+        client.left(&self.dbs);
+    }
 }
 
 impl Handler for Server {
-    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+    fn on_open(&mut self, shake: ws::Handshake) -> ws::Result<()> {
+        match listeners_limits().ws.accept(shake.peer_addr) {
+            Ok(permit) => self._permit = Some(permit),
+            Err(_) => return self.out.close(CloseCode::Policy),
+        }
         let ws_sender = self.out.clone();
         let (sender, mut receiver): (Sender<String>, Receiver<String>) = channel(100);
-        self.client.sender = sender;
+        let client = Client::new_empty(sender);
+        client.set_remote_address(shake.peer_addr.map(|addr| addr.ip().to_string()));
+        let framing = client.framing.clone();
+        self.client = Some(client);
         let _read_thread = thread::spawn(move || {
             let read_promise = async {
                 loop {
@@ -38,6 +84,10 @@ impl Handler for Server {
                             }
                             message => {
                                 log::debug!("ws_ops::_read_thread::message {}", message);
+                                let message = match *framing.read().unwrap() {
+                                    Framing::Text => message.to_string(),
+                                    Framing::Json => encode_json_message(message),
+                                };
                                 match ws_sender.send(message) {
                                     Err(e) => {
                                         log::warn!("ws_ops::_read_thread::send::Error {}", e)
@@ -54,79 +104,107 @@ impl Handler for Server {
             };
             block_on(read_promise);
         });
+        self.schedule_heartbeat();
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> ws::Result<Option<Frame>> {
+        self.last_frame = Instant::now();
+        // Same check as the default implementation, no extension uses the reserved bits
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            Err(ws::Error::new(
+                ws::ErrorKind::Protocol,
+                "Encountered frame with reserved bits set.",
+            ))
+        } else {
+            Ok(Some(frame))
+        }
+    }
+
+    fn on_timeout(&mut self, event: Token) -> ws::Result<()> {
+        let client = match self.client.as_ref() {
+            Some(client) if event == HEARTBEAT => client,
+            _ => return Ok(()),
+        };
+        match self.idle.action(client, self.last_frame.elapsed()) {
+            IdleAction::Wait => {}
+            IdleAction::Heartbeat => self.out.ping(Vec::new())?,
+            IdleAction::Close => {
+                log::debug!("Closing idle web socket connection");
+                self.disconnect();
+                return self.out.close_with_reason(CloseCode::Away, "idle-timeout");
+            }
+            IdleAction::Expired => {
+                log::debug!("Closing web socket connection with an expired token");
+                self.disconnect();
+                return self
+                    .out
+                    .close_with_reason(CloseCode::Policy, "token-expired");
+            }
+        }
+        self.schedule_heartbeat();
         Ok(())
     }
 
     fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
         let message = msg.as_text().unwrap();
         log::debug!(
             "[{}] Server got message '{}'. ",
             thread_id::get(),
             clean_string_to_log(&message, &self.dbs)
         );
-        let messages_part = message.split(";");
-        messages_part.for_each(|message| {
-            match process_request(&message, &self.dbs, &mut self.client) {
-                Response::Error { msg } => {
-                    log::debug!("Error: {}", msg);
-                    match self.client.sender.try_send(format!("error {} \n", msg)) {
-                        Ok(_) => {}
-                        Err(e) => log::warn!(
-                            "ws_ops::_read_thread::process_request::try_send::Error {}",
-                            e
-                        ),
+        let dbs = &self.dbs;
+        let framing = client.framing();
+        let messages_part: Vec<String> = match framing {
+            Framing::Text => message.split(";").map(String::from).collect(),
+            Framing::Json => match decode_json_frame(message) {
+                Ok(commands) => commands,
+                Err(e) => {
+                    if let Err(e) = client.sender.try_send(format!("error {} \n", e)) {
+                        log::warn!("ws_ops::on_message::try_send::Error {}", e);
                     }
+                    return Ok(());
                 }
-                Response::VersionError {
-                    msg,
-                    key: _,
-                    old_version: _,
-                    version: _,
-                    old_value: _,
-                    state: _,
-                    change: _,
-                    db: _,
-                } => {
-                    log::debug!("Error: {}", msg);
-                    match self.client.sender.try_send(format!("error {} \n", msg)) {
-                        Ok(_) => {}
-                        Err(e) => log::warn!(
-                            "ws_ops::_read_thread::process_request::try_send::Error {}",
-                            e
-                        ),
-                    }
-                }
-                e => {
-                    log::debug!(
-                        "[{}] Server responded message  {:?} to {}",
-                        thread_id::get(),
-                        e,
-                        message
-                    );
-                    match self.client.sender.try_send(format!("ok \n")) {
-                        Ok(_) => {}
-                        Err(e) => log::warn!(
-                            "ws_ops::_read_thread::process_request::_::try_send::Error {}",
-                            e
-                        ),
-                    }
-                    log::debug!("ws::Success processed");
-                }
-            }
-        });
+            },
+        };
+        let process = match framing {
+            Framing::Text => process_request,
+            Framing::Json => process_framed_request,
+        };
+        messages_part
+            .iter()
+            .filter(|message| !is_heartbeat_reply(message))
+            .for_each(|message| {
+                let response = process(message, dbs, client);
+                log::debug!(
+                    "[{}] Server responded message  {:?} to {}",
+                    thread_id::get(),
+                    response,
+                    message
+                );
+                send_response(client, &response);
+            });
 
         Ok(())
     }
 
+    fn upgrade_ssl_server(&mut self, sock: TcpStream) -> ws::Result<SslStream<TcpStream>> {
+        match ws_acceptor() {
+            Some(acceptor) => acceptor.accept(sock).map_err(From::from),
+            None => Err(ws::Error::new(
+                ws::ErrorKind::Internal,
+                "TLS is not enabled for the web socket listener",
+            )),
+        }
+    }
+
     fn on_close(&mut self, code: CloseCode, reason: &str) {
         log::debug!("WebSocket closing for ({:?}) {}", code, reason);
-        match self.client.sender.try_send(TO_CLOSE.to_string()) {
-            //To close the read thread
-            Ok(_) => {}
-            Err(e) => log::warn!("on_close::Error {}", e),
-        }
-        process_request("unwatch-all", &self.dbs, &mut self.client);
-        self.client.left(&self.dbs);
+        self.disconnect();
     }
 }
 
@@ -134,16 +212,19 @@ pub fn start_web_socket_client(dbs: Arc<Databases>, ws_address: Arc<String>) {
     let ws_address = ws_address.to_string();
     log::debug!("Starting the web socket client with addr: {}", ws_address);
     let server = thread::spawn(move || {
-        let (sender, _): (Sender<String>, Receiver<String>) = channel(100);
         ws::Builder::new()
             .with_settings(ws::Settings {
                 max_connections: 100000,
+                encrypt_server: ws_acceptor().is_some(),
                 ..ws::Settings::default()
             })
             .build(move |out| Server {
                 out,
                 dbs: dbs.clone(),
-                client: Client::new_empty(sender.clone()),
+                client: None,
+                _permit: None,
+                idle: IdleSettings::from_config(),
+                last_frame: Instant::now(),
             })
             .unwrap()
             .listen(ws_address)
@@ -1,4 +1,8 @@
 use crate::bo::*;
+use crate::network::framing_ops::Framing;
+use crate::rate_limit_ops::RateLimitConfig;
+use crate::replication_ops::decode_replicated_value;
+use crate::security::DEFAULT_PERMISSIONS_USER;
 use lazy_static::lazy_static;
 use log;
 use std::collections::HashMap;
@@ -9,23 +13,32 @@ lazy_static! {
             HashMap::new();
         map.insert("ack", parse_ack_command);
         map.insert("arbiter", parse_arbiter_command);
+        map.insert("audit-log", parse_audit_log_command);
         map.insert("auth", parse_auth_command);
+        map.insert("backup", parse_backup_command);
+        map.insert("cluster-auth", parse_cluster_auth_command);
         map.insert("cluster-state", |_| Ok(Request::ClusterState {}));
 
+        map.insert("create-admin", parse_create_admin_command);
         map.insert("create-db", parse_create_db_command);
         map.insert("create-user", parse_create_user_command);
 
         map.insert("debug", parse_debug_command);
         map.insert("election", parse_election_command);
 
+        map.insert("export", parse_export_command);
+        map.insert("framing", parse_framing_command);
         map.insert("get", parse_get_command);
         map.insert("get-safe", parse_get_safe_command);
+        map.insert("hello", parse_hello_command);
+        map.insert("import", parse_import_command);
         map.insert("increment", parse_increment_command);
         map.insert("join", parse_join_command);
         map.insert("keys", parse_keys_command);
         map.insert("leave", parse_leave_command);
         map.insert("ls", parse_keys_command);
         map.insert("metrics-state", |_| Ok(Request::MetricsState {}));
+        map.insert("ping", parse_ping_command);
         map.insert("remove", parse_remove_command);
         map.insert("replicate", parse_replicate_command);
         map.insert("replicate-increment", parse_replicate_increment_command);
@@ -47,18 +60,68 @@ lazy_static! {
         map.insert("use-db", parse_use_command);
         map.insert("watch", parse_watch_command);
         map.insert("list-commands", parse_list_commands_command);
+        map.insert("list-users", |_| Ok(Request::ListUsers {}));
+        map.insert("remove-user", parse_remove_user_command);
+        map.insert("rotate-db-token", parse_rotate_db_token_command);
+        map.insert("rotate-user-token", parse_rotate_user_token_command);
         map.insert("set-permissions", parse_set_permissions_command);
+        map.insert("set-rate-limits", parse_set_rate_limits_command);
 
         map
     };
 }
 
+const MAX_REQUEST_ID_SIZE: usize = 64;
+
 impl Request {
     pub fn command_list() -> Vec<String> {
         PARSER_HASH_TABLE.keys().map(|x| x.to_string()).collect()
     }
     pub fn parse(input: &str) -> Result<Request, String> {
-        let mut command = input.trim_end_matches(';').splitn(3, " ");
+        match Request::parse_exact(input.trim_end_matches(';')) {
+            // Line breaks are never part of text values
+            Ok(Request::Set {
+                key,
+                value,
+                version,
+            }) => Ok(Request::Set {
+                key,
+                value: value.replace('\n', ""),
+                version,
+            }),
+            request => request,
+        }
+    }
+
+    /// Splits the optional request id of a command, e.g. `#42 get b`
+    pub fn split_request_id(input: &str) -> Result<(Option<&str>, &str), String> {
+        match input.strip_prefix('#') {
+            Some(rest) => {
+                let (request_id, command) = rest.split_once(' ').unwrap_or((rest, ""));
+                if request_id.is_empty() || request_id.len() > MAX_REQUEST_ID_SIZE {
+                    return Err(format!("Invalid request id {}", request_id));
+                }
+                Ok((Some(request_id), command))
+            }
+            None => Ok((None, input)),
+        }
+    }
+
+    /// Parses a command decoded from a json frame, `;` and line breaks are part of the values
+    pub fn parse_exact(input: &str) -> Result<Request, String> {
+        let request = Request::parse_command(input)?;
+        match &request {
+            Request::Set { key, .. } if !key.contains('\n') => Ok(request),
+            // Commands reach the other nodes as lines, a line break would start a new command
+            _ if input.trim_end_matches('\n').contains('\n') => {
+                Err(String::from("Line breaks are only allowed in values"))
+            }
+            _ => Ok(request),
+        }
+    }
+
+    fn parse_command(input: &str) -> Result<Request, String> {
+        let mut command = input.splitn(3, " ");
         match command.next() {
             Some("") | None => {
                 log::debug!("empty command");
@@ -95,6 +158,25 @@ fn parse_auth_command(command: &mut std::str::SplitN<&str>) -> Result<Request, S
         password: pwd.to_string().replace("\n", ""),
     })
 }
+fn parse_audit_log_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    match command.next().map(|count| count.trim()) {
+        None | Some("") => Ok(Request::AuditLog { count: 100 }),
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) => Ok(Request::AuditLog { count }),
+            Err(_) => Err(format!("Invalid audit-log count {}", count)),
+        },
+    }
+}
+
+fn parse_cluster_auth_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    match command.next() {
+        Some(secret) => Ok(Request::ClusterAuth {
+            secret: secret.trim().to_string(),
+        }),
+        None => Err(String::from("cluster-auth needs the cluster secret")),
+    }
+}
+
 fn parse_remove_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
     let key = match command.next() {
         Some(key) => key,
@@ -194,7 +276,7 @@ fn parse_set_safe_command(command: &mut std::str::SplitN<&str>) -> Result<Reques
     };
 
     let value = match rest.next() {
-        Some(value) => value.replace("\n", ""),
+        Some(value) => value.to_string(),
         None => return Err(String::from("set-safe must be followed by a key")),
     };
 
@@ -214,7 +296,7 @@ fn parse_set_command(command: &mut std::str::SplitN<&str>) -> Result<Request, St
         }
     };
     let value = match command.next() {
-        Some(value) => value.replace("\n", ""),
+        Some(value) => value.to_string(),
         None => {
             log::debug!("SET needs a value");
             "".to_string()
@@ -411,6 +493,46 @@ fn parse_set_permissions_command(command: &mut std::str::SplitN<&str>) -> Result
     Ok(Request::SetPermissions { user, permissions })
 }
 
+fn parse_set_rate_limits_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    let limits_str = command.collect::<Vec<&str>>().join(" ");
+    let limits = RateLimitConfig::parse(&limits_str)?;
+    Ok(Request::SetRateLimits { limits })
+}
+
+fn parse_framing_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    let framing = Framing::parse(&command.collect::<Vec<&str>>().join(" "))?;
+    Ok(Request::SetFraming { framing })
+}
+
+fn parse_ping_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    let message = command.collect::<Vec<&str>>().join(" ");
+    Ok(Request::Ping {
+        message: Some(message).filter(|message| !message.is_empty()),
+    })
+}
+
+fn parse_hello_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    let protocol_version = match command.next().map(|version| version.parse::<u32>()) {
+        Some(Ok(protocol_version)) => protocol_version,
+        _ => {
+            return Err(String::from(
+                "hello must be followed by the protocol version",
+            ))
+        }
+    };
+    let capabilities = command
+        .next()
+        .unwrap_or("")
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|capability| !capability.is_empty())
+        .map(String::from)
+        .collect();
+    Ok(Request::Hello {
+        protocol_version,
+        capabilities,
+    })
+}
+
 fn parse_ack_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
     log::debug!("Parsing ack command");
     let opp_id: u64 = match command.next() {
@@ -498,7 +620,7 @@ fn parse_replicate_command(command: &mut std::str::SplitN<&str>) -> Result<Reque
             };
 
             let value = match command.next() {
-                Some(key) => String::from(key).replace("\n", ""),
+                Some(value) => decode_replicated_value(value)?,
                 None => {
                     log::debug!("ReplicateSet needs value");
                     "".to_string()
@@ -592,6 +714,41 @@ fn parse_snapshot_command(command: &mut std::str::SplitN<&str>) -> Result<Reques
     })
 }
 
+fn parse_backup_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    let destination = match command.next() {
+        Some(destination) if !destination.is_empty() => destination.to_string(),
+        _ => return Err(String::from("backup must contain a path or s3 uri")),
+    };
+
+    Ok(Request::Backup { destination })
+}
+
+fn parse_export_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    let db_name = match command.next() {
+        Some(db_name) if !db_name.is_empty() => db_name.to_string(),
+        _ => return Err(String::from("export must contain a db name")),
+    };
+    let include_system_keys = command.next().unwrap_or("false") == "true";
+
+    Ok(Request::Export {
+        db_name,
+        include_system_keys,
+    })
+}
+
+fn parse_import_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    let db_name = match command.next() {
+        Some(db_name) if !db_name.is_empty() => db_name.to_string(),
+        _ => return Err(String::from("import must contain a db name")),
+    };
+    let records = match command.next() {
+        Some(records) => crate::export_ops::parse_import_records(records)?,
+        None => return Err(String::from("import must contain the records")),
+    };
+
+    Ok(Request::Import { db_name, records })
+}
+
 fn parse_list_commands_command(_: &mut std::str::SplitN<&str>) -> Result<Request, String> {
     Ok(Request::ListCommands {})
 }
@@ -706,12 +863,86 @@ fn parse_create_user_command(command: &mut std::str::SplitN<&str>) -> Result<Req
             "".to_string()
         }
     };
+    // Reserved for the default permissions of the database
+    if user_name == DEFAULT_PERMISSIONS_USER {
+        return Err(format!("{} is not a valid user name", user_name));
+    }
     Ok(Request::CreateUser {
         user_name: user_name.to_string(),
         token: token.to_string(),
     })
 }
 
+fn parse_remove_user_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    match command.next() {
+        Some(user_name) => Ok(Request::RemoveUser {
+            user_name: user_name.replace("\n", ""),
+        }),
+        None => Err(String::from("user name is mandatory")),
+    }
+}
+
+fn parse_rotate_user_token_command(
+    command: &mut std::str::SplitN<&str>,
+) -> Result<Request, String> {
+    let user_name = match command.next() {
+        Some(user_name) => user_name.to_string(),
+        None => {
+            return Err(String::from("user name is mandatory"));
+        }
+    };
+    match command.next() {
+        Some(token) => Ok(Request::RotateUserToken {
+            user_name,
+            token: token.replace("\n", ""),
+        }),
+        None => Err(String::from("token is mandatory")),
+    }
+}
+
+fn parse_rotate_db_token_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    match command.next() {
+        Some(token) => Ok(Request::RotateDbToken {
+            token: token.replace("\n", ""),
+        }),
+        None => Err(String::from("token is mandatory")),
+    }
+}
+
+fn parse_create_admin_command(command: &mut std::str::SplitN<&str>) -> Result<Request, String> {
+    let user_name = match command.next() {
+        Some(name) => name.to_string(),
+        None => {
+            return Err(String::from("user name is mandatory"));
+        }
+    };
+    let mut rest = match command.next() {
+        Some(rest) => rest.splitn(2, " "),
+        None => {
+            return Err(String::from(
+                "create-admin must be followed by a token and roles",
+            ));
+        }
+    };
+    let token = match rest.next() {
+        Some(token) => String::from(token).replace("\n", ""),
+        None => {
+            return Err(String::from("token is mandatory"));
+        }
+    };
+    let roles = match rest.next() {
+        Some(roles) => AdminRole::roles_from_str(roles.replace("\n", "").as_str())?,
+        None => {
+            return Err(String::from("roles are mandatory"));
+        }
+    };
+    Ok(Request::CreateAdmin {
+        user_name,
+        token,
+        roles,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -820,6 +1051,102 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_parse_user_management_commands() {
+        assert_eq!(Request::parse("list-users"), Ok(Request::ListUsers {}));
+        assert_eq!(
+            Request::parse("remove-user foo"),
+            Ok(Request::RemoveUser {
+                user_name: String::from("foo")
+            })
+        );
+        assert_eq!(
+            Request::parse("rotate-user-token foo new-token"),
+            Ok(Request::RotateUserToken {
+                user_name: String::from("foo"),
+                token: String::from("new-token")
+            })
+        );
+        assert_eq!(
+            Request::parse("rotate-db-token new-token"),
+            Ok(Request::RotateDbToken {
+                token: String::from("new-token")
+            })
+        );
+        assert!(Request::parse("rotate-user-token foo").is_err());
+        assert!(Request::parse("remove-user").is_err());
+    }
+
+    #[test]
+    fn should_split_the_request_id() {
+        assert_eq!(
+            Request::split_request_id("#42 get b"),
+            Ok((Some("42"), "get b"))
+        );
+        assert_eq!(Request::split_request_id("get b"), Ok((None, "get b")));
+        assert_eq!(Request::split_request_id("#a-1"), Ok((Some("a-1"), "")));
+        assert!(Request::split_request_id("# get b").is_err());
+        assert!(Request::split_request_id(&format!("#{} get b", "1".repeat(65))).is_err());
+    }
+
+    #[test]
+    fn should_parse_ping_with_an_optional_message() {
+        assert_eq!(Request::parse("ping"), Ok(Request::Ping { message: None }));
+        assert_eq!(
+            Request::parse("ping are you there"),
+            Ok(Request::Ping {
+                message: Some(String::from("are you there")),
+            })
+        );
+    }
+
+    #[test]
+    fn should_parse_framed_values_exactly() {
+        assert_eq!(
+            Request::parse_exact("set name jose;\nmaria "),
+            Ok(Request::Set {
+                key: String::from("name"),
+                value: String::from("jose;\nmaria "),
+                version: -1,
+            })
+        );
+        assert_eq!(
+            Request::parse("framing json"),
+            Ok(Request::SetFraming {
+                framing: Framing::Json
+            })
+        );
+        assert!(Request::parse("framing").is_err());
+    }
+
+    #[test]
+    fn should_parse_create_admin() -> Result<(), String> {
+        match Request::parse("create-admin foo bar db-creator|backup-operator") {
+            Ok(Request::CreateAdmin {
+                user_name,
+                token,
+                roles,
+            }) => {
+                assert_eq!(user_name, "foo");
+                assert_eq!(token, "bar");
+                assert_eq!(roles, vec![AdminRole::DbCreator, AdminRole::BackupOperator]);
+                Ok(())
+            }
+            _ => Err(String::from(
+                "create-admin foo bar db-creator|backup-operator should be parsed to CreateAdmin command",
+            )),
+        }
+    }
+
+    #[test]
+    fn should_not_parse_create_admin_with_invalid_role() {
+        assert_eq!(
+            Request::parse("create-admin foo bar root"),
+            Err(String::from("Invalid role root"))
+        );
+        assert!(Request::parse("create-admin foo bar").is_err());
+    }
+
     #[test]
     fn should_parse_create_db() -> Result<(), String> {
         match Request::parse("create-db foo some-key") {
@@ -856,6 +1183,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_parse_audit_log() {
+        assert_eq!(
+            Request::parse("audit-log"),
+            Ok(Request::AuditLog { count: 100 })
+        );
+        assert_eq!(
+            Request::parse("audit-log 10"),
+            Ok(Request::AuditLog { count: 10 })
+        );
+        assert!(Request::parse("audit-log ten").is_err());
+    }
+
+    #[test]
+    fn should_parse_set_rate_limits() {
+        assert_eq!(
+            Request::parse("set-rate-limits 100 50 0"),
+            Ok(Request::SetRateLimits {
+                limits: RateLimitConfig {
+                    connection: 100,
+                    user: 50,
+                    ip: 0,
+                }
+            })
+        );
+        assert!(Request::parse("set-rate-limits 100").is_err());
+    }
+
+    #[test]
+    fn should_parse_cluster_auth() {
+        assert_eq!(
+            Request::parse("cluster-auth some-secret\n"),
+            Ok(Request::ClusterAuth {
+                secret: String::from("some-secret")
+            })
+        );
+        assert!(Request::parse("cluster-auth").is_err());
+    }
+
     #[test]
     fn should_parse_auth() -> Result<(), String> {
         match Request::parse("auth foo bar") {
@@ -1045,7 +1411,7 @@ mod tests {
 
     #[test]
     fn should_parse_replicaion() -> Result<(), String> {
-        match Request::parse("replicate vue jose 3 1") {
+        match Request::parse("replicate vue jose 3 \"1\"") {
             Ok(Request::ReplicateSet {
                 db,
                 key,
@@ -1096,6 +1462,69 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_parse_backup() -> Result<(), String> {
+        match Request::parse("backup s3://nun-db/backups/all.bkp") {
+            Ok(Request::Backup { destination }) => {
+                if destination == "s3://nun-db/backups/all.bkp" {
+                    Ok(())
+                } else {
+                    Err(String::from(
+                        "destination should be s3://nun-db/backups/all.bkp",
+                    ))
+                }
+            }
+            _ => Err(String::from("wrong command parsed")),
+        }
+    }
+
+    #[test]
+    fn should_parse_export() -> Result<(), String> {
+        match Request::parse("export vue true") {
+            Ok(Request::Export {
+                db_name,
+                include_system_keys,
+            }) => {
+                if db_name == "vue" && include_system_keys {
+                    Ok(())
+                } else {
+                    Err(String::from("db should vue and include system keys"))
+                }
+            }
+            _ => Err(String::from("wrong command parsed")),
+        }
+    }
+
+    #[test]
+    fn should_parse_import() -> Result<(), String> {
+        match Request::parse("import vue [{\"key\":\"name\",\"value\":\"a b\",\"version\":2}]") {
+            Ok(Request::Import { db_name, records }) => {
+                if db_name == "vue" && records.len() == 1 && records[0].value == "a b" {
+                    Ok(())
+                } else {
+                    Err(String::from("db should vue and have one record"))
+                }
+            }
+            _ => Err(String::from("wrong command parsed")),
+        }
+    }
+
+    #[test]
+    fn should_fail_to_parse_import_with_invalid_records() -> Result<(), String> {
+        match Request::parse("import vue {invalid}") {
+            Err(_) => Ok(()),
+            _ => Err(String::from("import with invalid records should fail")),
+        }
+    }
+
+    #[test]
+    fn should_fail_to_parse_backup_without_destination() -> Result<(), String> {
+        match Request::parse("backup") {
+            Err(_) => Ok(()),
+            _ => Err(String::from("backup without destination should fail")),
+        }
+    }
+
     #[test]
     fn should_parse_replicaion_snapshot() -> Result<(), String> {
         match Request::parse("replicate-snapshot vue") {
@@ -1448,6 +1877,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_parse_set_permission_with_deny_rules() {
+        match Request::parse("set-permissions jose rw room:*|!w room:*:private") {
+            Ok(Request::SetPermissions { permissions, .. }) => {
+                assert!(!permissions[0].deny);
+                assert!(permissions[1].deny);
+                assert_eq!(permissions[1].kinds, vec![PermissionKind::Write]);
+                assert_eq!(permissions[1].keys, vec![String::from("room:*:private")]);
+                assert_eq!(
+                    Permission::permissions_to_str_value(&permissions),
+                    "rw room:*|!w room:*:private"
+                );
+            }
+            _ => panic!("set-permissions should be parsed to SetPermissions command"),
+        }
+    }
+
+    #[test]
+    fn should_parse_set_permission_with_watch_kind() {
+        match Request::parse("set-permissions jose n feed|r room:*") {
+            Ok(Request::SetPermissions { permissions, .. }) => {
+                assert_eq!(permissions[0].kinds, vec![PermissionKind::Watch]);
+                assert!(permissions[0].grants(&PermissionKind::Watch));
+                assert!(!permissions[0].grants(&PermissionKind::Read));
+                assert!(permissions[1].grants(&PermissionKind::Watch));
+                assert_eq!(
+                    Permission::permissions_to_str_value(&permissions),
+                    "n feed|r room:*"
+                );
+            }
+            _ => panic!("set-permissions should be parsed to SetPermissions command"),
+        }
+    }
+
     #[test]
     fn should_parse_set_permission_command_as_write() -> Result<(), String> {
         match Request::parse("set-permissions jose rwix test") {
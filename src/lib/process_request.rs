@@ -2,9 +2,13 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Instant;
 
+use crate::audit_ops::*;
 use crate::bo::*;
 use crate::db_ops::*;
 use crate::election_ops::*;
+use crate::network::handshake_ops::{check_peer_protocol, server_info};
+use crate::network::tls_ops::is_node_certificate_required;
+use crate::rate_limit_ops::RATE_LIMITS_KEY;
 use crate::replication_ops::*;
 use crate::security::*;
 //use crate::consensus_ops::*;
@@ -12,22 +16,24 @@ use log;
 
 fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Client) -> Response {
     match request.clone() {
-        Request::ReplicateIncrement { db: name, key, inc } => apply_if_auth(&client.auth, &|| {
-            let dbs = dbs.map.read().expect("Could not lock the dbs mutex");
-            let respose: Response = match dbs.get(&name.to_string()) {
-                Some(db) => {
-                    db.inc_value(key.to_string(), inc);
-                    Response::Ok {}
-                }
-                _ => {
-                    log::debug!("Not a valid database name");
-                    Response::Error {
-                        msg: "Not a valid database name".to_string(),
+        Request::ReplicateIncrement { db: name, key, inc } => {
+            apply_if_cluster_node(client, &|| {
+                let dbs = dbs.map.read().expect("Could not lock the dbs mutex");
+                let respose: Response = match dbs.get(&name.to_string()) {
+                    Some(db) => {
+                        db.inc_value(key.to_string(), inc);
+                        Response::Ok {}
                     }
-                }
-            };
-            respose
-        }),
+                    _ => {
+                        log::debug!("Not a valid database name");
+                        Response::Error {
+                            msg: "Not a valid database name".to_string(),
+                        }
+                    }
+                };
+                respose
+            })
+        }
 
         Request::Increment { key, inc } => apply_if_safe_access(
             &dbs,
@@ -52,22 +58,52 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             },
             PermissionKind::Increment,
         ),
+        Request::ClusterAuth { secret } => {
+            let valid = has_node_certificate(client, is_node_certificate_required())
+                && is_valid_cluster_secret(dbs, &secret);
+            if valid {
+                client.auth_as_node();
+            }
+            dbs.audit(
+                "node",
+                "cluster-auth",
+                "",
+                if valid { "success" } else { "failure" },
+            );
+            let message = if valid {
+                "valid auth\n".to_string()
+            } else {
+                "invalid auth\n".to_string()
+            };
+            if let Err(e) = client.try_send_direct_message(message) {
+                log::debug!("Request::ClusterAuth sender.send Error: {}", e);
+            }
+            Response::Ok {}
+        }
         Request::Auth { user, password } => {
-            let valid_user = dbs.user.clone();
-            let valid_pwd = dbs.pwd.clone();
-
-            if user == valid_user && password == valid_pwd {
-                client.auth.swap(true, Ordering::Relaxed);
+            let roles = admin_roles_from_credentials(dbs, &user, &password);
+            dbs.audit(
+                &user,
+                "auth",
+                "",
+                if roles.is_some() {
+                    "success"
+                } else {
+                    "failure"
+                },
+            );
+            if let Some(roles) = roles {
+                client.auth_as(roles);
+                *client.admin_user_name.write().unwrap() = Some(user.to_string());
             };
             let message = if client.auth.load(Ordering::SeqCst) {
                 "valid auth\n".to_string()
             } else {
                 "invalid auth\n".to_string()
             };
-            match client.sender.clone().try_send(message) {
-                Ok(_n) => (),
-                Err(e) => log::debug!("Request::Auth sender.send Error: {}", e),
-            };
+            if let Err(e) = client.try_send_direct_message(message) {
+                log::debug!("Request::Auth sender.send Error: {}", e);
+            }
             Response::Ok {}
         }
 
@@ -75,7 +111,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             &dbs,
             &client,
             &key,
-            &|_db| get_key_value(&key, &client.sender, _db),
+            &|_db| get_key_value(&key, client, _db),
             PermissionKind::Read,
         ),
 
@@ -83,7 +119,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             &dbs,
             &client,
             &key,
-            &|_db| get_key_value_safe(&key, &client.sender, _db),
+            &|_db| get_key_value_safe(&key, client, _db),
             PermissionKind::Read,
         ),
 
@@ -122,10 +158,14 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             PermissionKind::Write,
         ),
 
-        Request::ReplicateRemove { db: name, key } => apply_if_auth(&client.auth, &|| {
-            let dbs = dbs.map.read().expect("Could not lock the dbs mutex");
-            let respose: Response = match dbs.get(&name.to_string()) {
-                Some(db) => remove_key(&key, db),
+        Request::ReplicateRemove { db: name, key } => apply_if_cluster_node(client, &|| {
+            let dbs_map = dbs.map.read().expect("Could not lock the dbs mutex");
+            let respose: Response = match dbs_map.get(&name.to_string()) {
+                Some(db) => {
+                    let respose = remove_key(&key, db);
+                    revoke_sessions_for_token_key(&key, db, dbs, None);
+                    respose
+                }
                 _ => {
                     log::debug!("Not a valid database name");
                     Response::Error {
@@ -141,10 +181,14 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             key,
             value,
             version,
-        } => apply_if_auth(&client.auth, &|| {
+        } => apply_if_cluster_node(client, &|| {
             let dbs_map = dbs.map.read().expect("Could not lock the dbs mutex");
             let respose: Response = match dbs_map.get(&name.to_string()) {
-                Some(db) => set_key_value(key.clone(), value.clone(), version, db, &dbs),
+                Some(db) => {
+                    let respose = set_key_value(key.clone(), value.clone(), version, db, dbs);
+                    revoke_sessions_for_token_key(&key, db, dbs, None);
+                    respose
+                }
                 _ => {
                     log::debug!("Not a valid database name");
                     Response::Error {
@@ -158,7 +202,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
         Request::Snapshot {
             reclaim_space,
             db_names,
-        } => apply_if_auth(&client.auth, &|| {
+        } => apply_if_role(client, AdminRole::BackupOperator, &|| {
             if db_names.is_empty() {
                 let db_name = client.selected_db.name.read().unwrap();
                 match db_name.clone() {
@@ -204,10 +248,75 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
                 }
             }
         }),
+        Request::Backup { destination } => apply_if_role(
+            client,
+            AdminRole::BackupOperator,
+            &|| match crate::backup_ops::backup(dbs, &destination) {
+                Ok(archive) => {
+                    if let Err(e) = client.try_send_direct_message(format!(
+                        "backup success {} {}\n",
+                        archive.databases.len(),
+                        archive.oplog_position
+                    )) {
+                        log::warn!("Request::Backup sender.send Error: {}", e);
+                    }
+                    Response::Ok {}
+                }
+                Err(msg) => Response::Error { msg },
+            },
+        ),
+        Request::Export {
+            db_name,
+            include_system_keys,
+        } => apply_if_role(client, AdminRole::BackupOperator, &|| {
+            let dbs_map = dbs.map.read().expect("Could not lock the dbs mutex");
+            match dbs_map.get(&db_name) {
+                Some(db) => {
+                    let (count, jsonl) =
+                        crate::export_ops::export_db_jsonl(db, include_system_keys);
+                    if let Err(e) = client
+                        .try_send_direct_message(format!("export {} {}\n{}", db_name, count, jsonl))
+                    {
+                        log::warn!("Request::Export sender.send Error: {}", e);
+                    }
+                    Response::Ok {}
+                }
+                None => Response::Error {
+                    msg: "Not a valid database name".to_string(),
+                },
+            }
+        }),
+        Request::Import { db_name, records } => {
+            apply_if_role(client, AdminRole::BackupOperator, &|| {
+                // Cloned so creating databases doesn't wait for the import
+                let db = dbs
+                    .map
+                    .read()
+                    .expect("Could not lock the dbs mutex")
+                    .get(&db_name)
+                    .cloned();
+                match db {
+                    Some(db) => {
+                        let (imported, conflicts, skipped) =
+                            crate::export_ops::import_records(&db, &records, dbs);
+                        if let Err(e) = client.try_send_direct_message(format!(
+                            "import success {} {} {}\n",
+                            imported, conflicts, skipped
+                        )) {
+                            log::warn!("Request::Import sender.send Error: {}", e);
+                        }
+                        Response::Ok {}
+                    }
+                    None => Response::Error {
+                        msg: "Not a valid database name".to_string(),
+                    },
+                }
+            })
+        }
         Request::ReplicateSnapshot {
             reclaim_space,
             db_names,
-        } => apply_if_auth(&client.auth, &|| {
+        } => apply_if_cluster_node(client, &|| {
             db_names
                 .clone()
                 .into_iter()
@@ -227,6 +336,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
 
         Request::UnWatchAll {} => apply_to_database(&dbs, &client, &|_db| {
             unwatch_all(&client.sender, _db);
+            client.set_watching(false);
             Response::Ok {}
         }),
 
@@ -235,10 +345,15 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             &client,
             &key,
             &|_db| {
-                watch_key(&key, &client.sender, _db);
+                if has_permission(client, &key, _db, &PermissionKind::Read) {
+                    watch_key(&key, &client.sender, _db);
+                } else {
+                    _db.watch_key_without_values(&key, &client.sender);
+                }
+                client.set_watching(true);
                 Response::Ok {}
             },
-            PermissionKind::Read,
+            PermissionKind::Watch,
         ),
 
         Request::UseDb {
@@ -249,7 +364,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             let dbs_map = dbs.map.read().expect("Could not lock the map mutex");
             let respose: Response = match dbs_map.get(&name.to_string()) {
                 Some(db) => {
-                    match user_name {
+                    let respose = match user_name {
                         Some(user_name) => {
                             let mut db_name_state = client.selected_db.name.write().unwrap();
                             let mut user_name_state = client.selected_db.user_name.write().unwrap();
@@ -260,6 +375,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
                                     &mut *user_name_state,
                                     Some(user_name.clone()),
                                 );
+                                client.selected_db.set_access_scope(None, None);
                                 db.inc_connections(); //Increment the number of connections
                                 set_connection_counter(db, &dbs);
                                 Response::Ok {}
@@ -269,20 +385,43 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
                                 }
                             }
                         }
-                        None => {
-                            if is_valid_token(&token, db) {
-                                let mut db_name_state = client.selected_db.name.write().unwrap();
-                                let _ = std::mem::replace(&mut *db_name_state, Some(name.clone()));
+                        None => match access_token_from_db(&token, db) {
+                            Some(Ok(claims)) => {
+                                let permissions = claims
+                                    .permissions
+                                    .map(|p| Permission::permissions_from_str(p.as_str()));
+                                *client.selected_db.name.write().unwrap() = Some(name.clone());
+                                *client.selected_db.user_name.write().unwrap() = Some(claims.sub);
+                                client
+                                    .selected_db
+                                    .set_access_scope(permissions, Some(claims.exp));
                                 db.inc_connections(); //Increment the number of connections
-                                set_connection_counter(db, &dbs);
+                                set_connection_counter(db, dbs);
                                 Response::Ok {}
-                            } else {
-                                Response::Error {
-                                    msg: "Invalid token".to_string(),
+                            }
+                            Some(Err(msg)) => Response::Error { msg },
+                            None => {
+                                if is_valid_token(&token, db) {
+                                    let mut db_name_state =
+                                        client.selected_db.name.write().unwrap();
+                                    let _ = db_name_state.replace(name.clone());
+                                    client.selected_db.set_access_scope(None, None);
+                                    db.inc_connections(); //Increment the number of connections
+                                    set_connection_counter(db, dbs);
+                                    Response::Ok {}
+                                } else {
+                                    Response::Error {
+                                        msg: "Invalid token".to_string(),
+                                    }
                                 }
                             }
-                        }
+                        },
+                    };
+                    // Registered after releasing the selected db locks
+                    if respose == (Response::Ok {}) {
+                        db.register_session(client);
                     }
+                    respose
                 }
                 _ => {
                     log::debug!("Not a valid database name");
@@ -293,22 +432,25 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             };
             respose
         }
-        Request::CreateUser { token, user_name } => apply_if_safe_access(
+        Request::CreateUser { token, user_name } => apply_if_user_admin_access(
             &dbs,
             &client,
             &String::from(USER_NAME_KEYS_PREFIX),
             &|_db| {
                 let key = user_name_key_from_user_name(&user_name);
-                let respose = set_key_value(key.to_string(), token.to_string(), -1, _db, &dbs);
+                let hashed_token = hash_token(&token);
+                let respose =
+                    set_key_value(key.to_string(), hashed_token.to_string(), -1, _db, dbs);
                 match respose {
                     Response::Set { .. } => {
+                        revoke_sessions_for_token_key(&key, _db, dbs, Some(client));
                         if !dbs.is_primary() {
                             let db_name_state = _db.name.clone();
                             send_message_to_primary(
                                 get_replicate_message(
                                     db_name_state.to_string(),
                                     key,
-                                    token.to_string(),
+                                    hashed_token,
                                     -1,
                                 ),
                                 dbs,
@@ -322,21 +464,130 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             PermissionKind::Write,
         ),
 
+        Request::ListUsers {} => apply_if_user_admin_access(
+            dbs,
+            client,
+            &String::from(USER_NAME_KEYS_PREFIX),
+            &|db| {
+                let user_names = list_user_names(db).join(",");
+                if let Err(e) = client.try_send_direct_message(format!("users {}\n", user_names)) {
+                    log::warn!("Request::ListUsers sender.send Error: {}", e);
+                }
+                Response::Ok {}
+            },
+            PermissionKind::Read,
+        ),
+        Request::RemoveUser { user_name } => apply_if_user_admin_access(
+            dbs,
+            client,
+            &String::from(USER_NAME_KEYS_PREFIX),
+            &|db| {
+                let key = user_name_key_from_user_name(&user_name);
+                if !list_user_names(db).contains(&user_name) {
+                    return Response::Error {
+                        msg: format!("User {} not found", user_name),
+                    };
+                }
+                for key in [key.to_string(), permissions_key_from_user_name(&user_name)] {
+                    remove_key(&key, db);
+                    if !dbs.is_primary() {
+                        send_message_to_primary(
+                            get_replicate_remove_message(db.name.to_string(), key),
+                            dbs,
+                        );
+                    }
+                }
+                revoke_sessions_for_token_key(&key, db, dbs, Some(client));
+                Response::Ok {}
+            },
+            PermissionKind::Remove,
+        ),
+        Request::RotateUserToken { user_name, token } => apply_if_user_admin_access(
+            dbs,
+            client,
+            &String::from(USER_NAME_KEYS_PREFIX),
+            &|db| {
+                if !list_user_names(db).contains(&user_name) {
+                    return Response::Error {
+                        msg: format!("User {} not found", user_name),
+                    };
+                }
+                rotate_token(
+                    user_name_key_from_user_name(&user_name),
+                    &token,
+                    db,
+                    dbs,
+                    client,
+                )
+            },
+            PermissionKind::Write,
+        ),
+        Request::RotateDbToken { token } => apply_if_safe_access(
+            dbs,
+            client,
+            &String::from(TOKEN_KEY),
+            &|db| rotate_token(String::from(TOKEN_KEY), &token, db, dbs, client),
+            PermissionKind::Write,
+        ),
+        Request::CreateAdmin {
+            user_name,
+            token,
+            roles,
+        } => apply_if_role(client, AdminRole::ClusterAdmin, &|| {
+            if user_name == dbs.user {
+                return Response::Error {
+                    msg: format!("{} is already the cluster admin", user_name),
+                };
+            }
+            let dbs_map = dbs.map.read().expect("Could not lock the dbs mutex");
+            let admin_db = dbs_map.get(ADMIN_DB).expect("Admin db must exist");
+            let changes = vec![
+                (user_name_key_from_user_name(&user_name), hash_token(&token)),
+                (
+                    role_key_from_user_name(&user_name),
+                    AdminRole::roles_to_str_value(&roles),
+                ),
+            ];
+            for (key, value) in changes {
+                if let Response::Error { msg } =
+                    set_key_value(key.to_string(), value.to_string(), -1, admin_db, dbs)
+                {
+                    return Response::Error { msg };
+                }
+                if !dbs.is_primary() {
+                    send_message_to_primary(
+                        get_replicate_message(String::from(ADMIN_DB), key, value, -1),
+                        dbs,
+                    );
+                }
+            }
+            if !dbs.is_primary() {
+                send_message_to_primary(format!("replicate-snapshot {} false", ADMIN_DB), dbs);
+            }
+            snapshot_db_by_name(&String::from(ADMIN_DB), dbs, false)
+        }),
+
         Request::CreateDb {
             name,
             token,
             strategy,
-        } => apply_if_auth(&client.auth, &|| {
-            create_db(&name, &token, &dbs, &client, strategy)
-        }),
+        } => {
+            let create = || create_db(&name, &token, dbs, client, strategy);
+            // The databases created on the primary are replicated as create-db
+            if client.is_cluster_node() {
+                apply_if_cluster_node(client, &create)
+            } else {
+                apply_if_role(client, AdminRole::DbCreator, &create)
+            }
+        }
 
         Request::ElectionActive { node_name: _ } => Response::Ok {}, //Nothing need to be done here now
-        Request::ElectionWin {} => apply_if_auth(&client.auth, &|| election_win(&dbs)),
+        Request::ElectionWin {} => apply_if_cluster_node(client, &|| election_win(dbs)),
         Request::Election { id, node_name } => {
-            apply_if_auth(&client.auth, &|| election_eval(&dbs, id, &node_name))
+            apply_if_cluster_node(client, &|| election_eval(dbs, id, &node_name))
         }
 
-        Request::SetPrimary { name } => apply_if_auth(&client.auth, &|| {
+        Request::SetPrimary { name } => apply_if_cluster_node(client, &|| {
             if !dbs.is_primary() {
                 log::info!("Setting {} as primary!", name);
                 match dbs
@@ -363,7 +614,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             Response::Ok {}
         }),
 
-        Request::SetScoundary { name } => apply_if_auth(&client.auth, &|| {
+        Request::SetScoundary { name } => apply_if_cluster_node(client, &|| {
             log::info!("Setting {} as secondary!", name);
             let member = Some(ClusterMember {
                 name: name.clone(),
@@ -375,7 +626,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             Response::Ok {}
         }),
 
-        Request::Join { name } => apply_if_auth(&client.auth, &|| {
+        Request::Join { name } => apply_if_cluster_node(client, &|| {
             if dbs.is_primary() || dbs.is_eligible() {
                 add_as_secoundary(&dbs, &name);
                 start_new_election(&dbs); //Slow operation here
@@ -385,7 +636,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             Response::Ok {}
         }),
 
-        Request::Leave { name } => apply_if_auth(&client.auth, &|| {
+        Request::Leave { name } => apply_if_cluster_node(client, &|| {
             match dbs
                 .replication_supervisor_sender
                 .clone()
@@ -398,7 +649,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             Response::Ok {}
         }),
 
-        Request::ReplicateLeave { name } => apply_if_auth(&client.auth, &|| {
+        Request::ReplicateLeave { name } => apply_if_cluster_node(client, &|| {
             match dbs
                 .replication_supervisor_sender
                 .clone()
@@ -410,7 +661,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             Response::Ok {}
         }),
 
-        Request::ReplicateJoin { name } => apply_if_auth(&client.auth, &|| {
+        Request::ReplicateJoin { name } => apply_if_cluster_node(client, &|| {
             match dbs
                 .replication_supervisor_sender
                 .clone()
@@ -425,7 +676,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
         Request::ReplicateSince {
             node_name,
             start_at,
-        } => apply_if_auth(&client.auth, &|| {
+        } => apply_if_cluster_node(client, &|| {
             match dbs
                 .replication_supervisor_sender
                 .clone()
@@ -437,7 +688,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             Response::Ok {}
         }),
 
-        Request::ClusterState {} => apply_if_auth(&client.auth, &|| {
+        Request::ClusterState {} => apply_if_role(client, AdminRole::ReadOnlyMonitor, &|| {
             let mut members: Vec<String> = dbs
                 .cluster_state
                 .lock()
@@ -453,13 +704,10 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             let cluster_state_str = members.iter().fold(String::from(""), |current, acc| {
                 format!("{} {},", current, acc)
             });
-            match client
-                .sender
-                .clone()
-                .try_send(format_args!("cluster-state {}\n", cluster_state_str).to_string())
+            if let Err(e) =
+                client.try_send_direct_message(format!("cluster-state {}\n", cluster_state_str))
             {
-                Err(e) => log::warn!("Request::ClusterState sender.send Error: {}", e),
-                _ => (),
+                log::warn!("Request::ClusterState sender.send Error: {}", e);
             }
 
             log::debug!("ClusterState {}", cluster_state_str);
@@ -470,20 +718,17 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             }
         }),
 
-        Request::MetricsState {} => apply_if_auth(&client.auth, &|| {
+        Request::MetricsState {} => apply_if_role(client, AdminRole::ReadOnlyMonitor, &|| {
             let oplog_state = dbs.get_oplog_state();
             log::debug!("MetricsState {}", oplog_state);
             let monitoring_state = dbs.get_monitoring_state();
             log::debug!("MonitoringState {}", monitoring_state);
             let metrics_state = format!("{},{}\n", oplog_state, monitoring_state);
 
-            match client
-                .sender
-                .clone()
-                .try_send(format_args!("metrics-state {}\n", metrics_state).to_string())
+            if let Err(e) =
+                client.try_send_direct_message(format!("metrics-state {}\n", metrics_state))
             {
-                Err(e) => log::warn!("Request::ClusterState sender.send Error: {}", e),
-                _ => (),
+                log::warn!("Request::ClusterState sender.send Error: {}", e);
             }
             Response::Value {
                 key: String::from("oplog-state"),
@@ -496,16 +741,12 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             let keys = db
                 .list_keys(&pattern, client.is_admin_auth())
                 .iter()
+                .filter(|key| has_permission(client, key, db, &PermissionKind::Read))
                 .fold(String::from(""), |current, acc| {
                     format!("{},{}", current, acc)
                 });
-            match client
-                .sender
-                .clone()
-                .try_send(format_args!("keys {}\n", keys).to_string())
-            {
-                Err(e) => log::warn!("Request::ClusterState sender.send Error: {}", e),
-                _ => (),
+            if let Err(e) = client.try_send_direct_message(format!("keys {}\n", keys)) {
+                log::warn!("Request::ClusterState sender.send Error: {}", e);
             }
 
             Response::Value {
@@ -517,7 +758,7 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
         Request::Acknowledge {
             opp_id,
             server_name,
-        } => apply_if_auth(&client.auth, &|| {
+        } => apply_if_cluster_node(client, &|| {
             dbs.acknowledge_pending_opp(opp_id, &server_name);
             Response::Ok {}
         }),
@@ -525,11 +766,14 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             request_str,
             opp_id,
         } => {
+            if !client.is_cluster_node() {
+                return Response::Error {
+                    msg: "Cluster auth required".to_string(),
+                };
+            }
             log::debug!("ack send_message_to_secoundary {} {}", opp_id, request_str);
             client
-                .sender
-                .clone()
-                .try_send(format!("ack {} {} \n", opp_id, dbs.external_tcp_address))
+                .try_send_direct_message(format!("ack {} {} \n", opp_id, dbs.external_tcp_address))
                 .unwrap();
             match process_request(&request_str, &dbs, client) {
                 Response::Error { msg } => {
@@ -543,18 +787,15 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
          * This command should only return get opperations
          * No change must be made as part of a Debug command
          */
-        Request::Debug { command } => apply_if_auth(&client.auth, &|| {
+        Request::Debug { command } => apply_if_role(client, AdminRole::ClusterAdmin, &|| {
             match command.as_str() {
                 "pending-ops" => {
                     let pendin_msgs = dbs.get_pending_messages_debug().join("\n");
                     log::info!("Peding messages on the server {}", pendin_msgs);
-                    match client
-                        .sender
-                        .clone()
-                        .try_send(format_args!("pending-ops {}\n", pendin_msgs).to_string())
+                    if let Err(e) =
+                        client.try_send_direct_message(format!("pending-ops {}\n", pendin_msgs))
                     {
-                        Err(e) => log::warn!("Request::pending-ops sender.send Error: {}", e),
-                        _ => (),
+                        log::warn!("Request::pending-ops sender.send Error: {}", e);
                     }
                 }
                 "pendding-conflitcts" => {
@@ -573,26 +814,20 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
                             format!("{},{}", current, acc)
                         });
                         log::info!("Peding conflitcts on the server {}", keys);
-                        match client
-                            .sender
-                            .clone()
-                            .try_send(format_args!("conflitcts-list {}\n", keys).to_string())
+                        if let Err(e) =
+                            client.try_send_direct_message(format!("conflitcts-list {}\n", keys))
                         {
-                            Err(e) => {
-                                log::warn!("Request::pending-ops sender.send Error: {}", e);
-                            }
-                            _ => (),
-                        };
+                            log::warn!("Request::pending-ops sender.send Error: {}", e);
+                        }
                         Response::Ok {}
                     });
                 }
                 "list-dbs" => {
                     let dbs_name_and_strategy = dbs.get_dbs_name_strategy().join("\n");
-                    match client.sender.clone().try_send(
-                        format_args!("dbs-list \n{}\n", dbs_name_and_strategy).to_string(),
-                    ) {
-                        Err(e) => log::warn!("Request::dbs-list sender.send Error: {}", e),
-                        _ => (),
+                    if let Err(e) = client
+                        .try_send_direct_message(format!("dbs-list \n{}\n", dbs_name_and_strategy))
+                    {
+                        log::warn!("Request::dbs-list sender.send Error: {}", e);
                     }
                 }
                 "force-election" => {
@@ -601,13 +836,10 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
                 }
                 "process-info" => {
                     let process_info = format!("process_id: {}", dbs.process_id);
-                    match client
-                        .sender
-                        .clone()
-                        .try_send(format_args!("process-info \n{}\n", process_info).to_string())
+                    if let Err(e) =
+                        client.try_send_direct_message(format!("process-info \n{}\n", process_info))
                     {
-                        Err(e) => log::warn!("Request::process-info sender.send Error: {}", e),
-                        _ => (),
+                        log::warn!("Request::process-info sender.send Error: {}", e);
                     }
                 }
 
@@ -624,86 +856,81 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             version,
         } => {
             log::info!("Processing resolve for {} to {} ", key, value);
-            // Replica set or admin auth resolving
-            if client.auth.load(Ordering::SeqCst) {
-                apply_to_database_name(
-                    dbs,
-                    client,
-                    &db_name,
-                    &|db| {
-                        if dbs.is_primary() {
-                            db.resolve_conflit(
-                                Change {
-                                    key: key.clone(),
-                                    value: value.clone(),
-                                    version,
-                                    opp_id,
-                                    resolve_conflict: true,
-                                },
-                                &dbs,
-                            )
-                        } else {
-                            send_message_to_primary(
-                                get_resolve_message(
-                                    opp_id,
-                                    db_name.to_string(),
-                                    key.clone(),
-                                    value.clone(),
-                                    version,
-                                ),
-                                dbs,
-                            );
-                            Response::Ok {}
-                        }
-                    },
-                    &PermissionKind::Read,
-                );
+            // The nodes send the value encoded, see get_resolve_message
+            let value = if client.is_cluster_node() {
+                match decode_replicated_value(&value) {
+                    Ok(value) => value,
+                    Err(msg) => return Response::Error { msg },
+                }
             } else {
-                apply_to_database(&dbs, &client, &|db| {
-                    if dbs.is_primary() {
-                        db.resolve_conflit(
-                            Change {
-                                key: key.clone(),
-                                value: value.clone(),
-                                version,
-                                opp_id,
-                                resolve_conflict: true,
-                            },
-                            &dbs,
-                        )
-                    } else {
-                        send_message_to_primary(
-                            get_resolve_message(
-                                opp_id,
-                                db_name.to_string(),
-                                key.clone(),
-                                value.clone(),
-                                version,
-                            ),
-                            dbs,
-                        );
-                        Response::Ok {}
-                    }
-                });
+                value
+            };
+            let resolve = |db: &Database| {
+                if dbs.is_primary() {
+                    db.resolve_conflit(
+                        Change {
+                            key: key.clone(),
+                            value: value.clone(),
+                            version,
+                            opp_id,
+                            resolve_conflict: true,
+                        },
+                        dbs,
+                    )
+                } else {
+                    send_message_to_primary(
+                        get_resolve_message(
+                            opp_id,
+                            db_name.to_string(),
+                            key.clone(),
+                            value.clone(),
+                            version,
+                        ),
+                        dbs,
+                    );
+                    Response::Ok {}
+                }
+            };
+            let resolve_in_db_name =
+                || apply_to_database_name(dbs, client, &db_name, &resolve, &PermissionKind::Read);
+            // Replica set or cluster admin resolving in any database, the others in the selected one
+            let response = if client.is_cluster_node() {
+                apply_if_cluster_node(client, &resolve_in_db_name)
+            } else if client.auth.load(Ordering::SeqCst) {
+                apply_if_role(client, AdminRole::ClusterAdmin, &resolve_in_db_name)
+            } else {
+                apply_to_database(dbs, client, &resolve)
             };
+            if let Response::Error { .. } = response {
+                return response;
+            }
             return Response::Ok {};
         }
-        Request::ListCommands {} => apply_if_auth(&client.auth, &|| {
+        Request::AuditLog { count } => apply_if_role(client, AdminRole::ClusterAdmin, &|| {
+            let entries: Vec<String> = dbs
+                .audit_log
+                .recent(count)
+                .iter()
+                .map(|entry| serde_json::to_string(entry).unwrap())
+                .collect();
+            let message = format!("audit-log {}\n{}\n", entries.len(), entries.join("\n"));
+            if let Err(e) = client.try_send_direct_message(message) {
+                log::warn!("Request::AuditLog sender.send Error: {}", e);
+            }
+            Response::Ok {}
+        }),
+        Request::ListCommands {} => apply_if_role(client, AdminRole::ReadOnlyMonitor, &|| {
             let commands = Request::command_list();
             let commands = commands.iter().fold(String::from(""), |current, acc| {
                 format!("{},{}", current, acc)
             });
-            match client
-                .sender
-                .clone()
-                .try_send(format_args!("commands-list {}\n", commands).to_string())
+            if let Err(e) = client.try_send_direct_message(format!("commands-list {}\n", commands))
             {
-                Err(e) => log::warn!("Request::commands-list sender.send Error: {}", e),
-                _ => (),
-            };
+                log::warn!("Request::commands-list sender.send Error: {}", e);
+            }
             Response::Ok {}
         }),
-        Request::SetPermissions { user, permissions } => apply_if_safe_access(
+        Request::SetPermissions { user, permissions } => apply_if_user_admin_access(
             &dbs,
             &client,
             &String::from(PERMISSION_KEYS_PREFIX),
@@ -733,22 +960,155 @@ fn process_request_obj(request: &Request, dbs: &Arc<Databases>, client: &mut Cli
             },
             PermissionKind::Write,
         ),
+        Request::SetFraming { framing } => {
+            client.set_framing(framing);
+            Response::Ok {}
+        }
+        Request::Ping { message } => {
+            match message {
+                Some(message) => client.send_direct_message(&format!("pong {}\n", message)),
+                None => client.send_direct_message("pong\n"),
+            }
+            Response::Ok {}
+        }
+        Request::Hello {
+            protocol_version,
+            capabilities,
+        } => {
+            *client.protocol_version.write().unwrap() = Some(protocol_version);
+            client.send_direct_message(&server_info(dbs, &capabilities));
+            match check_peer_protocol(client) {
+                Ok(()) => Response::Ok {},
+                Err(msg) => Response::Error { msg },
+            }
+        }
+        Request::SetRateLimits { limits } => apply_if_safe_access(
+            dbs,
+            client,
+            &String::from(RATE_LIMITS_KEY),
+            &|_db| {
+                let value = limits.to_str_value();
+                let respose =
+                    set_key_value(RATE_LIMITS_KEY.to_string(), value.to_string(), -1, _db, dbs);
+                match respose {
+                    Response::Set { .. } => {
+                        if !dbs.is_primary() {
+                            send_message_to_primary(
+                                get_replicate_message(
+                                    _db.name.to_string(),
+                                    RATE_LIMITS_KEY.to_string(),
+                                    value.to_string(),
+                                    -1,
+                                ),
+                                dbs,
+                            );
+                        }
+                        Response::Ok {}
+                    }
+                    _ => respose,
+                }
+            },
+            PermissionKind::Write,
+        ),
+    }
+}
+
+fn rotate_token(
+    key: String,
+    token: &str,
+    db: &Database,
+    dbs: &Arc<Databases>,
+    client: &Client,
+) -> Response {
+    let hashed_token = hash_token(token);
+    match set_key_value(key.to_string(), hashed_token.to_string(), -1, db, dbs) {
+        Response::Set { .. } => {
+            if !dbs.is_primary() {
+                send_message_to_primary(
+                    get_replicate_message(db.name.to_string(), key.to_string(), hashed_token, -1),
+                    dbs,
+                );
+            }
+            revoke_sessions_for_token_key(&key, db, dbs, Some(client));
+            Response::Ok {}
+        }
+        respose => respose,
     }
 }
 
 pub fn process_request(input: &str, dbs: &Arc<Databases>, client: &mut Client) -> Response {
-    let input_to_log = clean_string_to_log(input, &dbs);
+    process_command(input.trim_matches('\n'), dbs, client, Request::parse)
+}
+
+/// Processes a command decoded from a json frame (see network::framing_ops), nothing is trimmed
+pub fn process_framed_request(input: &str, dbs: &Arc<Databases>, client: &mut Client) -> Response {
+    process_command(input, dbs, client, Request::parse_exact)
+}
+
+fn process_command(
+    input: &str,
+    dbs: &Arc<Databases>,
+    client: &mut Client,
+    parse: fn(&str) -> Result<Request, String>,
+) -> Response {
+    let (request_id, input) = match Request::split_request_id(input) {
+        Ok(split) => split,
+        Err(msg) => {
+            *client.request_id.write().unwrap() = None;
+            return Response::Error { msg };
+        }
+    };
+    *client.request_id.write().unwrap() = request_id.map(String::from);
+    let input_to_log = clean_string_to_log(input, dbs);
     log::debug!(
         "[{}] process_request got message '{}'. ",
         thread_id::get(),
         input_to_log
     );
-    let db_name_state = client.selected_db_name();
-    let start = Instant::now();
-    let request = match Request::parse(String::from(input).trim_matches('\n')) {
+    let request = match parse(input) {
         Ok(req) => req,
         Err(e) => return Response::Error { msg: e },
     };
+    run_request(request, &input_to_log, dbs, client)
+}
+
+/// Sends the `ok` or `error` line of a command sent by a client connection
+pub fn send_response(client: &Client, response: &Response) {
+    let message = match response {
+        Response::Error { msg } | Response::VersionError { msg, .. } => {
+            log::debug!("Error: {}", msg);
+            format!("error {} \n", msg)
+        }
+        _ => String::from("ok \n"),
+    };
+    client.send_direct_message(&message);
+}
+
+/// Processes a request built by a protocol other than the text one, e.g. network::resp_ops
+pub fn process_parsed_request(
+    request: Request,
+    input_to_log: &str,
+    dbs: &Arc<Databases>,
+    client: &mut Client,
+) -> Response {
+    run_request(request, input_to_log, dbs, client)
+}
+
+fn run_request(
+    request: Request,
+    input_to_log: &str,
+    dbs: &Arc<Databases>,
+    client: &mut Client,
+) -> Response {
+    let db_name_state = client.selected_db_name();
+    let start = Instant::now();
+
+    // unwatch-all is sent by the server itself to clean up closed connections
+    if request != (Request::UnWatchAll {}) {
+        if let Err(msg) = dbs.check_rate_limits(client) {
+            return Response::Error { msg };
+        }
+    }
 
     log::debug!(
         "[{}] process_request parsed message '{}'. {}",
@@ -758,6 +1118,7 @@ pub fn process_request(input: &str, dbs: &Arc<Databases>, client: &mut Client) -
     );
 
     let result = process_request_obj(&request, &dbs, client);
+    audit_request(dbs, client, &request, &db_name_state, &result);
 
     let elapsed = start.elapsed();
     log::info!(
@@ -780,8 +1141,11 @@ pub fn process_request(input: &str, dbs: &Arc<Databases>, client: &mut Client) -
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::consensus_ops::CONFLICTS_KEY;
+    use crate::network::framing_ops::Framing;
     use futures::channel::mpsc::{channel, Receiver, Sender};
     use std::collections::HashMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
 
     pub fn create_default_args() -> (Receiver<String>, Arc<Databases>, Client) {
         let (sender1, _receiver): (Sender<String>, Receiver<String>) = channel(100);
@@ -814,6 +1178,17 @@ mod tests {
         };
     }
 
+    fn assert_received_token(receiver: &mut Receiver<String>, expected_token: &str) {
+        match receiver.try_next() {
+            Ok(Some(message)) => {
+                let stored = message.trim_end().strip_prefix("value ").unwrap();
+                assert!(is_hashed_token(stored), "Token stored in plain text");
+                assert!(verify_token(expected_token, stored));
+            }
+            _ => assert!(false, "Receiver doesnt have any message"),
+        };
+    }
+
     fn assert_valid_request(request: Response) {
         assert_eq!(Response::Ok {}, request);
     }
@@ -841,29 +1216,163 @@ mod tests {
     }
 
     #[test]
-    fn should_return_only_not_deleted_keys() {
-        let (mut _receiver, dbs, mut _client) = create_test_db();
-        // New client connected without admin auth
-        let (mut receiver, _, mut client) = create_default_args();
-        process_request("use-db test test-1", &dbs, &mut client);
-        process_request("set name jose", &dbs, &mut client);
-        process_request("set name1 jose", &dbs, &mut client);
-        process_request("keys", &dbs, &mut client);
-        assert_received(&mut receiver, "keys ,$connections,name,name1\n");
-        process_request("remove name1 jose", &dbs, &mut client);
-        process_request("keys", &dbs, &mut client);
-        assert_received(&mut receiver, "keys ,$connections,name\n");
+    fn should_only_backup_with_admin_auth() {
+        let (mut receiver, dbs, mut client) = create_default_args();
+        let backup_file = format!("/tmp/dbs-test-{}/process-backup.bkp", thread_id::get());
+
+        let request = process_request(&format!("backup {}", backup_file), &dbs, &mut client);
+        assert_invalid_request(request);
+
+        process_request("auth user token", &dbs, &mut client);
+        assert_received(&mut receiver, "valid auth\n");
+        let request = process_request(&format!("backup {}", backup_file), &dbs, &mut client);
+        assert_valid_request(request);
+        match receiver.try_next() {
+            Ok(Some(message)) => assert!(message.starts_with("backup success 1 ")),
+            _ => assert!(false, "Receiver doesnt have any message"),
+        };
     }
 
     #[test]
-    fn should_remove_key_via_replication_keys() {
-        let (mut _receiver, dbs, mut admin_client) = create_test_db();
-        let (mut receiver, _, mut client) = create_default_args();
+    fn should_check_the_admin_roles() {
+        let (mut receiver, dbs, mut client) = create_default_args();
+        process_request("auth user token", &dbs, &mut client);
+        assert_received(&mut receiver, "valid auth\n");
+        assert_valid_request(process_request(
+            "create-admin creator creator-token db-creator",
+            &dbs,
+            &mut client,
+        ));
+        assert_valid_request(process_request(
+            "create-admin monitor monitor-token read-only-monitor",
+            &dbs,
+            &mut client,
+        ));
+
+        let (mut client, mut receiver) = Client::new_empty_and_receiver();
+        process_request("auth creator wrong-token", &dbs, &mut client);
+        assert_received(&mut receiver, "invalid auth\n");
+        process_request("auth creator creator-token", &dbs, &mut client);
+        assert_received(&mut receiver, "valid auth\n");
+        assert_valid_request(process_request("create-db test test-1", &dbs, &mut client));
+        assert_eq!(
+            process_request("snapshot false test", &dbs, &mut client),
+            Response::Error {
+                msg: String::from("Role backup-operator required")
+            }
+        );
+        assert_invalid_request(process_request(
+            "create-admin other other-token cluster-admin",
+            &dbs,
+            &mut client,
+        ));
+
+        let (mut client, mut receiver) = Client::new_empty_and_receiver();
+        process_request("auth monitor monitor-token", &dbs, &mut client);
+        assert_received(&mut receiver, "valid auth\n");
+        assert!(matches!(
+            process_request("cluster-state", &dbs, &mut client),
+            Response::Value { .. }
+        ));
+        assert_invalid_request(process_request("create-db test2 test-2", &dbs, &mut client));
+        // Resolving a conflict writes to the database
+        assert_eq!(
+            process_request("resolve 1 test name 2 jose", &dbs, &mut client),
+            Response::Error {
+                msg: String::from("Role cluster-admin required")
+            }
+        );
+    }
+
+    #[test]
+    fn should_decode_the_values_resolved_by_the_nodes() {
+        let (mut receiver, dbs, mut client) = create_test_db();
+        dbs.set_cluster_secret("cluster-secret");
+        let (mut node, _node_receiver) = Client::new_empty_and_receiver();
+        process_request("cluster-auth cluster-secret", &dbs, &mut node);
+        process_request("hello 3", &dbs, &mut node);
+        let message = get_resolve_message(
+            1,
+            String::from("test"),
+            String::from("name"),
+            String::from("a;b"),
+            2,
+        );
+        assert_valid_request(process_request(&message, &dbs, &mut node));
+
+        while receiver.try_recv().is_ok() {}
+        process_request("get name", &dbs, &mut client);
+        assert_received(&mut receiver, "value a;b\n");
+    }
+
+    #[test]
+    fn should_not_create_an_admin_with_the_cluster_admin_name() {
+        let (mut receiver, dbs, mut client) = create_default_args();
+        process_request("auth user token", &dbs, &mut client);
+        assert_received(&mut receiver, "valid auth\n");
+        assert_invalid_request(process_request(
+            "create-admin user other-token read-only-monitor",
+            &dbs,
+            &mut client,
+        ));
+        process_request("auth user token", &dbs, &mut client);
+        assert_received(&mut receiver, "valid auth\n");
+    }
+
+    #[test]
+    fn should_export_and_import_a_database() {
+        let (mut receiver, dbs, mut client) = create_test_db();
+        process_request("set name jose", &dbs, &mut client);
+        process_request("export test", &dbs, &mut client);
+        assert_received(
+            &mut receiver,
+            "export test 1\n{\"db\":\"test\",\"consensus_strategy\":\"none\",\"keys\":1}\n{\"key\":\"name\",\"value\":\"jose\",\"version\":0}\n",
+        );
+        process_request("create-db test2 test-2", &dbs, &mut client);
+        assert_received(&mut receiver, "create-db success\n");
+        let request = process_request(
+            "import test2 [{\"key\":\"name\",\"value\":\"jose\",\"version\":3}]",
+            &dbs,
+            &mut client,
+        );
+        assert_valid_request(request);
+        assert_received(&mut receiver, "import success 1 0 0\n");
+        assert_valid_request(process_request(
+            "import test2 [{\"key\":\"$$token\",\"value\":\"new-token\",\"version\":9}]",
+            &dbs,
+            &mut client,
+        ));
+        assert_received(&mut receiver, "import success 0 0 1\n");
+        process_request("use-db test2 test-2", &dbs, &mut client);
+        process_request("get-safe name", &dbs, &mut client);
+        assert_received(&mut receiver, "value-version 3 jose\n");
+    }
+
+    #[test]
+    fn should_return_only_not_deleted_keys() {
+        let (mut _receiver, dbs, mut _client) = create_test_db();
+        // New client connected without admin auth
+        let (mut receiver, _, mut client) = create_default_args();
         process_request("use-db test test-1", &dbs, &mut client);
         process_request("set name jose", &dbs, &mut client);
         process_request("set name1 jose", &dbs, &mut client);
         process_request("keys", &dbs, &mut client);
         assert_received(&mut receiver, "keys ,$connections,name,name1\n");
+        process_request("remove name1 jose", &dbs, &mut client);
+        process_request("keys", &dbs, &mut client);
+        assert_received(&mut receiver, "keys ,$connections,name\n");
+    }
+
+    #[test]
+    fn should_remove_key_via_replication_keys() {
+        let (mut _receiver, dbs, mut admin_client) = create_test_db();
+        let (mut receiver, _, mut client) = create_default_args();
+        process_request("use-db test test-1", &dbs, &mut client);
+        process_request("set name jose", &dbs, &mut client);
+        process_request("set name1 jose", &dbs, &mut client);
+        process_request("keys", &dbs, &mut client);
+        assert_received(&mut receiver, "keys ,$connections,name,name1\n");
+        admin_client.auth_as_local_node();
         process_request("replicate-remove test name1", &dbs, &mut admin_client);
         process_request("keys", &dbs, &mut client);
         assert_received(&mut receiver, "keys ,$connections,name\n");
@@ -927,7 +1436,7 @@ mod tests {
     fn should_not_allow_to_remove_token_key() {
         let (mut receiver, dbs, mut client) = create_test_db();
         process_request("get $$token", &dbs, &mut client);
-        assert_received(&mut receiver, "value test-1\n");
+        assert_received_token(&mut receiver, "test-1");
         let r = process_request("remove $$token", &dbs, &mut client);
         if let Response::Error { msg: e } = r {
             assert_eq!(e, "$$token key cannot be removed");
@@ -941,7 +1450,7 @@ mod tests {
         let (mut receiver, dbs, mut client) = create_test_db();
         process_request("get $$token", &dbs, &mut client);
         process_request("set $$jose 1", &dbs, &mut client);
-        assert_received(&mut receiver, "value test-1\n");
+        assert_received_token(&mut receiver, "test-1");
         client.auth.store(false, Ordering::Relaxed); // Unauth
         let r = process_request("remove $$jose", &dbs, &mut client);
         if let Response::Error { msg: e } = r {
@@ -955,7 +1464,7 @@ mod tests {
     fn should_not_allow_non_admins_to_write_secure_keys() {
         let (mut receiver, dbs, mut client) = create_test_db();
         process_request("get $$token", &dbs, &mut client);
-        assert_received(&mut receiver, "value test-1\n");
+        assert_received_token(&mut receiver, "test-1");
         client.auth.store(false, Ordering::Relaxed); // Unauth
         let r = process_request("set $$token", &dbs, &mut client);
         if let Response::Error { msg: e } = r {
@@ -965,6 +1474,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_not_allow_db_creators_to_touch_secure_keys() {
+        let (mut receiver, dbs, mut client) = create_default_args();
+        process_request("auth user token", &dbs, &mut client);
+        assert_received(&mut receiver, "valid auth\n");
+        assert_valid_request(process_request(
+            "create-admin creator creator-token db-creator",
+            &dbs,
+            &mut client,
+        ));
+
+        let (mut client, mut receiver) = Client::new_empty_and_receiver();
+        process_request("auth creator creator-token", &dbs, &mut client);
+        assert_received(&mut receiver, "valid auth\n");
+        assert_valid_request(process_request("create-db test test-1", &dbs, &mut client));
+        process_request("use-db test test-1", &dbs, &mut client);
+        for command in ["get $$token", "set $$token other", "remove $$token"] {
+            assert_eq!(
+                process_request(command, &dbs, &mut client),
+                Response::Error {
+                    msg: String::from("To read security keys you must auth as an admin!")
+                }
+            );
+        }
+        assert_valid_request(process_request(
+            "create-user jose jose-token",
+            &dbs,
+            &mut client,
+        ));
+        assert_valid_request(process_request(
+            "set-permissions jose rw *",
+            &dbs,
+            &mut client,
+        ));
+    }
+
     #[test]
     fn should_increment() {
         let (mut receiver, dbs, mut client) = create_test_db();
@@ -986,6 +1531,7 @@ mod tests {
     #[test]
     fn should_process_replicate_increment() {
         let (mut receiver, dbs, mut client) = create_test_db();
+        client.auth_as_local_node();
         process_request("replicate-increment test some", &dbs, &mut client);
         process_request("get some", &dbs, &mut client);
         assert_received(&mut receiver, "value 1\n");
@@ -1049,6 +1595,106 @@ mod tests {
         (receiver, dbs, client)
     }
 
+    #[test]
+    fn should_answer_hello_and_refuse_incompatible_nodes() {
+        let (mut receiver, dbs, mut client) = create_default_args();
+        assert_valid_request(process_request(
+            "hello 2 request-ids,compression",
+            &dbs,
+            &mut client,
+        ));
+        let info = receiver.try_recv().unwrap();
+        assert!(info.starts_with("server-info version="));
+        assert!(info.contains(" protocol=3 "));
+        assert!(info.contains(" min-protocol=1 min-node-protocol=3 "));
+        assert!(info.contains(" role=Primary cluster=nun-db capabilities=request-ids\n"));
+        assert_invalid_request(process_request("hello", &dbs, &mut client));
+
+        dbs.set_cluster_secret("cluster-secret");
+        let (mut node_client, mut node_receiver) = Client::new_empty_and_receiver();
+        process_request("cluster-auth cluster-secret", &dbs, &mut node_client);
+        assert_received(&mut node_receiver, "valid auth\n");
+        assert_invalid_request(process_request("hello 0", &dbs, &mut node_client));
+        assert_invalid_request(process_request(
+            "join 127.0.0.1:3017",
+            &dbs,
+            &mut node_client,
+        ));
+        process_request("hello 3", &dbs, &mut node_client);
+        assert_valid_request(process_request(
+            "replicate-leave 127.0.0.1:3017",
+            &dbs,
+            &mut node_client,
+        ));
+    }
+
+    #[test]
+    fn should_answer_ping_and_track_watching_connections() {
+        let (mut receiver, dbs, mut client) = create_test_db();
+        assert_valid_request(process_request("ping", &dbs, &mut client));
+        assert_received(&mut receiver, "pong\n");
+        assert_valid_request(process_request("ping are you there", &dbs, &mut client));
+        assert_received(&mut receiver, "pong are you there\n");
+
+        assert!(!client.is_watching());
+        assert_valid_request(process_request("watch name", &dbs, &mut client));
+        assert!(client.is_watching());
+        assert_valid_request(process_request("unwatch-all", &dbs, &mut client));
+        assert!(!client.is_watching());
+    }
+
+    #[test]
+    fn should_echo_the_request_id_on_direct_responses() {
+        let (mut receiver, dbs, mut client) = create_test_db();
+        let (mut watcher, mut watcher_receiver) = Client::new_empty_and_receiver();
+        process_request("use-db test test-1", &dbs, &mut watcher);
+        assert_valid_request(process_request("#w1 watch name", &dbs, &mut watcher));
+
+        assert_valid_request(process_request("#42 set name jose", &dbs, &mut client));
+        assert_received(&mut watcher_receiver, "changed name jose\n");
+        process_request("#43 get name", &dbs, &mut client);
+        assert_received(&mut receiver, "#43 value jose\n");
+        process_request("get name", &dbs, &mut client);
+        assert_received(&mut receiver, "value jose\n");
+
+        let response = process_request("#44 get-safe", &dbs, &mut client);
+        send_response(&client, &response);
+        assert!(receiver.try_recv().unwrap().starts_with("#44 error "));
+        assert_invalid_request(process_request("# get name", &dbs, &mut client));
+    }
+
+    #[test]
+    fn should_keep_the_client_sender_on_commands_with_request_ids() {
+        let (mut receiver, dbs, mut client) = create_test_db();
+        assert_valid_request(process_request("#1 arbiter", &dbs, &mut client));
+        let dbs_map = dbs.map.read().unwrap();
+        let watchers = dbs_map.get("test").unwrap().watchers.map.read().unwrap();
+        // Conflicts are sent to the arbiter without the request id of the `arbiter` command
+        let arbiter_sender = &watchers.get(CONFLICTS_KEY).unwrap()[0];
+        arbiter_sender
+            .clone()
+            .try_send(String::from("resolve 1 test name 1 a b\n"))
+            .unwrap();
+        assert_received(&mut receiver, "resolve 1 test name 1 a b\n");
+    }
+
+    #[test]
+    fn should_keep_separators_in_json_framed_values() {
+        let (_, dbs, mut client) = create_test_db();
+        assert_valid_request(process_request("framing json", &dbs, &mut client));
+        assert_eq!(client.framing(), Framing::Json);
+        assert_valid_request(process_framed_request(
+            "set name jose; maria\nda silva",
+            &dbs,
+            &mut client,
+        ));
+        match process_framed_request("get name", &dbs, &mut client) {
+            Response::Value { value, .. } => assert_eq!(value, "jose; maria\nda silva"),
+            _ => assert!(false, "get should return the value"),
+        };
+        assert_invalid_request(process_request("framing binary", &dbs, &mut client));
+    }
+
     #[test]
     fn should_not_allow_non_admins_to_read_secure_keys() {
         let (mut receiver, dbs, mut client) = create_default_args();
@@ -1059,7 +1705,7 @@ mod tests {
         assert_received(&mut receiver, "create-db success\n");
         process_request("use-db test test-1", &dbs, &mut client);
         process_request("get $$token", &dbs, &mut client);
-        assert_received(&mut receiver, "value test-1\n");
+        assert_received_token(&mut receiver, "test-1");
         client.auth.store(false, Ordering::Relaxed); // Unauth
         let r = process_request("get $$token", &dbs, &mut client);
         if let Response::Error { msg: e } = r {
@@ -1072,7 +1718,7 @@ mod tests {
     #[test]
     fn should_create_db() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1088,7 +1734,7 @@ mod tests {
     #[test]
     fn should_handle_mult_statemente_permission() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1121,7 +1767,10 @@ mod tests {
         process_request("increment some", &dbs, &mut client);
         assert_received(&mut receiver, "permission denied\n");
 
-        assert_valid_request(process_request("increment incjose", &dbs, &mut client));
+        assert!(matches!(
+            process_request("increment incjose", &dbs, &mut client),
+            Response::Value { value, .. } if value == "1"
+        ));
         process_request("get incjose", &dbs, &mut client);
         assert_received(&mut receiver, "permission denied\n");
 
@@ -1131,10 +1780,396 @@ mod tests {
         assert_received(&mut receiver, "permission denied\n");
     }
 
+    #[test]
+    fn should_list_and_remove_users() {
+        let (mut receiver, dbs, mut client) = create_test_db();
+        assert_valid_request(process_request(
+            "create-user my-user my-token",
+            &dbs,
+            &mut client,
+        ));
+        assert_valid_request(process_request(
+            "create-user other-user other-token",
+            &dbs,
+            &mut client,
+        ));
+        process_request("list-users", &dbs, &mut client);
+        assert_received(&mut receiver, "users my-user,other-user\n");
+
+        process_request("set-permissions my-user r name", &dbs, &mut client);
+        let (mut user_client, mut user_receiver) = Client::new_empty_and_receiver();
+        assert_valid_request(process_request(
+            "use test my-user my-token",
+            &dbs,
+            &mut user_client,
+        ));
+        assert_valid_request(process_request("watch name", &dbs, &mut user_client));
+        assert_valid_request(process_request("remove-user my-user", &dbs, &mut client));
+        assert_received(&mut user_receiver, "error session-revoked\n");
+        assert_eq!(user_client.selected_db_name(), None);
+        // The watches go with the revoked session
+        assert!(!user_client.is_watching());
+        process_request("set name maria", &dbs, &mut client);
+        assert!(user_receiver.try_recv().is_err());
+        assert_invalid_request(process_request(
+            "use test my-user my-token",
+            &dbs,
+            &mut user_client,
+        ));
+
+        process_request("list-users", &dbs, &mut client);
+        assert_received(&mut receiver, "users other-user\n");
+        assert_invalid_request(process_request("remove-user my-user", &dbs, &mut client));
+    }
+
+    #[test]
+    fn should_rotate_the_user_and_db_tokens() {
+        let (_receiver, dbs, mut client) = create_test_db();
+        assert_valid_request(process_request(
+            "create-user my-user my-token",
+            &dbs,
+            &mut client,
+        ));
+        let (mut user_client, mut user_receiver) = Client::new_empty_and_receiver();
+        process_request("use test my-user my-token", &dbs, &mut user_client);
+        let (mut db_client, mut db_receiver) = Client::new_empty_and_receiver();
+        process_request("use test test-1", &dbs, &mut db_client);
+
+        assert_valid_request(process_request(
+            "rotate-user-token my-user new-token",
+            &dbs,
+            &mut client,
+        ));
+        assert_received(&mut user_receiver, "error session-revoked\n");
+        assert_eq!(db_client.selected_db_name(), Some(String::from("test")));
+        assert_invalid_request(process_request(
+            "use test my-user my-token",
+            &dbs,
+            &mut user_client,
+        ));
+        assert_valid_request(process_request(
+            "use test my-user new-token",
+            &dbs,
+            &mut user_client,
+        ));
+
+        assert_valid_request(process_request("watch name", &dbs, &mut db_client));
+        assert_valid_request(process_request("rotate-db-token test-2", &dbs, &mut client));
+        assert_received(&mut db_receiver, "error session-revoked\n");
+        assert_eq!(db_client.selected_db_name(), None);
+        assert!(!db_client.is_watching());
+        process_request("set name maria", &dbs, &mut client);
+        assert!(db_receiver.try_recv().is_err());
+        assert_eq!(user_client.selected_db_name(), Some(String::from("test")));
+        // The client that rotated the token keeps the database selected
+        assert_eq!(client.selected_db_name(), Some(String::from("test")));
+        assert_invalid_request(process_request("use test test-1", &dbs, &mut db_client));
+        assert_valid_request(process_request("use test test-2", &dbs, &mut db_client));
+
+        client.auth.store(false, Ordering::Relaxed);
+        assert_invalid_request(process_request("rotate-db-token test-3", &dbs, &mut client));
+        assert_invalid_request(process_request("list-users", &dbs, &mut client));
+    }
+
+    #[test]
+    fn should_apply_deny_rules_and_user_placeholders() {
+        let (_receiver, dbs, mut client) = create_test_db();
+        assert_valid_request(process_request(
+            "create-user my-user my-token",
+            &dbs,
+            &mut client,
+        ));
+        process_request(
+            "set-permissions my-user r room:*|w users:{user}:*|!r room:*:private",
+            &dbs,
+            &mut client,
+        );
+        process_request("set room:1:public hi", &dbs, &mut client);
+        process_request("set room:1:private secret", &dbs, &mut client);
+
+        let (mut user_client, mut receiver) = Client::new_empty_and_receiver();
+        process_request("use test my-user my-token", &dbs, &mut user_client);
+        process_request("get room:1:public", &dbs, &mut user_client);
+        assert_received(&mut receiver, "value hi\n");
+        process_request("get room:1:private", &dbs, &mut user_client);
+        assert_received(&mut receiver, "permission denied\n");
+
+        assert_valid_request(process_request(
+            "set users:my-user:name jose",
+            &dbs,
+            &mut user_client,
+        ));
+        process_request("set users:other-user:name jose", &dbs, &mut user_client);
+        assert_received(&mut receiver, "permission denied\n");
+    }
+
+    #[test]
+    fn should_apply_the_default_permissions_to_users_without_their_own() {
+        let (_receiver, dbs, mut client) = create_test_db();
+        for user in ["maria", "jose"] {
+            assert_valid_request(process_request(
+                &format!("create-user {} {}-token", user, user),
+                &dbs,
+                &mut client,
+            ));
+        }
+        assert_valid_request(process_request(
+            "set-permissions * w users:{user}:*",
+            &dbs,
+            &mut client,
+        ));
+        process_request("set-permissions jose r room:*", &dbs, &mut client);
+        assert_invalid_request(process_request("create-user * token", &dbs, &mut client));
+
+        let (mut maria, mut maria_receiver) = Client::new_empty_and_receiver();
+        process_request("use test maria maria-token", &dbs, &mut maria);
+        assert_valid_request(process_request(
+            "set users:maria:name maria",
+            &dbs,
+            &mut maria,
+        ));
+        process_request("set users:jose:name maria", &dbs, &mut maria);
+        assert_received(&mut maria_receiver, "permission denied\n");
+
+        // Users with their own permissions don't get the default ones
+        let (mut jose, mut jose_receiver) = Client::new_empty_and_receiver();
+        process_request("use test jose jose-token", &dbs, &mut jose);
+        process_request("set users:jose:name jose", &dbs, &mut jose);
+        assert_received(&mut jose_receiver, "permission denied\n");
+
+        // Nor the clients using the database token
+        let (mut db_client, _db_receiver) = Client::new_empty_and_receiver();
+        process_request("use-db test test-1", &dbs, &mut db_client);
+        assert_valid_request(process_request("set room:1 hi", &dbs, &mut db_client));
+    }
+
+    #[test]
+    fn should_audit_security_operations() {
+        let (mut receiver, dbs, mut client) = create_test_db();
+        let (mut other_client, mut other_receiver) = Client::new_empty_and_receiver();
+        process_request("auth user wrong-pwd", &dbs, &mut other_client);
+        assert_received(&mut other_receiver, "invalid auth\n");
+        // The admin password typed as user name must not be in the audit trail
+        process_request("auth token token", &dbs, &mut other_client);
+        assert_received(&mut other_receiver, "invalid auth\n");
+        process_request("create-user maria my-token", &dbs, &mut client);
+        process_request("set-permissions maria r name", &dbs, &mut client);
+        process_request("use-db test maria my-token", &dbs, &mut other_client);
+        process_request("set name jose", &dbs, &mut other_client);
+        assert_received(&mut other_receiver, "permission denied\n");
+        assert_invalid_request(process_request("audit-log", &dbs, &mut other_client));
+
+        let entries: Vec<(String, String, String, String)> = dbs
+            .audit_log
+            .recent(100)
+            .into_iter()
+            .map(|e| (e.actor, e.action, e.target, e.result))
+            .collect();
+        let expected = vec![
+            ("user", "auth", "", "success"),
+            ("user", "create-db", "test", "success"),
+            ("user", "auth", "", "failure"),
+            ("****", "auth", "", "failure"),
+            ("user", "create-user", "test/maria", "success"),
+            ("user", "set-permissions", "test/maria r name", "success"),
+            ("test/maria", "permission-denied", "test/name w", "denied"),
+        ];
+        let expected: Vec<(String, String, String, String)> = expected
+            .into_iter()
+            .map(|(a, b, c, d)| (a.to_string(), b.to_string(), c.to_string(), d.to_string()))
+            .collect();
+        assert_eq!(entries, expected);
+
+        assert_valid_request(process_request("audit-log 2", &dbs, &mut client));
+        let message = receiver.try_next().unwrap().unwrap();
+        let mut lines = message.lines();
+        assert_eq!(lines.next(), Some("audit-log 2"));
+        let last: AuditEntry = serde_json::from_str(lines.nth(1).unwrap()).unwrap();
+        assert_eq!(last.action, "permission-denied");
+    }
+
+    #[test]
+    fn should_only_accept_node_commands_from_cluster_auth_clients() {
+        let (mut receiver, dbs, mut client) = create_test_db();
+        assert_eq!(
+            process_request("replicate test name -1 \"jose\"", &dbs, &mut client),
+            Response::Error {
+                msg: "Cluster auth required".to_string()
+            }
+        );
+        assert_invalid_request(process_request("set-primary node-1", &dbs, &mut client));
+        assert_invalid_request(process_request(
+            "election candidate 1 node-1",
+            &dbs,
+            &mut client,
+        ));
+        assert_invalid_request(process_request(
+            "rp 1 replicate test name -1 \"jose\"",
+            &dbs,
+            &mut client,
+        ));
+
+        let (mut node_client, mut node_receiver) = Client::new_empty_and_receiver();
+        process_request("cluster-auth wrong-secret", &dbs, &mut node_client);
+        assert_received(&mut node_receiver, "invalid auth\n");
+        assert!(!node_client.is_cluster_node());
+
+        dbs.set_cluster_secret("cluster-secret");
+        // The admin password is only the secret while the cluster secret is not set
+        process_request(&format!("cluster-auth {}", dbs.pwd), &dbs, &mut node_client);
+        assert_received(&mut node_receiver, "invalid auth\n");
+        process_request("cluster-auth cluster-secret", &dbs, &mut node_client);
+        assert_received(&mut node_receiver, "valid auth\n");
+        // Nodes that never sent hello replicate values without encoding them
+        assert_invalid_request(process_request(
+            "replicate test name -1 \"jose\"",
+            &dbs,
+            &mut node_client,
+        ));
+        process_request("hello 3", &dbs, &mut node_client);
+        assert_valid_request(process_request(
+            "replicate test name -1 \"jose\"",
+            &dbs,
+            &mut node_client,
+        ));
+        process_request("get name", &dbs, &mut client);
+        assert_received(&mut receiver, "value jose\n");
+
+        // Nodes get no admin role, only the replicated create-db
+        assert!(!node_client.is_admin_auth());
+        assert_invalid_request(process_request(
+            "create-admin other other-token cluster-admin",
+            &dbs,
+            &mut node_client,
+        ));
+        assert_invalid_request(process_request("debug pending-ops", &dbs, &mut node_client));
+        assert_valid_request(process_request(
+            "create-db replicated replicated-token",
+            &dbs,
+            &mut node_client,
+        ));
+        assert!(dbs.has_db("replicated"));
+    }
+
+    #[test]
+    fn should_filter_keys_and_watches_by_permissions() {
+        let (_receiver, dbs, mut client) = create_test_db();
+        assert_valid_request(process_request(
+            "create-user my-user my-token",
+            &dbs,
+            &mut client,
+        ));
+        process_request(
+            "set-permissions my-user r room:*|!r room:*:private|n feed",
+            &dbs,
+            &mut client,
+        );
+        process_request("set room:1:public hi", &dbs, &mut client);
+        process_request("set room:1:private secret", &dbs, &mut client);
+        process_request("set feed news", &dbs, &mut client);
+        process_request("set other value", &dbs, &mut client);
+
+        let (mut user_client, mut receiver) = Client::new_empty_and_receiver();
+        process_request("use test my-user my-token", &dbs, &mut user_client);
+        process_request("keys", &dbs, &mut user_client);
+        assert_received(&mut receiver, "keys ,room:1:public\n");
+
+        // Read rules also grant watch
+        assert_valid_request(process_request(
+            "watch room:1:public",
+            &dbs,
+            &mut user_client,
+        ));
+        assert_valid_request(process_request("watch feed", &dbs, &mut user_client));
+        process_request("watch room:1:private", &dbs, &mut user_client);
+        assert_received(&mut receiver, "permission denied\n");
+        process_request("watch other", &dbs, &mut user_client);
+        assert_received(&mut receiver, "permission denied\n");
+        // Watch alone does not allow reading the key
+        process_request("get feed", &dbs, &mut user_client);
+        assert_received(&mut receiver, "permission denied\n");
+        // Nor receiving its values in the notifications
+        process_request("set feed more-news", &dbs, &mut client);
+        assert_received(&mut receiver, "changed-key feed 1\n");
+        assert!(receiver.try_recv().is_err());
+        process_request("set room:1:public hello", &dbs, &mut client);
+        assert_received(&mut receiver, "changed room:1:public hello\n");
+        assert_received(&mut receiver, "changed-version room:1:public 1 hello\n");
+        process_request("unwatch feed", &dbs, &mut user_client);
+        let dbs_map = dbs.map.read().unwrap();
+        let key_only = dbs_map
+            .get("test")
+            .unwrap()
+            .watchers
+            .key_only
+            .read()
+            .unwrap();
+        assert!(key_only.values().all(|senders| senders.is_empty()));
+    }
+
+    #[test]
+    fn should_use_db_with_a_signed_access_token() {
+        let (_receiver, dbs, mut client) = create_test_db();
+        process_request("set name jose", &dbs, &mut client);
+        let (mut token_client, mut token_receiver) = Client::new_empty_and_receiver();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let token = sign_access_token(
+            &AccessTokenClaims {
+                sub: String::from("browser"),
+                exp: now + 60,
+                permissions: Some(String::from("r name")),
+            },
+            "jwt-secret",
+        );
+        let use_command = format!("use-db test {}", token);
+        // No secret set for the database yet
+        assert_invalid_request(process_request(&use_command, &dbs, &mut token_client));
+
+        process_request("set $$jwt_secret jwt-secret", &dbs, &mut client);
+        assert_valid_request(process_request(&use_command, &dbs, &mut token_client));
+        assert_eq!(
+            token_client.selected_db_user_name(),
+            Some(String::from("browser"))
+        );
+        process_request("get name", &dbs, &mut token_client);
+        assert_received(&mut token_receiver, "value jose\n");
+        process_request("set name maria", &dbs, &mut token_client);
+        assert_received(&mut token_receiver, "permission denied\n");
+
+        let forged_token = sign_access_token(
+            &AccessTokenClaims {
+                sub: String::from("browser"),
+                exp: now + 60,
+                permissions: Some(String::from("rw name")),
+            },
+            "other-secret",
+        );
+        assert_invalid_request(process_request(
+            &format!("use-db test {}", forged_token),
+            &dbs,
+            &mut client,
+        ));
+
+        assert_valid_request(process_request("watch name", &dbs, &mut token_client));
+        token_client
+            .selected_db
+            .set_access_scope(None, Some(now - 1));
+        assert_invalid_request(process_request("get name", &dbs, &mut token_client));
+        assert_received(&mut token_receiver, "error token-expired\n");
+        assert_eq!(token_client.selected_db_name(), None);
+        // The watches are removed with the expired session
+        assert!(!token_client.is_watching());
+        process_request("set name maria", &dbs, &mut client);
+        assert!(token_receiver.try_recv().is_err());
+    }
+
     #[test]
     fn should_not_create_db_if_already_exist() {
         let (_, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
 
         // @todo start dbs args with db already created, instead of relying on
         // the correct function of create-db command
@@ -1150,7 +2185,7 @@ mod tests {
     #[test]
     fn should_list_commands_avaliable() {
         let (mut r, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         process_request("list-commands", &dbs, &mut client);
         let result = r.try_next().unwrap().unwrap();
         assert!(result.contains("create-db"));
@@ -1159,7 +2194,7 @@ mod tests {
     #[test]
     fn shoud_set_permission_to_user_correctly() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1201,7 +2236,7 @@ mod tests {
     #[test]
     fn shoud_set_allow_reading_by_default() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1220,9 +2255,9 @@ mod tests {
         process_request("get test-jose", &dbs, &mut client);
         assert_received(&mut receiver, "value jose\n");
 
-        // Admin again to test replicate set
-        client.auth.store(true, Ordering::Relaxed);
-        process_request("replicate my-db test-jose -1 maria", &dbs, &mut client);
+        // Node to test replicate set
+        client.auth_as_local_node();
+        process_request("replicate my-db test-jose -1 \"maria\"", &dbs, &mut client);
         process_request("get test-jose", &dbs, &mut client);
         assert_received(&mut receiver, "value maria\n");
     }
@@ -1230,7 +2265,7 @@ mod tests {
     #[test]
     fn should_deny_access_to_users_by_default() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1267,7 +2302,7 @@ mod tests {
     #[test]
     fn should_allow_access_to_db_token() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1297,7 +2332,7 @@ mod tests {
     #[test]
     fn snaptshot_should_fail_if_one_or_more_dbs_does_not_exists() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1354,7 +2389,7 @@ mod tests {
     #[test]
     fn should_snaptshot_many_dbs() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1397,7 +2432,7 @@ mod tests {
     #[test]
     fn should_snaptshot_the_current_db() {
         let (mut receiver, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1427,7 +2462,7 @@ mod tests {
     #[test]
     fn replicate_snapshot_should_return_an_erro_if_the_db_doesnot_exists() {
         let (_, dbs, mut client) = create_default_args();
-        client.auth.store(true, Ordering::Relaxed);
+        client.auth_as(vec![AdminRole::ClusterAdmin]);
         assert_valid_request(process_request(
             "create-db my-db my-token",
             &dbs,
@@ -1446,4 +2481,61 @@ mod tests {
             &mut client,
         ));
     }
+
+    #[test]
+    fn should_rate_limit_database_users() {
+        let (_, dbs, mut client) = create_test_db();
+        process_request("create-user maria my-token", &dbs, &mut client);
+        process_request("set-permissions maria rw *", &dbs, &mut client);
+        assert_valid_request(process_request("set-rate-limits 0 2 0", &dbs, &mut client));
+        assert_invalid_request(process_request("set-rate-limits 0 2", &dbs, &mut client));
+
+        let (mut user_client, _user_receiver) = Client::new_empty_and_receiver();
+        process_request("use-db test maria my-token", &dbs, &mut user_client);
+        assert_valid_request(process_request("set name jose", &dbs, &mut user_client));
+        assert_valid_request(process_request("set name maria", &dbs, &mut user_client));
+        let rate_limited = Response::Error {
+            msg: "rate-limited".to_string(),
+        };
+        assert_eq!(
+            process_request("set name ana", &dbs, &mut user_client),
+            rate_limited
+        );
+
+        // The limit is shared by all the connections of the user
+        let (mut other_client, _other_receiver) = Client::new_empty_and_receiver();
+        process_request("use-db test maria my-token", &dbs, &mut other_client);
+        assert_eq!(
+            process_request("set name ana", &dbs, &mut other_client),
+            rate_limited
+        );
+        assert!(dbs
+            .get_monitoring_state()
+            .contains("throttled_connection: 0, throttled_user: 2, throttled_ip: 0"));
+    }
+
+    #[test]
+    fn should_rate_limit_connections_and_source_ips() {
+        let (_, dbs, mut client) = create_test_db();
+        assert_valid_request(process_request("set-rate-limits 1 0 2", &dbs, &mut client));
+
+        let (mut first_client, _first_receiver) = Client::new_empty_and_receiver();
+        first_client.set_remote_address(Some(String::from("10.0.0.1")));
+        process_request("use-db test test-1", &dbs, &mut first_client);
+        assert_valid_request(process_request("set name jose", &dbs, &mut first_client));
+        assert_invalid_request(process_request("set name ana", &dbs, &mut first_client));
+
+        let (mut second_client, _second_receiver) = Client::new_empty_and_receiver();
+        second_client.set_remote_address(Some(String::from("10.0.0.1")));
+        process_request("use-db test test-1", &dbs, &mut second_client);
+        assert_valid_request(process_request("set name jose", &dbs, &mut second_client));
+
+        let (mut third_client, _third_receiver) = Client::new_empty_and_receiver();
+        third_client.set_remote_address(Some(String::from("10.0.0.1")));
+        process_request("use-db test test-1", &dbs, &mut third_client);
+        assert_invalid_request(process_request("set name jose", &dbs, &mut third_client));
+        assert!(dbs
+            .get_monitoring_state()
+            .contains("throttled_connection: 1, throttled_user: 0, throttled_ip: 1"));
+    }
 }
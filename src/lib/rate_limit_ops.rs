@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::bo::*;
+use crate::configuration::{NUN_RATE_LIMIT_CONNECTION, NUN_RATE_LIMIT_IP, NUN_RATE_LIMIT_USER};
+
+pub const RATE_LIMITED_MESSAGE: &str = "rate-limited";
+pub const RATE_LIMITS_KEY: &str = "$$rate_limits";
+
+// Idle buckets are dropped once the maps get this big
+const MAX_TRACKED_BUCKETS: usize = 10000;
+
+/// Requests per second allowed per connection, per database user and per source ip, 0 means unlimited
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    pub connection: u32,
+    pub user: u32,
+    pub ip: u32,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> RateLimitConfig {
+        RateLimitConfig {
+            connection: *NUN_RATE_LIMIT_CONNECTION,
+            user: *NUN_RATE_LIMIT_USER,
+            ip: *NUN_RATE_LIMIT_IP,
+        }
+    }
+
+    /// Parses `$connection $user $ip`
+    pub fn parse(value: &str) -> Result<RateLimitConfig, String> {
+        let limits: Vec<u32> = value
+            .split_whitespace()
+            .map(|limit| limit.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| format!("Invalid rate limits {}", value))?;
+        match limits.as_slice() {
+            [connection, user, ip] => Ok(RateLimitConfig {
+                connection: *connection,
+                user: *user,
+                ip: *ip,
+            }),
+            _ => Err(format!("Invalid rate limits {}", value)),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.connection == 0 && self.user == 0 && self.ip == 0
+    }
+
+    pub fn to_str_value(&self) -> String {
+        format!("{} {} {}", self.connection, self.user, self.ip)
+    }
+}
+
+/// Token bucket holding up to one second of requests
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Default for TokenBucket {
+    fn default() -> TokenBucket {
+        TokenBucket {
+            tokens: f64::MAX,
+            last_refill: Instant::now(),
+        }
+    }
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate: u32) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last_refill = now;
+    }
+
+    fn has_token(&mut self, rate: u32) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        self.refill(rate);
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self, rate: u32) {
+        if rate != 0 {
+            self.tokens -= 1.0;
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.last_refill.elapsed().as_secs() > 1
+    }
+}
+
+#[derive(Default)]
+pub struct RateLimits {
+    users: Mutex<HashMap<String, TokenBucket>>,
+    ips: Mutex<HashMap<String, TokenBucket>>,
+    throttled_connection: AtomicU64,
+    throttled_user: AtomicU64,
+    throttled_ip: AtomicU64,
+}
+
+impl RateLimits {
+    pub fn get_state(&self) -> String {
+        format!(
+            "throttled_connection: {}, throttled_user: {}, throttled_ip: {}",
+            self.throttled_connection.load(Ordering::Relaxed),
+            self.throttled_user.load(Ordering::Relaxed),
+            self.throttled_ip.load(Ordering::Relaxed),
+        )
+    }
+}
+
+fn bucket<'a>(buckets: &'a mut HashMap<String, TokenBucket>, key: &str) -> &'a mut TokenBucket {
+    if buckets.len() > MAX_TRACKED_BUCKETS {
+        buckets.retain(|_, bucket| !bucket.is_idle());
+    }
+    buckets.entry(key.to_string()).or_default()
+}
+
+impl Databases {
+    /// Limits of the database stored with set-rate-limits, the server defaults otherwise
+    pub fn rate_limit_config(&self, db_name: &Option<String>) -> RateLimitConfig {
+        db_name
+            .as_ref()
+            .and_then(|db_name| {
+                let dbs_map = self.map.read().unwrap();
+                dbs_map
+                    .get(db_name)
+                    .and_then(|db| db.get_value(RATE_LIMITS_KEY.to_string()))
+            })
+            .and_then(|value| RateLimitConfig::parse(&value.value).ok())
+            .unwrap_or_else(RateLimitConfig::from_env)
+    }
+
+    /// Takes one token of each bucket of the client, nothing is taken if any of them is empty
+    pub fn check_rate_limits(self: &Arc<Databases>, client: &Client) -> Result<(), String> {
+        if client.is_cluster_node() {
+            return Ok(());
+        }
+        let db_name = client.selected_db_name();
+        let config = self.rate_limit_config(&db_name);
+        if config.is_unlimited() {
+            return Ok(());
+        }
+        let db_prefix = db_name.unwrap_or_default();
+        let user_key = client
+            .selected_db_user_name()
+            .map(|user_name| format!("{}/{}", db_prefix, user_name));
+        let ip_key = client
+            .remote_address()
+            .map(|address| format!("{}/{}", db_prefix, address));
+
+        let rate_limits = &self.rate_limits;
+        let mut connection_bucket = client.rate_limit.lock().unwrap();
+        // The shared maps are only locked for the limits in use
+        let mut users = user_key
+            .filter(|_| config.user > 0)
+            .map(|key| (rate_limits.users.lock().unwrap(), key));
+        let mut ips = ip_key
+            .filter(|_| config.ip > 0)
+            .map(|key| (rate_limits.ips.lock().unwrap(), key));
+        let mut user_bucket = users.as_mut().map(|(users, key)| bucket(users, key));
+        let mut ip_bucket = ips.as_mut().map(|(ips, key)| bucket(ips, key));
+
+        let throttled = if !connection_bucket.has_token(config.connection) {
+            Some((&rate_limits.throttled_connection, "connection"))
+        } else if !user_bucket
+            .as_mut()
+            .is_none_or(|b| b.has_token(config.user))
+        {
+            Some((&rate_limits.throttled_user, "user"))
+        } else if !ip_bucket.as_mut().is_none_or(|b| b.has_token(config.ip)) {
+            Some((&rate_limits.throttled_ip, "ip"))
+        } else {
+            None
+        };
+
+        match throttled {
+            Some((counter, scope)) => {
+                counter.fetch_add(1, Ordering::Relaxed);
+                log::debug!("Request rate limited by the {} limit", scope);
+                Err(String::from(RATE_LIMITED_MESSAGE))
+            }
+            None => {
+                connection_bucket.take(config.connection);
+                if let Some(b) = user_bucket.as_mut() {
+                    b.take(config.user);
+                }
+                if let Some(b) = ip_bucket.as_mut() {
+                    b.take(config.ip);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_rate_limit_config() {
+        assert_eq!(
+            RateLimitConfig::parse("10 0 30"),
+            Ok(RateLimitConfig {
+                connection: 10,
+                user: 0,
+                ip: 30,
+            })
+        );
+        assert_eq!(
+            RateLimitConfig::parse("10 0 30").unwrap().to_str_value(),
+            "10 0 30"
+        );
+        assert!(!RateLimitConfig::parse("10 0 30").unwrap().is_unlimited());
+        assert!(RateLimitConfig::parse("0 0 0").unwrap().is_unlimited());
+        assert!(RateLimitConfig::parse("10 0").is_err());
+        assert!(RateLimitConfig::parse("10 a 30").is_err());
+    }
+
+    #[test]
+    fn token_bucket_should_allow_a_burst_of_one_second() {
+        let mut bucket = TokenBucket::default();
+        for _ in 0..5 {
+            assert!(bucket.has_token(5));
+            bucket.take(5);
+        }
+        assert!(!bucket.has_token(5));
+        assert!(bucket.has_token(0));
+    }
+}
@@ -5,13 +5,20 @@ use std::collections::HashMap;
 use std::sync::Mutex;
 
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+// How long a join waits for the server-info of the peer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+use crate::rate_limit_ops::RATE_LIMITS_KEY;
+use crate::security::hash_token;
 use crate::security::permissions_key_from_user_name;
+use crate::security::role_key_from_user_name;
 use crate::security::user_name_key_from_user_name;
+use crate::security::{ROLE_KEYS_PREFIX, USER_NAME_KEYS_PREFIX};
 use async_std::net::TcpStream;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufRead, Read, Write};
 use std::thread;
 
 use futures::channel::mpsc::{channel, Receiver, Sender};
@@ -30,6 +37,83 @@ use std::sync::Arc;
 use crate::bo::*;
 use crate::db_ops::*;
 use crate::disk_ops::*;
+use crate::network::handshake_ops::{check_server_info, PROTOCOL_VERSION};
+use crate::network::tls_ops::{
+    async_replication_connector, domain_from_address, is_tls_enabled_for, replication_connector,
+    TLS_REPLICATION,
+};
+
+// Connections to the other nodes are either plain tcp or TLS streams
+trait ReplicationStream: futures::AsyncRead + futures::AsyncWrite + Unpin + Send {}
+
+impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin + Send> ReplicationStream for S {}
+
+fn to_io_error(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::other(e.to_string())
+}
+
+async fn connect_to_node(address: &str) -> Result<Box<dyn ReplicationStream>, std::io::Error> {
+    let stream = TcpStream::connect(address).await?;
+    if is_tls_enabled_for(TLS_REPLICATION) {
+        let tls_stream = async_replication_connector()
+            .map_err(to_io_error)?
+            .connect(domain_from_address(address), stream)
+            .await
+            .map_err(to_io_error)?;
+        Ok(Box::new(tls_stream))
+    } else {
+        Ok(Box::new(stream))
+    }
+}
+
+fn handshake_message(cluster_secret: &String) -> String {
+    format!(
+        "cluster-auth {}\nhello {}\n",
+        cluster_secret, PROTOCOL_VERSION
+    )
+}
+
+// The replies before the server-info are the ones of cluster-auth
+fn check_handshake_reply(line: &str) -> Option<Result<(), std::io::Error>> {
+    if line.starts_with("server-info ") {
+        Some(check_server_info(line).map_err(to_io_error))
+    } else if line.trim() == "invalid auth" {
+        Some(Err(to_io_error("invalid cluster secret")))
+    } else {
+        None
+    }
+}
+
+fn closed_before_server_info() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "connection closed before the server-info",
+    )
+}
+
+/// Joins only peers whose server-info is compatible with this node
+fn send_join_message<S: Read + Write>(
+    stream: &mut S,
+    cluster_secret: &String,
+    external_addr: &String,
+) -> std::io::Result<()> {
+    stream.write_all(handshake_message(cluster_secret).as_bytes())?;
+    stream.flush()?;
+    let mut reader = std::io::BufReader::new(&mut *stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(closed_before_server_info());
+        }
+        if let Some(result) = check_handshake_reply(&line) {
+            result?;
+            break;
+        }
+    }
+    stream.write_all(format!("join {}\n", external_addr).as_bytes())?;
+    stream.flush()
+}
 
 impl Databases {
     pub fn replicate_message(&self, message: String) -> Result<u64, String> {
@@ -216,7 +300,24 @@ pub fn get_replicate_remove_message(db_name: String, key: String) -> String {
 }
 
 pub fn get_replicate_message(db_name: String, key: String, value: String, version: i32) -> String {
-    return format!("replicate {} {} {} {}", db_name, key, version, value);
+    format!(
+        "replicate {} {} {} {}",
+        db_name,
+        key,
+        version,
+        encode_replicated_value(&value)
+    )
+}
+
+/// Values are sent as json strings, so line breaks and `;` never split a replication message
+pub fn encode_replicated_value(value: &str) -> String {
+    serde_json::to_string(value)
+        .unwrap()
+        .replace(';', "\\u003b")
+}
+
+pub fn decode_replicated_value(value: &str) -> Result<String, String> {
+    serde_json::from_str(value).map_err(|e| format!("Invalid replicated value, {}", e))
 }
 
 pub fn get_resolve_message(
@@ -226,10 +327,14 @@ pub fn get_resolve_message(
     value: String,
     version: i32,
 ) -> String {
-    return format!(
+    format!(
         "resolve {} {} {} {} {}",
-        opp_id, db_name, key, version, value
-    );
+        opp_id,
+        db_name,
+        key,
+        version,
+        encode_replicated_value(&value)
+    )
 }
 
 pub fn get_replicate_increment_message(db_name: String, key: String, inc: String) -> String {
@@ -414,7 +519,8 @@ pub fn replicate_request(
                         replication_sender,
                         get_replicate_remove_message(db_name.to_string(), key),
                     );
-                    Response::Ok {}
+                    // Keeps the new or removed value for the client
+                    response
                 }
 
                 Request::ReplicateRemove { db, key } => {
@@ -460,25 +566,82 @@ pub fn replicate_request(
                         replication_sender,
                         get_replicate_increment_message(db_name.to_string(), key, inc.to_string()),
                     );
-                    Response::Ok {}
+                    // Keeps the new or removed value for the client
+                    response
                 }
                 Request::CreateUser { token, user_name } => {
                     let db_name = db_name
                         .clone()
                         .expect("db_name should be set for create user replication");
                     let key = user_name_key_from_user_name(&user_name);
-                    let value = token.to_string();
-                    log::debug!(
-                        "Will replicate user creating set of the key {} to {} ",
-                        key,
-                        value
-                    );
+                    // Replicates the stored hash so all nodes have the same value
+                    let value =
+                        get_stored_value(dbs, &db_name, &key).unwrap_or_else(|| hash_token(&token));
+                    log::debug!("Will replicate user creating set of the key {}", key);
                     replicate_web(
                         replication_sender,
                         get_replicate_message(db_name.to_string(), key, value, -1),
                     );
                     Response::Ok {}
                 }
+                Request::RemoveUser { user_name } => {
+                    let db_name = db_name
+                        .clone()
+                        .expect("db_name should be set for remove user replication");
+                    for key in [
+                        user_name_key_from_user_name(&user_name),
+                        permissions_key_from_user_name(&user_name),
+                    ] {
+                        log::debug!("Will replicate the remove of the key {}", key);
+                        replicate_web(
+                            replication_sender,
+                            get_replicate_remove_message(db_name.to_string(), key),
+                        );
+                    }
+                    Response::Ok {}
+                }
+                Request::RotateUserToken { user_name, .. } => {
+                    let db_name = db_name
+                        .clone()
+                        .expect("db_name should be set for rotate user token replication");
+                    let key = user_name_key_from_user_name(&user_name);
+                    replicate_stored_value(replication_sender, dbs, &db_name, key);
+                    Response::Ok {}
+                }
+                Request::RotateDbToken { .. } => {
+                    let db_name = db_name
+                        .clone()
+                        .expect("db_name should be set for rotate db token replication");
+                    replicate_stored_value(
+                        replication_sender,
+                        dbs,
+                        &db_name,
+                        String::from(TOKEN_KEY),
+                    );
+                    Response::Ok {}
+                }
+                Request::CreateAdmin { user_name, .. } => {
+                    let keys = vec![
+                        user_name_key_from_user_name(&user_name),
+                        role_key_from_user_name(&user_name),
+                    ];
+                    let dbs_map = dbs.map.read().unwrap();
+                    let admin_db = dbs_map.get(ADMIN_DB).expect("Admin db must exist");
+                    for key in keys {
+                        if let Some(value) = admin_db.get_value(key.to_string()) {
+                            log::debug!("Will replicate admin account key {}", key);
+                            replicate_web(
+                                replication_sender,
+                                get_replicate_message(String::from(ADMIN_DB), key, value.value, -1),
+                            );
+                        }
+                    }
+                    replicate_web(
+                        replication_sender,
+                        format!("replicate-snapshot {} false", ADMIN_DB),
+                    );
+                    Response::Ok {}
+                }
                 Request::SetPermissions { user, permissions } => {
                     let db_name = db_name
                         .clone()
@@ -496,12 +659,54 @@ pub fn replicate_request(
                     );
                     Response::Ok {}
                 }
+                Request::SetRateLimits { limits } => {
+                    let db_name = db_name
+                        .clone()
+                        .expect("db_name should be set for set rate limits replication");
+                    replicate_web(
+                        replication_sender,
+                        get_replicate_message(
+                            db_name.to_string(),
+                            RATE_LIMITS_KEY.to_string(),
+                            limits.to_str_value(),
+                            -1,
+                        ),
+                    );
+                    Response::Ok {}
+                }
                 _ => response,
             }
         }
     }
 }
 
+fn get_stored_value(dbs: &Arc<Databases>, db_name: &str, key: &str) -> Option<String> {
+    dbs.map
+        .read()
+        .unwrap()
+        .get(db_name)
+        .and_then(|db| db.get_value(key.to_string()))
+        .map(|value| value.value)
+}
+
+fn replicate_stored_value(
+    replication_sender: &Sender<String>,
+    dbs: &Arc<Databases>,
+    db_name: &str,
+    key: String,
+) {
+    match get_stored_value(dbs, db_name, &key) {
+        Some(value) => {
+            log::debug!("Will replicate the stored value of the key {}", key);
+            replicate_web(
+                replication_sender,
+                get_replicate_message(db_name.to_string(), key, value, -1),
+            );
+        }
+        None => log::warn!("Key {} not found in {} to replicate", key, db_name),
+    }
+}
+
 /**
  * Will replicate the message if the sender is Some reference, if not will print and message
  *
@@ -613,6 +818,7 @@ pub async fn start_replication_thread(
     dbs: Arc<Databases>,
 ) {
     let mut op_log_stream = Oplog::get_log_file_append_mode();
+    let mut op_log_values_stream = Oplog::get_op_log_values_file_append_mode(&dbs);
     let mut invalidate_stream = get_invalidate_file_write_mode();
     // Loop replicating messages
     loop {
@@ -699,6 +905,9 @@ pub async fn start_replication_thread(
                             &ReplicateOpp::Update,
                             op_log_id_in,
                         )
+                        .and_then(|id| {
+                            Oplog::write_op_log_value(&mut op_log_values_stream, id, &request_str)
+                        })
                     }
 
                     Request::ReplicateIncrement { db, key, inc: _ } => {
@@ -711,6 +920,9 @@ pub async fn start_replication_thread(
                             &ReplicateOpp::Update,
                             op_log_id_in,
                         )
+                        .and_then(|id| {
+                            Oplog::write_op_log_value(&mut op_log_values_stream, id, &request_str)
+                        })
                     }
 
                     Request::ReplicateRemove { db, key } => {
@@ -723,6 +935,9 @@ pub async fn start_replication_thread(
                             &ReplicateOpp::Remove,
                             op_log_id_in,
                         )
+                        .and_then(|id| {
+                            Oplog::write_op_log_value(&mut op_log_values_stream, id, &request_str)
+                        })
                     }
 
                     // Even if not in op log we need to return a valid id so the message can be ack
@@ -823,8 +1038,7 @@ fn add_primary_to_secoundary(
     dbs: Arc<Databases>,
     receiver: Receiver<String>,
 ) -> std::thread::JoinHandle<()> {
-    let user = dbs.user.to_string();
-    let pwd = dbs.pwd.to_string();
+    let cluster_secret = dbs.cluster_secret();
     dbs.add_cluster_member(ClusterMember {
         name: name.clone(),
         role: ClusterRole::Primary,
@@ -832,7 +1046,14 @@ fn add_primary_to_secoundary(
     });
     let tcp_addr = tcp_addr.clone();
     let guard = thread::spawn(move || {
-        start_replication(name, receiver, user, pwd, tcp_addr.to_string(), false, &dbs);
+        start_replication(
+            name,
+            receiver,
+            cluster_secret,
+            tcp_addr.to_string(),
+            false,
+            &dbs,
+        );
     });
     guard
 }
@@ -844,8 +1065,7 @@ fn add_secondary_to_primary(
     dbs: Arc<Databases>,
     receiver: Receiver<String>,
 ) -> std::thread::JoinHandle<()> {
-    let user = dbs.user.to_string();
-    let pwd = dbs.pwd.to_string();
+    let cluster_secret = dbs.cluster_secret();
     replicate_join(sender.clone(), name.to_string());
     dbs.add_cluster_member(ClusterMember {
         name: name.clone(),
@@ -859,8 +1079,7 @@ fn add_secondary_to_primary(
         start_replication(
             name.clone(),
             receiver,
-            user,
-            pwd,
+            cluster_secret,
             tcp_addr.to_string(),
             true,
             &dbs,
@@ -882,8 +1101,7 @@ fn add_secondary_to_secoundary(
     dbs: Arc<Databases>,
     receiver: Receiver<String>,
 ) -> std::thread::JoinHandle<()> {
-    let user = dbs.user.to_string();
-    let pwd = dbs.pwd.to_string();
+    let cluster_secret = dbs.cluster_secret();
     dbs.add_cluster_member(ClusterMember {
         name: name.clone(),
         role: ClusterRole::Secoundary,
@@ -897,8 +1115,7 @@ fn add_secondary_to_secoundary(
         start_replication(
             name.clone(),
             receiver,
-            user,
-            pwd,
+            cluster_secret,
             tcp_addr.to_string(),
             false,
             &dbs,
@@ -1074,8 +1291,7 @@ pub fn ask_to_join_all_replicas(
     replicate_address_to_thread: &String,
     tcp_addr: &String,
     external_tcp_addr: &String,
-    user: &String,
-    pwd: &String,
+    cluster_secret: &String,
 ) {
     if replicate_address_to_thread.len() > 0 {
         let mut parts: Vec<&str> = replicate_address_to_thread.split(",").collect();
@@ -1090,13 +1306,13 @@ pub fn ask_to_join_all_replicas(
             if replica != tcp_addr && replica != external_tcp_addr {
                 // Don't ask to join the server sending it
                 let replica_str = String::from(replica);
-                ask_to_join(&replica_str, &external_tcp_addr, &user, &pwd);
+                ask_to_join(&replica_str, external_tcp_addr, cluster_secret);
             }
         }
     }
 }
 
-pub fn ask_to_join(replica_addr: &String, external_addr: &String, user: &String, pwd: &String) {
+pub fn ask_to_join(replica_addr: &String, external_addr: &String, cluster_secret: &String) {
     log::debug!(
         "Will ask to join {}, from external_addr {}",
         replica_addr,
@@ -1104,15 +1320,26 @@ pub fn ask_to_join(replica_addr: &String, external_addr: &String, user: &String,
     );
     match std::net::TcpStream::connect(replica_addr.clone()) {
         Ok(socket) => {
-            let writer = &mut std::io::BufWriter::new(&socket);
-            writer
-                .write_fmt(format_args!("auth {} {}\n", user, pwd))
-                .unwrap();
-
-            writer
-                .write_fmt(format_args!("join {}\n", external_addr))
-                .unwrap();
-            writer.flush().unwrap();
+            if let Err(e) = socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+                log::warn!("Could not set the join timeout: {}", e);
+            }
+            let sent = if is_tls_enabled_for(TLS_REPLICATION) {
+                replication_connector()
+                    .map_err(to_io_error)
+                    .and_then(|connector| {
+                        connector
+                            .connect(domain_from_address(replica_addr), socket)
+                            .map_err(to_io_error)
+                    })
+                    .and_then(|mut tls_socket| {
+                        send_join_message(&mut tls_socket, cluster_secret, external_addr)
+                    })
+            } else {
+                send_join_message(&mut &socket, cluster_secret, external_addr)
+            };
+            if let Err(e) = sent {
+                log::warn!("Could not ask to join {}: {}", replica_addr, e)
+            }
         }
         Err(_) => {
             log::warn!(
@@ -1125,8 +1352,7 @@ pub fn ask_to_join(replica_addr: &String, external_addr: &String, user: &String,
 fn start_replication(
     replicate_address: String,
     mut command_receiver: Receiver<String>,
-    user: String,
-    pwd: String,
+    cluster_secret: String,
     tcp_addr: String,
     is_primary: bool,
     dbs: &Arc<Databases>,
@@ -1137,9 +1363,7 @@ fn start_replication(
     );
     let global_fut = async {
         let (mut client, _receiver) = Client::new_empty_and_receiver();
-        client
-            .auth
-            .store(true, std::sync::atomic::Ordering::Relaxed); // Auth as node
+        client.auth_as_local_node();
         let member = Some(ClusterMember {
             name: tcp_addr.clone(),
             role: ClusterRole::Secoundary, // Todo not sure if this is correct
@@ -1151,11 +1375,19 @@ fn start_replication(
         }
 
         log::warn!("replication::start_replication::reader");
-        let stream = TcpStream::connect(replicate_address.clone()).await?;
+        let stream = connect_to_node(&replicate_address).await?;
+        let (read_half, write_half) = futures::io::AsyncReadExt::split(stream);
         let mut line = String::new();
-        let mut reader = futures::io::BufReader::new(&stream);
-        let mut writer = futures::io::BufWriter::new(&stream);
-        auth_on_replication(&user, &pwd, &tcp_addr, is_primary, &mut writer).await?;
+        let mut reader = futures::io::BufReader::new(read_half);
+        let mut writer = futures::io::BufWriter::new(write_half);
+        auth_on_replication(
+            &cluster_secret,
+            &tcp_addr,
+            is_primary,
+            &mut reader,
+            &mut writer,
+        )
+        .await?;
         let reader_fut = async {
             loop {
                 let len = reader.read_line(&mut line).await?;
@@ -1222,16 +1454,26 @@ fn start_replication(
 }
 
 pub async fn auth_on_replication(
-    user: &String,
-    pwd: &String,
+    cluster_secret: &String,
     tcp_addr: &String,
     is_primary: bool,
-    writer: &mut futures::io::BufWriter<&TcpStream>,
+    reader: &mut (impl futures::AsyncBufRead + Unpin),
+    writer: &mut (impl futures::AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
     log::debug!("authenticating on replication {}", tcp_addr);
-    writer
-        .write_fmt(format_args!("auth {} {}\n", user, pwd))
-        .await?;
+    AsyncWriteExt::write_all(writer, handshake_message(cluster_secret).as_bytes()).await?;
+    AsyncWriteExt::flush(writer).await?;
+    // Nothing is replicated to peers with an incompatible server-info
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(closed_before_server_info());
+        }
+        if let Some(result) = check_handshake_reply(&line) {
+            break result?;
+        }
+    }
     if is_primary {
         // do I need this?
         writer
@@ -1272,19 +1514,41 @@ fn get_full_sync_opps(dbs: &Arc<Databases>) -> Vec<String> {
     log::info!("Will perform a full sync!");
     let mut opps_vec = Vec::new();
     let dbs = dbs.map.read().unwrap(); // Will lock db creation and deletion for a long time...
-    let db_list: Vec<&Database> = dbs.values().collect();
+    let db_list: Vec<&Arc<Database>> = dbs.values().collect();
     for db in db_list {
         let db_name = db.name.clone();
-        if db_name != ADMIN_DB {
+        if db_name == ADMIN_DB {
+            // Only the admin accounts, the $admin db is recreated on each node
+            let account_keys: Vec<(String, Value)> = db
+                .all_values()
+                .into_iter()
+                .filter(|(key, _)| {
+                    key.starts_with(USER_NAME_KEYS_PREFIX) || key.starts_with(ROLE_KEYS_PREFIX)
+                })
+                .collect();
+            for (key, value) in &account_keys {
+                opps_vec.push(get_replicate_message(
+                    db_name.to_string(),
+                    key.to_string(),
+                    value.value.to_string(),
+                    -1,
+                ));
+            }
+            if !account_keys.is_empty() {
+                opps_vec.push(format!("replicate-snapshot {}", db_name));
+            }
+        } else {
             log::debug!("Praparing the db {}", db_name);
             opps_vec.push(make_create_db_command(&db));
-            let map_values = {
-                let values = db.map.read().unwrap();
-                values.clone()
-            };
+            let map_values = db.all_values();
             for (key, value) in &map_values {
                 if key != TOKEN_KEY && key != CONNECTIONS_KEY {
-                    opps_vec.push(format!("replicate {} {} {}", db_name, key, value));
+                    opps_vec.push(get_replicate_message(
+                        db_name.to_string(),
+                        key.to_string(),
+                        value.value.to_string(),
+                        -1,
+                    ));
                 }
             }
 
@@ -1327,7 +1591,7 @@ fn get_pendding_opps_since_from_sync(since: u64, dbs: &Arc<Databases>) -> Vec<St
                     } => value,
                     _ => String::from(""),
                 };
-                format!("replicate {} {} {}", db_name, key_str, value)
+                get_replicate_message(db_name.to_string(), key_str.to_string(), value, -1)
             }
             ReplicateOpp::Remove => {
                 let db_name = id_name_db_map.get(&op_record.db).unwrap();
@@ -1359,7 +1623,7 @@ pub fn get_pendding_opps_since(since: u64, dbs: &Arc<Databases>) -> Vec<String>
     }
 }
 
-async fn start_sync_process(writer: &mut futures::io::BufWriter<&TcpStream>, tcp_addr: &String) {
+async fn start_sync_process(writer: &mut (impl futures::AsyncWrite + Unpin), tcp_addr: &String) {
     log::debug!("start_sync_process to {}", tcp_addr);
     // todo make sure it replicates
     match writer
@@ -1401,6 +1665,8 @@ fn latency_trap() {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::process_request::process_framed_request;
+    use crate::security::verify_token;
     use futures::channel::mpsc::{channel, Receiver, Sender};
     use std::collections::HashMap;
     use std::fs;
@@ -1524,13 +1790,13 @@ mod tests {
         let commands = get_pendding_opps_since(0, &dbs);
         print!("{:?}", commands);
         assert!(commands.len() == 3, "Only 3 command expected");
-        assert!(
-            commands[0] == "create-db sample sample",
-            "Create sample comman error"
-        );
+        let token = commands[0]
+            .strip_prefix("create-db sample ")
+            .expect("Create sample comman error");
+        assert!(verify_token("sample", token), "Create sample token error");
 
         assert!(
-            commands[1] == "replicate sample key value3",
+            commands[1] == "replicate sample key -1 \"value3\"",
             "Expected secound message to be sample key value3"
         );
 
@@ -1574,13 +1840,13 @@ mod tests {
         let commands = get_pendding_opps_since(test_start, &dbs);
         log::debug!("{:?}", commands);
         assert!(commands.len() == 3, "Only 3 command expected");
-        assert!(
-            commands[0] == "create-db sample sample",
-            "Create sample comman error"
-        );
+        let token = commands[0]
+            .strip_prefix("create-db sample ")
+            .expect("Create sample comman error");
+        assert!(verify_token("sample", token), "Create sample token error");
 
         assert!(
-            commands[1] == "replicate sample key value3",
+            commands[1] == "replicate sample key -1 \"value3\"",
             "Expected secound message to be sample key value3"
         );
 
@@ -1624,7 +1890,7 @@ mod tests {
         let commands = get_pendding_opps_since(test_start, &dbs);
         assert!(commands.len() == 1, "Only one command expected");
         assert!(
-            commands[0] == "replicate $admin key value8",
+            commands[0] == "replicate $admin key -1 \"value8\"",
             "Only one command expected"
         );
 
@@ -1677,7 +1943,145 @@ mod tests {
         };
         assert!(result, "should have returned an ok response!");
         let replicate_command = receiver.try_next().unwrap().unwrap();
-        assert!(replicate_command.ends_with("replicate sample any_key -1 any_value"));
+        assert!(replicate_command.ends_with("replicate sample any_key -1 \"any_value\""));
+    }
+
+    fn create_node(role: ClusterRole) -> (Arc<Databases>, Receiver<String>) {
+        let (sender, _): (Sender<String>, Receiver<String>) = channel(100);
+        let (replication_sender, replication_receiver): (Sender<String>, Receiver<String>) =
+            channel(100);
+        let dbs = Arc::new(Databases::new(
+            String::from("user"),
+            String::from("token"),
+            String::from(""),
+            String::from(""),
+            sender,
+            replication_sender,
+            HashMap::new(),
+            1 as u128,
+            true,
+        ));
+        dbs.node_state.swap(role as usize, Ordering::Relaxed);
+        (dbs, replication_receiver)
+    }
+
+    #[test]
+    fn should_replicate_values_with_line_breaks_and_semicolons() {
+        let value = "jose;\nreplicate $admin $$token -1 \"pwd\";";
+        let (primary, mut replication_receiver) = create_node(ClusterRole::Primary);
+        let (mut client, _receiver) = Client::new_empty_and_receiver();
+        process_request("auth user token", &primary, &mut client);
+        process_request("create-db test test-1", &primary, &mut client);
+        process_request("use-db test test-1", &primary, &mut client);
+        process_framed_request(&format!("set name {}", value), &primary, &mut client);
+        let message = std::iter::from_fn(|| replication_receiver.try_recv().ok())
+            .find(|message| message.contains(" replicate test name "))
+            .unwrap();
+        assert_eq!(message.lines().count(), 1);
+        assert!(!message.ends_with(';'));
+
+        let (secondary, _) = create_node(ClusterRole::Primary);
+        let (mut admin, mut admin_receiver) = Client::new_empty_and_receiver();
+        process_request("auth user token", &secondary, &mut admin);
+        process_request("create-db test test-1", &secondary, &mut admin);
+        secondary
+            .node_state
+            .swap(ClusterRole::Secoundary as usize, Ordering::Relaxed);
+        secondary.set_cluster_secret("cluster-secret");
+        let (mut node, _node_receiver) = Client::new_empty_and_receiver();
+        process_request("cluster-auth cluster-secret", &secondary, &mut node);
+        process_request(
+            &format!("hello {}", PROTOCOL_VERSION),
+            &secondary,
+            &mut node,
+        );
+        // The nodes read the replication messages line by line
+        for line in message.lines() {
+            assert_eq!(
+                process_request(line, &secondary, &mut node),
+                Response::Ok {}
+            );
+        }
+
+        process_request("use-db test test-1", &secondary, &mut admin);
+        while admin_receiver.try_recv().is_ok() {}
+        process_request("get name", &secondary, &mut admin);
+        assert_eq!(
+            admin_receiver.try_recv().unwrap(),
+            format!("value {}\n", value)
+        );
+    }
+
+    #[test]
+    fn should_encode_the_values_of_the_resolve_messages() {
+        let message = get_resolve_message(
+            1,
+            String::from("test"),
+            String::from("name"),
+            String::from("a;\nb"),
+            2,
+        );
+        assert_eq!(message, "resolve 1 test name 2 \"a\\u003b\\nb\"");
+        let value = message.splitn(6, ' ').last().unwrap();
+        assert_eq!(decode_replicated_value(value).unwrap(), "a;\nb");
+    }
+
+    // Replies with `input` and keeps what the node wrote
+    struct PeerStream {
+        input: std::io::Cursor<String>,
+        output: Vec<u8>,
+    }
+
+    impl Read for PeerStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for PeerStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Write::write(&mut self.output, buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_only_join_peers_with_a_compatible_server_info() {
+        let (peer, _) = create_node(ClusterRole::Primary);
+        let join = |replies: String| {
+            let mut stream = PeerStream {
+                input: std::io::Cursor::new(replies),
+                output: Vec::new(),
+            };
+            let secret = String::from("cluster-secret");
+            let result = send_join_message(&mut stream, &secret, &String::from("node-2:3014"));
+            (result, String::from_utf8(stream.output).unwrap())
+        };
+        let server_info = crate::network::handshake_ops::server_info(&peer, &[]);
+
+        let (result, sent) = join(format!("valid auth\n{}", server_info));
+        assert!(result.is_ok());
+        assert_eq!(
+            sent,
+            format!(
+                "cluster-auth cluster-secret\nhello {}\njoin node-2:3014\n",
+                PROTOCOL_VERSION
+            )
+        );
+
+        let old_server_info = "server-info version=0.1 protocol=2 min-protocol=1\n";
+        for replies in [
+            format!("valid auth\n{}", old_server_info),
+            format!("invalid auth\n{}", server_info),
+            String::from("valid auth\n"),
+        ] {
+            let (result, sent) = join(replies);
+            assert!(result.is_err());
+            assert!(!sent.contains("join"));
+        }
     }
 
     #[test]
@@ -1700,7 +2104,7 @@ mod tests {
         };
         assert!(result, "should have returned an ok response!");
         let replicate_command = receiver.try_next().unwrap().unwrap();
-        assert!(replicate_command.ends_with("replicate sample any_key -1 any_value"));
+        assert!(replicate_command.ends_with("replicate sample any_key -1 \"any_value\""));
     }
 
     #[test]
@@ -1,22 +1,68 @@
-use crate::db_ops::get_function_by_pattern;
-use core::sync::atomic::{AtomicBool, Ordering};
+use crate::db_ops::{set_connection_counter, unwatch_all};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use core::sync::atomic::Ordering;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::audit_ops::audit_actor;
 use crate::bo::*;
+use crate::network::handshake_ops::check_peer_protocol;
 use std::sync::Arc;
 
 pub const SECURY_KEYS_PREFIX: &'static str = "$$";
 pub const USER_NAME_KEYS_PREFIX: &'static str = "$$user";
 pub const PERMISSION_KEYS_PREFIX: &'static str = "$$permission_$";
+pub const ROLE_KEYS_PREFIX: &str = "$$role_$";
+// `set-permissions *` sets the permissions of the users without their own
+pub const DEFAULT_PERMISSIONS_USER: &str = "*";
+// HMAC secret used to verify the signed access tokens of the database
+pub const JWT_SECRET_KEY: &str = "$$jwt_secret";
+// PHC string prefix of the argon2 hashes, values without it are plain text from old versions
+pub const HASHED_TOKEN_PREFIX: &str = "$argon2";
 
 const PERMISSION_DENIED_MESSAGE: &'static str = "permission denied\n";
 const NO_DB_SELECTED_MESSAGE: &'static str = "error no-db-selected\n";
+const TOKEN_EXPIRED_MESSAGE: &str = "error token-expired\n";
 
-pub fn apply_if_auth(auth: &Arc<AtomicBool>, opp: &dyn Fn() -> Response) -> Response {
-    if auth.load(Ordering::SeqCst) {
+/// Node to node commands (replication, election and cluster membership)
+pub fn apply_if_cluster_node(client: &Client, opp: &dyn Fn() -> Response) -> Response {
+    if !client.is_cluster_node() {
+        return Response::Error {
+            msg: "Cluster auth required".to_string(),
+        };
+    }
+    // Nodes running an incompatible protocol can't join or replicate
+    match check_peer_protocol(client) {
+        Ok(()) => opp(),
+        Err(msg) => Response::Error { msg },
+    }
+}
+
+pub fn is_valid_cluster_secret(dbs: &Arc<Databases>, secret: &str) -> bool {
+    verify_token(secret, &dbs.cluster_secret())
+}
+
+/// With mutual TLS the secret is not enough, the node also needs a certificate signed by the CA
+pub fn has_node_certificate(client: &Client, certificate_required: bool) -> bool {
+    !certificate_required || client.verified_certificate.load(Ordering::SeqCst)
+}
+
+pub fn apply_if_role(client: &Client, role: AdminRole, opp: &dyn Fn() -> Response) -> Response {
+    if !client.auth.load(Ordering::SeqCst) {
+        Response::Error {
+            msg: "Not auth".to_string(),
+        }
+    } else if client.has_admin_role(&role) {
         opp()
     } else {
         Response::Error {
-            msg: "Not auth".to_string(),
+            msg: format!("Role {} required", role),
         }
     }
 }
@@ -29,6 +75,7 @@ pub fn apply_if_safe_access(
     permission_required: PermissionKind,
 ) -> Response {
     if key.starts_with(SECURY_KEYS_PREFIX) && !client.is_admin_auth() {
+        audit_permission_denied(dbs, client, key, &permission_required);
         Response::Error {
             msg: "To read security keys you must auth as an admin!".to_string(),
         }
@@ -48,13 +95,51 @@ pub fn apply_if_safe_access(
     }
 }
 
+/// User management writes the `$$` user and permission keys, db-creators are allowed to run it
+pub fn apply_if_user_admin_access(
+    dbs: &Arc<Databases>,
+    client: &Client,
+    key: &String,
+    opp: &dyn Fn(&Database) -> Response,
+    permission_required: PermissionKind,
+) -> Response {
+    if !client.has_admin_role(&AdminRole::DbCreator) {
+        audit_permission_denied(dbs, client, key, &permission_required);
+        return Response::Error {
+            msg: "To read security keys you must auth as an admin!".to_string(),
+        };
+    }
+    match client.selected_db_name() {
+        Some(db_name) => apply_to_database_name(dbs, client, &db_name, opp, &permission_required),
+        None => reject_request_for_no_selected_db(client),
+    }
+}
+
+// The watches go with the session, the token no longer allows reading the keys
+fn reject_expired_session(dbs: &Arc<Databases>, client: &Client) -> Response {
+    let db_name = client.selected_db.name.write().unwrap().take();
+    *client.selected_db.user_name.write().unwrap() = None;
+    client.selected_db.set_access_scope(None, None);
+    if let Some(db_name) = db_name {
+        if let Some(db) = dbs.acquire_dbs_read_lock().get(&db_name) {
+            unwatch_all(&client.sender, db);
+            client.set_watching(false);
+            db.dec_connections();
+            set_connection_counter(db, dbs);
+        }
+    }
+    let msg = String::from(TOKEN_EXPIRED_MESSAGE);
+    client.send_direct_message(&msg);
+    Response::Error { msg }
+}
+
 fn reject_request_for_no_selected_db(client: &Client) -> Response {
     let msg = String::from(NO_DB_SELECTED_MESSAGE);
-    client.send_message(&msg);
+    client.send_direct_message(&msg);
     Response::Error { msg }
 }
 
-fn has_permission(
+pub(crate) fn has_permission(
     client: &Client,
     key: &String,
     db: &Database,
@@ -63,29 +148,28 @@ fn has_permission(
     if key.starts_with(SECURY_KEYS_PREFIX) {
         client.is_admin_auth()
     } else {
-        let selected_db_user_name = client.selected_db_user_name().unwrap_or("all".to_string());
-        let permisions = db.get_value(String::from(format!(
-            "$$permission_${}",
-            selected_db_user_name
-        )));
+        let user_name = client.selected_db_user_name();
+        let selected_db_user_name = user_name.clone().unwrap_or("all".to_string());
+        let stored_permissions = |user_name: &str| {
+            db.get_value(format!("{}{}", PERMISSION_KEYS_PREFIX, user_name))
+                .map(|permisions| Permission::permissions_from_str(permisions.value.as_str()))
+        };
+        // Inline permissions of the access token take precedence over the stored ones, the db
+        // default only applies to users, `{user}` expands to the user name in both
+        let inline_permisions = client.selected_db.permissions.read().unwrap().clone();
+        let permisions = inline_permisions
+            .or_else(|| stored_permissions(&selected_db_user_name))
+            .or_else(|| user_name.and_then(|_| stored_permissions(DEFAULT_PERMISSIONS_USER)));
         log::debug!("permisions: {:?}", permisions);
         match permisions {
             Some(permisions) => {
-                let permisions = Permission::permissions_from_str(permisions.value.as_str());
-
-                permisions.into_iter().any(|permision| {
-                    log::debug!("permisions_parsed: {:?}", permision.kinds);
-                    let kinds = permision.kinds.clone();
-                    if !kinds.contains(&required_permission) {
-                        return false;
-                    }
-                    log::debug!("Has kind: {:?}", permision.keys);
-                    let is_allowed = permision
-                        .keys
-                        .into_iter()
-                        .any(|x| get_function_by_pattern(&x)(key, &x));
-                    is_allowed
-                })
+                let applicable: Vec<&Permission> = permisions
+                    .iter()
+                    .filter(|permision| permision.grants(required_permission))
+                    .filter(|permision| permision.matches(key, &selected_db_user_name))
+                    .collect();
+                log::debug!("applicable permisions: {:?}", applicable);
+                !applicable.is_empty() && applicable.iter().all(|permision| !permision.deny)
             }
             None => selected_db_user_name == "all",
         }
@@ -117,14 +201,18 @@ pub fn apply_to_database_name_if_has_permission(
     key: Option<&String>,
     permission_required: &PermissionKind,
 ) -> Response {
-    let dbs = dbs.acquire_dbs_read_lock();
-    let result: Response = match dbs.get(&db_name.to_string()) {
+    if client.selected_db.is_expired() {
+        return reject_expired_session(dbs, client);
+    }
+    let dbs_map = dbs.acquire_dbs_read_lock();
+    let result: Response = match dbs_map.get(&db_name.to_string()) {
         Some(db) => {
             if key == None || has_permission(client, key.unwrap(), db, &permission_required) {
                 opp(db)
             } else {
+                audit_permission_denied(dbs, client, key.unwrap(), permission_required);
                 let msg = String::from(PERMISSION_DENIED_MESSAGE);
-                client.send_message(&msg);
+                client.send_direct_message(&msg);
                 Response::Error { msg }
             }
         }
@@ -133,6 +221,25 @@ pub fn apply_to_database_name_if_has_permission(
     return result;
 }
 
+fn audit_permission_denied(
+    dbs: &Arc<Databases>,
+    client: &Client,
+    key: &String,
+    permission_required: &PermissionKind,
+) {
+    dbs.audit(
+        &audit_actor(client),
+        "permission-denied",
+        &format!(
+            "{}/{} {}",
+            client.selected_db_name().unwrap_or_default(),
+            key,
+            permission_required
+        ),
+        "denied",
+    );
+}
+
 pub fn apply_to_database(
     dbs: &Arc<Databases>,
     client: &Client,
@@ -145,7 +252,7 @@ pub fn apply_to_database(
         }
         None => {
             let msg = String::from(NO_DB_SELECTED_MESSAGE);
-            client.send_message(&msg);
+            client.send_direct_message(&msg);
             Response::Error { msg }
         }
     }
@@ -156,7 +263,8 @@ pub fn clean_string_to_log(input: &str, dbs: &Arc<Databases>) -> String {
     let pwd_replacer: String = format!("{}", &dbs.pwd.to_string());
     return input
         .replace(&user_replacer.to_string(), "**** ")
-        .replace(&pwd_replacer.to_string(), "****");
+        .replace(&pwd_replacer.to_string(), "****")
+        .replace(&dbs.cluster_secret(), "****");
 }
 
 pub fn user_name_key_from_user_name(user_name: &String) -> String {
@@ -167,6 +275,256 @@ pub fn permissions_key_from_user_name(user_name: &String) -> String {
     String::from(format!("{}{}", PERMISSION_KEYS_PREFIX, user_name))
 }
 
+/// Keys of the accounts created with create-admin in the $admin database
+pub fn is_admin_account_key(key: &str) -> bool {
+    key.starts_with(&format!("{}_", USER_NAME_KEYS_PREFIX)) || key.starts_with(ROLE_KEYS_PREFIX)
+}
+
+pub fn list_user_names(db: &Database) -> Vec<String> {
+    let prefix = format!("{}_", USER_NAME_KEYS_PREFIX);
+    let mut user_names: Vec<String> = db
+        .all_values()
+        .into_iter()
+        .filter(|(_, value)| value.state != ValueStatus::Deleted)
+        .filter_map(|(key, _)| key.strip_prefix(&prefix).map(|name| name.to_string()))
+        .collect();
+    user_names.sort();
+    user_names
+}
+
+/// Revokes the clients using the token stored in `key`, does nothing if it is not a token key
+pub fn revoke_sessions_for_token_key(
+    key: &str,
+    db: &Database,
+    dbs: &Arc<Databases>,
+    keep: Option<&Client>,
+) -> usize {
+    let user_name = if key == TOKEN_KEY {
+        None
+    } else {
+        match key.strip_prefix(&format!("{}_", USER_NAME_KEYS_PREFIX)) {
+            Some(user_name) => Some(user_name),
+            None => return 0,
+        }
+    };
+    let revoked = db.revoke_sessions(user_name, keep.map(|client| &client.selected_db));
+    if revoked > 0 {
+        log::info!("Revoked {} clients of the db {}", revoked, db.name);
+        set_connection_counter(db, dbs);
+    }
+    revoked
+}
+
+pub fn role_key_from_user_name(user_name: &String) -> String {
+    format!("{}{}", ROLE_KEYS_PREFIX, user_name)
+}
+
+/// Roles of the server account, the NUN_USER account is a cluster-admin and the others are
+/// stored in the $admin database
+pub fn admin_roles_from_credentials(
+    dbs: &Arc<Databases>,
+    user: &String,
+    password: &str,
+) -> Option<Vec<AdminRole>> {
+    if *user == dbs.user {
+        return if verify_token(password, &dbs.pwd) {
+            Some(vec![AdminRole::ClusterAdmin])
+        } else {
+            None
+        };
+    }
+    let dbs_map = dbs.map.read().unwrap();
+    let admin_db = dbs_map.get(ADMIN_DB)?;
+    let token = admin_db
+        .get_value(user_name_key_from_user_name(user))
+        .filter(|value| value.state != ValueStatus::Deleted)?;
+    if !verify_token(password, &token.value) {
+        return None;
+    }
+    admin_db
+        .get_value(role_key_from_user_name(user))
+        .filter(|value| value.state != ValueStatus::Deleted)
+        .and_then(|roles| AdminRole::roles_from_str(&roles.value).ok())
+}
+
+/// Claims of the HS256 JWT accepted by `use-db`, `permissions` uses the `set-permissions` format
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessTokenClaims {
+    pub sub: String,
+    pub exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AccessTokenHeader {
+    alg: String,
+}
+
+pub fn is_access_token(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+fn access_token_signature(content: &str, secret: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(content.as_bytes());
+    mac
+}
+
+pub fn sign_access_token(claims: &AccessTokenClaims, secret: &str) -> String {
+    let header = serde_json::to_vec(&AccessTokenHeader {
+        alg: String::from("HS256"),
+    })
+    .unwrap();
+    let content = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header),
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap())
+    );
+    let signature = access_token_signature(&content, secret)
+        .finalize()
+        .into_bytes();
+    format!("{}.{}", content, URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// Validates the signature and the expiration of the access token
+pub fn verify_access_token(token: &str, secret: &str) -> Result<AccessTokenClaims, String> {
+    let parts: Vec<&str> = token.split('.').collect();
+    let (header_part, claims_part, signature_part) = match parts[..] {
+        [header, claims, signature] => (header, claims, signature),
+        _ => return Err(String::from("Invalid access token")),
+    };
+    let decode = |part: &str| {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|_| String::from("Invalid access token"))
+    };
+    let header: AccessTokenHeader = serde_json::from_slice(&decode(header_part)?)
+        .map_err(|_| String::from("Invalid access token"))?;
+    if header.alg != "HS256" {
+        return Err(format!("Unsupported access token algorithm {}", header.alg));
+    }
+    access_token_signature(&format!("{}.{}", header_part, claims_part), secret)
+        .verify_slice(&decode(signature_part)?)
+        .map_err(|_| String::from("Invalid access token signature"))?;
+    let claims: AccessTokenClaims = serde_json::from_slice(&decode(claims_part)?)
+        .map_err(|e| format!("Invalid access token claims: {}", e))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    if claims.exp <= now {
+        return Err(String::from("Access token expired"));
+    }
+    Ok(claims)
+}
+
+/// The access token claims if the token looks like a JWT and the database has a secret
+pub fn access_token_from_db(
+    token: &str,
+    db: &Database,
+) -> Option<Result<AccessTokenClaims, String>> {
+    if !is_access_token(token) {
+        return None;
+    }
+    db.get_value(String::from(JWT_SECRET_KEY))
+        .filter(|secret| secret.state != ValueStatus::Deleted)
+        .map(|secret| verify_access_token(token, &secret.value))
+}
+
+pub fn is_hashed_token(value: &str) -> bool {
+    value.starts_with(HASHED_TOKEN_PREFIX)
+}
+
+/// Salted argon2 hash of the token, already hashed tokens are returned as they are so hashes
+/// received from the primary are not hashed twice
+pub fn hash_token(token: &str) -> String {
+    if is_hashed_token(token) {
+        return token.to_string();
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(token.as_bytes(), &salt)
+        .expect("Could not hash the token")
+        .to_string()
+}
+
+/// Checks the token against the stored hash, plain text values not migrated yet are compared
+/// in constant time
+pub fn verify_token(token: &str, stored: &str) -> bool {
+    if is_hashed_token(stored) {
+        match PasswordHash::new(stored) {
+            Ok(hash) => Argon2::default()
+                .verify_password(token.as_bytes(), &hash)
+                .is_ok(),
+            Err(e) => {
+                log::warn!("Invalid token hash: {}", e);
+                false
+            }
+        }
+    } else {
+        constant_time_eq(token.as_bytes(), stored.as_bytes())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_token_key(key: &str) -> bool {
+    key == TOKEN_KEY || key.starts_with(&format!("{}_", USER_NAME_KEYS_PREFIX))
+}
+
+/// Replaces the plain text database and user tokens by their hashes, returns the number of
+/// migrated keys. The changed databases are added to the next snapshot
+pub fn migrate_plain_text_tokens(dbs: &Arc<Databases>) -> usize {
+    let mut migrated_dbs = vec![];
+    let mut migrated = 0;
+    {
+        let dbs_map = dbs.map.read().unwrap();
+        for (db_name, db) in dbs_map.iter() {
+            let plain_text_tokens: Vec<(String, Value)> = db
+                .map
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(key, value)| {
+                    is_token_key(key)
+                        && value.state != ValueStatus::Deleted
+                        && !is_hashed_token(&value.value)
+                })
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect();
+            for (key, value) in &plain_text_tokens {
+                db.set_value(&Change::new(
+                    key.to_string(),
+                    hash_token(&value.value),
+                    value.version,
+                ));
+            }
+            if !plain_text_tokens.is_empty() {
+                log::info!(
+                    "Migrated {} plain text tokens in {}",
+                    plain_text_tokens.len(),
+                    db_name
+                );
+                migrated += plain_text_tokens.len();
+                migrated_dbs.push(db_name.to_string());
+            }
+        }
+    }
+    for db_name in migrated_dbs {
+        if let Err(e) = dbs.add_db_to_snapshot_by_name(&db_name, false) {
+            log::warn!("Could not snapshot the migrated db {}: {}", db_name, e);
+        }
+    }
+    migrated
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -174,6 +532,15 @@ mod tests {
     use futures::channel::mpsc::{channel, Receiver, Sender};
     use std::collections::HashMap;
 
+    #[test]
+    fn should_require_a_verified_certificate_from_nodes_with_mutual_tls() {
+        let (client, _receiver) = Client::new_empty_and_receiver();
+        assert!(has_node_certificate(&client, false));
+        assert!(!has_node_certificate(&client, true));
+        client.verified_certificate.store(true, Ordering::SeqCst);
+        assert!(has_node_certificate(&client, true));
+    }
+
     #[test]
     fn should_clean_user_and_pwd() -> Result<(), String> {
         let (replication_supervisor_sender, _receiver): (Sender<String>, Receiver<String>) =
@@ -197,4 +564,123 @@ mod tests {
         assert_eq!(clean_input, String::from("auth **** ****;"));
         Ok(())
     }
+
+    #[test]
+    fn should_hash_and_verify_tokens() {
+        let hash = hash_token("my-token");
+        assert!(is_hashed_token(&hash));
+        assert_ne!(hash, hash_token("my-token"));
+        assert_eq!(hash_token(&hash), hash);
+        assert!(verify_token("my-token", &hash));
+        assert!(!verify_token("other-token", &hash));
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn should_sign_and_verify_access_tokens() {
+        let claims = AccessTokenClaims {
+            sub: String::from("foo"),
+            exp: now_secs() + 60,
+            permissions: Some(String::from("rw some*|r test")),
+        };
+        let token = sign_access_token(&claims, "secret");
+        assert!(is_access_token(&token));
+        assert_eq!(verify_access_token(&token, "secret"), Ok(claims));
+        assert_eq!(
+            verify_access_token(&token, "other-secret"),
+            Err(String::from("Invalid access token signature"))
+        );
+        assert_eq!(
+            verify_access_token("not-a-token", "secret"),
+            Err(String::from("Invalid access token"))
+        );
+    }
+
+    #[test]
+    fn should_reject_expired_and_unsigned_access_tokens() {
+        let claims = AccessTokenClaims {
+            sub: String::from("foo"),
+            exp: now_secs() - 1,
+            permissions: None,
+        };
+        let token = sign_access_token(&claims, "secret");
+        assert_eq!(
+            verify_access_token(&token, "secret"),
+            Err(String::from("Access token expired"))
+        );
+
+        let unsigned_header = URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#);
+        let claims_part = token.split('.').nth(1).unwrap();
+        assert_eq!(
+            verify_access_token(&format!("{}.{}.", unsigned_header, claims_part), "secret"),
+            Err(String::from("Unsupported access token algorithm none"))
+        );
+    }
+
+    #[test]
+    fn should_verify_plain_text_tokens_not_migrated_yet() {
+        assert!(verify_token("my-token", "my-token"));
+        assert!(!verify_token("my-token", "my-tokem"));
+        assert!(!verify_token("my-token", "my-token-long"));
+    }
+
+    #[test]
+    fn should_migrate_plain_text_tokens() {
+        let (replication_supervisor_sender, _receiver): (Sender<String>, Receiver<String>) =
+            channel(100);
+        let (replication_sender, _receiver): (Sender<String>, Receiver<String>) = channel(100);
+        let tcp_addr = String::from("127.0.0.1");
+        let dbs = create_init_dbs(
+            String::from("mateus"),
+            String::from("mateus-123"),
+            tcp_addr.to_string(),
+            tcp_addr,
+            replication_supervisor_sender,
+            replication_sender,
+            HashMap::new(),
+            true,
+        );
+        let db = Database::new(
+            String::from("org-1"),
+            DatabaseMataData::new(0, ConsensuStrategy::Newer),
+        );
+        db.set_value(&Change::new(
+            String::from(TOKEN_KEY),
+            String::from("db-token"),
+            -1,
+        ));
+        let user_key = user_name_key_from_user_name(&String::from("foo"));
+        db.set_value(&Change::new(
+            user_key.to_string(),
+            String::from("foo-token"),
+            -1,
+        ));
+        db.set_value(&Change::new(
+            String::from("name"),
+            String::from("plain"),
+            -1,
+        ));
+        dbs.add_database(db);
+
+        assert_eq!(migrate_plain_text_tokens(&dbs), 2);
+
+        let dbs_map = dbs.map.read().unwrap();
+        let db = dbs_map.get("org-1").unwrap();
+        let db_token = db.get_value(String::from(TOKEN_KEY)).unwrap().value;
+        assert!(is_hashed_token(&db_token));
+        assert!(verify_token("db-token", &db_token));
+        assert!(verify_token(
+            "foo-token",
+            &db.get_value(user_key).unwrap().value
+        ));
+        assert_eq!(db.get_value(String::from("name")).unwrap().value, "plain");
+        drop(dbs_map);
+        assert_eq!(migrate_plain_text_tokens(&dbs), 0);
+    }
 }
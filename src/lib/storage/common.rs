@@ -1,4 +1,17 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use aws_config::Region;
+use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::Client;
+use tokio::runtime::Runtime;
+
 use crate::bo::{Database, Value, ValueStatus};
+use crate::configuration::{
+    NUN_S3_API_URL, NUN_S3_BUCKET, NUN_S3_KEY_ID, NUN_S3_MAX_INFLIGHT_REQUESTS, NUN_S3_PREFIX,
+    NUN_S3_SECRET_KEY,
+};
 
 pub fn get_keys_to_update(db: &Database, reclame_space: bool) -> Vec<(String, Value)> {
     get_keys_by_filter(&db, &|_k: &String, v: &Value| {
@@ -6,6 +19,24 @@ pub fn get_keys_to_update(db: &Database, reclame_space: bool) -> Vec<(String, Va
     })
 }
 
+/// Removes from memory the deleted keys that were already stored, unless they changed since.
+/// Returns the number of removed keys
+pub fn remove_stored_deleted_keys(db: &Database, stored_deleted: &[(String, i32)]) -> usize {
+    let mut map = db.map.write().unwrap();
+    let mut removed = 0;
+    for (key, version) in stored_deleted {
+        let is_same_deleted_value = map
+            .get(key)
+            .map(|value| value.state == ValueStatus::Deleted && value.version == *version)
+            .unwrap_or(false);
+        if is_same_deleted_value {
+            map.remove(key);
+            removed += 1;
+        }
+    }
+    removed
+}
+
 pub fn get_keys_by_filter(
     db: &Database,
     filter: &dyn Fn(&String, &Value) -> bool,
@@ -20,3 +51,105 @@ pub fn get_keys_by_filter(
     // Release the locker
     keys_to_update
 }
+
+pub fn build_s3_client() -> Client {
+    let key_id = NUN_S3_KEY_ID.as_str();
+    let secret_key = NUN_S3_SECRET_KEY.as_str();
+    let cred = Credentials::new(key_id, secret_key, None, None, "loaded-from-custom-env");
+    let s3_config = aws_sdk_s3::config::Builder::new()
+        .endpoint_url(NUN_S3_API_URL.as_str())
+        .credentials_provider(cred)
+        .region(Region::new("us-east"))
+        .force_path_style(true)
+        .build();
+    Client::from_conf(s3_config)
+}
+
+/// Lists all object keys in the bucket starting with `prefix`
+pub fn list_s3_objects(rt: &Runtime, client: &Client, prefix: &str) -> Vec<String> {
+    rt.block_on(async {
+        client
+            .list_objects_v2()
+            .set_prefix(Some(prefix.to_string()))
+            .bucket(NUN_S3_BUCKET.as_str())
+            .send()
+            .await
+            .unwrap()
+            .contents()
+            .iter()
+            .flat_map(|x| x.key())
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+    })
+}
+
+/// Extracts the database names from object keys like `{prefix}/{db_name}/{file}`,
+/// only objects containing `file_marker` are considered
+pub fn db_names_from_s3_objects(
+    objects: &[String],
+    prefix: &str,
+    file_marker: &str,
+) -> Vec<String> {
+    let prefix_to_clean = format!("{}/", prefix);
+    let mut db_names = objects
+        .iter()
+        .filter(|x| x.contains(file_marker))
+        .map(|x| x.replacen(&prefix_to_clean, "", 1))
+        .map(|x| {
+            let mut paths = x.split('/').collect::<Vec<&str>>();
+            paths.truncate(paths.len() - 1);
+            paths.join("/")
+        })
+        .collect::<Vec<String>>();
+    db_names.dedup();
+    db_names
+}
+
+/// Deletes all objects under `{NUN_S3_PREFIX}/{db_name}/`
+pub fn delete_db_from_s3(db_name: &str) -> Result<(), String> {
+    let rt = Runtime::new().unwrap();
+    let client = build_s3_client();
+    let objects = list_s3_objects(&rt, &client, &format!("{}/{}/", *NUN_S3_PREFIX, db_name));
+    rt.block_on(async {
+        for object in objects {
+            client
+                .delete_object()
+                .bucket(NUN_S3_BUCKET.as_str())
+                .key(&object)
+                .send()
+                .await
+                .map_err(|e| format!("Fail to delete {} from s3: {}", object, e))?;
+        }
+        Ok(())
+    })
+}
+
+pub fn release_lock(running_threads: &Arc<AtomicUsize>) {
+    running_threads.fetch_sub(1, Ordering::SeqCst);
+}
+
+pub fn await_thread_availability(running_threads: &Arc<AtomicUsize>) {
+    loop {
+        let prev_val = running_threads.load(Ordering::Acquire);
+        if prev_val > *NUN_S3_MAX_INFLIGHT_REQUESTS {
+            log::debug!("Too many threads running, waiting for a slot to open");
+            thread::sleep(std::time::Duration::from_millis(10));
+            continue;
+        } else {
+            log::debug!("Thread running await: {}", prev_val);
+        }
+        match running_threads.compare_exchange(
+            prev_val,
+            prev_val + 1,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => break,
+            Err(_) => {
+                log::debug!("Thread failed to update the counter : {}", prev_val);
+                thread::sleep(std::time::Duration::from_millis(10));
+                continue;
+            }
+        }
+    }
+}
@@ -17,6 +17,8 @@ use crate::bo::Databases;
 use crate::bo::{Database, Value, ValueStatus};
 
 use super::common::get_keys_to_update;
+use super::encryption::{decrypt_at_rest, encrypt_at_rest};
+use super::StorageBackend;
 
 const DB_KEYS_FILE_NAME: &'static str = "-nun.data.keys";
 const BASE_FILE_NAME: &'static str = "-nun.data";
@@ -183,16 +185,54 @@ impl NodeDrive {
     }
 }
 
+impl StorageBackend for NodeDrive {
+    fn load_all_dbs(&self, dbs: &Arc<Databases>) {
+        NodeDrive::load_all_dbs_from_disk(dbs)
+    }
+
+    fn store_db(&self, db: &Database, db_name: &str, reclame_space: bool) -> u32 {
+        NodeDrive::storage_data_disk(db, reclame_space, &db_name.to_string())
+    }
+
+    fn delete_db(&self, db_name: &str) -> Result<(), String> {
+        let file_name = file_name_from_db_name(&db_name.to_string());
+        let (keys_file_name, values_file_name) =
+            get_key_value_files_name_from_file_name(file_name.to_string());
+        let meta_file_name = meta_file_name_from_db_name(db_name.to_string());
+        for file in [file_name, keys_file_name, values_file_name, meta_file_name] {
+            if Path::new(&file).exists() {
+                fs::remove_file(&file).map_err(|e| format!("Could not delete {}, {}", file, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn list_dbs(&self) -> Vec<String> {
+        match read_dir(get_dir_name()) {
+            Ok(entries) => entries
+                .flatten()
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| name.ends_with(DB_KEYS_FILE_NAME))
+                .map(|name| db_name_from_file_name(&name.replace(".keys", "")))
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
 /// Writes a value to a giving file
 ///
 fn write_value(values_file: &mut BufWriter<File>, value: &Value, status: ValueStatus) -> u64 {
-    values_file.write(&value.value.len().to_le_bytes()).unwrap();
+    // Encrypted when encryption at rest is enabled
+    let value_as_bytes = encrypt_at_rest(value.value.as_bytes());
+    values_file
+        .write_all(&value_as_bytes.len().to_le_bytes())
+        .unwrap();
     //8bytes
-    let value_as_bytes = value.value.as_bytes();
     //Nth bytes
-    values_file.write(&value_as_bytes).unwrap();
+    values_file.write_all(&value_as_bytes).unwrap();
     //4 bytes
-    values_file.write(&status.to_le_bytes()).unwrap();
+    values_file.write_all(&status.to_le_bytes()).unwrap();
     let record_size = (U64_SIZE + value_as_bytes.len() + VERSION_SIZE) as u64;
     record_size
 }
@@ -415,6 +455,8 @@ pub fn create_db_from_file_name(file_name: &String, dbs: &Arc<Databases>) -> (Da
         let value_length = usize::from_le_bytes(length_buffer);
         let mut value_buffer = vec![0; value_length];
         let _ = values_file.read(&mut value_buffer);
+        let value_buffer = decrypt_at_rest(&value_buffer)
+            .unwrap_or_else(|e| panic!("Could not load the key {} of {}: {}", key, db_name, e));
         let value = str::from_utf8(&value_buffer).unwrap();
         if version != VERSION_DELETED {
             value_data.insert(
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::Path;
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use redb::{Database as RedbDatabase, ReadableTable, TableDefinition, TableHandle};
+
+use crate::bo::{ConsensuStrategy, Database, DatabaseMataData, Databases, Value, ValueStatus};
+use crate::configuration::{NUN_DBS_DIR, NUN_EMBEDDED_CACHE_KEYS};
+
+use super::common::{get_keys_to_update, remove_stored_deleted_keys};
+use super::{ColdStorage, StorageBackend};
+
+const EMBEDDED_FILE_NAME: &str = "nun-embedded.redb";
+const DB_TABLE_PREFIX: &str = "nun-db/";
+const METADATA_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("nun-metadata");
+
+const VERSION_SIZE: usize = 4;
+const U64_SIZE: usize = 8;
+
+// System keys start with $ and are always kept in memory, % is the next char
+const SYSTEM_KEYS_START: &str = "$";
+const SYSTEM_KEYS_END: &str = "%";
+
+lazy_static! {
+    pub static ref EMBEDDED_STORAGE: EmbeddedStorage = EmbeddedStorage::open(
+        &format!("{}/{}", *NUN_DBS_DIR, EMBEDDED_FILE_NAME),
+        *NUN_EMBEDDED_CACHE_KEYS
+    );
+}
+
+/// Stores each database in a table of an embedded B-tree file. Only the system keys and up to
+/// `cache_keys` recently written or read keys are kept in memory, other keys are read from the
+/// file on demand.
+#[derive(Clone)]
+pub struct EmbeddedStorage {
+    store: Arc<RedbDatabase>,
+    cache_keys: usize,
+}
+
+impl EmbeddedStorage {
+    pub fn open(file_name: &str, cache_keys: usize) -> EmbeddedStorage {
+        if let Some(dir) = Path::new(file_name).parent() {
+            if let Err(e) = create_dir_all(dir) {
+                log::error!("Error creating the data dirs {}", e);
+                panic!("Error creating the data dirs");
+            }
+        }
+        match RedbDatabase::create(file_name) {
+            Ok(store) => EmbeddedStorage {
+                store: Arc::new(store),
+                cache_keys,
+            },
+            Err(e) => {
+                log::error!("Error opening the embedded storage {}: {}", file_name, e);
+                panic!("Error opening the embedded storage");
+            }
+        }
+    }
+
+    fn read_metadata(&self, db_name: &str) -> Result<Option<DatabaseMataData>, String> {
+        let read = self.store.begin_read().map_err(|e| e.to_string())?;
+        let table = match read.open_table(METADATA_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        let metadata = table.get(db_name).map_err(|e| e.to_string())?;
+        Ok(metadata.map(|bytes| decode_metadata(bytes.value())))
+    }
+
+    fn read_system_values(&self, db_name: &str) -> Result<HashMap<String, Value>, String> {
+        let read = self.store.begin_read().map_err(|e| e.to_string())?;
+        let table_name = table_name_from_db_name(db_name);
+        let table = match read.open_table(db_table(&table_name)) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(HashMap::new()),
+            Err(e) => return Err(e.to_string()),
+        };
+        let range = table
+            .range(SYSTEM_KEYS_START..SYSTEM_KEYS_END)
+            .map_err(|e| e.to_string())?;
+        let mut values = HashMap::new();
+        for entry in range {
+            let (key, value) = entry.map_err(|e| e.to_string())?;
+            let mut value = decode_value(value.value());
+            value.opp_id = Databases::next_op_log_id();
+            values.insert(key.value().to_string(), value);
+        }
+        Ok(values)
+    }
+
+    fn write_changes(
+        &self,
+        db: &Database,
+        db_name: &str,
+        keys_to_update: &[(String, Value)],
+    ) -> Result<(), String> {
+        let write = self.store.begin_write().map_err(|e| e.to_string())?;
+        {
+            let table_name = table_name_from_db_name(db_name);
+            let mut table = write
+                .open_table(db_table(&table_name))
+                .map_err(|e| e.to_string())?;
+            for (key, value) in keys_to_update {
+                if value.state == ValueStatus::Deleted {
+                    table.remove(key.as_str()).map_err(|e| e.to_string())?;
+                } else {
+                    table
+                        .insert(key.as_str(), encode_value(value).as_slice())
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+            let mut metadata = write
+                .open_table(METADATA_TABLE)
+                .map_err(|e| e.to_string())?;
+            metadata
+                .insert(db_name, encode_metadata(&db.metadata).as_slice())
+                .map_err(|e| e.to_string())?;
+        }
+        write.commit().map_err(|e| e.to_string())
+    }
+
+    /// Removes the stored deleted keys and the least recently written or read keys from memory
+    fn evict_cold_values(&self, db: &Database, stored_deleted: &[(String, i32)]) -> usize {
+        let mut evicted = remove_stored_deleted_keys(db, stored_deleted);
+        let mut map = db.map.write().unwrap();
+        if map.len() <= self.cache_keys {
+            return evicted;
+        }
+        let mut candidates: Vec<(u64, String)> = map
+            .iter()
+            .filter(|(key, value)| {
+                value.state == ValueStatus::Ok && !key.starts_with(SYSTEM_KEYS_START)
+            })
+            .map(|(key, value)| (value.opp_id, key.to_string()))
+            .collect();
+        candidates.sort();
+        let to_evict = map.len() - self.cache_keys;
+        for (_, key) in candidates.into_iter().take(to_evict) {
+            map.remove(&key);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn read_values(&self, db_name: &str, with_values: bool) -> Vec<(String, Option<Value>)> {
+        let table_name = table_name_from_db_name(db_name);
+        let result = (|| -> Result<Vec<(String, Option<Value>)>, String> {
+            let read = self.store.begin_read().map_err(|e| e.to_string())?;
+            let table = match read.open_table(db_table(&table_name)) {
+                Ok(table) => table,
+                Err(redb::TableError::TableDoesNotExist(_)) => return Ok(vec![]),
+                Err(e) => return Err(e.to_string()),
+            };
+            let mut values = vec![];
+            for entry in table.iter().map_err(|e| e.to_string())? {
+                let (key, value) = entry.map_err(|e| e.to_string())?;
+                let value = if with_values {
+                    Some(decode_value(value.value()))
+                } else {
+                    None
+                };
+                values.push((key.value().to_string(), value));
+            }
+            Ok(values)
+        })();
+        match result {
+            Ok(values) => values,
+            Err(e) => {
+                log::error!(
+                    "Error reading the db {} from embedded storage: {}",
+                    db_name,
+                    e
+                );
+                vec![]
+            }
+        }
+    }
+}
+
+impl StorageBackend for EmbeddedStorage {
+    fn load_all_dbs(&self, dbs: &Arc<Databases>) {
+        for db_name in self.list_dbs() {
+            let metadata = match self.read_metadata(&db_name) {
+                Ok(Some(metadata)) => metadata,
+                Ok(None) => {
+                    DatabaseMataData::new(dbs.map.read().unwrap().len(), ConsensuStrategy::Newer)
+                }
+                Err(e) => panic!("Fail to load the db {} metadata: {}", db_name, e),
+            };
+            let values = match self.read_system_values(&db_name) {
+                Ok(values) => values,
+                Err(e) => panic!("Fail to load the db {}: {}", db_name, e),
+            };
+            let db = Database::create_db_from_value_hash(db_name.to_string(), values, metadata);
+            db.set_cold_storage(Arc::new(self.clone()));
+            dbs.add_database(db);
+            log::debug!("Loaded db {} from embedded storage", db_name);
+        }
+    }
+
+    fn store_db(&self, db: &Database, db_name: &str, reclame_space: bool) -> u32 {
+        let keys_to_update = get_keys_to_update(db, reclame_space);
+        if let Err(e) = self.write_changes(db, db_name, &keys_to_update) {
+            log::error!(
+                "Fail to store the db {} in embedded storage: {}",
+                db_name,
+                e
+            );
+            panic!(
+                "Fail to store the db {} in embedded storage: {}",
+                db_name, e
+            );
+        }
+        let mut stored_deleted = vec![];
+        for (key, value) in &keys_to_update {
+            if value.state == ValueStatus::Deleted {
+                stored_deleted.push((key.to_string(), value.version));
+            } else {
+                db.set_value_as_ok(key, value, 0, 0, Databases::next_op_log_id());
+            }
+        }
+        if db.cold_storage.read().unwrap().is_none() {
+            db.set_cold_storage(Arc::new(self.clone()));
+        }
+        let evicted = self.evict_cold_values(db, &stored_deleted);
+        log::debug!(
+            "snapshoted {} keys, evicted {} keys from memory",
+            keys_to_update.len(),
+            evicted
+        );
+        keys_to_update.len() as u32
+    }
+
+    fn delete_db(&self, db_name: &str) -> Result<(), String> {
+        let write = self.store.begin_write().map_err(|e| e.to_string())?;
+        {
+            let table_name = table_name_from_db_name(db_name);
+            write
+                .delete_table(db_table(&table_name))
+                .map_err(|e| e.to_string())?;
+            let mut metadata = write
+                .open_table(METADATA_TABLE)
+                .map_err(|e| e.to_string())?;
+            metadata.remove(db_name).map_err(|e| e.to_string())?;
+        }
+        write.commit().map_err(|e| e.to_string())
+    }
+
+    fn list_dbs(&self) -> Vec<String> {
+        let result = (|| -> Result<Vec<String>, String> {
+            let read = self.store.begin_read().map_err(|e| e.to_string())?;
+            let tables = read.list_tables().map_err(|e| e.to_string())?;
+            Ok(tables
+                .filter_map(|table| {
+                    table
+                        .name()
+                        .strip_prefix(DB_TABLE_PREFIX)
+                        .map(|name| name.to_string())
+                })
+                .collect())
+        })();
+        match result {
+            Ok(db_names) => db_names,
+            Err(e) => {
+                log::error!("Error listing the embedded storage dbs: {}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+impl ColdStorage for EmbeddedStorage {
+    fn get_value(&self, db_name: &str, key: &str) -> Option<Value> {
+        let table_name = table_name_from_db_name(db_name);
+        let read = self.store.begin_read().ok()?;
+        let table = read.open_table(db_table(&table_name)).ok()?;
+        match table.get(key) {
+            Ok(value) => value.map(|value| decode_value(value.value())),
+            Err(e) => {
+                log::error!("Error reading the key {} from embedded storage: {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn list_keys(&self, db_name: &str) -> Vec<String> {
+        self.read_values(db_name, false)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    fn list_values(&self, db_name: &str) -> Vec<(String, Value)> {
+        self.read_values(db_name, true)
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect()
+    }
+}
+
+fn table_name_from_db_name(db_name: &str) -> String {
+    format!("{}{}", DB_TABLE_PREFIX, db_name)
+}
+
+fn db_table(table_name: &str) -> TableDefinition<'_, &'static str, &'static [u8]> {
+    TableDefinition::new(table_name)
+}
+
+fn encode_value(value: &Value) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(VERSION_SIZE + value.value.len());
+    bytes.extend_from_slice(&value.version.to_le_bytes());
+    bytes.extend_from_slice(value.value.as_bytes());
+    bytes
+}
+
+fn decode_value(bytes: &[u8]) -> Value {
+    let mut version_buffer = [0; VERSION_SIZE];
+    version_buffer.copy_from_slice(&bytes[..VERSION_SIZE]);
+    Value {
+        value: String::from_utf8_lossy(&bytes[VERSION_SIZE..]).to_string(),
+        version: i32::from_le_bytes(version_buffer),
+        state: ValueStatus::Ok,
+        value_disk_addr: 0,
+        key_disk_addr: 0,
+        opp_id: 0,
+    }
+}
+
+fn encode_metadata(metadata: &DatabaseMataData) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(U64_SIZE + VERSION_SIZE);
+    bytes.extend_from_slice(&(metadata.id as u64).to_le_bytes());
+    bytes.extend_from_slice(&metadata.consensus_strategy.to_le_bytes());
+    bytes
+}
+
+fn decode_metadata(bytes: &[u8]) -> DatabaseMataData {
+    let mut id_buffer = [0; U64_SIZE];
+    id_buffer.copy_from_slice(&bytes[..U64_SIZE]);
+    let mut strategy_buffer = [0; VERSION_SIZE];
+    strategy_buffer.copy_from_slice(&bytes[U64_SIZE..U64_SIZE + VERSION_SIZE]);
+    DatabaseMataData::new(
+        u64::from_le_bytes(id_buffer) as usize,
+        ConsensuStrategy::from(i32::from_le_bytes(strategy_buffer)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bo::Change;
+    use futures::channel::mpsc::{channel, Receiver, Sender};
+    use std::fs;
+
+    fn create_dbs() -> Arc<Databases> {
+        let (sender, _): (Sender<String>, Receiver<String>) = channel(100);
+        Arc::new(Databases::new(
+            String::from(""),
+            String::from(""),
+            String::from(""),
+            String::from(""),
+            sender.clone(),
+            sender.clone(),
+            HashMap::new(),
+            1 as u128,
+            true,
+        ))
+    }
+
+    fn open_test_storage(name: &str, cache_keys: usize) -> EmbeddedStorage {
+        let file_name = format!("/tmp/dbs-test-{}/{}.redb", thread_id::get(), name);
+        if Path::new(&file_name).exists() {
+            fs::remove_file(&file_name).unwrap();
+        }
+        EmbeddedStorage::open(&file_name, cache_keys)
+    }
+
+    fn create_test_db(name: &str) -> Database {
+        let db = Database::new(
+            name.to_string(),
+            DatabaseMataData::new(7, ConsensuStrategy::Arbiter),
+        );
+        db.set_value(&Change::new(String::from("$$token"), String::from("t"), -1));
+        for (key, value) in [("c", "3"), ("a", "1"), ("b", "2"), ("d", "4")] {
+            db.set_value(&Change::new(key.to_string(), value.to_string(), -1));
+        }
+        db
+    }
+
+    #[test]
+    fn should_store_and_load_dbs_keeping_only_system_keys_in_memory() {
+        let storage = open_test_storage("embedded-load", 100);
+        let db_name = String::from("embedded-load-test");
+        let db = create_test_db(&db_name);
+        assert_eq!(storage.store_db(&db, &db_name, false), 5);
+        db.remove_value(String::from("d"));
+        assert_eq!(storage.store_db(&db, &db_name, false), 1);
+
+        let dbs = create_dbs();
+        storage.load_all_dbs(&dbs);
+        let dbs_map = dbs.map.read().unwrap();
+        let loaded = dbs_map.get(&db_name).unwrap();
+        assert_eq!(loaded.metadata.id, 7);
+        assert_eq!(
+            loaded.metadata.consensus_strategy,
+            ConsensuStrategy::Arbiter
+        );
+        assert_eq!(loaded.count_keys(), 1);
+        assert_eq!(loaded.get_value(String::from("$$token")).unwrap(), "t");
+
+        let b = loaded.get_value(String::from("b")).unwrap();
+        assert_eq!(b.value, "2");
+        assert_eq!(b.version, 0);
+        assert_eq!(b.state, ValueStatus::Ok);
+        assert_eq!(loaded.get_value(String::from("d")), None);
+        assert_eq!(loaded.count_keys(), 2);
+
+        assert_eq!(
+            loaded.list_keys(&String::from(""), false),
+            vec![String::from("a"), String::from("b"), String::from("c")]
+        );
+        assert_eq!(loaded.all_values().len(), 4);
+    }
+
+    #[test]
+    fn should_evict_cold_keys_and_read_them_back_from_disk() {
+        let storage = open_test_storage("embedded-evict", 2);
+        let db_name = String::from("embedded-evict-test");
+        let db = create_test_db(&db_name);
+        storage.store_db(&db, &db_name, false);
+        // The system key is never evicted
+        assert_eq!(db.count_keys(), 2);
+        assert_eq!(db.get_value(String::from("$$token")).unwrap(), "t");
+
+        assert_eq!(db.get_value(String::from("a")).unwrap(), "1");
+        db.set_value(&Change::new(String::from("c"), String::from("30"), 0));
+        let c = db.get_value(String::from("c")).unwrap();
+        assert_eq!(c.value, "30");
+        assert_eq!(c.version, 1);
+        assert_eq!(c.state, ValueStatus::Updated);
+        db.inc_value(String::from("b"), 1);
+        assert_eq!(db.get_value(String::from("b")).unwrap(), "3");
+
+        storage.store_db(&db, &db_name, false);
+        assert_eq!(storage.get_value(&db_name, "c").unwrap().version, 1);
+        assert_eq!(storage.get_value(&db_name, "b").unwrap(), "3");
+        assert_eq!(db.count_keys(), 2);
+    }
+
+    #[test]
+    fn should_list_and_delete_dbs() {
+        let storage = open_test_storage("embedded-delete", 100);
+        let db_name = String::from("embedded-delete-test");
+        storage.store_db(&create_test_db(&db_name), &db_name, false);
+        assert_eq!(storage.list_dbs(), vec![db_name.to_string()]);
+
+        storage.delete_db(&db_name).unwrap();
+        assert_eq!(storage.list_dbs(), Vec::<String>::new());
+        assert_eq!(storage.get_value(&db_name, "a"), None);
+        assert!(storage.read_metadata(&db_name).unwrap().is_none());
+    }
+}
@@ -0,0 +1,213 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use lazy_static::lazy_static;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::RwLock;
+
+use crate::configuration::{NUN_ENCRYPTION_KEY, NUN_ENCRYPTION_KEY_FILE};
+
+// 0xff never starts a valid utf-8 value, so plaintext records can't be mistaken for encrypted ones
+const MAGIC: &[u8] = b"\xffNUNENC1";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// AES-256-GCM keys by id, the first one encrypts and all of them decrypt
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Keyring {
+    active_key_id: Option<String>,
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl Keyring {
+    /// Parses `$key_id:$base64_key` entries separated by commas or new lines
+    pub fn parse(value: &str) -> Result<Keyring, String> {
+        let mut keyring = Keyring::default();
+        for entry in value
+            .split([',', '\n'])
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+        {
+            let (key_id, key) = match entry.split_once(':') {
+                Some((key_id, key)) if !key_id.is_empty() && key_id.len() <= u8::MAX as usize => {
+                    (key_id, key)
+                }
+                _ => return Err(String::from("Encryption keys must be $key_id:$base64_key")),
+            };
+            let key = match STANDARD.decode(key) {
+                Ok(key) if key.len() == KEY_SIZE => key,
+                _ => {
+                    return Err(format!(
+                        "Encryption key {} must be {} base64 encoded bytes",
+                        key_id, KEY_SIZE
+                    ))
+                }
+            };
+            if keyring.keys.insert(key_id.to_string(), key).is_some() {
+                return Err(format!("Duplicated encryption key {}", key_id));
+            }
+            if keyring.active_key_id.is_none() {
+                keyring.active_key_id = Some(key_id.to_string());
+            }
+        }
+        Ok(keyring)
+    }
+
+    /// Keys from NUN_ENCRYPTION_KEY, or from the NUN_ENCRYPTION_KEY_FILE file
+    pub fn from_env() -> Result<Keyring, String> {
+        if !NUN_ENCRYPTION_KEY.is_empty() {
+            Keyring::parse(&NUN_ENCRYPTION_KEY)
+        } else if !NUN_ENCRYPTION_KEY_FILE.is_empty() {
+            let keys = fs::read_to_string(NUN_ENCRYPTION_KEY_FILE.as_str()).map_err(|e| {
+                format!(
+                    "Could not read the encryption key file {}: {}",
+                    *NUN_ENCRYPTION_KEY_FILE, e
+                )
+            })?;
+            Keyring::parse(&keys)
+        } else {
+            Ok(Keyring::default())
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.active_key_id.is_some()
+    }
+
+    /// Record is the magic, key id length, key id, nonce, tag and the cipher text
+    pub fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>, String> {
+        let key_id = match &self.active_key_id {
+            Some(key_id) => key_id,
+            None => return Ok(plain.to_vec()),
+        };
+        let mut nonce = [0; NONCE_SIZE];
+        rand_bytes(&mut nonce).map_err(|e| e.to_string())?;
+        let header = record_header(key_id);
+        let mut tag = [0; TAG_SIZE];
+        let cipher_text = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.keys[key_id],
+            Some(&nonce),
+            &header,
+            plain,
+            &mut tag,
+        )
+        .map_err(|e| e.to_string())?;
+        let mut record = header;
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&tag);
+        record.extend_from_slice(&cipher_text);
+        Ok(record)
+    }
+
+    /// Plaintext records are returned as they are
+    pub fn decrypt(&self, record: &[u8]) -> Result<Vec<u8>, String> {
+        if !record.starts_with(MAGIC) {
+            return Ok(record.to_vec());
+        }
+        let invalid = || String::from("Invalid encrypted record");
+        let key_id_size = *record.get(MAGIC.len()).ok_or_else(invalid)? as usize;
+        let header_size = MAGIC.len() + 1 + key_id_size;
+        if record.len() < header_size + NONCE_SIZE + TAG_SIZE {
+            return Err(invalid());
+        }
+        let key_id = String::from_utf8_lossy(&record[MAGIC.len() + 1..header_size]);
+        let key = self
+            .keys
+            .get(key_id.as_ref())
+            .ok_or_else(|| format!("Unknown encryption key {}", key_id))?;
+        let (header, rest) = record.split_at(header_size);
+        let (nonce, rest) = rest.split_at(NONCE_SIZE);
+        let (tag, cipher_text) = rest.split_at(TAG_SIZE);
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(nonce),
+            header,
+            cipher_text,
+            tag,
+        )
+        .map_err(|_| format!("Could not decrypt a record with the key {}", key_id))
+    }
+}
+
+fn record_header(key_id: &str) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(key_id.len() as u8);
+    header.extend_from_slice(key_id.as_bytes());
+    header
+}
+
+lazy_static! {
+    static ref KEYRING: RwLock<Keyring> = RwLock::new(Keyring::default());
+}
+
+/// Sets the keys used to encrypt the values files and the S3 objects
+pub fn init_encryption(keyring: Keyring) {
+    if keyring.is_enabled() {
+        log::info!(
+            "Encryption at rest enabled with the key {}",
+            keyring.active_key_id.as_ref().unwrap()
+        );
+    }
+    *KEYRING.write().unwrap() = keyring;
+}
+
+pub fn encrypt_at_rest(plain: &[u8]) -> Vec<u8> {
+    KEYRING
+        .read()
+        .unwrap()
+        .encrypt(plain)
+        .expect("Could not encrypt the record")
+}
+
+pub fn decrypt_at_rest(record: &[u8]) -> Result<Vec<u8>, String> {
+    KEYRING.read().unwrap().decrypt(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> String {
+        STANDARD.encode([byte; KEY_SIZE])
+    }
+
+    #[test]
+    fn should_encrypt_and_decrypt_with_rotated_keys() {
+        let old_keyring = Keyring::parse(&format!("k1:{}", key(1))).unwrap();
+        let old_record = old_keyring.encrypt(b"jose").unwrap();
+        assert!(old_record.starts_with(MAGIC));
+        assert_ne!(&old_record[old_record.len() - 4..], b"jose");
+
+        let keyring = Keyring::parse(&format!("k2:{}\nk1:{}", key(2), key(1))).unwrap();
+        let record = keyring.encrypt(b"maria").unwrap();
+        assert_eq!(keyring.decrypt(&record).unwrap(), b"maria");
+        assert_eq!(keyring.decrypt(&old_record).unwrap(), b"jose");
+        assert!(old_keyring.decrypt(&record).is_err());
+    }
+
+    #[test]
+    fn should_keep_plaintext_records_readable() {
+        let keyring = Keyring::parse(&format!("k1:{}", key(1))).unwrap();
+        assert_eq!(keyring.decrypt(b"jose").unwrap(), b"jose");
+        assert_eq!(Keyring::default().encrypt(b"jose").unwrap(), b"jose");
+    }
+
+    #[test]
+    fn should_reject_invalid_keys_and_records() {
+        assert!(Keyring::parse("k1:c2hvcnQ=").is_err());
+        assert!(Keyring::parse(&key(1)).is_err());
+        assert!(Keyring::parse(&format!("k1:{},k1:{}", key(1), key(2))).is_err());
+
+        let keyring = Keyring::parse(&format!("k1:{}", key(1))).unwrap();
+        let mut record = keyring.encrypt(b"jose").unwrap();
+        let last = record.len() - 1;
+        record[last] ^= 1;
+        assert!(keyring.decrypt(&record).is_err());
+        assert!(keyring.decrypt(&record[..MAGIC.len() + 3]).is_err());
+    }
+}
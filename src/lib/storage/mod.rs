@@ -1,4 +1,104 @@
 pub mod common;
 pub mod disk;
+pub mod embedded;
+pub mod encryption;
 pub mod s3;
 pub mod s3_partition;
+
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+
+use crate::bo::{Database, Databases, StorageStrategy, Value};
+use crate::configuration::{NUN_READ_STORAGE_STRATEGY, NUN_WRITE_STORAGE_STRATEGY};
+
+use self::disk::NodeDrive;
+use self::embedded::EMBEDDED_STORAGE;
+use self::s3::S3Storage;
+use self::s3_partition::S3PartitionStorage;
+
+/// Where the databases are persisted. Disk, S3 and S3Patition are the built-in backends,
+/// an embedding application can provide its own with `set_storage_backend`.
+pub trait StorageBackend: Send + Sync {
+    /// Loads all stored databases into `dbs`
+    fn load_all_dbs(&self, dbs: &Arc<Databases>);
+    /// Stores the changed keys of `db` (all keys if `reclame_space`), returns the number of keys stored
+    fn store_db(&self, db: &Database, db_name: &str, reclame_space: bool) -> u32;
+    /// Removes all stored data of the database
+    fn delete_db(&self, db_name: &str) -> Result<(), String>;
+    /// Names of the stored databases
+    fn list_dbs(&self) -> Vec<String>;
+}
+
+/// Keys of a database that are stored but not kept in memory, read on demand by `Database`
+pub trait ColdStorage: Send + Sync {
+    fn get_value(&self, db_name: &str, key: &str) -> Option<Value>;
+    /// Stored keys sorted
+    fn list_keys(&self, db_name: &str) -> Vec<String>;
+    /// Stored values sorted by key
+    fn list_values(&self, db_name: &str) -> Vec<(String, Value)>;
+}
+
+lazy_static! {
+    static ref READ_STORAGE_BACKEND: RwLock<Arc<dyn StorageBackend>> =
+        RwLock::new(storage_backend_from_strategy(*NUN_READ_STORAGE_STRATEGY));
+    static ref WRITE_STORAGE_BACKEND: RwLock<Arc<dyn StorageBackend>> =
+        RwLock::new(storage_backend_from_strategy(*NUN_WRITE_STORAGE_STRATEGY));
+}
+
+pub fn storage_backend_from_strategy(strategy: StorageStrategy) -> Arc<dyn StorageBackend> {
+    match strategy {
+        StorageStrategy::Disk => Arc::new(NodeDrive {}),
+        StorageStrategy::S3 => Arc::new(S3Storage {}),
+        StorageStrategy::S3Patition => Arc::new(S3PartitionStorage {}),
+        StorageStrategy::Embedded => Arc::new(EMBEDDED_STORAGE.clone()),
+    }
+}
+
+/// Backend used to load the databases, defaults to NUN_STORAGE_READ_STRATEGY
+pub fn read_storage_backend() -> Arc<dyn StorageBackend> {
+    READ_STORAGE_BACKEND.read().unwrap().clone()
+}
+
+/// Backend used to store the databases, defaults to NUN_STORAGE_WRITE_STRATEGY
+pub fn write_storage_backend() -> Arc<dyn StorageBackend> {
+    WRITE_STORAGE_BACKEND.read().unwrap().clone()
+}
+
+/// Replaces both the read and the write backends, must be called before the databases are loaded
+pub fn set_storage_backend(backend: Arc<dyn StorageBackend>) {
+    set_read_storage_backend(backend.clone());
+    set_write_storage_backend(backend);
+}
+
+pub fn set_read_storage_backend(backend: Arc<dyn StorageBackend>) {
+    *READ_STORAGE_BACKEND.write().unwrap() = backend;
+}
+
+pub fn set_write_storage_backend(backend: Arc<dyn StorageBackend>) {
+    *WRITE_STORAGE_BACKEND.write().unwrap() = backend;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bo::{Change, ConsensuStrategy, DatabaseMataData};
+
+    #[test]
+    fn should_store_list_and_delete_dbs_with_the_disk_backend() {
+        let backend = storage_backend_from_strategy(StorageStrategy::Disk);
+        let db_name = String::from("storage-backend-test");
+        let db = Database::new(
+            db_name.to_string(),
+            DatabaseMataData::new(1, ConsensuStrategy::Newer),
+        );
+        db.set_value(&Change::new(String::from("name"), String::from("jose"), -1));
+
+        assert_eq!(backend.store_db(&db, &db_name, true), 1);
+        assert!(backend.list_dbs().contains(&db_name));
+
+        backend.delete_db(&db_name).unwrap();
+        assert!(!backend.list_dbs().contains(&db_name));
+        assert_eq!(backend.delete_db(&db_name), Ok(()));
+    }
+}
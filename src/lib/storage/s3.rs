@@ -4,24 +4,31 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread;
 
-use aws_config::Region;
-use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::Client;
 use bytes::{BufMut, BytesMut};
 use futures::io::Cursor;
 use futures::{AsyncReadExt, AsyncSeekExt};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::Read;
 use tokio::runtime::Runtime;
 
 use crate::bo::{ConsensuStrategy, Database, DatabaseMataData, Databases, Value, ValueStatus};
 use crate::configuration::{
-    NUN_S3_API_URL, NUN_S3_BUCKET, NUN_S3_KEY_ID, NUN_S3_MAX_INFLIGHT_REQUESTS, NUN_S3_PREFIX,
-    NUN_S3_READ_PREFIX, NUN_S3_SECRET_KEY,
+    NUN_S3_API_URL, NUN_S3_BUCKET, NUN_S3_MAX_DELTA_SEGMENTS, NUN_S3_PREFIX, NUN_S3_READ_PREFIX,
 };
 
-use super::common::get_keys_to_update;
+use super::common::{
+    await_thread_availability, build_s3_client, db_names_from_s3_objects, delete_db_from_s3,
+    get_keys_to_update, list_s3_objects, release_lock, remove_stored_deleted_keys,
+};
+use super::encryption::{decrypt_at_rest, encrypt_at_rest};
+use super::StorageBackend;
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+const SEGMENTS_DIR: &str = "segments";
 
 const VERSION_SIZE: usize = 4;
-const ADDR_SIZE: usize = 8;
 const U64_SIZE: usize = 8;
 // const U32_SIZE: usize = 4;
 
@@ -31,117 +38,206 @@ const OP_TIME_SIZE: usize = 8;
 const OP_OP_SIZE: usize = 1;
 const OP_RECORD_SIZE: usize = OP_TIME_SIZE + OP_DB_ID_SIZE + OP_KEY_SIZE + OP_OP_SIZE;
 
-fn get_key_disk_size(key_size: usize) -> u64 {
-    (U64_SIZE + key_size + ADDR_SIZE + VERSION_SIZE) as u64
-}
-
 pub struct S3Storage {}
 impl S3Storage {
+    /// Uploads the changed keys as a new delta segment and adds it to the database manifest.
+    /// When `reclame_space`, when there is no manifest yet or when the manifest has
+    /// NUN_S3_MAX_DELTA_SEGMENTS segments, all keys are uploaded in one segment that replaces
+    /// the previous ones (consolidation).
     pub fn storage_data_on_cloud(db: &Database, reclame_space: bool, db_name: &String) -> u32 {
-        let mut changed_keys = 0;
         let rt = Runtime::new().unwrap();
-        let keys_to_update = get_keys_to_update(db, reclame_space);
+        let client = build_s3_client();
+        // Without the current manifest the new one would drop the segments already uploaded
+        let current_manifest = match rt.block_on(S3Storage::read_manifest(
+            &client,
+            NUN_S3_PREFIX.as_str(),
+            db_name,
+        )) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                log::error!("Snapshot of {} aborted, {}", db_name, e);
+                return 0;
+            }
+        };
+        let consolidate = match &current_manifest {
+            Some(manifest) => reclame_space || manifest.needs_consolidation(),
+            None => true,
+        };
+        let mut manifest = current_manifest.unwrap_or_default();
+        let keys_to_update = get_keys_to_update(db, consolidate);
+        if keys_to_update.is_empty() && !consolidate {
+            log::debug!("Nothing changed in {}, no segment to upload", db_name);
+            return 0;
+        }
+        // Segments are encrypted as a whole, the manifest has no values and stays in plaintext
+        let segment_buffer = BytesMut::from(
+            encrypt_at_rest(&encode_segment(&keys_to_update, consolidate)).as_slice(),
+        );
+        let segment_name = manifest.next_segment_name();
+        log::debug!(
+            "Will upload the segment {} of {} with {} keys, consolidate: {}",
+            segment_name,
+            db_name,
+            keys_to_update.len(),
+            consolidate
+        );
+        if rt
+            .block_on(S3Storage::store_buffer_to_s3(
+                segment_buffer,
+                &format!("{}/{}", db_name, segment_name),
+            ))
+            .is_none()
+        {
+            log::error!("Fail to upload the segment {} of {}", segment_name, db_name);
+            panic!("Fail to upload the segment {} of {}", segment_name, db_name);
+        }
+        let replaced_segments = manifest.add_segment(segment_name, consolidate, &db.metadata);
+        let manifest_buffer = BytesMut::from(serde_json::to_vec(&manifest).unwrap().as_slice());
+        if rt
+            .block_on(S3Storage::store_buffer_to_s3(
+                manifest_buffer,
+                &format!("{}/{}", db_name, MANIFEST_FILE_NAME),
+            ))
+            .is_none()
+        {
+            log::error!("Fail to upload the manifest of {}", db_name);
+            panic!("Fail to upload the manifest of {}", db_name);
+        }
+        // Only removed after the manifest stops pointing to them
+        rt.block_on(S3Storage::delete_segments(
+            &client,
+            db_name,
+            &replaced_segments,
+        ));
+
+        let mut stored_deleted = vec![];
+        for (key, value) in &keys_to_update {
+            if value.state == ValueStatus::Deleted {
+                stored_deleted.push((key.to_string(), value.version));
+            } else {
+                db.set_value_as_ok(key, value, 0, 0, Databases::next_op_log_id());
+            }
+        }
+        remove_stored_deleted_keys(db, &stored_deleted);
+        log::debug!("snapshoted {} keys", keys_to_update.len());
+        keys_to_update.len() as u32
+    }
 
-        let key_buffer = BytesMut::with_capacity(OP_RECORD_SIZE * 10);
-        //@todo should this really be te buffer size of the values????
-        let value_buffer = BytesMut::with_capacity(OP_RECORD_SIZE * 10);
+    async fn read_manifest(
+        client: &Client,
+        prefix: &str,
+        db_name: &String,
+    ) -> Result<Option<SnapshotManifest>, String> {
+        let manifest_key = format!("{}/{}/{}", prefix, db_name, MANIFEST_FILE_NAME);
+        let object = match client
+            .get_object()
+            .bucket(NUN_S3_BUCKET.as_str())
+            .key(&manifest_key)
+            .send()
+            .await
         {
-            let mut keys_file = key_buffer;
-            let mut values_file = value_buffer;
-            let current_key_file_size = 0;
-
-            //let (mut values_file, current_value_file_size) = get_values_file_append_mode(&db_name, reclame_space);
-            // To inplace update
-            log::debug!("current_key_file_size: {}", current_key_file_size);
-
-            let mut value_addr = u64::from(0 as u64); //current_value_file_size;
-            let mut next_key_addr = current_key_file_size;
-
-            for (key, value) in keys_to_update {
-                changed_keys = changed_keys + 1;
-
-                values_file.put_slice(&value.value.len().to_le_bytes());
-                //8bytes
-                let value_as_bytes = value.value.as_bytes();
-                //Nth bytes
-                values_file.put_slice(&value_as_bytes);
-                //4 bytes
-                values_file.put_slice(&value.state.to_le_bytes());
-                let record_size = (U64_SIZE + value_as_bytes.len() + VERSION_SIZE) as u64;
-                log::debug!(
-                    "Write key: {}, addr: {} value_addr: {}, record_size: {}",
-                    key,
-                    next_key_addr,
-                    value_addr,
-                    record_size
-                );
-                // Append key file
-                // Write key
-
-                let len = key.len();
-
-                //8bytes
-                keys_file.put_slice(&len.to_le_bytes());
-                //Nth bytes
-                keys_file.put_slice(&key.as_bytes());
-                //4 bytes
-                keys_file.put_slice(&value.version.to_le_bytes());
-                //8 bytes
-                keys_file.put_slice(&value_addr.to_le_bytes());
-                let key_size = get_key_disk_size(key.len());
-                db.set_value_as_ok(
-                    &key,
-                    &value,
-                    value_addr,
-                    next_key_addr,
-                    Databases::next_op_log_id(),
-                );
-                value_addr = value_addr + record_size;
-                log::debug!("Next Value addr: {}", value_addr);
-                next_key_addr = next_key_addr + key_size;
+            Ok(object) => object,
+            Err(e)
+                if e.as_service_error()
+                    .map(|e| e.is_no_such_key())
+                    .unwrap_or(false) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(format!("Fail to read the manifest {}: {}", manifest_key, e)),
+        };
+        let bytes = match object.body.collect().await {
+            Ok(data) => data.into_bytes(),
+            Err(e) => return Err(format!("Fail to read the manifest {}: {}", manifest_key, e)),
+        };
+        match serde_json::from_slice(&bytes) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(e) => Err(format!("Invalid manifest {}: {}", manifest_key, e)),
+        }
+    }
+
+    async fn delete_segments(client: &Client, db_name: &String, segments: &[String]) {
+        for segment in segments {
+            let segment_key = format!("{}/{}/{}", *NUN_S3_PREFIX, db_name, segment);
+            if let Err(e) = client
+                .delete_object()
+                .bucket(NUN_S3_BUCKET.as_str())
+                .key(&segment_key)
+                .send()
+                .await
+            {
+                log::warn!("Fail to delete the old segment {}: {}", segment_key, e);
             }
+        }
+    }
 
-            //keys_file.flush().unwrap();
-            rt.block_on(S3Storage::store_buffer_to_s3(
-                keys_file,
-                &format!("{}/nun.keys", db_name),
-            ));
-            rt.block_on(S3Storage::store_buffer_to_s3(
-                values_file,
-                &format!("{}/nun.values", db_name),
-            ));
+    /// Reads the database from the segments listed in its manifest, databases stored before
+    /// the manifests existed are read from the nun.keys and nun.values files
+    fn read_data_from_cloud(db_name: &String) -> Option<Database> {
+        let rt = Runtime::new().unwrap();
+        let client = build_s3_client();
+        let manifest = match rt.block_on(S3Storage::read_manifest(
+            &client,
+            NUN_S3_READ_PREFIX.as_str(),
+            db_name,
+        )) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => return S3Storage::read_legacy_data_from_cloud(db_name),
+            Err(e) => {
+                log::error!("{}", e);
+                return None;
+            }
+        };
+        let mut value_data: HashMap<String, Value> = HashMap::new();
+        for segment in &manifest.segments {
+            let segment_key = format!("{}/{}/{}", *NUN_S3_READ_PREFIX, db_name, segment);
+            let bytes = rt.block_on(async {
+                let object = client
+                    .get_object()
+                    .bucket(NUN_S3_BUCKET.as_str())
+                    .key(&segment_key)
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                object
+                    .body
+                    .collect()
+                    .await
+                    .map(|data| data.into_bytes())
+                    .map_err(|e| e.to_string())
+            });
+            match bytes {
+                Ok(bytes) => match decrypt_at_rest(&bytes) {
+                    Ok(segment) => apply_segment(&segment, &mut value_data),
+                    Err(e) => {
+                        log::error!("Fail to decrypt the segment {}: {}", segment_key, e);
+                        return None;
+                    }
+                },
+                Err(e) => {
+                    log::error!("Fail to read the segment {}: {}", segment_key, e);
+                    return None;
+                }
+            }
         }
-        //keys_file.
-        //values_file.flush().unwrap();
-        //write_metadata_file(db_name, db);
-        //log::debug!("snapshoted {} keys", changed_keys);
-        changed_keys
+        Some(Database::create_db_from_value_hash(
+            db_name.to_string(),
+            value_data,
+            manifest.metadata(),
+        ))
     }
 
     async fn store_buffer_to_s3(mut buff: BytesMut, db_name: &String) -> Option<bool> {
         let key = format!("{}/{}", NUN_S3_PREFIX.to_string(), db_name);
 
-        let url = NUN_S3_API_URL.as_str();
         let bucket = NUN_S3_BUCKET.as_str();
-        let key_id = NUN_S3_KEY_ID.as_str();
-        let secret_key = NUN_S3_SECRET_KEY.as_str();
         log::debug!(
-            "Reading from s3, buket: {}, key_id: {}, secret_key: {}, server: {}\n",
+            "Writing to s3, buket: {}, server: {}\n",
             bucket,
-            key_id,
-            secret_key,
             NUN_S3_API_URL.as_str()
         );
 
-        let cred = Credentials::new(key_id, secret_key, None, None, "loaded-from-custom-env");
-        let s3_config = aws_sdk_s3::config::Builder::new()
-            .endpoint_url(url)
-            .credentials_provider(cred)
-            .region(Region::new("us-east"))
-            .force_path_style(true)
-            .build();
-
-        let client = aws_sdk_s3::Client::from_conf(s3_config);
+        let client = build_s3_client();
         let body = aws_sdk_s3::primitives::ByteStream::from(buff.split().freeze());
 
         match client
@@ -157,26 +253,21 @@ impl S3Storage {
         }
     }
 
-    fn read_data_from_cloud(db_name: &String) -> Option<Database> {
+    fn read_legacy_data_from_cloud(db_name: &String) -> Option<Database> {
         let keys_key_file = format!("{}/{}/nun.keys", NUN_S3_READ_PREFIX.to_string(), db_name);
         let values_key_file = format!("{}/{}/nun.values", NUN_S3_READ_PREFIX.to_string(), db_name);
 
-        let url = NUN_S3_API_URL.as_str();
         let bucket = NUN_S3_BUCKET.as_str();
-        let key_id = NUN_S3_KEY_ID.as_str();
-        let secret_key = NUN_S3_SECRET_KEY.as_str();
-        log::debug!("Reading from s3, key: {}, values: {}, buket: {}, key_id: {}, secret_key: {}, server: {}\n", keys_key_file, values_key_file, bucket, key_id, secret_key, url);
+        log::debug!(
+            "Reading from s3, key: {}, values: {}, buket: {}, server: {}\n",
+            keys_key_file,
+            values_key_file,
+            bucket,
+            NUN_S3_API_URL.as_str()
+        );
 
-        let cred = Credentials::new(key_id, secret_key, None, None, "loaded-from-custom-env");
         let mut value_data: HashMap<String, Value> = HashMap::new();
-        let s3_config = aws_sdk_s3::config::Builder::new()
-            .endpoint_url(NUN_S3_API_URL.as_str())
-            .credentials_provider(cred)
-            .region(Region::new("us-east"))
-            .force_path_style(true)
-            .build();
-
-        let client = aws_sdk_s3::Client::from_conf(s3_config);
+        let client = build_s3_client();
         let rt = Runtime::new().unwrap();
         let mut values_cursor = rt.block_on(async {
             let r = client
@@ -276,46 +367,11 @@ impl S3Storage {
 
     pub fn load_all_dbs_from_cloud(dbs: &Arc<Databases>) {
         let start = std::time::Instant::now();
-        let bucket = NUN_S3_BUCKET.as_str();
-        let key_id = NUN_S3_KEY_ID.as_str();
-        let secret_key = NUN_S3_SECRET_KEY.as_str();
-
-        let cred = Credentials::new(key_id, secret_key, None, None, "loaded-from-custom-env");
-        let s3_config = aws_sdk_s3::config::Builder::new()
-            .endpoint_url(NUN_S3_API_URL.as_str())
-            .credentials_provider(cred)
-            .region(Region::new("us-east"))
-            .force_path_style(true)
-            .build();
-
-        let client = aws_sdk_s3::Client::from_conf(s3_config);
+        let client = build_s3_client();
         let rt = Runtime::new().unwrap();
-        let objects = rt.block_on(async {
-            client
-                .list_objects_v2()
-                .set_prefix(Some(NUN_S3_READ_PREFIX.to_string()))
-                .bucket(bucket)
-                .send()
-                .await
-                .unwrap()
-                .contents()
-                .into_iter()
-                .flat_map(|x| x.key())
-                .map(ToString::to_string)
-                .collect::<Vec<String>>()
-        });
+        let objects = list_s3_objects(&rt, &client, NUN_S3_READ_PREFIX.as_str());
         log::debug!("Objects: {:?}", objects);
-        let prefix_to_clean = format!("{}/", &NUN_S3_READ_PREFIX.to_string());
-        let db_names = objects
-            .iter()
-            .filter(|x| x.contains("nun.values")) // Filter only the values files
-            .map(|x| x.to_string().replacen(&prefix_to_clean, "", 1))
-            .map(|x| {
-                let mut paths = x.split("/").collect::<Vec<&str>>();
-                paths.truncate(paths.len() - 1);
-                paths.join("/")
-            })
-            .collect::<Vec<String>>();
+        let db_names = db_names_from_objects(&objects);
         log::debug!("DbsNames: {:?}", db_names);
         let running_threads = Arc::new(AtomicUsize::new(0));
         let dbs_threads: Vec<thread::JoinHandle<()>> = db_names
@@ -344,35 +400,158 @@ impl S3Storage {
     }
 }
 
-fn release_lock(running_threads: &Arc<AtomicUsize>) {
-    running_threads.fetch_sub(1, Ordering::SeqCst);
+impl StorageBackend for S3Storage {
+    fn load_all_dbs(&self, dbs: &Arc<Databases>) {
+        S3Storage::load_all_dbs_from_cloud(dbs)
+    }
+
+    fn store_db(&self, db: &Database, db_name: &str, reclame_space: bool) -> u32 {
+        S3Storage::storage_data_on_cloud(db, reclame_space, &db_name.to_string())
+    }
+
+    fn delete_db(&self, db_name: &str) -> Result<(), String> {
+        delete_db_from_s3(db_name)
+    }
+
+    fn list_dbs(&self) -> Vec<String> {
+        let rt = Runtime::new().unwrap();
+        let objects = list_s3_objects(&rt, &build_s3_client(), NUN_S3_READ_PREFIX.as_str());
+        db_names_from_objects(&objects)
+    }
 }
-fn await_thread_availability(running_threads: &Arc<AtomicUsize>) {
-    loop {
-        let prev_val = running_threads.load(Ordering::Acquire);
-        if prev_val > *NUN_S3_MAX_INFLIGHT_REQUESTS {
-            log::debug!("Too many threads running, waiting for a slot to open");
-            thread::sleep(std::time::Duration::from_millis(10));
-            continue;
+
+/// Segments of a database in the order they must be applied, the first one is the last
+/// consolidation
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct SnapshotManifest {
+    pub segments: Vec<String>,
+    pub next_segment: u64,
+    pub db_id: usize,
+    pub consensus_strategy: String,
+}
+
+impl SnapshotManifest {
+    fn needs_consolidation(&self) -> bool {
+        self.segments.len() >= *NUN_S3_MAX_DELTA_SEGMENTS
+    }
+
+    /// The op id makes the name unique, an upload never overwrites a segment of another manifest
+    fn next_segment_name(&self) -> String {
+        format!(
+            "{}/{:020}-{}.nun",
+            SEGMENTS_DIR,
+            self.next_segment,
+            Databases::next_op_log_id()
+        )
+    }
+
+    /// Adds the uploaded segment, returns the segments it replaced
+    fn add_segment(
+        &mut self,
+        segment_name: String,
+        consolidate: bool,
+        metadata: &DatabaseMataData,
+    ) -> Vec<String> {
+        let replaced_segments = if consolidate {
+            std::mem::take(&mut self.segments)
         } else {
-            log::debug!("Thread running await: {}", prev_val);
+            vec![]
+        };
+        self.segments.push(segment_name);
+        self.next_segment += 1;
+        self.db_id = metadata.id;
+        self.consensus_strategy = metadata.consensus_strategy.to_string();
+        replaced_segments
+    }
+
+    fn metadata(&self) -> DatabaseMataData {
+        DatabaseMataData::new(
+            self.db_id,
+            ConsensuStrategy::from(self.consensus_strategy.to_string()),
+        )
+    }
+}
+
+/// Each record is key length, key, value length, value, version and status. Deleted keys are
+/// only written in delta segments, a consolidated segment has only the existing keys
+fn encode_segment(keys_to_update: &[(String, Value)], consolidate: bool) -> BytesMut {
+    let mut buffer = BytesMut::with_capacity(OP_RECORD_SIZE * 10);
+    for (key, value) in keys_to_update {
+        if consolidate && value.state == ValueStatus::Deleted {
+            continue;
         }
-        match running_threads.compare_exchange(
-            prev_val,
-            prev_val + 1,
-            Ordering::Acquire,
-            Ordering::Acquire,
-        ) {
-            Ok(_) => break,
-            Err(_) => {
-                log::debug!("Thread failed to update the counter : {}", prev_val);
-                thread::sleep(std::time::Duration::from_millis(10));
-                continue;
+        buffer.put_slice(&key.len().to_le_bytes());
+        buffer.put_slice(key.as_bytes());
+        buffer.put_slice(&value.value.len().to_le_bytes());
+        buffer.put_slice(value.value.as_bytes());
+        buffer.put_slice(&value.version.to_le_bytes());
+        buffer.put_slice(&value.state.to_le_bytes());
+    }
+    buffer
+}
+
+fn apply_segment(segment: &[u8], value_data: &mut HashMap<String, Value>) {
+    let mut segment_cursor = std::io::Cursor::new(segment);
+    while (segment_cursor.position() as usize) < segment.len() {
+        match read_segment_record(&mut segment_cursor) {
+            Ok((key, value)) if value.state == ValueStatus::Deleted => {
+                value_data.remove(&key);
+            }
+            Ok((key, value)) => {
+                value_data.insert(key, value);
+            }
+            Err(e) => {
+                log::error!("Invalid segment record: {}", e);
+                break;
             }
         }
     }
 }
 
+fn read_segment_record(
+    segment_cursor: &mut std::io::Cursor<&[u8]>,
+) -> std::io::Result<(String, Value)> {
+    let key = read_segment_str(segment_cursor)?;
+    let value = read_segment_str(segment_cursor)?;
+    let mut version_buffer = [0; VERSION_SIZE];
+    segment_cursor.read_exact(&mut version_buffer)?;
+    let mut status_buffer = [0; VERSION_SIZE];
+    segment_cursor.read_exact(&mut status_buffer)?;
+    let state = match ValueStatus::from(i32::from_le_bytes(status_buffer)) {
+        ValueStatus::Deleted => ValueStatus::Deleted,
+        _ => ValueStatus::Ok,
+    };
+    Ok((
+        key,
+        Value {
+            value,
+            version: i32::from_le_bytes(version_buffer),
+            state,
+            value_disk_addr: 0,
+            key_disk_addr: 0,
+            opp_id: Databases::next_op_log_id(),
+        },
+    ))
+}
+
+fn read_segment_str(segment_cursor: &mut std::io::Cursor<&[u8]>) -> std::io::Result<String> {
+    let mut length_buffer = [0; U64_SIZE];
+    segment_cursor.read_exact(&mut length_buffer)?;
+    let mut str_buffer = vec![0; usize::from_le_bytes(length_buffer)];
+    segment_cursor.read_exact(&mut str_buffer)?;
+    Ok(String::from_utf8_lossy(&str_buffer).to_string())
+}
+
+/// Databases with a manifest plus the ones stored before the manifests existed
+fn db_names_from_objects(objects: &[String]) -> Vec<String> {
+    let prefix = NUN_S3_READ_PREFIX.as_str();
+    let mut db_names = db_names_from_s3_objects(objects, prefix, MANIFEST_FILE_NAME);
+    db_names.extend(db_names_from_s3_objects(objects, prefix, "nun.values"));
+    db_names.sort();
+    db_names.dedup();
+    db_names
+}
+
 #[cfg(test)]
 mod tests {
     use core::time;
@@ -455,6 +634,88 @@ mod tests {
         assert!(db1.get_value("this_is_totally_new".to_string()).unwrap() == String::from("jose"));
     }
 
+    fn value_with_state(value: &str, version: i32, state: ValueStatus) -> Value {
+        Value {
+            value: value.to_string(),
+            version,
+            state,
+            value_disk_addr: 0,
+            key_disk_addr: 0,
+            opp_id: 0,
+        }
+    }
+
+    #[test]
+    fn should_apply_delta_segments_in_order() {
+        let base = encode_segment(
+            &[
+                (
+                    String::from("a"),
+                    value_with_state("1", 1, ValueStatus::New),
+                ),
+                (String::from("b"), value_with_state("2", 1, ValueStatus::Ok)),
+                (
+                    String::from("c"),
+                    value_with_state("x", 2, ValueStatus::Deleted),
+                ),
+            ],
+            true,
+        );
+        let delta = encode_segment(
+            &[
+                (
+                    String::from("a"),
+                    value_with_state("x", 2, ValueStatus::Deleted),
+                ),
+                (
+                    String::from("b"),
+                    value_with_state("3", 2, ValueStatus::Updated),
+                ),
+            ],
+            false,
+        );
+        let mut value_data = HashMap::new();
+        apply_segment(&base, &mut value_data);
+        assert_eq!(value_data.len(), 2);
+        apply_segment(&delta, &mut value_data);
+        assert_eq!(value_data.len(), 1);
+        let b = value_data.get("b").unwrap();
+        assert_eq!(b.value, "3");
+        assert_eq!(b.version, 2);
+        assert_eq!(b.state, ValueStatus::Ok);
+    }
+
+    #[test]
+    fn should_consolidate_the_manifest_segments() {
+        let metadata = DatabaseMataData::new(3, ConsensuStrategy::Arbiter);
+        let mut manifest = SnapshotManifest::default();
+        for _ in 0..*NUN_S3_MAX_DELTA_SEGMENTS {
+            assert!(!manifest.needs_consolidation());
+            let segment_name = manifest.next_segment_name();
+            assert_eq!(
+                manifest.add_segment(segment_name, false, &metadata),
+                Vec::<String>::new()
+            );
+        }
+        assert!(manifest.needs_consolidation());
+        assert!(manifest.segments[1].starts_with("segments/00000000000000000001-"));
+        assert_ne!(manifest.next_segment_name(), manifest.next_segment_name());
+
+        let segment_name = manifest.next_segment_name();
+        let replaced = manifest.add_segment(segment_name, true, &metadata);
+        assert_eq!(replaced.len(), *NUN_S3_MAX_DELTA_SEGMENTS);
+        assert_eq!(manifest.segments.len(), 1);
+
+        let json = serde_json::to_vec(&manifest).unwrap();
+        let parsed: SnapshotManifest = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed, manifest);
+        assert_eq!(parsed.metadata().id, 3);
+        assert_eq!(
+            parsed.metadata().consensus_strategy,
+            ConsensuStrategy::Arbiter
+        );
+    }
+
     fn create_test_db() -> Database {
         let db_name = String::from("test-db");
         let mut hash = HashMap::new();
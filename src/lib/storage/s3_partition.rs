@@ -3,12 +3,9 @@ use std::hash::DefaultHasher;
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread;
 
-use aws_config::Region;
-use aws_sdk_s3::config::Credentials;
 use aws_sdk_s3::Client;
 use bytes::{BufMut, BytesMut};
 use futures::io::Cursor;
@@ -16,15 +13,16 @@ use futures::AsyncReadExt;
 use std::collections::HashMap;
 use tokio::runtime::Runtime;
 
+use super::common::{
+    await_thread_availability, build_s3_client, db_names_from_s3_objects, delete_db_from_s3,
+    get_keys_by_filter, get_keys_to_update, list_s3_objects, release_lock,
+};
+use super::encryption::{decrypt_at_rest, encrypt_at_rest};
+use super::StorageBackend;
 use crate::bo::{ConsensuStrategy, Database, DatabaseMataData, Databases, Value, ValueStatus};
 use crate::configuration::{
-    NUN_S3_API_URL, NUN_S3_BUCKET, NUN_S3_KEY_ID, NUN_S3_MAX_INFLIGHT_REQUESTS,
-    NUN_S3_NUMBER_OF_PARTITIONS, NUN_S3_PREFIX, NUN_S3_READ_PREFIX, NUN_S3_RETRY,
-    NUN_S3_SECRET_KEY,
+    NUN_S3_BUCKET, NUN_S3_NUMBER_OF_PARTITIONS, NUN_S3_PREFIX, NUN_S3_READ_PREFIX, NUN_S3_RETRY,
 };
-use crate::storage::common::get_keys_by_filter;
-
-use super::common::get_keys_to_update;
 
 const VERSION_SIZE: usize = 4;
 const U64_SIZE: usize = 8;
@@ -108,7 +106,7 @@ impl S3PartitionStorage {
                             partition
                         );
                         let store_result = rt.block_on(S3PartitionStorage::store_buffer_to_s3(
-                            file_buffer,
+                            BytesMut::from(encrypt_at_rest(&file_buffer).as_slice()),
                             &format!("{}/{}.nun", db_name, partition),
                         ));
                         match store_result {
@@ -189,6 +187,16 @@ impl S3PartitionStorage {
                                     partition.split(".").next().unwrap().parse::<u64>().unwrap();
                                 log::debug!("Reading the file {}", &partition_file);
                                 let nun_file = r.body.collect().await.unwrap().into_bytes();
+                                let nun_file = match decrypt_at_rest(&nun_file) {
+                                    Ok(nun_file) => bytes::Bytes::from(nun_file),
+                                    Err(e) => {
+                                        log::error!("{} trying to decrypt {}", e, partition_file);
+                                        return Err(format!(
+                                            "Fail to decrypt partition {} from s3.",
+                                            partition
+                                        ));
+                                    }
+                                };
 
                                 let mut file_cursor = Cursor::new(nun_file);
                                 let mut key_length_buffer = [0; U64_SIZE];
@@ -265,36 +273,11 @@ impl S3PartitionStorage {
         );
         let rt = Runtime::new().unwrap();
         let start = std::time::Instant::now();
-        let bucket = NUN_S3_BUCKET.as_str();
         let client = build_s3_client();
-        let objects = rt.block_on(async {
-            client
-                .list_objects_v2()
-                .set_prefix(Some(NUN_S3_READ_PREFIX.to_string()))
-                .bucket(bucket)
-                .send()
-                .await
-                .unwrap()
-                .contents()
-                .into_iter()
-                .flat_map(|x| x.key())
-                .map(ToString::to_string)
-                .collect::<Vec<String>>()
-        });
+        let objects = list_s3_objects(&rt, &client, NUN_S3_READ_PREFIX.as_str());
         log::debug!("Objects: {:?}", objects);
-        let prefix_to_clean = format!("{}/", &NUN_S3_READ_PREFIX.to_string());
-        log::debug!("Prefix to clean: {}", prefix_to_clean);
-        let mut db_names = objects
-            .iter()
-            .filter(|x| x.contains(".nun")) // Filter only the values files
-            .map(|x| x.to_string().replacen(&prefix_to_clean, "", 1))
-            .map(|x| {
-                let mut paths = x.split("/").collect::<Vec<&str>>();
-                paths.truncate(paths.len() - 1);
-                paths.join("/")
-            })
-            .collect::<Vec<String>>();
-        db_names.dedup();
+        // Filter only the partition files
+        let db_names = db_names_from_s3_objects(&objects, NUN_S3_READ_PREFIX.as_str(), ".nun");
         log::debug!("DbsNames: {:?}", db_names);
         let running_threads = Arc::new(AtomicUsize::new(0));
         let dbs_threads: Vec<thread::JoinHandle<()>> = db_names
@@ -322,20 +305,6 @@ impl S3PartitionStorage {
     }
 }
 
-fn build_s3_client() -> Client {
-    let key_id = NUN_S3_KEY_ID.as_str();
-    let secret_key = NUN_S3_SECRET_KEY.as_str();
-    let cred = Credentials::new(key_id, secret_key, None, None, "loaded-from-custom-env");
-    let s3_config = aws_sdk_s3::config::Builder::new()
-        .endpoint_url(NUN_S3_API_URL.as_str())
-        .credentials_provider(cred)
-        .region(Region::new("us-east"))
-        .force_path_style(true)
-        .build();
-    let client = aws_sdk_s3::Client::from_conf(s3_config);
-    client
-}
-
 fn get_patirion_list_form_s3(
     rt: &Runtime,
     client: &Client,
@@ -373,36 +342,6 @@ async fn read_str_value(
     String::from(value)
 }
 
-fn release_lock(running_threads: &Arc<AtomicUsize>) {
-    running_threads.fetch_sub(1, Ordering::SeqCst);
-}
-
-fn await_thread_availability(running_threads: &Arc<AtomicUsize>) {
-    loop {
-        let prev_val = running_threads.load(Ordering::Acquire);
-        if prev_val > *NUN_S3_MAX_INFLIGHT_REQUESTS {
-            log::debug!("Too many threads running, waiting for a slot to open");
-            thread::sleep(std::time::Duration::from_millis(10));
-            continue;
-        } else {
-            log::debug!("Thread running await: {}", prev_val);
-        }
-        match running_threads.compare_exchange(
-            prev_val,
-            prev_val + 1,
-            Ordering::Acquire,
-            Ordering::Acquire,
-        ) {
-            Ok(_) => break,
-            Err(_) => {
-                log::debug!("Thread failed to update the counter : {}", prev_val);
-                thread::sleep(std::time::Duration::from_millis(10));
-                continue;
-            }
-        }
-    }
-}
-
 fn error_if_error<T, E>(a: Result<T, E>, b: Result<T, E>) -> Result<T, E> {
     if let Err(_) = a {
         return a;
@@ -432,6 +371,26 @@ where
     }
 }
 
+impl StorageBackend for S3PartitionStorage {
+    fn load_all_dbs(&self, dbs: &Arc<Databases>) {
+        S3PartitionStorage::load_all_dbs_from_cloud(dbs)
+    }
+
+    fn store_db(&self, db: &Database, db_name: &str, reclame_space: bool) -> u32 {
+        S3PartitionStorage::storage_data_on_cloud(db, reclame_space, &db_name.to_string())
+    }
+
+    fn delete_db(&self, db_name: &str) -> Result<(), String> {
+        delete_db_from_s3(db_name)
+    }
+
+    fn list_dbs(&self) -> Vec<String> {
+        let rt = Runtime::new().unwrap();
+        let objects = list_s3_objects(&rt, &build_s3_client(), NUN_S3_READ_PREFIX.as_str());
+        db_names_from_s3_objects(&objects, NUN_S3_READ_PREFIX.as_str(), ".nun")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use core::time;